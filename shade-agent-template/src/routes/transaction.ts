@@ -41,6 +41,8 @@ app.get("/", async (c) => {
         payload: uint8ArrayToHex(hashesToSign[0]),
         key_type: "Ecdsa",
       },
+      // Must cover the contract's sign deposit, anything above it is refunded
+      deposit: "1",
     });
     console.log("signRes", signRes);
 
@@ -0,0 +1,595 @@
+//! Typed parser for the NEP-297 events logged by the Shade Agent contract template.
+//!
+//! The contract logs every state change as `EVENT_JSON:{...}`. [`parse_log`] turns one of
+//! those logs back into a [`ContractEvent`], so indexers can follow the contract without
+//! matching on raw JSON.
+//!
+//! The types mirror `Event` in the contract's `src/internal/events.rs` and have to be
+//! updated together with it. `U64` fields, which the contract writes as strings, are
+//! parsed into `u64`.
+
+use near_account_id::AccountId;
+use serde::{Deserialize, Serialize};
+use serde_with::{DisplayFromStr, serde_as};
+use shade_attestation::{measurements::FullMeasurementsHex, tcb_info::HexBytes};
+
+/// The NEP-297 `standard` the contract logs its events under.
+pub const EVENT_STANDARD: &str = "shade-contract-template";
+
+/// Prefix NEP-297 puts in front of the event JSON in a log.
+pub const EVENT_LOG_PREFIX: &str = "EVENT_JSON:";
+
+pub type Ppid = HexBytes<16>;
+
+pub type MeasurementsId = u32;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ParseError {
+    #[error("invalid event JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("expected exactly one data entry, found {0}")]
+    DataEntries(usize),
+}
+
+/// An event together with the NEP-297 envelope it was logged in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventLog {
+    pub standard: String,
+    pub version: String,
+    pub event: ContractEvent,
+}
+
+// The contract serializes these enums with their Rust variant names
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AgentRemovalReason {
+    ManualRemoval,
+    ExpiredAttestation,
+    InvalidMeasurements,
+    MeasurementsSunset,
+    InvalidPpid,
+    RevokedPpid(PpidRevocationReason),
+    ExpiredPpid,
+    InvalidMeasurementsPpidPairing,
+    NotWhitelistedForLocal,
+    StorageUnregistered,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PpidRevocationReason {
+    Compromised,
+    Decommissioned,
+    ProviderChange,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Role {
+    MeasurementAdmin,
+    PpidAdmin,
+    AgentAdmin,
+    Upgrader,
+    Pauser,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PausableFeature {
+    Registration,
+    Signing,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SignatureScheme {
+    Secp256k1,
+    Ed25519,
+    V2Secp256k1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PayloadFormat {
+    Ecdsa,
+    Eddsa,
+}
+
+/// The MPC domain a signature scheme is signed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignatureDomain {
+    pub domain_id: u64,
+    pub payload_format: PayloadFormat,
+}
+
+/// What agents on a set of measurements can sign as EVM transactions.
+/// Addresses and function selectors are hex without a `0x` prefix.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EvmPolicy {
+    pub chain_ids: Vec<u64>,
+    pub allowed_destinations: Vec<HexBytes<20>>,
+    #[serde_as(as = "DisplayFromStr")]
+    pub max_value: u128,
+    pub allowed_selectors: Vec<HexBytes<4>>,
+}
+
+/// Where agents on a set of measurements can send bitcoin.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BitcoinPolicy {
+    pub allowed_outputs: Vec<BitcoinOutputRule>,
+}
+
+/// An output script, in hex, and the highest amount in satoshis a single output to it can have.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BitcoinOutputRule {
+    pub script_pubkey: String,
+    #[serde_as(as = "DisplayFromStr")]
+    pub max_amount: u64,
+}
+
+/// What agents on a set of measurements can sign as Solana messages. Addresses are base58.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SolanaPolicy {
+    pub fee_payers: Vec<String>,
+    pub allowed_programs: Vec<SolanaProgramRule>,
+    pub allowed_transfer_destinations: Vec<String>,
+    #[serde_as(as = "DisplayFromStr")]
+    pub max_transfer_lamports: u64,
+}
+
+/// A program and the hex prefixes its instruction data has to start with, empty for any.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SolanaProgramRule {
+    pub program_id: String,
+    pub instruction_prefixes: Vec<String>,
+}
+
+/// Asset whose transfers rate limit budgets count, in its smallest unit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Asset {
+    Evm { chain_id: u64 },
+    Bitcoin,
+    Solana,
+}
+
+/// Limits on signature requests per rolling window.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimits {
+    #[serde_as(as = "DisplayFromStr")]
+    pub window_ms: u64,
+    pub max_signatures_per_agent: Option<u32>,
+    pub max_signatures: Option<u32>,
+    pub budgets: Vec<AssetBudget>,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AssetBudget {
+    pub asset: Asset,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub max_per_agent: Option<u128>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub max_total: Option<u128>,
+}
+
+/// The rate limit a signature request went over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RateLimit {
+    AgentSignatures,
+    Signatures,
+    AgentBudget(Asset),
+    Budget(Asset),
+}
+
+/// Derivation paths agents can sign with and the namespace their paths are prefixed with.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathPolicy {
+    pub allowed_paths: Vec<PathRule>,
+    pub namespace: Option<PathNamespace>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PathRule {
+    Exact(String),
+    Prefix(String),
+    Pattern(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PathNamespace {
+    Account,
+    Measurements,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AffinePoint {
+    pub affine_point: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Scalar {
+    pub scalar: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EcdsaSignature {
+    pub big_r: AffinePoint,
+    pub s: Scalar,
+    pub recovery_id: u8,
+}
+
+/// A signature as the MPC contract returns it, tagged with its `scheme`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "scheme")]
+pub enum SignatureResponse {
+    Secp256k1(EcdsaSignature),
+    Ed25519 { signature: Vec<u8> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SignatureFailureReason {
+    MpcCallFailed,
+    InvalidResponse(String),
+}
+
+/// An event logged by the contract. Code hashes are base58 strings.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", content = "data")]
+#[serde(rename_all = "snake_case")]
+pub enum ContractEvent {
+    AgentRegistered {
+        account_id: AccountId,
+        measurements_id: MeasurementsId,
+        measurements: FullMeasurementsHex,
+        ppid: Ppid,
+        advisory_ids_truncated: Vec<String>,
+        number_of_advisory_ids: u16,
+        #[serde_as(as = "DisplayFromStr")]
+        current_time_ms: u64,
+        #[serde_as(as = "DisplayFromStr")]
+        valid_until_ms: u64,
+    },
+    AgentRemoved {
+        account_id: AccountId,
+        reasons: Vec<AgentRemovalReason>,
+    },
+    AgentsPruned {
+        account_id: AccountId,
+        removed: u32,
+        // In yoctoNEAR
+        #[serde_as(as = "DisplayFromStr")]
+        reward: u128,
+    },
+    StorageDeposited {
+        account_id: AccountId,
+        // In yoctoNEAR
+        #[serde_as(as = "DisplayFromStr")]
+        amount: u128,
+    },
+    StorageWithdrawn {
+        account_id: AccountId,
+        // In yoctoNEAR
+        #[serde_as(as = "DisplayFromStr")]
+        amount: u128,
+    },
+    StorageDepositRefunded {
+        account_id: AccountId,
+        // In yoctoNEAR
+        #[serde_as(as = "DisplayFromStr")]
+        amount: u128,
+    },
+    PruneRewardUpdated {
+        old_prune_reward_bps: u16,
+        new_prune_reward_bps: u16,
+    },
+    AttestationExpirationTimeUpdated {
+        #[serde_as(as = "DisplayFromStr")]
+        old_attestation_expiration_time_ms: u64,
+        #[serde_as(as = "DisplayFromStr")]
+        new_attestation_expiration_time_ms: u64,
+    },
+    OwnerUpdated {
+        old_owner_id: AccountId,
+        new_owner_id: AccountId,
+    },
+    MpcContractUpdated {
+        old_mpc_contract_id: AccountId,
+        new_mpc_contract_id: AccountId,
+    },
+    SignConfigUpdated {
+        old_sign_gas_tgas: u64,
+        new_sign_gas_tgas: u64,
+        // In yoctoNEAR
+        #[serde_as(as = "DisplayFromStr")]
+        old_sign_deposit: u128,
+        #[serde_as(as = "DisplayFromStr")]
+        new_sign_deposit: u128,
+    },
+    // domain is None when the scheme was removed
+    SignatureDomainSet {
+        scheme: SignatureScheme,
+        domain: Option<SignatureDomain>,
+    },
+    // domain_id is None when confidential key derivation was stopped
+    CkdDomainSet {
+        domain_id: Option<u64>,
+    },
+    // policy is None when the policy was removed
+    EvmPolicySet {
+        measurements_id: MeasurementsId,
+        policy: Option<EvmPolicy>,
+    },
+    // policy is None when the policy was removed
+    BitcoinPolicySet {
+        measurements_id: MeasurementsId,
+        policy: Option<BitcoinPolicy>,
+    },
+    // policy is None when the policy was removed
+    SolanaPolicySet {
+        measurements_id: MeasurementsId,
+        policy: Option<SolanaPolicy>,
+    },
+    // policy is None when the policy was removed
+    PathPolicySet {
+        measurements_id: MeasurementsId,
+        policy: Option<PathPolicy>,
+    },
+    // policy is None when the policy was removed
+    AgentPathPolicySet {
+        account_id: AccountId,
+        policy: Option<PathPolicy>,
+    },
+    // rate_limits is None when the limits were removed
+    RateLimitsUpdated {
+        rate_limits: Option<RateLimits>,
+    },
+    RateLimitExceeded {
+        account_id: AccountId,
+        limit: RateLimit,
+    },
+    MeasurementsApproved {
+        measurements_id: MeasurementsId,
+        measurements: FullMeasurementsHex,
+        label: String,
+        source: String,
+        approved_by: AccountId,
+    },
+    MeasurementsMetadataUpdated {
+        measurements_id: MeasurementsId,
+        label: String,
+        source: String,
+    },
+    MeasurementsRemoved {
+        measurements_id: MeasurementsId,
+        measurements: FullMeasurementsHex,
+    },
+    MeasurementsAgentQuotaSet {
+        measurements_id: MeasurementsId,
+        max_agents: Option<u32>,
+    },
+    MeasurementsPpidGroupSet {
+        measurements_id: MeasurementsId,
+        ppid_group: Option<String>,
+    },
+    PpidGroupSet {
+        name: String,
+        ppids: Vec<Ppid>,
+    },
+    PpidGroupRemoved {
+        name: String,
+    },
+    PpidMeasurementsScopeSet {
+        ppid: Ppid,
+        measurements_ids: Option<Vec<MeasurementsId>>,
+    },
+    PpidsApproved {
+        ppids: Vec<Ppid>,
+        operator: String,
+        region: String,
+        #[serde_as(as = "Option<DisplayFromStr>")]
+        expires_at_ms: Option<u64>,
+    },
+    PpidMetadataUpdated {
+        ppid: Ppid,
+        operator: String,
+        region: String,
+        #[serde_as(as = "Option<DisplayFromStr>")]
+        expires_at_ms: Option<u64>,
+    },
+    PpidsRevoked {
+        ppids: Vec<Ppid>,
+        reason: Option<PpidRevocationReason>,
+    },
+    MeasurementsSunsetScheduled {
+        measurements_id: MeasurementsId,
+        #[serde_as(as = "DisplayFromStr")]
+        sunset_at_ms: u64,
+    },
+    MeasurementsSunsetCancelled {
+        measurements_id: MeasurementsId,
+    },
+    MeasurementsSunset {
+        measurements_id: MeasurementsId,
+        #[serde_as(as = "DisplayFromStr")]
+        sunset_at_ms: u64,
+        #[serde_as(as = "DisplayFromStr")]
+        current_time_ms: u64,
+    },
+    ProposalCreated {
+        #[serde_as(as = "DisplayFromStr")]
+        proposal_id: u64,
+        proposer: AccountId,
+        action: String,
+        #[serde_as(as = "DisplayFromStr")]
+        expires_at_ms: u64,
+    },
+    ProposalConfirmed {
+        #[serde_as(as = "DisplayFromStr")]
+        proposal_id: u64,
+        account_id: AccountId,
+        confirmations: u32,
+        threshold: u32,
+    },
+    ProposalExecuted {
+        #[serde_as(as = "DisplayFromStr")]
+        proposal_id: u64,
+        action: String,
+    },
+    ProposalCancelled {
+        #[serde_as(as = "DisplayFromStr")]
+        proposal_id: u64,
+        account_id: AccountId,
+    },
+    ActionQueued {
+        #[serde_as(as = "DisplayFromStr")]
+        action_id: u64,
+        action: String,
+        queued_by: AccountId,
+        #[serde_as(as = "DisplayFromStr")]
+        executable_at_ms: u64,
+    },
+    QueuedActionExecuted {
+        #[serde_as(as = "DisplayFromStr")]
+        action_id: u64,
+        action: String,
+    },
+    QueuedActionCancelled {
+        #[serde_as(as = "DisplayFromStr")]
+        action_id: u64,
+        action: String,
+    },
+    AgentWhitelistedForLocal {
+        account_id: AccountId,
+    },
+    AgentRemovedFromWhitelistForLocal {
+        account_id: AccountId,
+    },
+    MultisigUpdated {
+        owners: Vec<AccountId>,
+        threshold: u32,
+        #[serde_as(as = "DisplayFromStr")]
+        proposal_lifetime_ms: u64,
+    },
+    MultisigDisabled {
+        account_id: AccountId,
+    },
+    TimelockDelayUpdated {
+        #[serde_as(as = "DisplayFromStr")]
+        old_timelock_delay_ms: u64,
+        #[serde_as(as = "DisplayFromStr")]
+        new_timelock_delay_ms: u64,
+    },
+    Paused {
+        feature: PausableFeature,
+        account_id: AccountId,
+    },
+    Unpaused {
+        feature: PausableFeature,
+        account_id: AccountId,
+    },
+    RoleGranted {
+        account_id: AccountId,
+        role: Role,
+    },
+    RoleRevoked {
+        account_id: AccountId,
+        role: Role,
+    },
+    StateMigrated {
+        from_version: u32,
+        to_version: u32,
+    },
+    AgentsMigrated {
+        migrated: u32,
+        remaining: u32,
+    },
+    CodeUpgradeApproved {
+        code_hash: String,
+        migrate_gas_tgas: u64,
+        approved_by: AccountId,
+    },
+    CodeUpgradeCancelled {
+        code_hash: String,
+    },
+    ContractUpgraded {
+        code_hash: String,
+        previous_code_hash: Option<String>,
+    },
+    ContractUpgradeFailed {
+        code_hash: String,
+    },
+    SignatureProduced {
+        account_id: AccountId,
+        path: String,
+        payload_hash: String,
+        signature: SignatureResponse,
+    },
+    SignatureFailed {
+        account_id: AccountId,
+        path: String,
+        payload_hash: String,
+        reason: SignatureFailureReason,
+    },
+    AppPrivateKeyProduced {
+        account_id: AccountId,
+        app_public_key: String,
+    },
+    AppPrivateKeyFailed {
+        account_id: AccountId,
+        app_public_key: String,
+        reason: SignatureFailureReason,
+    },
+}
+
+// NEP-297 envelope, the contract always logs a single data entry
+#[derive(Deserialize)]
+struct RawEventLog {
+    standard: String,
+    version: String,
+    event: String,
+    #[serde(default)]
+    data: Vec<serde_json::Value>,
+}
+
+/// Parse a single log line.
+///
+/// Returns `Ok(None)` for logs that are not NEP-297 events or belong to another standard,
+/// and an error for events of this contract that do not match [`ContractEvent`].
+pub fn parse_log(log: &str) -> Result<Option<EventLog>, ParseError> {
+    let Some(json) = log.strip_prefix(EVENT_LOG_PREFIX) else {
+        return Ok(None);
+    };
+    let raw: RawEventLog = serde_json::from_str(json)?;
+    if raw.standard != EVENT_STANDARD {
+        return Ok(None);
+    }
+
+    let [data]: [serde_json::Value; 1] = raw
+        .data
+        .try_into()
+        .map_err(|data: Vec<_>| ParseError::DataEntries(data.len()))?;
+    let event = serde_json::from_value(serde_json::json!({
+        "event": raw.event,
+        "data": data,
+    }))?;
+
+    Ok(Some(EventLog {
+        standard: raw.standard,
+        version: raw.version,
+        event,
+    }))
+}
+
+/// Parse the events of this contract out of a list of logs, skipping any other logs.
+pub fn parse_logs<I, S>(logs: I) -> Result<Vec<ContractEvent>, ParseError>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let mut events = Vec::new();
+    for log in logs {
+        if let Some(event_log) = parse_log(log.as_ref())? {
+            events.push(event_log.event);
+        }
+    }
+    Ok(events)
+}
@@ -0,0 +1,139 @@
+use shade_attestation::tcb_info::HexBytes;
+use shade_contract_events::{
+    AffinePoint, AgentRemovalReason, ContractEvent, EVENT_STANDARD, EcdsaSignature, ParseError,
+    PpidRevocationReason, Role, Scalar, SignatureResponse, parse_log, parse_logs,
+};
+
+fn event_log(event: &str, data: &str) -> String {
+    format!(
+        r#"EVENT_JSON:{{"standard":"{EVENT_STANDARD}","version":"1.0.0","event":"{event}","data":[{data}]}}"#
+    )
+}
+
+#[test]
+fn test_parse_owner_updated() {
+    let log = event_log(
+        "owner_updated",
+        r#"{"old_owner_id":"owner.near","new_owner_id":"new-owner.near"}"#,
+    );
+    let event_log = parse_log(&log).unwrap().unwrap();
+    assert_eq!(event_log.standard, EVENT_STANDARD);
+    assert_eq!(event_log.version, "1.0.0");
+    assert_eq!(
+        event_log.event,
+        ContractEvent::OwnerUpdated {
+            old_owner_id: "owner.near".parse().unwrap(),
+            new_owner_id: "new-owner.near".parse().unwrap(),
+        }
+    );
+}
+
+#[test]
+fn test_parse_u64_strings() {
+    let log = event_log(
+        "timelock_delay_updated",
+        r#"{"old_timelock_delay_ms":"0","new_timelock_delay_ms":"18446744073709551615"}"#,
+    );
+    assert_eq!(
+        parse_log(&log).unwrap().unwrap().event,
+        ContractEvent::TimelockDelayUpdated {
+            old_timelock_delay_ms: 0,
+            new_timelock_delay_ms: u64::MAX,
+        }
+    );
+
+    let log = event_log(
+        "ppids_approved",
+        r#"{"ppids":["00000000000000000000000000000001"],"operator":"op","region":"eu","expires_at_ms":null}"#,
+    );
+    let mut ppid = [0; 16];
+    ppid[15] = 1;
+    assert_eq!(
+        parse_log(&log).unwrap().unwrap().event,
+        ContractEvent::PpidsApproved {
+            ppids: vec![HexBytes::from(ppid)],
+            operator: "op".to_string(),
+            region: "eu".to_string(),
+            expires_at_ms: None,
+        }
+    );
+}
+
+#[test]
+fn test_parse_removal_reasons() {
+    let log = event_log(
+        "agent_removed",
+        r#"{"account_id":"agent.near","reasons":["InvalidMeasurements",{"RevokedPpid":"Compromised"},"InvalidPpid"]}"#,
+    );
+    assert_eq!(
+        parse_log(&log).unwrap().unwrap().event,
+        ContractEvent::AgentRemoved {
+            account_id: "agent.near".parse().unwrap(),
+            reasons: vec![
+                AgentRemovalReason::InvalidMeasurements,
+                AgentRemovalReason::RevokedPpid(PpidRevocationReason::Compromised),
+                AgentRemovalReason::InvalidPpid,
+            ],
+        }
+    );
+}
+
+#[test]
+fn test_parse_signature_produced() {
+    let log = event_log(
+        "signature_produced",
+        r#"{"account_id":"agent.near","path":"eth-1","payload_hash":"ab","signature":{"scheme":"Secp256k1","big_r":{"affine_point":"02AB"},"s":{"scalar":"CD"},"recovery_id":1}}"#,
+    );
+    assert_eq!(
+        parse_log(&log).unwrap().unwrap().event,
+        ContractEvent::SignatureProduced {
+            account_id: "agent.near".parse().unwrap(),
+            path: "eth-1".to_string(),
+            payload_hash: "ab".to_string(),
+            signature: SignatureResponse::Secp256k1(EcdsaSignature {
+                big_r: AffinePoint {
+                    affine_point: "02AB".to_string(),
+                },
+                s: Scalar {
+                    scalar: "CD".to_string(),
+                },
+                recovery_id: 1,
+            }),
+        }
+    );
+}
+
+#[test]
+fn test_parse_logs_skips_other_logs() {
+    let logs = vec![
+        "plain log line".to_string(),
+        r#"EVENT_JSON:{"standard":"nep141","version":"1.0.0","event":"ft_transfer","data":[{}]}"#
+            .to_string(),
+        event_log(
+            "role_granted",
+            r#"{"account_id":"admin.near","role":"Upgrader"}"#,
+        ),
+    ];
+    assert_eq!(
+        parse_logs(&logs).unwrap(),
+        vec![ContractEvent::RoleGranted {
+            account_id: "admin.near".parse().unwrap(),
+            role: Role::Upgrader,
+        }]
+    );
+}
+
+#[test]
+fn test_parse_invalid_events() {
+    // Unknown event of this contract
+    let log = event_log("not_an_event", "{}");
+    assert!(matches!(parse_log(&log), Err(ParseError::Json(_))));
+
+    // Missing field
+    let log = event_log("owner_updated", r#"{"old_owner_id":"owner.near"}"#);
+    assert!(matches!(parse_log(&log), Err(ParseError::Json(_))));
+
+    // More than one data entry
+    let log = event_log("paused", r#"{},{}"#);
+    assert!(matches!(parse_log(&log), Err(ParseError::DataEntries(2))));
+}
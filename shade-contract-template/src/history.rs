@@ -0,0 +1,115 @@
+use crate::*;
+
+impl Contract {
+    // Append a change to the allowlist history and index it under its measurements or PPID
+    pub(crate) fn record_allowlist_change(
+        &mut self,
+        change: AllowlistChange,
+        changed_by: AccountId,
+    ) {
+        let index = self.allowlist_history.len();
+        match &change {
+            AllowlistChange::MeasurementsApproved { measurements, .. }
+            | AllowlistChange::MeasurementsRemoved { measurements, .. }
+            | AllowlistChange::MeasurementsSunset { measurements, .. } => self
+                .measurements_history
+                .entry(measurements.clone())
+                .or_default()
+                .push(index),
+            AllowlistChange::PpidApproved { ppid, .. }
+            | AllowlistChange::PpidExpiryUpdated { ppid, .. }
+            | AllowlistChange::PpidRevoked { ppid, .. } => self
+                .ppid_history
+                .entry(ppid.clone())
+                .or_default()
+                .push(index),
+        }
+        self.allowlist_history.push(AllowlistHistoryEntry {
+            change,
+            changed_by,
+            changed_at_ms: block_timestamp_ms(),
+        });
+    }
+
+    // The history entries at the given positions, oldest first
+    pub(crate) fn allowlist_history_entries<'a>(
+        &'a self,
+        indexes: Option<&'a Vec<u32>>,
+    ) -> impl Iterator<Item = (u32, &'a AllowlistHistoryEntry)> + 'a {
+        indexes.into_iter().flatten().map(|index| {
+            let entry = self
+                .allowlist_history
+                .get(*index)
+                .expect("Allowlist history entry not found");
+            (*index, entry)
+        })
+    }
+
+    // Whether a set of measurements was approved and not yet sunset at timestamp_ms
+    pub(crate) fn measurements_approved_at(
+        &self,
+        measurements: &FullMeasurementsHex,
+        timestamp_ms: u64,
+    ) -> bool {
+        let mut approved = false;
+        for (_, entry) in
+            self.allowlist_history_entries(self.measurements_history.get(measurements))
+        {
+            match &entry.change {
+                AllowlistChange::MeasurementsApproved { .. }
+                    if entry.changed_at_ms <= timestamp_ms =>
+                {
+                    approved = true
+                }
+                AllowlistChange::MeasurementsRemoved { .. }
+                    if entry.changed_at_ms <= timestamp_ms =>
+                {
+                    approved = false
+                }
+                // A sunset takes effect at its sunset time, before it is executed and recorded
+                AllowlistChange::MeasurementsSunset { sunset_at_ms, .. }
+                    if sunset_at_ms.0 <= timestamp_ms =>
+                {
+                    approved = false
+                }
+                _ => {}
+            }
+        }
+
+        // The current approval may have a sunset that has passed but has not been executed yet
+        approved
+            && self
+                .measurements_ids
+                .get(measurements)
+                .and_then(|measurements_id| self.approved_measurements.get(measurements_id))
+                .and_then(|approved| approved.sunset_at_ms)
+                .is_none_or(|sunset_at_ms| sunset_at_ms > timestamp_ms)
+    }
+
+    // Whether a PPID was approved and not yet expired at timestamp_ms
+    pub(crate) fn ppid_approved_at(&self, ppid: &Ppid, timestamp_ms: u64) -> bool {
+        let mut approved = false;
+        let mut expires_at_ms = None;
+        for (_, entry) in self.allowlist_history_entries(self.ppid_history.get(ppid)) {
+            if entry.changed_at_ms > timestamp_ms {
+                break;
+            }
+            match &entry.change {
+                AllowlistChange::PpidApproved {
+                    expires_at_ms: expiry,
+                    ..
+                } => {
+                    approved = true;
+                    expires_at_ms = *expiry;
+                }
+                AllowlistChange::PpidExpiryUpdated {
+                    expires_at_ms: expiry,
+                    ..
+                } => expires_at_ms = *expiry,
+                AllowlistChange::PpidRevoked { .. } => approved = false,
+                _ => {}
+            }
+        }
+        approved && expires_at_ms.is_none_or(|expires_at_ms| expires_at_ms.0 > timestamp_ms)
+    }
+}
@@ -4,8 +4,8 @@ impl Contract {
     pub(crate) fn verify_attestation(
         &self,
         attestation: DstackAttestation,
-    ) -> (FullMeasurementsHex, Ppid, Vec<String>) {
-        let result: (FullMeasurementsHex, Ppid, Vec<String>) = match self.requires_tee {
+    ) -> (MeasurementsId, Ppid, Vec<String>) {
+        let result: (MeasurementsId, Ppid, Vec<String>) = match self.requires_tee {
             true => {
                 // Verify account_ID is an implicit account ID
                 let account_id_str = env::predecessor_account_id().to_string();
@@ -27,13 +27,17 @@ impl Contract {
                 // Convert measurements to Vec and convert to FullMeasurements
                 let expected_measurements: Vec<FullMeasurements> = self
                     .approved_measurements
-                    .iter()
-                    .cloned()
-                    .map(Into::into)
+                    .values()
+                    .map(|approved| approved.measurements.clone().into())
                     .collect();
 
-                // Convert PPIDs to Vec
-                let approved_ppids: Vec<Ppid> = self.approved_ppids.iter().cloned().collect();
+                // Convert PPIDs that have not expired to Vec
+                let approved_ppids: Vec<Ppid> = self
+                    .approved_ppids
+                    .iter()
+                    .filter(|(_, approved)| !approved.is_expired())
+                    .map(|(ppid, _)| ppid.clone())
+                    .collect();
 
                 // Verify the attestation
                 match attestation.verify(
@@ -46,7 +50,15 @@ impl Contract {
                         measurements,
                         ppid,
                         advisory_ids,
-                    }) => (measurements.into(), ppid, advisory_ids),
+                    }) => {
+                        let measurements: FullMeasurementsHex = measurements.into();
+                        let measurements_id = self
+                            .measurements_ids
+                            .get(&measurements)
+                            .copied()
+                            .expect("Verified measurements are not approved");
+                        (measurements_id, ppid, advisory_ids)
+                    }
                     Err(e) => {
                         panic!("Attestation verification failed: {}", e);
                     }
@@ -59,18 +71,26 @@ impl Contract {
                         .contains(&env::predecessor_account_id()),
                     "Agent needs to be whitelisted for local mode"
                 );
-                let default_measurements = create_mock_full_measurements_hex();
+                let default_measurements_id = self
+                    .measurements_ids
+                    .get(&create_mock_full_measurements_hex())
+                    .copied()
+                    .expect("Default measurements must be approved for local mode");
                 require!(
-                    self.approved_measurements.contains(&default_measurements),
-                    "Default measurements must be approved for local mode"
-                );
-                require!(
-                    self.approved_ppids.contains(&Ppid::default()),
+                    self.approved_ppids
+                        .get(&Ppid::default())
+                        .is_some_and(|approved| !approved.is_expired()),
                     "Default PPID must be approved for local mode"
                 );
-                (default_measurements, Ppid::default(), Vec::new())
+                (default_measurements_id, Ppid::default(), Vec::new())
             }
         };
+
+        // Check the measurements are allowed to run on this PPID
+        require!(
+            self.is_measurements_ppid_pairing_allowed(result.0, &result.1),
+            "Measurements are not allowed to run on this PPID"
+        );
         result
     }
 }
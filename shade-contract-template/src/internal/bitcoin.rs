@@ -0,0 +1,327 @@
+use crate::*;
+
+const SIGHASH_ALL: u32 = 1;
+const P2WPKH_LEN: usize = 22;
+const P2TR_LEN: usize = 34;
+
+// Where agents on a set of measurements can send bitcoin with request_bitcoin_transaction_signature
+#[near(serializers = [borsh, json])]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BitcoinPolicy {
+    pub allowed_outputs: Vec<BitcoinOutputRule>,
+}
+
+#[near(serializers = [borsh, json])]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BitcoinOutputRule {
+    // Hex of the output script an address stands for
+    pub script_pubkey: String,
+    // Highest amount in satoshis a single output to the script can have
+    pub max_amount: U64,
+}
+
+// The output spent by the input being signed, BIP-143 commits to its script and amount
+#[near(serializers = [json])]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BitcoinSpentOutput {
+    pub script_pubkey: String,
+    pub amount: U64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct BitcoinInput {
+    pub txid: [u8; 32],
+    pub vout: u32,
+    pub sequence: u32,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct BitcoinOutput {
+    pub amount: u64,
+    pub script_pubkey: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct BitcoinTransaction {
+    pub version: u32,
+    pub inputs: Vec<BitcoinInput>,
+    pub outputs: Vec<BitcoinOutput>,
+    pub lock_time: u32,
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() < len {
+            return Err("Unexpected end of transaction".to_string());
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    // CompactSize, which has to use the shortest encoding
+    fn compact_size(&mut self) -> Result<u64, String> {
+        let (value, min) = match self.take(1)?[0] {
+            0xfd => (
+                u64::from(u16::from_le_bytes(self.take(2)?.try_into().unwrap())),
+                0xfd,
+            ),
+            0xfe => (u64::from(self.u32()?), 0x1_0000),
+            0xff => (self.u64()?, 0x1_0000_0000),
+            byte => return Ok(u64::from(byte)),
+        };
+        if value < min {
+            return Err("Non-canonical CompactSize".to_string());
+        }
+        Ok(value)
+    }
+
+    fn var_bytes(&mut self) -> Result<&'a [u8], String> {
+        let len = self.compact_size()?;
+        self.take(usize::try_from(len).map_err(|_| "Unexpected end of transaction")?)
+    }
+}
+
+// Decode an unsigned transaction in the serialization without witnesses
+pub(crate) fn decode_bitcoin_transaction(bytes: &[u8]) -> Result<BitcoinTransaction, String> {
+    let mut reader = Reader { bytes };
+    let version = reader.u32()?;
+
+    let input_count = reader.compact_size()?;
+    if input_count == 0 {
+        return Err("Expected an unsigned transaction without witnesses".to_string());
+    }
+    let mut inputs = Vec::new();
+    for _ in 0..input_count {
+        let txid = reader.take(32)?.try_into().unwrap();
+        let vout = reader.u32()?;
+        if !reader.var_bytes()?.is_empty() {
+            return Err("Expected empty scriptSigs in an unsigned transaction".to_string());
+        }
+        inputs.push(BitcoinInput {
+            txid,
+            vout,
+            sequence: reader.u32()?,
+        });
+    }
+
+    let output_count = reader.compact_size()?;
+    let mut outputs = Vec::new();
+    for _ in 0..output_count {
+        outputs.push(BitcoinOutput {
+            amount: reader.u64()?,
+            script_pubkey: reader.var_bytes()?.to_vec(),
+        });
+    }
+
+    let lock_time = reader.u32()?;
+    if !reader.bytes.is_empty() {
+        return Err("Trailing bytes after transaction".to_string());
+    }
+    Ok(BitcoinTransaction {
+        version,
+        inputs,
+        outputs,
+        lock_time,
+    })
+}
+
+fn double_sha256(bytes: &[u8]) -> Vec<u8> {
+    env::sha256(env::sha256(bytes))
+}
+
+fn write_compact_size(buffer: &mut Vec<u8>, len: usize) {
+    match len {
+        0..=0xfc => buffer.push(len as u8),
+        0xfd..=0xffff => {
+            buffer.push(0xfd);
+            buffer.extend_from_slice(&(len as u16).to_le_bytes());
+        }
+        _ => {
+            buffer.push(0xfe);
+            buffer.extend_from_slice(&(len as u32).to_le_bytes());
+        }
+    }
+}
+
+// BIP-143 SIGHASH_ALL digest of a P2WPKH input spending spent_script with amount
+// There is no BIP-341 sighash, Taproot needs Schnorr signatures and the MPC network only signs ECDSA and EdDSA
+pub(crate) fn bip143_sighash(
+    transaction: &BitcoinTransaction,
+    input_index: usize,
+    spent_script: &[u8],
+    amount: u64,
+) -> Result<[u8; 32], String> {
+    let input = transaction
+        .inputs
+        .get(input_index)
+        .ok_or_else(|| format!("Transaction has no input {input_index}"))?;
+    let public_key_hash = match spent_script {
+        [0x00, 0x14, public_key_hash @ ..] if spent_script.len() == P2WPKH_LEN => public_key_hash,
+        [0x51, 0x20, ..] if spent_script.len() == P2TR_LEN => {
+            return Err(
+                "Taproot inputs need Schnorr signatures, which the MPC network does not produce"
+                    .to_string(),
+            );
+        }
+        _ => return Err("Only P2WPKH inputs can be signed".to_string()),
+    };
+
+    let mut prevouts = Vec::new();
+    let mut sequences = Vec::new();
+    for input in &transaction.inputs {
+        prevouts.extend_from_slice(&input.txid);
+        prevouts.extend_from_slice(&input.vout.to_le_bytes());
+        sequences.extend_from_slice(&input.sequence.to_le_bytes());
+    }
+    let mut outputs = Vec::new();
+    for output in &transaction.outputs {
+        outputs.extend_from_slice(&output.amount.to_le_bytes());
+        write_compact_size(&mut outputs, output.script_pubkey.len());
+        outputs.extend_from_slice(&output.script_pubkey);
+    }
+
+    let mut preimage = Vec::new();
+    preimage.extend_from_slice(&transaction.version.to_le_bytes());
+    preimage.extend_from_slice(&double_sha256(&prevouts));
+    preimage.extend_from_slice(&double_sha256(&sequences));
+    preimage.extend_from_slice(&input.txid);
+    preimage.extend_from_slice(&input.vout.to_le_bytes());
+    // The script code of P2WPKH is the P2PKH script of the key hash
+    preimage.extend_from_slice(&[0x19, 0x76, 0xa9, 0x14]);
+    preimage.extend_from_slice(public_key_hash);
+    preimage.extend_from_slice(&[0x88, 0xac]);
+    preimage.extend_from_slice(&amount.to_le_bytes());
+    preimage.extend_from_slice(&input.sequence.to_le_bytes());
+    preimage.extend_from_slice(&double_sha256(&outputs));
+    preimage.extend_from_slice(&transaction.lock_time.to_le_bytes());
+    preimage.extend_from_slice(&SIGHASH_ALL.to_le_bytes());
+    Ok(double_sha256(&preimage).try_into().unwrap())
+}
+
+impl BitcoinTransaction {
+    // Satoshis the outputs send anywhere but back to the spent script
+    pub(crate) fn sent_amount(&self, spent_script: &[u8]) -> u128 {
+        self.outputs
+            .iter()
+            .filter(|output| output.script_pubkey != spent_script)
+            .map(|output| u128::from(output.amount))
+            .sum()
+    }
+}
+
+impl BitcoinPolicy {
+    // Outputs back to the script of the signed input are change and always allowed,
+    // the signature only verifies if that script is really the one spent
+    pub(crate) fn check(
+        &self,
+        transaction: &BitcoinTransaction,
+        spent_script: &[u8],
+    ) -> Result<(), String> {
+        for (index, output) in transaction.outputs.iter().enumerate() {
+            if output.script_pubkey == spent_script {
+                continue;
+            }
+            let script_pubkey = hex::encode(&output.script_pubkey);
+            let rule = self
+                .allowed_outputs
+                .iter()
+                .find(|rule| rule.script_pubkey.eq_ignore_ascii_case(&script_pubkey))
+                .ok_or_else(|| format!("Output {index} to {script_pubkey} is not allowed"))?;
+            if output.amount > rule.max_amount.0 {
+                return Err(format!(
+                    "Output {index} amount {} is more than the maximum {}",
+                    output.amount, rule.max_amount.0
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Contract {
+    // Check an unsigned transaction against the policy of the caller's measurements and sign the
+    // BIP-143 sighash of one of its inputs, the caller has to be a valid agent
+    pub(crate) fn internal_request_bitcoin_transaction_signature(
+        &mut self,
+        path: String,
+        transaction: String,
+        input_index: u32,
+        spent_output: BitcoinSpentOutput,
+    ) -> Promise {
+        let bytes = hex::decode(&transaction).expect("Transaction must be a hex string");
+        let spent_script =
+            hex::decode(&spent_output.script_pubkey).expect("Script pubkey must be a hex string");
+        let measurements_id = self
+            .agents
+            .get(&env::predecessor_account_id())
+            .expect("Agent not registered")
+            .measurements_id;
+        let policy = self
+            .bitcoin_policies
+            .get(&measurements_id)
+            .expect("No Bitcoin policy for the agent's measurements");
+
+        let transaction =
+            decode_bitcoin_transaction(&bytes).unwrap_or_else(|error| env::panic_str(&error));
+        policy
+            .check(&transaction, &spent_script)
+            .unwrap_or_else(|error| env::panic_str(&error));
+        let sighash = bip143_sighash(
+            &transaction,
+            input_index as usize,
+            &spent_script,
+            spent_output.amount.0,
+        )
+        .unwrap_or_else(|error| env::panic_str(&error));
+
+        let spend = (Asset::Bitcoin, transaction.sent_amount(&spent_script));
+        self.internal_request_signature(
+            path,
+            hex::encode(sighash),
+            SignatureScheme::Secp256k1,
+            Some(spend),
+        )
+    }
+
+    // Set or, with None, remove the Bitcoin policy of a set of measurements
+    pub(crate) fn internal_set_bitcoin_policy(
+        &mut self,
+        measurements_id: MeasurementsId,
+        policy: Option<BitcoinPolicy>,
+    ) {
+        if let Some(policy) = &policy {
+            require!(
+                self.approved_measurements.contains_key(&measurements_id),
+                "Measurements not in approved list"
+            );
+            for rule in &policy.allowed_outputs {
+                require!(
+                    hex::decode(&rule.script_pubkey).is_ok(),
+                    "Script pubkey must be a hex string"
+                );
+            }
+        }
+        Event::BitcoinPolicySet {
+            measurements_id,
+            policy: policy.as_ref(),
+        }
+        .emit();
+        match policy {
+            Some(policy) => self.bitcoin_policies.insert(measurements_id, policy),
+            None => self.bitcoin_policies.remove(&measurements_id),
+        };
+    }
+}
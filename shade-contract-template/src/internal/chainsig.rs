@@ -1,4 +1,6 @@
 use crate::*;
+use internal::ckd::{CKD_SCHEME, CkdRequest};
+use near_sdk::PromiseOrValue;
 
 #[derive(Debug, Serialize)]
 pub enum Payload {
@@ -17,38 +19,455 @@ pub struct SignRequest {
 #[ext_contract(mpc_contract)]
 trait MPCContract {
     fn sign(&self, request: SignRequest);
+    fn request_app_private_key(&self, request: CkdRequest);
+    fn state(&self);
 }
 
-const GAS: Gas = Gas::from_tgas(15);
-const ATTACHED_DEPOSIT: NearToken = NearToken::from_yoctonear(1);
+// Signature scheme of an MPC domain, named like in the MPC contract
+// Signing with a scheme added to the MPC contract later needs a new variant and a contract release
+// Agents can keep passing the key types Ecdsa and Eddsa
+#[near(serializers = [borsh, json])]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SignatureScheme {
+    #[serde(alias = "Ecdsa")]
+    Secp256k1,
+    #[serde(alias = "Eddsa")]
+    Ed25519,
+    V2Secp256k1,
+}
+
+impl SignatureScheme {
+    const ALL: [SignatureScheme; 3] = [
+        SignatureScheme::Secp256k1,
+        SignatureScheme::Ed25519,
+        SignatureScheme::V2Secp256k1,
+    ];
+
+    // Payload format of a domain that sync_signature_domains adds for the scheme
+    fn default_payload_format(self) -> PayloadFormat {
+        match self {
+            SignatureScheme::Secp256k1 | SignatureScheme::V2Secp256k1 => PayloadFormat::Ecdsa,
+            SignatureScheme::Ed25519 => PayloadFormat::Eddsa,
+        }
+    }
+}
+
+// Variant of payload_v2 the MPC contract expects for a domain, also decides how its signatures are decoded
+#[near(serializers = [borsh, json])]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadFormat {
+    Ecdsa,
+    Eddsa,
+}
+
+// MPC domain that signs for a scheme
+#[near(serializers = [borsh, json])]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignatureDomain {
+    pub domain_id: u64,
+    pub payload_format: PayloadFormat,
+}
 
+pub(crate) const CALLBACK_GAS: Gas = Gas::from_tgas(10);
+pub(crate) const FAIL_GAS: Gas = Gas::from_tgas(5);
+const STATE_GAS: Gas = Gas::from_tgas(20);
+const SYNC_DOMAINS_CALLBACK_GAS: Gas = Gas::from_tgas(20);
+
+// Longest MPC contract state sync_signature_domains reads, the state lists every participant
+const MAX_MPC_STATE_LEN: usize = 256 * 1024;
+
+// Longest MPC response the callback reads, a signature is well under this
+const MAX_SIGN_RESPONSE_LEN: usize = 1024;
+
+const COMPRESSED_POINT_LEN: usize = 33;
+const SCALAR_LEN: usize = 32;
+const ED25519_SIGNATURE_LEN: usize = 64;
+
+#[near(serializers = [json])]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AffinePoint {
+    // Hex of the compressed point
+    pub affine_point: String,
+}
+
+#[near(serializers = [json])]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scalar {
+    // Hex of the big-endian scalar
+    pub scalar: String,
+}
+
+#[near(serializers = [json])]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EcdsaSignature {
+    pub big_r: AffinePoint,
+    pub s: Scalar,
+    pub recovery_id: u8,
+}
+
+// Signature returned to the agent, serialized the same way the MPC contract returns it
+#[near(serializers = [json])]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "scheme")]
+pub enum SignatureResponse {
+    Secp256k1(EcdsaSignature),
+    Ed25519 { signature: Vec<u8> },
+}
+
+#[near(serializers = [json])]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SignatureFailureReason {
+    // The sign call failed or timed out before the MPC network responded
+    MpcCallFailed,
+    // The MPC contract responded with something that is not a signature for the key type
+    InvalidResponse(String),
+}
+
+impl SignatureResponse {
+    // Decode an MPC sign response and check it is a well-formed signature for the payload format
+    pub(crate) fn decode(bytes: &[u8], payload_format: PayloadFormat) -> Result<Self, String> {
+        let response: SignatureResponse =
+            serde_json::from_slice(bytes).map_err(|error| error.to_string())?;
+        match (&response, payload_format) {
+            (SignatureResponse::Secp256k1(signature), PayloadFormat::Ecdsa) => {
+                require_hex_len(&signature.big_r.affine_point, COMPRESSED_POINT_LEN, "big_r")?;
+                require_hex_len(&signature.s.scalar, SCALAR_LEN, "s")?;
+                if signature.recovery_id > 3 {
+                    return Err(format!("Invalid recovery_id {}", signature.recovery_id));
+                }
+            }
+            (SignatureResponse::Ed25519 { signature }, PayloadFormat::Eddsa) => {
+                if signature.len() != ED25519_SIGNATURE_LEN {
+                    return Err(format!(
+                        "Expected a {ED25519_SIGNATURE_LEN} byte signature, got {} bytes",
+                        signature.len()
+                    ));
+                }
+            }
+            _ => {
+                return Err(format!(
+                    "Signature scheme does not match payload format {payload_format:?}"
+                ));
+            }
+        }
+        Ok(response)
+    }
+}
+
+fn require_hex_len(value: &str, len: usize, name: &str) -> Result<(), String> {
+    let bytes = hex::decode(value).map_err(|error| format!("Invalid {name}: {error}"))?;
+    if bytes.len() != len {
+        return Err(format!(
+            "Expected {name} of {len} bytes, got {} bytes",
+            bytes.len()
+        ));
+    }
+    Ok(())
+}
+
+#[near]
 impl Contract {
-    pub(crate) fn internal_request_signature(
+    // Called with the result of the MPC sign call, emits SignatureProduced or SignatureFailed
+    // A failed signature is reported with an event first and then fails in the next block, like fail_on_invalid_agent
+    // The part of the deposit the MPC contract did not take is refunded by take_mpc_result
+    #[private]
+    pub fn on_signature(
         &self,
+        account_id: AccountId,
+        path: String,
+        payload_hash: String,
+        payload_format: PayloadFormat,
+        deposit: NearToken,
+        sign_deposit: NearToken,
+    ) -> PromiseOrValue<SignatureResponse> {
+        let result = take_mpc_result(&account_id, deposit, sign_deposit, MAX_SIGN_RESPONSE_LEN)
+            .and_then(|bytes| {
+                SignatureResponse::decode(&bytes, payload_format)
+                    .map_err(SignatureFailureReason::InvalidResponse)
+            });
+
+        match result {
+            Ok(signature) => {
+                Event::SignatureProduced {
+                    account_id: &account_id,
+                    path: &path,
+                    payload_hash: &payload_hash,
+                    signature: &signature,
+                }
+                .emit();
+                PromiseOrValue::Value(signature)
+            }
+            Err(reason) => {
+                Event::SignatureFailed {
+                    account_id: &account_id,
+                    path: &path,
+                    payload_hash: &payload_hash,
+                    reason: &reason,
+                }
+                .emit();
+                PromiseOrValue::Promise(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(FAIL_GAS)
+                        .fail_on_signature_failure(reason),
+                )
+            }
+        }
+    }
+
+    #[private]
+    pub fn fail_on_signature_failure(reason: SignatureFailureReason) {
+        env::panic_str(&format!("Signature failed: {:?}", reason));
+    }
+
+    // Update the signature domains and the CKD domain from the MPC contract's domain list
+    // Only the owner can sync, the changes go through the timelock like set_signature_domain
+    pub fn sync_signature_domains(&mut self) -> Promise {
+        self.require_owner();
+        mpc_contract::ext(self.mpc_contract_id.clone())
+            .with_static_gas(STATE_GAS)
+            .state()
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(SYNC_DOMAINS_CALLBACK_GAS)
+                    .on_mpc_state(env::predecessor_account_id()),
+            )
+    }
+
+    // Called with the MPC contract's state, runs each change as an owner action authorized by the caller
+    // Returns the signature domains after the changes that did not have to wait for the timelock
+    #[private]
+    pub fn on_mpc_state(&mut self, authorized_by: AccountId) -> Vec<SignatureDomainView> {
+        let bytes = env::promise_result_checked(0, MAX_MPC_STATE_LEN)
+            .unwrap_or_else(|_| env::panic_str("Failed to read the MPC contract state"));
+        let domains = parse_mpc_domains(&bytes).unwrap_or_else(|error| env::panic_str(&error));
+        // Schemes this contract does not know are skipped, signing with them needs a contract release
+        let signature_domains: Vec<(u64, SignatureScheme)> = domains
+            .iter()
+            .filter_map(|(domain_id, scheme)| {
+                let scheme = serde_json::from_value(serde_json::json!(scheme)).ok()?;
+                Some((*domain_id, scheme))
+            })
+            .collect();
+        for (scheme, domain) in self.signature_domain_changes(&signature_domains) {
+            self.run_owner_action(
+                OwnerAction::SetSignatureDomain { scheme, domain },
+                authorized_by.clone(),
+            );
+        }
+        let ckd_domain_ids: Vec<u64> = domains
+            .iter()
+            .filter(|(_, scheme)| scheme == CKD_SCHEME)
+            .map(|(domain_id, _)| *domain_id)
+            .collect();
+        if let Some(domain_id) = self.ckd_domain_change(&ckd_domain_ids) {
+            self.run_owner_action(OwnerAction::SetCkdDomain { domain_id }, authorized_by);
+        }
+        self.get_signature_domains()
+    }
+}
+
+// Read the result of an MPC call and refund the part of the deposit the MPC contract did not take
+// deposit is what the agent attached and sign_deposit the part of it forwarded to the MPC contract
+// If the call failed the MPC contract returned its deposit, so all of it is refunded
+pub(crate) fn take_mpc_result(
+    account_id: &AccountId,
+    deposit: NearToken,
+    sign_deposit: NearToken,
+    max_len: usize,
+) -> Result<Vec<u8>, SignatureFailureReason> {
+    let (result, refund) = match env::promise_result_checked(0, max_len) {
+        Ok(bytes) => (Ok(bytes), deposit.saturating_sub(sign_deposit)),
+        Err(near_sdk::PromiseError::TooLong(len)) => (
+            Err(SignatureFailureReason::InvalidResponse(format!(
+                "Response of {len} bytes"
+            ))),
+            deposit.saturating_sub(sign_deposit),
+        ),
+        Err(_) => (Err(SignatureFailureReason::MpcCallFailed), deposit),
+    };
+    if !refund.is_zero() {
+        Promise::new(account_id.clone()).transfer(refund).detach();
+    }
+    result
+}
+
+// Domain IDs and schemes from the MPC contract's state
+// While the MPC network is resharing, the domains of the running state it came from are used
+pub(crate) fn parse_mpc_domains(bytes: &[u8]) -> Result<Vec<(u64, String)>, String> {
+    let state: serde_json::Value =
+        serde_json::from_slice(bytes).map_err(|error| error.to_string())?;
+    let domains = state
+        .pointer("/Running/domains/domains")
+        .or_else(|| state.pointer("/Resharing/previous_running_state/domains/domains"))
+        .ok_or("The MPC contract is not running")?
+        .as_array()
+        .ok_or("Invalid MPC domain list")?;
+    Ok(domains
+        .iter()
+        .filter_map(|domain| {
+            let domain_id = domain.get("id")?.as_u64()?;
+            let scheme = domain.get("scheme")?.as_str()?;
+            Some((domain_id, scheme.to_string()))
+        })
+        .collect())
+}
+
+impl Contract {
+    // Forwards sign_deposit of the attached deposit to the MPC contract, on_signature refunds the rest
+    // spend is the asset and amount the signed transaction moves, counted against the rate limits
+    pub(crate) fn internal_request_signature(
+        &mut self,
         path: String,
         payload: String,
-        key_type: String,
+        scheme: SignatureScheme,
+        spend: Option<(Asset, u128)>,
     ) -> Promise {
-        // Convert the payload to the correct type
-        let (payload_v2, domain_id) = match key_type.as_str() {
-            "Ecdsa" => (Payload::Ecdsa(payload), 0),
-            "Eddsa" => (Payload::Eddsa(payload), 1),
-            _ => {
-                panic!("Invalid key type");
-            }
+        let domain = *self.signature_domains.get(&scheme).unwrap_or_else(|| {
+            env::panic_str(&format!("No signature domain configured for {scheme:?}"))
+        });
+        let path = self.internal_derivation_path(&env::predecessor_account_id(), path);
+
+        // Events identify the request by the SHA-256 of the payload as it was passed
+        let payload_hash = hex::encode(env::sha256(payload.as_bytes()));
+
+        let deposit = env::attached_deposit();
+        require!(
+            deposit >= self.sign_deposit,
+            format!(
+                "Attached deposit must cover the sign deposit {}",
+                self.sign_deposit.exact_amount_display()
+            )
+        );
+
+        if let Some(failure_promise) = self.require_rate_limits(spend) {
+            return failure_promise;
+        }
+
+        // Convert the payload to the format of the domain
+        let payload_v2 = match domain.payload_format {
+            PayloadFormat::Ecdsa => Payload::Ecdsa(payload),
+            PayloadFormat::Eddsa => Payload::Eddsa(payload),
         };
 
         // Create the request
         let request = SignRequest {
             payload_v2,
-            path,
-            domain_id,
+            path: path.clone(),
+            domain_id: domain.domain_id,
         };
 
-        // Call the sign function on the MPC contract
+        // Call the sign function on the MPC contract and handle the result in on_signature
         mpc_contract::ext(self.mpc_contract_id.clone())
-            .with_static_gas(GAS)
-            .with_attached_deposit(ATTACHED_DEPOSIT)
+            .with_static_gas(Gas::from_tgas(self.sign_gas_tgas))
+            .with_attached_deposit(self.sign_deposit)
             .sign(request)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_GAS)
+                    .on_signature(
+                        env::predecessor_account_id(),
+                        path,
+                        payload_hash,
+                        domain.payload_format,
+                        deposit,
+                        self.sign_deposit,
+                    ),
+            )
+    }
+
+    // Agents whose measurements have a transaction policy cannot sign raw payloads with the key
+    // the policy covers, a raw payload could be the hash of any transaction
+    pub(crate) fn require_raw_signing_allowed(&self, scheme: SignatureScheme) {
+        let measurements_id = self
+            .agents
+            .get(&env::predecessor_account_id())
+            .expect("Agent not registered")
+            .measurements_id;
+        let policies = [
+            (
+                "EVM",
+                SignatureScheme::Secp256k1,
+                self.evm_policies.contains_key(&measurements_id),
+            ),
+            (
+                "Bitcoin",
+                SignatureScheme::Secp256k1,
+                self.bitcoin_policies.contains_key(&measurements_id),
+            ),
+            (
+                "Solana",
+                SignatureScheme::Ed25519,
+                self.solana_policies.contains_key(&measurements_id),
+            ),
+        ];
+        for (name, policy_scheme, has_policy) in policies {
+            require!(
+                !has_policy || !self.signs_with_same_domain(scheme, policy_scheme),
+                format!(
+                    "Raw {scheme:?} signing is disabled by the {name} policy of the agent's measurements"
+                )
+            );
+        }
+    }
+
+    // Whether two schemes sign with the same MPC key
+    fn signs_with_same_domain(&self, scheme: SignatureScheme, other: SignatureScheme) -> bool {
+        scheme == other
+            || matches!(
+                (self.signature_domains.get(&scheme), self.signature_domains.get(&other)),
+                (Some(domain), Some(other_domain)) if domain.domain_id == other_domain.domain_id
+            )
+    }
+
+    // Set or, with None, remove the domain a scheme is signed with
+    pub(crate) fn internal_set_signature_domain(
+        &mut self,
+        scheme: SignatureScheme,
+        domain: Option<SignatureDomain>,
+    ) {
+        Event::SignatureDomainSet {
+            scheme,
+            domain: domain.as_ref(),
+        }
+        .emit();
+        match domain {
+            Some(domain) => {
+                self.signature_domains.insert(scheme, domain);
+            }
+            None => {
+                self.signature_domains.remove(&scheme);
+            }
+        }
+    }
+
+    // Changes that point each scheme at a domain the MPC contract lists for it
+    // A configured domain is kept while the MPC contract still lists it, so is its payload format
+    // Schemes the MPC contract has no domain for are removed
+    pub(crate) fn signature_domain_changes(
+        &self,
+        mpc_domains: &[(u64, SignatureScheme)],
+    ) -> Vec<(SignatureScheme, Option<SignatureDomain>)> {
+        let mut changes = Vec::new();
+        for scheme in SignatureScheme::ALL {
+            let domain_ids: Vec<u64> = mpc_domains
+                .iter()
+                .filter(|(_, mpc_scheme)| *mpc_scheme == scheme)
+                .map(|(domain_id, _)| *domain_id)
+                .collect();
+            let current = self.signature_domains.get(&scheme).copied();
+            let domain = match (current, domain_ids.first()) {
+                (Some(current), _) if domain_ids.contains(&current.domain_id) => continue,
+                (current, Some(&domain_id)) => Some(SignatureDomain {
+                    domain_id,
+                    payload_format: current.map_or(scheme.default_payload_format(), |current| {
+                        current.payload_format
+                    }),
+                }),
+                (Some(_), None) => None,
+                (None, None) => continue,
+            };
+            changes.push((scheme, domain));
+        }
+        changes
     }
 }
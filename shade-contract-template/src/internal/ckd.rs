@@ -0,0 +1,163 @@
+use crate::*;
+use internal::chainsig::{CALLBACK_GAS, FAIL_GAS, mpc_contract, take_mpc_result};
+use near_sdk::{PromiseOrValue, bs58};
+
+// Scheme of the MPC domain that derives confidential keys
+pub(crate) const CKD_SCHEME: &str = "Bls12381";
+
+const BLS12381_G1_PREFIX: &str = "bls12381g1:";
+const COMPRESSED_G1_POINT_LEN: usize = 48;
+
+// Longest MPC response the callback reads, two encoded points are well under this
+const MAX_CKD_RESPONSE_LEN: usize = 1024;
+
+#[derive(Debug, Serialize)]
+pub struct CkdRequest {
+    pub app_public_key: String,
+    pub domain_id: u64,
+}
+
+// Confidential key derived for the contract, encrypted to the agent's ephemeral public key
+// big_c minus the ephemeral private key times big_y is the app private key
+#[near(serializers = [json])]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CkdResponse {
+    pub big_y: String,
+    pub big_c: String,
+}
+
+impl CkdResponse {
+    // Decode an MPC CKD response and check both points are well-formed
+    pub(crate) fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let response: CkdResponse =
+            serde_json::from_slice(bytes).map_err(|error| error.to_string())?;
+        require_g1_point(&response.big_y, "big_y")?;
+        require_g1_point(&response.big_c, "big_c")?;
+        Ok(response)
+    }
+}
+
+// Check a point is a compressed BLS12-381 G1 point in the MPC contract's bls12381g1:<base58> format
+pub(crate) fn require_g1_point(value: &str, name: &str) -> Result<(), String> {
+    let encoded = value
+        .strip_prefix(BLS12381_G1_PREFIX)
+        .ok_or(format!("{name} must start with {BLS12381_G1_PREFIX}"))?;
+    let bytes = bs58::decode(encoded)
+        .into_vec()
+        .map_err(|error| format!("Invalid {name}: {error}"))?;
+    if bytes.len() != COMPRESSED_G1_POINT_LEN {
+        return Err(format!(
+            "Expected {name} of {COMPRESSED_G1_POINT_LEN} bytes, got {} bytes",
+            bytes.len()
+        ));
+    }
+    Ok(())
+}
+
+#[near]
+impl Contract {
+    // Called with the result of the MPC CKD call, emits AppPrivateKeyProduced or AppPrivateKeyFailed
+    // A failed request is reported with an event first and then fails in the next block, like on_signature
+    #[private]
+    pub fn on_app_private_key(
+        &self,
+        account_id: AccountId,
+        app_public_key: String,
+        deposit: NearToken,
+        sign_deposit: NearToken,
+    ) -> PromiseOrValue<CkdResponse> {
+        let result = take_mpc_result(&account_id, deposit, sign_deposit, MAX_CKD_RESPONSE_LEN)
+            .and_then(|bytes| {
+                CkdResponse::decode(&bytes).map_err(SignatureFailureReason::InvalidResponse)
+            });
+
+        match result {
+            Ok(response) => {
+                Event::AppPrivateKeyProduced {
+                    account_id: &account_id,
+                    app_public_key: &app_public_key,
+                }
+                .emit();
+                PromiseOrValue::Value(response)
+            }
+            Err(reason) => {
+                Event::AppPrivateKeyFailed {
+                    account_id: &account_id,
+                    app_public_key: &app_public_key,
+                    reason: &reason,
+                }
+                .emit();
+                PromiseOrValue::Promise(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(FAIL_GAS)
+                        .fail_on_app_private_key_failure(reason),
+                )
+            }
+        }
+    }
+
+    #[private]
+    pub fn fail_on_app_private_key_failure(reason: SignatureFailureReason) {
+        env::panic_str(&format!("App private key request failed: {:?}", reason));
+    }
+}
+
+impl Contract {
+    // Ask the MPC contract for the key it derives for this contract, encrypted to app_public_key
+    // Uses the same gas and deposit as sign calls, on_app_private_key refunds the rest of the deposit
+    pub(crate) fn internal_request_app_private_key(&self, app_public_key: String) -> Promise {
+        let domain_id = self
+            .ckd_domain_id
+            .expect("No CKD domain configured, call sync_signature_domains");
+        require_g1_point(&app_public_key, "app_public_key")
+            .unwrap_or_else(|error| env::panic_str(&error));
+
+        let deposit = env::attached_deposit();
+        require!(
+            deposit >= self.sign_deposit,
+            format!(
+                "Attached deposit must cover the sign deposit {}",
+                self.sign_deposit.exact_amount_display()
+            )
+        );
+
+        let request = CkdRequest {
+            app_public_key: app_public_key.clone(),
+            domain_id,
+        };
+
+        mpc_contract::ext(self.mpc_contract_id.clone())
+            .with_static_gas(Gas::from_tgas(self.sign_gas_tgas))
+            .with_attached_deposit(self.sign_deposit)
+            .request_app_private_key(request)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_GAS)
+                    .on_app_private_key(
+                        env::predecessor_account_id(),
+                        app_public_key,
+                        deposit,
+                        self.sign_deposit,
+                    ),
+            )
+    }
+
+    // Set or, with None, remove the MPC domain confidential keys are derived with
+    pub(crate) fn internal_set_ckd_domain(&mut self, domain_id: Option<u64>) {
+        Event::CkdDomainSet { domain_id }.emit();
+        self.ckd_domain_id = domain_id;
+    }
+
+    // The CKD domain to change to, keeping it while the MPC contract still lists it
+    // Otherwise it moves to the first one the MPC contract lists, None if nothing changes
+    pub(crate) fn ckd_domain_change(&self, mpc_domain_ids: &[u64]) -> Option<Option<u64>> {
+        if self
+            .ckd_domain_id
+            .is_some_and(|domain_id| mpc_domain_ids.contains(&domain_id))
+        {
+            return None;
+        }
+        let domain_id = mpc_domain_ids.first().copied();
+        (domain_id != self.ckd_domain_id).then_some(domain_id)
+    }
+}
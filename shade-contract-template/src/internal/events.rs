@@ -27,6 +27,7 @@ pub fn summarize_advisory_ids(advisory_ids: &[String]) -> (Vec<String>, u16) {
 pub enum Event<'a> {
     AgentRegistered {
         account_id: &'a AccountId,
+        measurements_id: MeasurementsId,
         measurements: &'a FullMeasurementsHex,
         ppid: &'a Ppid,
         advisory_ids_truncated: Vec<String>,
@@ -39,6 +40,264 @@ pub enum Event<'a> {
         account_id: &'a AccountId,
         reasons: Vec<AgentRemovalReason>,
     },
+    AgentsPruned {
+        account_id: &'a AccountId,
+        removed: u32,
+        reward: NearToken,
+    },
+    StorageDeposited {
+        account_id: &'a AccountId,
+        amount: NearToken,
+    },
+    StorageWithdrawn {
+        account_id: &'a AccountId,
+        amount: NearToken,
+    },
+    StorageDepositRefunded {
+        account_id: &'a AccountId,
+        amount: NearToken,
+    },
+    PruneRewardUpdated {
+        old_prune_reward_bps: u16,
+        new_prune_reward_bps: u16,
+    },
+    AttestationExpirationTimeUpdated {
+        old_attestation_expiration_time_ms: U64,
+        new_attestation_expiration_time_ms: U64,
+    },
+    OwnerUpdated {
+        old_owner_id: &'a AccountId,
+        new_owner_id: &'a AccountId,
+    },
+    MpcContractUpdated {
+        old_mpc_contract_id: &'a AccountId,
+        new_mpc_contract_id: &'a AccountId,
+    },
+    SignConfigUpdated {
+        old_sign_gas_tgas: u64,
+        new_sign_gas_tgas: u64,
+        old_sign_deposit: NearToken,
+        new_sign_deposit: NearToken,
+    },
+    // domain is None when the scheme was removed
+    SignatureDomainSet {
+        scheme: SignatureScheme,
+        domain: Option<&'a SignatureDomain>,
+    },
+    // domain_id is None when confidential key derivation was stopped
+    CkdDomainSet {
+        domain_id: Option<u64>,
+    },
+    // policy is None when the policy was removed
+    EvmPolicySet {
+        measurements_id: MeasurementsId,
+        policy: Option<&'a EvmPolicy>,
+    },
+    // policy is None when the policy was removed
+    BitcoinPolicySet {
+        measurements_id: MeasurementsId,
+        policy: Option<&'a BitcoinPolicy>,
+    },
+    // policy is None when the policy was removed
+    SolanaPolicySet {
+        measurements_id: MeasurementsId,
+        policy: Option<&'a SolanaPolicy>,
+    },
+    // policy is None when the policy was removed
+    PathPolicySet {
+        measurements_id: MeasurementsId,
+        policy: Option<&'a PathPolicy>,
+    },
+    // policy is None when the policy was removed
+    AgentPathPolicySet {
+        account_id: &'a AccountId,
+        policy: Option<&'a PathPolicy>,
+    },
+    // rate_limits is None when the limits were removed
+    RateLimitsUpdated {
+        rate_limits: Option<&'a RateLimits>,
+    },
+    RateLimitExceeded {
+        account_id: &'a AccountId,
+        limit: RateLimit,
+    },
+    MeasurementsApproved {
+        measurements_id: MeasurementsId,
+        measurements: &'a FullMeasurementsHex,
+        label: &'a str,
+        source: &'a str,
+        approved_by: &'a AccountId,
+    },
+    MeasurementsMetadataUpdated {
+        measurements_id: MeasurementsId,
+        label: &'a str,
+        source: &'a str,
+    },
+    MeasurementsRemoved {
+        measurements_id: MeasurementsId,
+        measurements: &'a FullMeasurementsHex,
+    },
+    MeasurementsAgentQuotaSet {
+        measurements_id: MeasurementsId,
+        max_agents: Option<u32>,
+    },
+    MeasurementsPpidGroupSet {
+        measurements_id: MeasurementsId,
+        ppid_group: Option<&'a str>,
+    },
+    PpidGroupSet {
+        name: &'a str,
+        ppids: &'a [Ppid],
+    },
+    PpidGroupRemoved {
+        name: &'a str,
+    },
+    PpidMeasurementsScopeSet {
+        ppid: &'a Ppid,
+        measurements_ids: Option<&'a [MeasurementsId]>,
+    },
+    PpidsApproved {
+        // Only the PPIDs that were not approved already
+        ppids: &'a [Ppid],
+        operator: &'a str,
+        region: &'a str,
+        expires_at_ms: Option<U64>,
+    },
+    PpidMetadataUpdated {
+        ppid: &'a Ppid,
+        operator: &'a str,
+        region: &'a str,
+        expires_at_ms: Option<U64>,
+    },
+    PpidsRevoked {
+        ppids: &'a [Ppid],
+        reason: Option<PpidRevocationReason>,
+    },
+    MeasurementsSunsetScheduled {
+        measurements_id: MeasurementsId,
+        sunset_at_ms: U64,
+    },
+    MeasurementsSunsetCancelled {
+        measurements_id: MeasurementsId,
+    },
+    MeasurementsSunset {
+        measurements_id: MeasurementsId,
+        sunset_at_ms: U64,
+        current_time_ms: U64,
+    },
+    ProposalCreated {
+        proposal_id: U64,
+        proposer: &'a AccountId,
+        action: &'a str,
+        expires_at_ms: U64,
+    },
+    ProposalConfirmed {
+        proposal_id: U64,
+        account_id: &'a AccountId,
+        confirmations: u32,
+        threshold: u32,
+    },
+    ProposalExecuted {
+        proposal_id: U64,
+        action: &'a str,
+    },
+    ProposalCancelled {
+        proposal_id: U64,
+        account_id: &'a AccountId,
+    },
+    ActionQueued {
+        action_id: U64,
+        action: &'a str,
+        queued_by: &'a AccountId,
+        executable_at_ms: U64,
+    },
+    QueuedActionExecuted {
+        action_id: U64,
+        action: &'a str,
+    },
+    QueuedActionCancelled {
+        action_id: U64,
+        action: &'a str,
+    },
+    AgentWhitelistedForLocal {
+        account_id: &'a AccountId,
+    },
+    AgentRemovedFromWhitelistForLocal {
+        account_id: &'a AccountId,
+    },
+    MultisigUpdated {
+        owners: &'a [AccountId],
+        threshold: u32,
+        proposal_lifetime_ms: U64,
+    },
+    MultisigDisabled {
+        account_id: &'a AccountId,
+    },
+    TimelockDelayUpdated {
+        old_timelock_delay_ms: U64,
+        new_timelock_delay_ms: U64,
+    },
+    Paused {
+        feature: PausableFeature,
+        account_id: &'a AccountId,
+    },
+    Unpaused {
+        feature: PausableFeature,
+        account_id: &'a AccountId,
+    },
+    RoleGranted {
+        account_id: &'a AccountId,
+        role: Role,
+    },
+    RoleRevoked {
+        account_id: &'a AccountId,
+        role: Role,
+    },
+    StateMigrated {
+        from_version: u32,
+        to_version: u32,
+    },
+    AgentsMigrated {
+        migrated: u32,
+        remaining: u32,
+    },
+    CodeUpgradeApproved {
+        code_hash: Base58CryptoHash,
+        migrate_gas_tgas: u64,
+        approved_by: &'a AccountId,
+    },
+    CodeUpgradeCancelled {
+        code_hash: Base58CryptoHash,
+    },
+    ContractUpgraded {
+        code_hash: Base58CryptoHash,
+        previous_code_hash: Option<Base58CryptoHash>,
+    },
+    ContractUpgradeFailed {
+        code_hash: Base58CryptoHash,
+    },
+    SignatureProduced {
+        account_id: &'a AccountId,
+        path: &'a str,
+        // Hex of the SHA-256 of the payload bytes
+        payload_hash: &'a str,
+        signature: &'a SignatureResponse,
+    },
+    SignatureFailed {
+        account_id: &'a AccountId,
+        path: &'a str,
+        payload_hash: &'a str,
+        reason: &'a SignatureFailureReason,
+    },
+    AppPrivateKeyProduced {
+        account_id: &'a AccountId,
+        app_public_key: &'a str,
+    },
+    AppPrivateKeyFailed {
+        account_id: &'a AccountId,
+        app_public_key: &'a str,
+        reason: &'a SignatureFailureReason,
+    },
 }
 
 impl Event<'_> {
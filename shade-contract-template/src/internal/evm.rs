@@ -0,0 +1,278 @@
+use crate::*;
+
+pub type EvmAddress = HexBytes<20>;
+
+pub type FunctionSelector = HexBytes<4>;
+
+const EIP1559_TRANSACTION_TYPE: u8 = 0x02;
+const SELECTOR_LEN: usize = 4;
+// An access list is the deepest part of a transaction, a list of lists of lists
+const MAX_RLP_DEPTH: usize = 4;
+
+// What agents on a set of measurements can sign with request_evm_transaction_signature
+#[near(serializers = [borsh, json])]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EvmPolicy {
+    pub chain_ids: Vec<u64>,
+    pub allowed_destinations: Vec<EvmAddress>,
+    // Highest value in wei a transaction can transfer
+    pub max_value: U128,
+    // Functions a transaction can call, transactions without data are plain transfers and always allowed
+    pub allowed_selectors: Vec<FunctionSelector>,
+}
+
+// The fields of an unsigned transaction the policy is checked against
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct EvmTransaction {
+    pub chain_id: u64,
+    // None for contract creation
+    pub to: Option<EvmAddress>,
+    pub value: u128,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug)]
+enum Rlp<'a> {
+    Bytes(&'a [u8]),
+    List(Vec<Rlp<'a>>),
+}
+
+impl<'a> Rlp<'a> {
+    fn bytes(&self, name: &str) -> Result<&'a [u8], String> {
+        match self {
+            Rlp::Bytes(bytes) => Ok(bytes),
+            Rlp::List(_) => Err(format!("Expected {name} to be a string")),
+        }
+    }
+
+    // Integers are big-endian without leading zeros, zero is the empty string
+    fn uint(&self, name: &str, max_len: usize) -> Result<u128, String> {
+        let bytes = self.bytes(name)?;
+        if bytes.first() == Some(&0) {
+            return Err(format!("Non-canonical integer {name}"));
+        }
+        if bytes.len() > max_len {
+            return Err(format!("{name} is too large"));
+        }
+        Ok(bytes
+            .iter()
+            .fold(0, |value, byte| (value << 8) | u128::from(*byte)))
+    }
+}
+
+// Decode a single RLP item, rejecting trailing bytes and non-canonical encodings
+// so the fields checked are the only reading of the bytes that are hashed
+fn decode_rlp(input: &[u8]) -> Result<Rlp<'_>, String> {
+    let (item, rest) = decode_rlp_item(input, 0)?;
+    if !rest.is_empty() {
+        return Err("Trailing bytes after RLP item".to_string());
+    }
+    Ok(item)
+}
+
+fn decode_rlp_item(input: &[u8], depth: usize) -> Result<(Rlp<'_>, &[u8]), String> {
+    let (&prefix, rest) = input.split_first().ok_or("Unexpected end of RLP")?;
+    match prefix {
+        0x00..=0x7f => Ok((Rlp::Bytes(&input[..1]), rest)),
+        0x80..=0xb7 => {
+            let (payload, rest) = split_rlp(rest, usize::from(prefix - 0x80))?;
+            if payload.len() == 1 && payload[0] < 0x80 {
+                return Err("Non-canonical RLP string".to_string());
+            }
+            Ok((Rlp::Bytes(payload), rest))
+        }
+        0xb8..=0xbf => {
+            let (len, rest) = read_rlp_length(rest, usize::from(prefix - 0xb7))?;
+            let (payload, rest) = split_rlp(rest, len)?;
+            Ok((Rlp::Bytes(payload), rest))
+        }
+        0xc0..=0xf7 => {
+            let (payload, rest) = split_rlp(rest, usize::from(prefix - 0xc0))?;
+            Ok((Rlp::List(decode_rlp_list(payload, depth)?), rest))
+        }
+        0xf8..=0xff => {
+            let (len, rest) = read_rlp_length(rest, usize::from(prefix - 0xf7))?;
+            let (payload, rest) = split_rlp(rest, len)?;
+            Ok((Rlp::List(decode_rlp_list(payload, depth)?), rest))
+        }
+    }
+}
+
+fn decode_rlp_list(mut payload: &[u8], depth: usize) -> Result<Vec<Rlp<'_>>, String> {
+    if depth >= MAX_RLP_DEPTH {
+        return Err("RLP is nested too deeply".to_string());
+    }
+    let mut items = Vec::new();
+    while !payload.is_empty() {
+        let (item, rest) = decode_rlp_item(payload, depth + 1)?;
+        items.push(item);
+        payload = rest;
+    }
+    Ok(items)
+}
+
+// Length of a long string or list, which has to need the long form
+fn read_rlp_length(input: &[u8], len_of_len: usize) -> Result<(usize, &[u8]), String> {
+    let (len_bytes, rest) = split_rlp(input, len_of_len)?;
+    if len_bytes.first() == Some(&0) || len_of_len > size_of::<usize>() {
+        return Err("Non-canonical RLP length".to_string());
+    }
+    let len = len_bytes
+        .iter()
+        .fold(0usize, |len, byte| (len << 8) | usize::from(*byte));
+    if len <= 55 {
+        return Err("Non-canonical RLP length".to_string());
+    }
+    Ok((len, rest))
+}
+
+fn split_rlp(input: &[u8], len: usize) -> Result<(&[u8], &[u8]), String> {
+    if input.len() < len {
+        return Err("Unexpected end of RLP".to_string());
+    }
+    Ok(input.split_at(len))
+}
+
+// Decode an unsigned EIP-1559 or EIP-155 legacy transaction
+pub(crate) fn decode_evm_transaction(bytes: &[u8]) -> Result<EvmTransaction, String> {
+    let (fields, chain_id, to, value, data) = match bytes.first() {
+        // [chain_id, nonce, max_priority_fee_per_gas, max_fee_per_gas, gas_limit, to, value, data, access_list]
+        Some(&EIP1559_TRANSACTION_TYPE) => match decode_rlp(&bytes[1..])? {
+            Rlp::List(fields) if fields.len() == 9 => {
+                if !matches!(fields[8], Rlp::List(_)) {
+                    return Err("Expected access_list to be a list".to_string());
+                }
+                (fields, 0, 5, 6, 7)
+            }
+            _ => return Err("Expected an EIP-1559 transaction with 9 fields".to_string()),
+        },
+        // [nonce, gas_price, gas_limit, to, value, data, chain_id, 0, 0]
+        Some(0xc0..=0xff) => match decode_rlp(bytes)? {
+            Rlp::List(fields) if fields.len() == 9 => {
+                if fields[7].uint("r", 0).is_err() || fields[8].uint("s", 0).is_err() {
+                    return Err("Expected empty r and s in an unsigned transaction".to_string());
+                }
+                (fields, 6, 3, 4, 5)
+            }
+            Rlp::List(fields) if fields.len() == 6 => {
+                return Err("Legacy transactions must include the chain ID (EIP-155)".to_string());
+            }
+            _ => return Err("Expected a legacy transaction with 9 fields".to_string()),
+        },
+        _ => return Err("Unsupported transaction type".to_string()),
+    };
+
+    let to = match fields[to].bytes("to")? {
+        [] => None,
+        address => Some(EvmAddress::from(
+            <[u8; 20]>::try_from(address).map_err(|_| "Expected to to be 20 bytes")?,
+        )),
+    };
+    Ok(EvmTransaction {
+        chain_id: fields[chain_id].uint("chain_id", size_of::<u64>())? as u64,
+        to,
+        value: fields[value].uint("value", size_of::<u128>())?,
+        data: fields[data].bytes("data")?.to_vec(),
+    })
+}
+
+impl EvmPolicy {
+    pub(crate) fn check(&self, transaction: &EvmTransaction) -> Result<(), String> {
+        if !self.chain_ids.contains(&transaction.chain_id) {
+            return Err(format!("Chain ID {} is not allowed", transaction.chain_id));
+        }
+        let to = transaction
+            .to
+            .as_ref()
+            .ok_or("Contract creation is not allowed")?;
+        if !self.allowed_destinations.contains(to) {
+            return Err(format!(
+                "Destination {} is not allowed",
+                hex::encode(to.as_ref())
+            ));
+        }
+        if transaction.value > self.max_value.0 {
+            return Err(format!(
+                "Value {} is more than the maximum {}",
+                transaction.value, self.max_value.0
+            ));
+        }
+        if !transaction.data.is_empty() {
+            let selector = transaction
+                .data
+                .get(..SELECTOR_LEN)
+                .ok_or("Data is too short for a function selector")?;
+            if !self
+                .allowed_selectors
+                .iter()
+                .any(|allowed| allowed.as_ref() == selector)
+            {
+                return Err(format!(
+                    "Function selector {} is not allowed",
+                    hex::encode(selector)
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Contract {
+    // Check an unsigned transaction against the policy of the caller's measurements and sign its hash
+    // The caller has to be a valid agent
+    pub(crate) fn internal_request_evm_transaction_signature(
+        &mut self,
+        path: String,
+        transaction: String,
+    ) -> Promise {
+        let bytes = hex::decode(&transaction).expect("Transaction must be a hex string");
+        let measurements_id = self
+            .agents
+            .get(&env::predecessor_account_id())
+            .expect("Agent not registered")
+            .measurements_id;
+        let policy = self
+            .evm_policies
+            .get(&measurements_id)
+            .expect("No EVM policy for the agent's measurements");
+
+        let transaction =
+            decode_evm_transaction(&bytes).unwrap_or_else(|error| env::panic_str(&error));
+        policy
+            .check(&transaction)
+            .unwrap_or_else(|error| env::panic_str(&error));
+
+        // Only the hash of the checked transaction is signed
+        let sighash = hex::encode(env::keccak256_array(&bytes));
+        let spend = (
+            Asset::Evm {
+                chain_id: transaction.chain_id,
+            },
+            transaction.value,
+        );
+        self.internal_request_signature(path, sighash, SignatureScheme::Secp256k1, Some(spend))
+    }
+
+    // Set or, with None, remove the EVM policy of a set of measurements
+    pub(crate) fn internal_set_evm_policy(
+        &mut self,
+        measurements_id: MeasurementsId,
+        policy: Option<EvmPolicy>,
+    ) {
+        if policy.is_some() {
+            require!(
+                self.approved_measurements.contains_key(&measurements_id),
+                "Measurements not in approved list"
+            );
+        }
+        Event::EvmPolicySet {
+            measurements_id,
+            policy: policy.as_ref(),
+        }
+        .emit();
+        match policy {
+            Some(policy) => self.evm_policies.insert(measurements_id, policy),
+            None => self.evm_policies.remove(&measurements_id),
+        };
+    }
+}
@@ -1,43 +1,81 @@
 use crate::*;
 
+// Basis points in a whole, used for the prune reward
+pub(crate) const BPS_DENOMINATOR: u128 = 10_000;
+
 #[near(serializers = [json])]
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum AgentRemovalReason {
     ManualRemoval,
     ExpiredAttestation,
     InvalidMeasurements,
+    MeasurementsSunset,
     InvalidPpid,
+    // The PPID was removed with a revocation reason
+    RevokedPpid(PpidRevocationReason),
+    ExpiredPpid,
+    InvalidMeasurementsPpidPairing,
     NotWhitelistedForLocal,
+    // The agent closed its storage balance with storage_unregister
+    StorageUnregistered,
 }
 
 impl Contract {
     // Require the caller to be the owner
+    // In multisig mode owner methods are disabled and the actions have to be proposed instead
     pub(crate) fn require_owner(&mut self) {
+        require!(
+            self.multisig.is_none(),
+            "Owner methods are disabled in multisig mode, submit a proposal instead"
+        );
         require!(
             env::predecessor_account_id() == self.owner_id,
             "Caller is not the owner"
         );
     }
 
+    // Require the caller to have the role, the owner has every role
+    // In multisig mode roles are disabled like owner methods, or a single role holder could
+    // approve what the multisig owners have to agree on
+    pub(crate) fn require_role(&mut self, role: Role) {
+        let predecessor = env::predecessor_account_id();
+        if self.has_role(&predecessor, role) {
+            require!(
+                self.multisig.is_none(),
+                "Role methods are disabled in multisig mode, submit a proposal instead"
+            );
+            return;
+        }
+        require!(
+            self.multisig.is_none(),
+            "Owner methods are disabled in multisig mode, submit a proposal instead"
+        );
+        require!(
+            predecessor == self.owner_id,
+            format!("Caller is not the owner and does not have the {role:?} role")
+        );
+    }
+
+    pub(crate) fn has_role(&self, account_id: &AccountId, role: Role) -> bool {
+        self.roles
+            .get(account_id)
+            .is_some_and(|roles| roles.contains(&role))
+    }
+
     // Require the caller to be a valid agent or remove it from the agents map
     // Just because an agent is registered does not mean it is currently valid
     // Returns Some(Promise) if agent is invalid (to fail the request), None if valid
     pub(crate) fn require_valid_agent(&mut self) -> Option<Promise> {
         // Get the agent and check if it is registered
         let account_id = env::predecessor_account_id();
+        self.migrate_legacy_agent(&account_id);
         let agent = self.agents.get(&account_id).expect("Agent not registered");
 
         // Check if the agent is invalid and return a promise to panic if it is
-        let removal_reasons = self.check_invalid_reasons(&account_id, &agent);
+        let removal_reasons = self.check_invalid_reasons(&account_id, agent);
 
         if !removal_reasons.is_empty() {
-            self.agents.remove(&account_id);
-
-            Event::AgentRemoved {
-                account_id: &account_id,
-                reasons: removal_reasons.clone(),
-            }
-            .emit();
+            self.internal_remove_invalid_agent(&account_id, removal_reasons.clone(), 0);
 
             let args_json = serde_json::json!({
                 "reasons": removal_reasons
@@ -54,6 +92,172 @@ impl Contract {
         None
     }
 
+    // Send the attached deposit back to the caller, for requests that fail without using it
+    pub(crate) fn refund_attached_deposit(&self) {
+        let deposit = env::attached_deposit();
+        if !deposit.is_zero() {
+            Promise::new(env::predecessor_account_id())
+                .transfer(deposit)
+                .detach();
+        }
+    }
+
+    // Remove an agent and release its slot in the quota of its measurements
+    // Its rate counters are removed with its storage balance by internal_unregister_storage
+    pub(crate) fn internal_remove_agent(&mut self, account_id: &AccountId) -> Option<Agent> {
+        self.migrate_legacy_agent(account_id);
+        let agent = self.agents.remove(account_id)?;
+        self.release_agent_from_measurements(agent.measurements_id);
+        Some(agent)
+    }
+
+    // Remove an agent that check_invalid_reasons flagged and refund its storage balance
+    // reward_bps of the balance is kept back and returned so the caller can be paid from it
+    pub(crate) fn internal_remove_invalid_agent(
+        &mut self,
+        account_id: &AccountId,
+        reasons: Vec<AgentRemovalReason>,
+        reward_bps: u16,
+    ) -> NearToken {
+        let agent = self
+            .internal_remove_agent(account_id)
+            .expect("Agent not registered");
+
+        // The first agent to hit a passed sunset finalizes it
+        if reasons.contains(&AgentRemovalReason::MeasurementsSunset)
+            && self
+                .approved_measurements
+                .contains_key(&agent.measurements_id)
+        {
+            self.internal_execute_measurements_sunset(agent.measurements_id);
+        }
+
+        Event::AgentRemoved {
+            account_id,
+            reasons,
+        }
+        .emit();
+        self.internal_unregister_storage(account_id, reward_bps)
+    }
+
+    // Check up to limit agents starting at the prune cursor and remove the invalid ones
+    // Removing an agent moves the last agent into its place, so that position is checked again
+    // Returns the number of agents removed and the reward kept back from their storage balances
+    pub(crate) fn internal_prune_agents(&mut self, limit: u32) -> (u32, NearToken) {
+        let mut index = self.prune_cursor;
+        let mut removed = 0;
+        let mut reward = NearToken::from_yoctonear(0);
+        for _ in 0..limit.min(self.agents.len()) {
+            if index >= self.agents.len() {
+                index = 0;
+            }
+            let (account_id, agent) = self
+                .agents
+                .iter()
+                .nth(index as usize)
+                .map(|(account_id, agent)| (account_id.clone(), agent))
+                .expect("Agent index out of range");
+            let reasons = self.check_invalid_reasons(&account_id, agent);
+            if reasons.is_empty() {
+                index += 1;
+                continue;
+            }
+            reward = reward.saturating_add(self.internal_remove_invalid_agent(
+                &account_id,
+                reasons,
+                self.prune_reward_bps,
+            ));
+            removed += 1;
+        }
+        self.prune_cursor = index;
+        (removed, reward)
+    }
+
+    // Count an agent towards the quota of a set of measurements, panicking if the quota is reached
+    // Invalid agents keep counting until they are removed, prune_agents frees their slots
+    pub(crate) fn add_agent_to_measurements(&mut self, measurements_id: MeasurementsId) {
+        let approved = self
+            .approved_measurements
+            .get_mut(&measurements_id)
+            .expect("Measurements not in approved list");
+        if let Some(max_agents) = approved.max_agents {
+            require!(
+                approved.active_agents < max_agents,
+                format!(
+                    "Agent quota reached for measurements {measurements_id}: {} of {max_agents} agents registered",
+                    approved.active_agents
+                )
+            );
+        }
+        approved.active_agents += 1;
+    }
+
+    // Release an agent's slot, measurements that are no longer approved have no count to update
+    pub(crate) fn release_agent_from_measurements(&mut self, measurements_id: MeasurementsId) {
+        if let Some(approved) = self.approved_measurements.get_mut(&measurements_id) {
+            approved.active_agents = approved.active_agents.saturating_sub(1);
+        }
+    }
+
+    // Check the scoping rules between a set of measurements and a PPID
+    // Measurements limited to a PPID group require the PPID to be in it, and a PPID limited
+    // to certain measurements requires the measurements to be among them
+    pub(crate) fn is_measurements_ppid_pairing_allowed(
+        &self,
+        measurements_id: MeasurementsId,
+        ppid: &Ppid,
+    ) -> bool {
+        let group_allows = match self
+            .approved_measurements
+            .get(&measurements_id)
+            .and_then(|approved| approved.ppid_group.as_ref())
+        {
+            Some(group) => self
+                .ppid_groups
+                .get(group)
+                .is_some_and(|ppids| ppids.contains(ppid)),
+            None => true,
+        };
+        let scope_allows = self
+            .ppid_measurements_scopes
+            .get(ppid)
+            .is_none_or(|measurements_ids| measurements_ids.contains(&measurements_id));
+        group_allows && scope_allows
+    }
+
+    // Remove measurements whose sunset has passed and record that they were sunset
+    pub(crate) fn internal_execute_measurements_sunset(&mut self, measurements_id: MeasurementsId) {
+        let approved = self
+            .approved_measurements
+            .remove(&measurements_id)
+            .expect("Measurements not in approved list");
+        self.measurements_ids.remove(&approved.measurements);
+        self.evm_policies.remove(&measurements_id);
+        self.bitcoin_policies.remove(&measurements_id);
+        self.solana_policies.remove(&measurements_id);
+        self.path_policies.remove(&measurements_id);
+        let sunset_at_ms = approved
+            .sunset_at_ms
+            .expect("Measurements are not scheduled for sunset");
+        self.sunset_measurements
+            .insert(measurements_id, sunset_at_ms);
+        self.record_allowlist_change(
+            AllowlistChange::MeasurementsSunset {
+                measurements_id,
+                measurements: approved.measurements,
+                sunset_at_ms: U64::from(sunset_at_ms),
+            },
+            env::predecessor_account_id(),
+        );
+
+        Event::MeasurementsSunset {
+            measurements_id,
+            sunset_at_ms: U64::from(sunset_at_ms),
+            current_time_ms: U64::from(block_timestamp_ms()),
+        }
+        .emit();
+    }
+
     pub(crate) fn check_invalid_reasons(
         &self,
         account_id: &AccountId,
@@ -63,17 +267,49 @@ impl Contract {
         if agent.valid_until_ms < block_timestamp_ms() {
             reasons.push(AgentRemovalReason::ExpiredAttestation);
         }
-        if !self.approved_measurements.contains(&agent.measurements) {
-            reasons.push(AgentRemovalReason::InvalidMeasurements);
-        }
-        if !self.approved_ppids.contains(&agent.ppid) {
-            reasons.push(AgentRemovalReason::InvalidPpid);
+        match self.approved_measurements.get(&agent.measurements_id) {
+            Some(approved) => {
+                if approved
+                    .sunset_at_ms
+                    .is_some_and(|sunset_at_ms| sunset_at_ms <= block_timestamp_ms())
+                {
+                    reasons.push(AgentRemovalReason::MeasurementsSunset);
+                }
+                if !self.is_measurements_ppid_pairing_allowed(agent.measurements_id, &agent.ppid) {
+                    reasons.push(AgentRemovalReason::InvalidMeasurementsPpidPairing);
+                }
+            }
+            None => {
+                if self
+                    .sunset_measurements
+                    .contains_key(&agent.measurements_id)
+                {
+                    reasons.push(AgentRemovalReason::MeasurementsSunset);
+                } else {
+                    reasons.push(AgentRemovalReason::InvalidMeasurements);
+                }
+            }
         }
-        if !self.requires_tee {
-            if !self.whitelisted_agents_for_local.contains(account_id) {
-                reasons.push(AgentRemovalReason::NotWhitelistedForLocal);
+        match self.approved_ppids.get(&agent.ppid) {
+            Some(approved) => {
+                if approved.is_expired() {
+                    reasons.push(AgentRemovalReason::ExpiredPpid);
+                }
+            }
+            None => {
+                match self
+                    .revoked_ppids
+                    .get(&agent.ppid)
+                    .and_then(|revocation| revocation.reason)
+                {
+                    Some(reason) => reasons.push(AgentRemovalReason::RevokedPpid(reason)),
+                    None => reasons.push(AgentRemovalReason::InvalidPpid),
+                }
             }
         }
+        if !self.requires_tee && !self.whitelisted_agents_for_local.contains(account_id) {
+            reasons.push(AgentRemovalReason::NotWhitelistedForLocal);
+        }
         reasons
     }
 }
@@ -85,3 +321,10 @@ impl Contract {
         env::panic_str(&format!("Invalid agent: {:?}", reasons));
     }
 }
+
+impl ApprovedPpid {
+    pub(crate) fn is_expired(&self) -> bool {
+        self.expires_at_ms
+            .is_some_and(|expires_at_ms| expires_at_ms <= block_timestamp_ms())
+    }
+}
@@ -1,6 +1,13 @@
 pub mod attestation;
+pub mod bitcoin;
 pub mod chainsig;
+pub mod ckd;
 pub mod events;
+pub mod evm;
 pub mod helpers;
+pub mod owner_actions;
+pub mod paths;
+pub mod rate_limits;
+pub mod solana;
 #[cfg(test)]
 mod unit_tests;
@@ -0,0 +1,1120 @@
+use crate::*;
+
+// A privileged operation, called directly by the owner, carried by a multisig proposal or queued behind the timelock
+#[near(serializers = [borsh, json])]
+#[derive(Debug, Clone)]
+pub enum OwnerAction {
+    UpdateAttestationExpirationTime {
+        attestation_expiration_time_ms: U64,
+    },
+    UpdateOwnerId {
+        owner_id: AccountId,
+    },
+    UpdateMpcContractId {
+        mpc_contract_id: AccountId,
+    },
+    // Gas in TGas and deposit forwarded to the MPC contract with each sign call
+    SetSignConfig {
+        sign_gas_tgas: u64,
+        sign_deposit: NearToken,
+    },
+    // None stops signing with the scheme
+    SetSignatureDomain {
+        scheme: SignatureScheme,
+        domain: Option<SignatureDomain>,
+    },
+    // None stops confidential key derivation
+    SetCkdDomain {
+        domain_id: Option<u64>,
+    },
+    ApproveMeasurements {
+        measurements: FullMeasurementsHex,
+        label: Option<String>,
+        source: Option<String>,
+    },
+    UpdateMeasurementsMetadata {
+        measurements_id: MeasurementsId,
+        label: Option<String>,
+        source: Option<String>,
+    },
+    RemoveMeasurements {
+        measurements: FullMeasurementsHex,
+    },
+    ScheduleMeasurementsSunset {
+        measurements_id: MeasurementsId,
+        sunset_at_ms: U64,
+    },
+    CancelMeasurementsSunset {
+        measurements_id: MeasurementsId,
+    },
+    SetMeasurementsAgentQuota {
+        measurements_id: MeasurementsId,
+        max_agents: Option<u32>,
+    },
+    SetMeasurementsPpidGroup {
+        measurements_id: MeasurementsId,
+        ppid_group: Option<String>,
+    },
+    SetEvmPolicy {
+        measurements_id: MeasurementsId,
+        policy: Option<EvmPolicy>,
+    },
+    SetBitcoinPolicy {
+        measurements_id: MeasurementsId,
+        policy: Option<BitcoinPolicy>,
+    },
+    SetSolanaPolicy {
+        measurements_id: MeasurementsId,
+        policy: Option<SolanaPolicy>,
+    },
+    SetRateLimits {
+        rate_limits: Option<RateLimits>,
+    },
+    SetPathPolicy {
+        measurements_id: MeasurementsId,
+        policy: Option<PathPolicy>,
+    },
+    SetAgentPathPolicy {
+        account_id: AccountId,
+        policy: Option<PathPolicy>,
+    },
+    SetPpidGroup {
+        name: String,
+        ppids: Vec<Ppid>,
+    },
+    RemovePpidGroup {
+        name: String,
+    },
+    SetPpidMeasurementsScope {
+        ppid: Ppid,
+        measurements_ids: Option<Vec<MeasurementsId>>,
+    },
+    ApprovePpids {
+        ppids: Vec<Ppid>,
+        operator: Option<String>,
+        region: Option<String>,
+        expires_at_ms: Option<U64>,
+    },
+    UpdatePpidMetadata {
+        ppid: Ppid,
+        operator: String,
+        region: String,
+        expires_at_ms: Option<U64>,
+    },
+    RemovePpids {
+        ppids: Vec<Ppid>,
+        reason: Option<PpidRevocationReason>,
+    },
+    RemoveAgent {
+        account_id: AccountId,
+    },
+    // Share of a pruned agent's storage deposit paid to the caller of prune_agents, in basis points
+    SetPruneReward {
+        prune_reward_bps: u16,
+    },
+    // SHA-256 of the WASM code that anyone can then deploy with upload_code, and the gas for migrate in TGas
+    ApproveCodeHash {
+        code_hash: Base58CryptoHash,
+        migrate_gas_tgas: u64,
+    },
+    // Approve the code hash that was deployed before the current one
+    RollbackCode {
+        migrate_gas_tgas: u64,
+    },
+    CancelCodeUpgrade,
+    WhitelistAgentForLocal {
+        account_id: AccountId,
+    },
+    RemoveAgentFromWhitelistForLocal {
+        account_id: AccountId,
+    },
+    SetMultisig {
+        owners: Vec<AccountId>,
+        threshold: u32,
+        proposal_lifetime_ms: U64,
+    },
+    // Hand control back to the single owner_id
+    DisableMultisig,
+    SetTimelockDelay {
+        timelock_delay_ms: U64,
+    },
+    CancelQueuedAction {
+        action_id: U64,
+    },
+    SetPaused {
+        feature: PausableFeature,
+        paused: bool,
+    },
+    GrantRole {
+        account_id: AccountId,
+        role: Role,
+    },
+    RevokeRole {
+        account_id: AccountId,
+        role: Role,
+    },
+}
+
+impl OwnerAction {
+    // Name of the action for events, the arguments can be read from the proposal or queued action
+    pub fn name(&self) -> &'static str {
+        match self {
+            OwnerAction::UpdateAttestationExpirationTime { .. } => {
+                "update_attestation_expiration_time"
+            }
+            OwnerAction::UpdateOwnerId { .. } => "update_owner_id",
+            OwnerAction::UpdateMpcContractId { .. } => "update_mpc_contract_id",
+            OwnerAction::SetSignConfig { .. } => "set_sign_config",
+            OwnerAction::SetSignatureDomain {
+                domain: Some(_), ..
+            } => "set_signature_domain",
+            OwnerAction::SetSignatureDomain { domain: None, .. } => "remove_signature_domain",
+            OwnerAction::SetCkdDomain { domain_id: Some(_) } => "set_ckd_domain",
+            OwnerAction::SetCkdDomain { domain_id: None } => "remove_ckd_domain",
+            OwnerAction::ApproveMeasurements { .. } => "approve_measurements",
+            OwnerAction::UpdateMeasurementsMetadata { .. } => "update_measurements_metadata",
+            OwnerAction::RemoveMeasurements { .. } => "remove_measurements",
+            OwnerAction::ScheduleMeasurementsSunset { .. } => "schedule_measurements_sunset",
+            OwnerAction::CancelMeasurementsSunset { .. } => "cancel_measurements_sunset",
+            OwnerAction::SetMeasurementsAgentQuota { .. } => "set_measurements_agent_quota",
+            OwnerAction::SetMeasurementsPpidGroup { .. } => "set_measurements_ppid_group",
+            OwnerAction::SetEvmPolicy {
+                policy: Some(_), ..
+            } => "set_evm_policy",
+            OwnerAction::SetEvmPolicy { policy: None, .. } => "remove_evm_policy",
+            OwnerAction::SetBitcoinPolicy {
+                policy: Some(_), ..
+            } => "set_bitcoin_policy",
+            OwnerAction::SetBitcoinPolicy { policy: None, .. } => "remove_bitcoin_policy",
+            OwnerAction::SetSolanaPolicy {
+                policy: Some(_), ..
+            } => "set_solana_policy",
+            OwnerAction::SetSolanaPolicy { policy: None, .. } => "remove_solana_policy",
+            OwnerAction::SetRateLimits {
+                rate_limits: Some(_),
+            } => "set_rate_limits",
+            OwnerAction::SetRateLimits { rate_limits: None } => "remove_rate_limits",
+            OwnerAction::SetPathPolicy {
+                policy: Some(_), ..
+            } => "set_path_policy",
+            OwnerAction::SetPathPolicy { policy: None, .. } => "remove_path_policy",
+            OwnerAction::SetAgentPathPolicy {
+                policy: Some(_), ..
+            } => "set_agent_path_policy",
+            OwnerAction::SetAgentPathPolicy { policy: None, .. } => "remove_agent_path_policy",
+            OwnerAction::SetPpidGroup { .. } => "set_ppid_group",
+            OwnerAction::RemovePpidGroup { .. } => "remove_ppid_group",
+            OwnerAction::SetPpidMeasurementsScope { .. } => "set_ppid_measurements_scope",
+            OwnerAction::ApprovePpids { .. } => "approve_ppids",
+            OwnerAction::UpdatePpidMetadata { .. } => "update_ppid_metadata",
+            OwnerAction::RemovePpids { .. } => "remove_ppids",
+            OwnerAction::RemoveAgent { .. } => "remove_agent",
+            OwnerAction::SetPruneReward { .. } => "set_prune_reward",
+            OwnerAction::ApproveCodeHash { .. } => "approve_code_hash",
+            OwnerAction::RollbackCode { .. } => "rollback_code",
+            OwnerAction::CancelCodeUpgrade => "cancel_code_upgrade",
+            OwnerAction::WhitelistAgentForLocal { .. } => "whitelist_agent_for_local",
+            OwnerAction::RemoveAgentFromWhitelistForLocal { .. } => {
+                "remove_agent_from_whitelist_for_local"
+            }
+            OwnerAction::SetMultisig { .. } => "set_multisig",
+            OwnerAction::DisableMultisig => "disable_multisig",
+            OwnerAction::SetTimelockDelay { .. } => "set_timelock_delay",
+            OwnerAction::CancelQueuedAction { .. } => "cancel_queued_action",
+            OwnerAction::SetPaused { paused: true, .. } => "pause",
+            OwnerAction::SetPaused { paused: false, .. } => "unpause",
+            OwnerAction::GrantRole { .. } => "grant_role",
+            OwnerAction::RevokeRole { .. } => "revoke_role",
+        }
+    }
+
+    // Role that allows an account other than the owner to run the action, None if only the owner can
+    // Cancelling a queued action requires the role of the queued action
+    pub fn required_role(&self) -> Option<Role> {
+        match self {
+            OwnerAction::ApproveMeasurements { .. }
+            | OwnerAction::UpdateMeasurementsMetadata { .. }
+            | OwnerAction::RemoveMeasurements { .. }
+            | OwnerAction::ScheduleMeasurementsSunset { .. }
+            | OwnerAction::CancelMeasurementsSunset { .. }
+            | OwnerAction::SetMeasurementsAgentQuota { .. }
+            | OwnerAction::SetMeasurementsPpidGroup { .. }
+            | OwnerAction::SetEvmPolicy { .. }
+            | OwnerAction::SetBitcoinPolicy { .. }
+            | OwnerAction::SetSolanaPolicy { .. }
+            | OwnerAction::SetPathPolicy { .. } => Some(Role::MeasurementAdmin),
+            OwnerAction::SetPpidGroup { .. }
+            | OwnerAction::RemovePpidGroup { .. }
+            | OwnerAction::SetPpidMeasurementsScope { .. }
+            | OwnerAction::ApprovePpids { .. }
+            | OwnerAction::UpdatePpidMetadata { .. }
+            | OwnerAction::RemovePpids { .. } => Some(Role::PpidAdmin),
+            OwnerAction::UpdateAttestationExpirationTime { .. }
+            | OwnerAction::RemoveAgent { .. }
+            | OwnerAction::SetPruneReward { .. }
+            | OwnerAction::SetAgentPathPolicy { .. }
+            | OwnerAction::WhitelistAgentForLocal { .. }
+            | OwnerAction::RemoveAgentFromWhitelistForLocal { .. } => Some(Role::AgentAdmin),
+            OwnerAction::ApproveCodeHash { .. }
+            | OwnerAction::RollbackCode { .. }
+            | OwnerAction::CancelCodeUpgrade => Some(Role::Upgrader),
+            OwnerAction::SetPaused { .. } => Some(Role::Pauser),
+            OwnerAction::UpdateOwnerId { .. }
+            | OwnerAction::UpdateMpcContractId { .. }
+            | OwnerAction::SetSignConfig { .. }
+            | OwnerAction::SetSignatureDomain { .. }
+            | OwnerAction::SetCkdDomain { .. }
+            | OwnerAction::SetRateLimits { .. }
+            | OwnerAction::SetMultisig { .. }
+            | OwnerAction::DisableMultisig
+            | OwnerAction::SetTimelockDelay { .. }
+            | OwnerAction::CancelQueuedAction { .. }
+            | OwnerAction::GrantRole { .. }
+            | OwnerAction::RevokeRole { .. } => None,
+        }
+    }
+
+    // Whether the action waits for the timelock delay before it is applied
+    // Removals and other actions that only restrict access are exempt so incident response stays instant
+    // Pausing is exempt but unpausing is not, it reopens what the pause closed
+    pub fn requires_timelock(&self) -> bool {
+        !matches!(
+            self,
+            OwnerAction::UpdateMeasurementsMetadata { .. }
+                | OwnerAction::RemoveMeasurements { .. }
+                | OwnerAction::ScheduleMeasurementsSunset { .. }
+                | OwnerAction::RemovePpidGroup { .. }
+                | OwnerAction::RemovePpids { .. }
+                | OwnerAction::RemoveAgent { .. }
+                | OwnerAction::RemoveAgentFromWhitelistForLocal { .. }
+                | OwnerAction::CancelCodeUpgrade
+                | OwnerAction::CancelQueuedAction { .. }
+                | OwnerAction::SetPaused { paused: true, .. }
+                | OwnerAction::RevokeRole { .. }
+                | OwnerAction::SetSignatureDomain { domain: None, .. }
+                | OwnerAction::SetCkdDomain { domain_id: None }
+                | OwnerAction::SetEvmPolicy { policy: None, .. }
+                | OwnerAction::SetBitcoinPolicy { policy: None, .. }
+                | OwnerAction::SetSolanaPolicy { policy: None, .. }
+        )
+    }
+}
+
+impl Contract {
+    // Check that the caller may run the action and run it on their behalf
+    // Returns whether the action was applied, false if it was queued behind the timelock
+    pub(crate) fn run_caller_action(&mut self, action: OwnerAction) -> bool {
+        let required_role = match &action {
+            OwnerAction::CancelQueuedAction { action_id } => self
+                .queued_actions
+                .get(&action_id.0)
+                .expect("Queued action not found")
+                .action
+                .required_role(),
+            action => action.required_role(),
+        };
+        match required_role {
+            // Pausers can still pause in multisig mode, pausing only stops agents
+            Some(Role::Pauser)
+                if self.multisig.is_some()
+                    && matches!(action, OwnerAction::SetPaused { paused: true, .. }) =>
+            {
+                require!(
+                    self.has_role(&env::predecessor_account_id(), Role::Pauser),
+                    "Caller does not have the Pauser role"
+                )
+            }
+            Some(role) => self.require_role(role),
+            None => self.require_owner(),
+        }
+        self.run_owner_action(action, env::predecessor_account_id())
+    }
+
+    // Apply a privileged action, or queue it if it is subject to the timelock
+    // The caller is responsible for the permission check
+    // Returns whether the action was applied, false if it was queued
+    pub(crate) fn run_owner_action(
+        &mut self,
+        action: OwnerAction,
+        authorized_by: AccountId,
+    ) -> bool {
+        if self.timelock_delay_ms > 0 && action.requires_timelock() {
+            self.internal_queue_action(action, authorized_by);
+            return false;
+        }
+        self.apply_owner_action(action, authorized_by);
+        true
+    }
+
+    // Apply a privileged action, authorized_by is the account recorded as having approved it
+    pub(crate) fn apply_owner_action(&mut self, action: OwnerAction, authorized_by: AccountId) {
+        match action {
+            OwnerAction::UpdateAttestationExpirationTime {
+                attestation_expiration_time_ms,
+            } => {
+                Event::AttestationExpirationTimeUpdated {
+                    old_attestation_expiration_time_ms: U64::from(
+                        self.attestation_expiration_time_ms,
+                    ),
+                    new_attestation_expiration_time_ms: attestation_expiration_time_ms,
+                }
+                .emit();
+                self.attestation_expiration_time_ms = attestation_expiration_time_ms.into();
+            }
+            OwnerAction::UpdateOwnerId { owner_id } => {
+                Event::OwnerUpdated {
+                    old_owner_id: &self.owner_id,
+                    new_owner_id: &owner_id,
+                }
+                .emit();
+                self.owner_id = owner_id;
+            }
+            OwnerAction::UpdateMpcContractId { mpc_contract_id } => {
+                Event::MpcContractUpdated {
+                    old_mpc_contract_id: &self.mpc_contract_id,
+                    new_mpc_contract_id: &mpc_contract_id,
+                }
+                .emit();
+                self.mpc_contract_id = mpc_contract_id;
+            }
+            OwnerAction::SetSignConfig {
+                sign_gas_tgas,
+                sign_deposit,
+            } => {
+                require!(
+                    sign_gas_tgas > 0 && sign_gas_tgas <= MAX_SIGN_GAS_TGAS,
+                    format!("Sign gas must be between 1 and {MAX_SIGN_GAS_TGAS} TGas")
+                );
+                Event::SignConfigUpdated {
+                    old_sign_gas_tgas: self.sign_gas_tgas,
+                    new_sign_gas_tgas: sign_gas_tgas,
+                    old_sign_deposit: self.sign_deposit,
+                    new_sign_deposit: sign_deposit,
+                }
+                .emit();
+                self.sign_gas_tgas = sign_gas_tgas;
+                self.sign_deposit = sign_deposit;
+            }
+            OwnerAction::SetSignatureDomain { scheme, domain } => {
+                self.internal_set_signature_domain(scheme, domain)
+            }
+            OwnerAction::SetCkdDomain { domain_id } => self.internal_set_ckd_domain(domain_id),
+            OwnerAction::ApproveMeasurements {
+                measurements,
+                label,
+                source,
+            } => {
+                self.internal_approve_measurements(measurements, label, source, authorized_by);
+            }
+            OwnerAction::UpdateMeasurementsMetadata {
+                measurements_id,
+                label,
+                source,
+            } => self.internal_update_measurements_metadata(measurements_id, label, source),
+            OwnerAction::RemoveMeasurements { measurements } => {
+                self.internal_remove_measurements(measurements, authorized_by)
+            }
+            OwnerAction::ScheduleMeasurementsSunset {
+                measurements_id,
+                sunset_at_ms,
+            } => self.internal_schedule_measurements_sunset(measurements_id, sunset_at_ms),
+            OwnerAction::CancelMeasurementsSunset { measurements_id } => {
+                self.internal_cancel_measurements_sunset(measurements_id)
+            }
+            OwnerAction::SetMeasurementsAgentQuota {
+                measurements_id,
+                max_agents,
+            } => {
+                self.approved_measurements
+                    .get_mut(&measurements_id)
+                    .expect("Measurements not in approved list")
+                    .max_agents = max_agents;
+                Event::MeasurementsAgentQuotaSet {
+                    measurements_id,
+                    max_agents,
+                }
+                .emit();
+            }
+            OwnerAction::SetMeasurementsPpidGroup {
+                measurements_id,
+                ppid_group,
+            } => self.internal_set_measurements_ppid_group(measurements_id, ppid_group),
+            OwnerAction::SetEvmPolicy {
+                measurements_id,
+                policy,
+            } => self.internal_set_evm_policy(measurements_id, policy),
+            OwnerAction::SetBitcoinPolicy {
+                measurements_id,
+                policy,
+            } => self.internal_set_bitcoin_policy(measurements_id, policy),
+            OwnerAction::SetSolanaPolicy {
+                measurements_id,
+                policy,
+            } => self.internal_set_solana_policy(measurements_id, policy),
+            OwnerAction::SetRateLimits { rate_limits } => {
+                self.internal_set_rate_limits(rate_limits)
+            }
+            OwnerAction::SetPathPolicy {
+                measurements_id,
+                policy,
+            } => self.internal_set_path_policy(measurements_id, policy),
+            OwnerAction::SetAgentPathPolicy { account_id, policy } => {
+                self.internal_set_agent_path_policy(account_id, policy)
+            }
+            OwnerAction::SetPpidGroup { name, ppids } => self.internal_set_ppid_group(name, ppids),
+            OwnerAction::RemovePpidGroup { name } => self.internal_remove_ppid_group(name),
+            OwnerAction::SetPpidMeasurementsScope {
+                ppid,
+                measurements_ids,
+            } => {
+                Event::PpidMeasurementsScopeSet {
+                    ppid: &ppid,
+                    measurements_ids: measurements_ids.as_deref(),
+                }
+                .emit();
+                match measurements_ids {
+                    Some(measurements_ids) => {
+                        self.ppid_measurements_scopes.insert(ppid, measurements_ids);
+                    }
+                    None => {
+                        self.ppid_measurements_scopes.remove(&ppid);
+                    }
+                }
+            }
+            OwnerAction::ApprovePpids {
+                ppids,
+                operator,
+                region,
+                expires_at_ms,
+            } => self.internal_approve_ppids(ppids, operator, region, expires_at_ms, authorized_by),
+            OwnerAction::UpdatePpidMetadata {
+                ppid,
+                operator,
+                region,
+                expires_at_ms,
+            } => self.internal_update_ppid_metadata(
+                ppid,
+                operator,
+                region,
+                expires_at_ms,
+                authorized_by,
+            ),
+            OwnerAction::RemovePpids { ppids, reason } => {
+                self.internal_remove_ppids(ppids, reason, authorized_by)
+            }
+            OwnerAction::RemoveAgent { account_id } => {
+                self.internal_remove_agent(&account_id)
+                    .expect("Agent not registered");
+                Event::AgentRemoved {
+                    account_id: &account_id,
+                    reasons: vec![AgentRemovalReason::ManualRemoval],
+                }
+                .emit();
+                self.internal_unregister_storage(&account_id, 0);
+            }
+            OwnerAction::SetPruneReward { prune_reward_bps } => {
+                require!(
+                    prune_reward_bps <= MAX_PRUNE_REWARD_BPS,
+                    format!("Prune reward must be at most {MAX_PRUNE_REWARD_BPS} basis points")
+                );
+                Event::PruneRewardUpdated {
+                    old_prune_reward_bps: self.prune_reward_bps,
+                    new_prune_reward_bps: prune_reward_bps,
+                }
+                .emit();
+                self.prune_reward_bps = prune_reward_bps;
+            }
+            OwnerAction::ApproveCodeHash {
+                code_hash,
+                migrate_gas_tgas,
+            } => self.internal_approve_code_hash(code_hash.into(), migrate_gas_tgas, authorized_by),
+            OwnerAction::RollbackCode { migrate_gas_tgas } => {
+                let previous_code_hash = self.require_rollback_possible();
+                self.internal_approve_code_hash(
+                    previous_code_hash,
+                    migrate_gas_tgas,
+                    authorized_by,
+                );
+            }
+            OwnerAction::CancelCodeUpgrade => {
+                let pending_upgrade = self
+                    .pending_upgrade
+                    .take()
+                    .expect("There is no pending upgrade");
+                Event::CodeUpgradeCancelled {
+                    code_hash: pending_upgrade.code_hash.into(),
+                }
+                .emit();
+            }
+            OwnerAction::WhitelistAgentForLocal { account_id } => {
+                if self.requires_tee {
+                    panic!("Whitelisting agents is not supported for TEE");
+                }
+                // Only insert and emit if not already whitelisted
+                if self.whitelisted_agents_for_local.insert(account_id.clone()) {
+                    Event::AgentWhitelistedForLocal {
+                        account_id: &account_id,
+                    }
+                    .emit();
+                }
+            }
+            OwnerAction::RemoveAgentFromWhitelistForLocal { account_id } => {
+                if self.requires_tee {
+                    panic!("Removing agents from the whitelist is not supported for TEE");
+                }
+                require!(
+                    self.whitelisted_agents_for_local.remove(&account_id),
+                    "Agent not in whitelist for local"
+                );
+                Event::AgentRemovedFromWhitelistForLocal {
+                    account_id: &account_id,
+                }
+                .emit();
+            }
+            OwnerAction::SetMultisig {
+                owners,
+                threshold,
+                proposal_lifetime_ms,
+            } => self.internal_set_multisig(owners, threshold, proposal_lifetime_ms),
+            OwnerAction::DisableMultisig => {
+                require!(self.multisig.take().is_some(), "Multisig is not enabled");
+                Event::MultisigDisabled {
+                    account_id: &authorized_by,
+                }
+                .emit();
+            }
+            OwnerAction::SetTimelockDelay { timelock_delay_ms } => {
+                Event::TimelockDelayUpdated {
+                    old_timelock_delay_ms: U64::from(self.timelock_delay_ms),
+                    new_timelock_delay_ms: timelock_delay_ms,
+                }
+                .emit();
+                self.timelock_delay_ms = timelock_delay_ms.0;
+            }
+            OwnerAction::SetPaused { feature, paused } => {
+                let flag = match feature {
+                    PausableFeature::Registration => &mut self.registration_paused,
+                    PausableFeature::Signing => &mut self.signing_paused,
+                };
+                require!(
+                    *flag != paused,
+                    if paused {
+                        format!("{feature:?} is already paused")
+                    } else {
+                        format!("{feature:?} is not paused")
+                    }
+                );
+                *flag = paused;
+                if paused {
+                    Event::Paused {
+                        feature,
+                        account_id: &authorized_by,
+                    }
+                    .emit();
+                } else {
+                    Event::Unpaused {
+                        feature,
+                        account_id: &authorized_by,
+                    }
+                    .emit();
+                }
+            }
+            OwnerAction::GrantRole { account_id, role } => {
+                let roles = self.roles.entry(account_id.clone()).or_default();
+                if !roles.contains(&role) {
+                    roles.push(role);
+                }
+                Event::RoleGranted {
+                    account_id: &account_id,
+                    role,
+                }
+                .emit();
+            }
+            OwnerAction::RevokeRole { account_id, role } => {
+                let roles = self
+                    .roles
+                    .get_mut(&account_id)
+                    .filter(|roles| roles.contains(&role))
+                    .expect("Account does not have this role");
+                roles.retain(|granted| *granted != role);
+                if roles.is_empty() {
+                    self.roles.remove(&account_id);
+                }
+                Event::RoleRevoked {
+                    account_id: &account_id,
+                    role,
+                }
+                .emit();
+                self.cancel_actions_queued_with_role(&account_id, role);
+            }
+            OwnerAction::CancelQueuedAction { action_id } => {
+                let queued = self
+                    .queued_actions
+                    .remove(&action_id.0)
+                    .expect("Queued action not found");
+                Event::QueuedActionCancelled {
+                    action_id,
+                    action: queued.action.name(),
+                }
+                .emit();
+            }
+        }
+    }
+
+    // Cancel the actions an account queued through a role it lost, they would still run after the delay
+    // The owner and multisig owners keep theirs, they may have queued them with their own authority
+    fn cancel_actions_queued_with_role(&mut self, account_id: &AccountId, role: Role) {
+        if *account_id == self.owner_id
+            || self
+                .multisig
+                .as_ref()
+                .is_some_and(|multisig| multisig.owners.contains(account_id))
+        {
+            return;
+        }
+        let action_ids: Vec<QueuedActionId> = self
+            .queued_actions
+            .iter()
+            .filter(|(_, queued)| {
+                queued.queued_by == *account_id && queued.action.required_role() == Some(role)
+            })
+            .map(|(action_id, _)| *action_id)
+            .collect();
+        for action_id in action_ids {
+            let queued = self.queued_actions.remove(&action_id).unwrap();
+            Event::QueuedActionCancelled {
+                action_id: U64::from(action_id),
+                action: queued.action.name(),
+            }
+            .emit();
+        }
+    }
+
+    // Return the code hash to roll back to, if the code before the current one can read the state
+    // Older code refuses state written by a newer state version in migrate, so the rollback would fail
+    pub(crate) fn require_rollback_possible(&self) -> CryptoHash {
+        let previous_code_hash = self
+            .previous_code_hash
+            .expect("There is no previous code hash to roll back to");
+        require!(
+            self.previous_code_state_version
+                .is_some_and(|version| version >= migration::read_state_version()),
+            "The previous code cannot read the current state version"
+        );
+        previous_code_hash
+    }
+
+    pub(crate) fn internal_approve_measurements(
+        &mut self,
+        measurements: FullMeasurementsHex,
+        label: Option<String>,
+        source: Option<String>,
+        approved_by: AccountId,
+    ) -> MeasurementsId {
+        if let Some(measurements_id) = self.measurements_ids.get(&measurements) {
+            return *measurements_id;
+        }
+        let label = label.unwrap_or_default();
+        let source = source.unwrap_or_default();
+        require_valid_measurements_metadata(&label, &source);
+
+        let measurements_id = self.next_measurements_id;
+        self.next_measurements_id = measurements_id
+            .checked_add(1)
+            .expect("Measurements ID overflow");
+        self.measurements_ids
+            .insert(measurements.clone(), measurements_id);
+        Event::MeasurementsApproved {
+            measurements_id,
+            measurements: &measurements,
+            label: &label,
+            source: &source,
+            approved_by: &approved_by,
+        }
+        .emit();
+        self.record_allowlist_change(
+            AllowlistChange::MeasurementsApproved {
+                measurements_id,
+                measurements: measurements.clone(),
+            },
+            approved_by.clone(),
+        );
+        self.approved_measurements.insert(
+            measurements_id,
+            ApprovedMeasurements {
+                measurements,
+                label,
+                source,
+                approved_by,
+                approved_at_ms: block_timestamp_ms(),
+                sunset_at_ms: None,
+                max_agents: None,
+                active_agents: 0,
+                ppid_group: None,
+            },
+        );
+        measurements_id
+    }
+
+    fn internal_update_measurements_metadata(
+        &mut self,
+        measurements_id: MeasurementsId,
+        label: Option<String>,
+        source: Option<String>,
+    ) {
+        let approved = self
+            .approved_measurements
+            .get_mut(&measurements_id)
+            .expect("Measurements not in approved list");
+        if let Some(label) = label {
+            approved.label = label;
+        }
+        if let Some(source) = source {
+            approved.source = source;
+        }
+        require_valid_measurements_metadata(&approved.label, &approved.source);
+
+        Event::MeasurementsMetadataUpdated {
+            measurements_id,
+            label: &approved.label,
+            source: &approved.source,
+        }
+        .emit();
+    }
+
+    fn internal_remove_measurements(
+        &mut self,
+        measurements: FullMeasurementsHex,
+        removed_by: AccountId,
+    ) {
+        let measurements_id = self
+            .measurements_ids
+            .remove(&measurements)
+            .expect("Measurements not in approved list");
+        self.approved_measurements.remove(&measurements_id);
+        self.evm_policies.remove(&measurements_id);
+        self.bitcoin_policies.remove(&measurements_id);
+        self.solana_policies.remove(&measurements_id);
+        self.path_policies.remove(&measurements_id);
+
+        Event::MeasurementsRemoved {
+            measurements_id,
+            measurements: &measurements,
+        }
+        .emit();
+        self.record_allowlist_change(
+            AllowlistChange::MeasurementsRemoved {
+                measurements_id,
+                measurements,
+            },
+            removed_by,
+        );
+    }
+
+    fn internal_schedule_measurements_sunset(
+        &mut self,
+        measurements_id: MeasurementsId,
+        sunset_at_ms: U64,
+    ) {
+        require!(
+            sunset_at_ms.0 > block_timestamp_ms(),
+            "Sunset time must be in the future"
+        );
+        let approved = self
+            .approved_measurements
+            .get_mut(&measurements_id)
+            .expect("Measurements not in approved list");
+        approved.sunset_at_ms = Some(sunset_at_ms.0);
+
+        Event::MeasurementsSunsetScheduled {
+            measurements_id,
+            sunset_at_ms,
+        }
+        .emit();
+    }
+
+    fn internal_cancel_measurements_sunset(&mut self, measurements_id: MeasurementsId) {
+        let approved = self
+            .approved_measurements
+            .get_mut(&measurements_id)
+            .expect("Measurements not in approved list");
+        let sunset_at_ms = approved
+            .sunset_at_ms
+            .take()
+            .expect("Measurements are not scheduled for sunset");
+        require!(
+            sunset_at_ms > block_timestamp_ms(),
+            "Measurements sunset has already taken effect"
+        );
+
+        Event::MeasurementsSunsetCancelled { measurements_id }.emit();
+    }
+
+    fn internal_set_measurements_ppid_group(
+        &mut self,
+        measurements_id: MeasurementsId,
+        ppid_group: Option<String>,
+    ) {
+        if let Some(group) = &ppid_group {
+            require!(self.ppid_groups.contains_key(group), "PPID group not found");
+        }
+        self.approved_measurements
+            .get_mut(&measurements_id)
+            .expect("Measurements not in approved list")
+            .ppid_group = ppid_group.clone();
+
+        Event::MeasurementsPpidGroupSet {
+            measurements_id,
+            ppid_group: ppid_group.as_deref(),
+        }
+        .emit();
+    }
+
+    fn internal_set_ppid_group(&mut self, name: String, ppids: Vec<Ppid>) {
+        require!(
+            !name.is_empty() && name.len() <= MAX_PPID_GROUP_NAME_LEN,
+            format!("PPID group name must be between 1 and {MAX_PPID_GROUP_NAME_LEN} bytes")
+        );
+        Event::PpidGroupSet {
+            name: &name,
+            ppids: &ppids,
+        }
+        .emit();
+        self.ppid_groups.insert(name, ppids);
+    }
+
+    fn internal_remove_ppid_group(&mut self, name: String) {
+        require!(
+            !self
+                .approved_measurements
+                .values()
+                .any(|approved| approved.ppid_group.as_ref() == Some(&name)),
+            "PPID group is still used by approved measurements"
+        );
+        require!(
+            self.ppid_groups.remove(&name).is_some(),
+            "PPID group not found"
+        );
+
+        Event::PpidGroupRemoved { name: &name }.emit();
+    }
+
+    fn internal_approve_ppids(
+        &mut self,
+        ppids: Vec<Ppid>,
+        operator: Option<String>,
+        region: Option<String>,
+        expires_at_ms: Option<U64>,
+        approved_by: AccountId,
+    ) {
+        let operator = operator.unwrap_or_default();
+        let region = region.unwrap_or_default();
+        require_valid_ppid_metadata(&operator, &region);
+        // A PPID approved with an expiry that already passed would never be valid
+        require!(
+            expires_at_ms.is_none_or(|expires_at_ms| expires_at_ms.0 > block_timestamp_ms()),
+            "PPID expiry must be in the future"
+        );
+        let mut approved = Vec::new();
+        for id in ppids {
+            if self.approved_ppids.contains_key(&id) {
+                continue;
+            }
+            self.revoked_ppids.remove(&id);
+            approved.push(id.clone());
+            self.record_allowlist_change(
+                AllowlistChange::PpidApproved {
+                    ppid: id.clone(),
+                    expires_at_ms,
+                },
+                approved_by.clone(),
+            );
+            self.approved_ppids.insert(
+                id,
+                ApprovedPpid {
+                    operator: operator.clone(),
+                    region: region.clone(),
+                    added_at_ms: block_timestamp_ms(),
+                    expires_at_ms: expires_at_ms.map(|expires_at_ms| expires_at_ms.0),
+                },
+            );
+        }
+
+        if !approved.is_empty() {
+            Event::PpidsApproved {
+                ppids: &approved,
+                operator: &operator,
+                region: &region,
+                expires_at_ms,
+            }
+            .emit();
+        }
+    }
+
+    fn internal_update_ppid_metadata(
+        &mut self,
+        ppid: Ppid,
+        operator: String,
+        region: String,
+        expires_at_ms: Option<U64>,
+        updated_by: AccountId,
+    ) {
+        require_valid_ppid_metadata(&operator, &region);
+        let approved = self
+            .approved_ppids
+            .get_mut(&ppid)
+            .expect("PPID not in approved list");
+        approved.operator = operator;
+        approved.region = region;
+        let new_expires_at_ms = expires_at_ms.map(|expires_at_ms| expires_at_ms.0);
+        let expiry_changed = approved.expires_at_ms != new_expires_at_ms;
+        approved.expires_at_ms = new_expires_at_ms;
+
+        Event::PpidMetadataUpdated {
+            ppid: &ppid,
+            operator: &approved.operator,
+            region: &approved.region,
+            expires_at_ms,
+        }
+        .emit();
+        // Only the expiry changes whether the PPID is approved, so the history skips the rest
+        if expiry_changed {
+            self.record_allowlist_change(
+                AllowlistChange::PpidExpiryUpdated {
+                    ppid,
+                    expires_at_ms,
+                },
+                updated_by,
+            );
+        }
+    }
+
+    fn internal_remove_ppids(
+        &mut self,
+        ppids: Vec<Ppid>,
+        reason: Option<PpidRevocationReason>,
+        removed_by: AccountId,
+    ) {
+        for id in &ppids {
+            require!(
+                self.approved_ppids.remove(id).is_some(),
+                "PPID not in approved list"
+            );
+            self.revoked_ppids.insert(
+                id.clone(),
+                PpidRevocation {
+                    reason,
+                    revoked_at_ms: block_timestamp_ms(),
+                },
+            );
+            self.record_allowlist_change(
+                AllowlistChange::PpidRevoked {
+                    ppid: id.clone(),
+                    reason,
+                },
+                removed_by.clone(),
+            );
+        }
+        Event::PpidsRevoked {
+            ppids: &ppids,
+            reason,
+        }
+        .emit();
+    }
+
+    // Replace any pending upgrade with the code hash
+    fn internal_approve_code_hash(
+        &mut self,
+        code_hash: CryptoHash,
+        migrate_gas_tgas: u64,
+        approved_by: AccountId,
+    ) {
+        require!(
+            self.code_hash != Some(code_hash),
+            "Code hash is already deployed"
+        );
+        Event::CodeUpgradeApproved {
+            code_hash: code_hash.into(),
+            migrate_gas_tgas,
+            approved_by: &approved_by,
+        }
+        .emit();
+        self.pending_upgrade = Some(PendingUpgrade {
+            code_hash,
+            migrate_gas_tgas,
+            approved_by,
+            approved_at_ms: block_timestamp_ms(),
+        });
+    }
+
+    fn internal_set_multisig(
+        &mut self,
+        owners: Vec<AccountId>,
+        threshold: u32,
+        proposal_lifetime_ms: U64,
+    ) {
+        require!(
+            !owners.is_empty() && owners.len() <= MAX_MULTISIG_OWNERS,
+            format!("Multisig must have between 1 and {MAX_MULTISIG_OWNERS} owners")
+        );
+        require!(
+            owners
+                .iter()
+                .enumerate()
+                .all(|(i, owner)| !owners[..i].contains(owner)),
+            "Multisig owners must be unique"
+        );
+        require!(
+            threshold >= 1 && threshold as usize <= owners.len(),
+            "Threshold must be between 1 and the number of owners"
+        );
+        require!(
+            proposal_lifetime_ms.0 > 0,
+            "Proposal lifetime must be greater than zero"
+        );
+        Event::MultisigUpdated {
+            owners: &owners,
+            threshold,
+            proposal_lifetime_ms,
+        }
+        .emit();
+        self.multisig = Some(Multisig {
+            owners,
+            threshold,
+            proposal_lifetime_ms: proposal_lifetime_ms.0,
+        });
+    }
+}
+
+const MAX_MEASUREMENTS_LABEL_LEN: usize = 64;
+const MAX_MEASUREMENTS_SOURCE_LEN: usize = 256;
+const MAX_PPID_OPERATOR_LEN: usize = 64;
+const MAX_PPID_REGION_LEN: usize = 64;
+const MAX_PPID_GROUP_NAME_LEN: usize = 64;
+const MAX_MULTISIG_OWNERS: usize = 16;
+// The prune reward is meant to cover the caller's gas, most of the deposit goes back to the agent
+const MAX_PRUNE_REWARD_BPS: u16 = 1_000;
+// Leaves room in the 300 TGas of a transaction for request_signature and on_signature
+const MAX_SIGN_GAS_TGAS: u64 = 250;
+
+fn require_valid_measurements_metadata(label: &str, source: &str) {
+    require!(
+        label.len() <= MAX_MEASUREMENTS_LABEL_LEN,
+        format!("Label must be at most {MAX_MEASUREMENTS_LABEL_LEN} bytes")
+    );
+    require!(
+        source.len() <= MAX_MEASUREMENTS_SOURCE_LEN,
+        format!("Source must be at most {MAX_MEASUREMENTS_SOURCE_LEN} bytes")
+    );
+}
+
+fn require_valid_ppid_metadata(operator: &str, region: &str) {
+    require!(
+        operator.len() <= MAX_PPID_OPERATOR_LEN,
+        format!("Operator must be at most {MAX_PPID_OPERATOR_LEN} bytes")
+    );
+    require!(
+        region.len() <= MAX_PPID_REGION_LEN,
+        format!("Region must be at most {MAX_PPID_REGION_LEN} bytes")
+    );
+}
@@ -0,0 +1,169 @@
+use crate::*;
+
+// Namespaced paths start with these, account IDs and measurement IDs cannot contain a colon
+const ACCOUNT_NAMESPACE: &str = "account:";
+const MEASUREMENTS_NAMESPACE: &str = "measurements:";
+
+// Derivation paths agents can sign with, set per set of measurements or per agent
+#[near(serializers = [borsh, json])]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathPolicy {
+    // Paths agents can pass, empty allows any path
+    pub allowed_paths: Vec<PathRule>,
+    // Prefix added to the path agents pass before it is sent to the MPC contract, None for no prefix
+    pub namespace: Option<PathNamespace>,
+}
+
+#[near(serializers = [borsh, json])]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathRule {
+    Exact(String),
+    Prefix(String),
+    // Segments are separated by /, a * matches any part of a single segment
+    Pattern(String),
+}
+
+#[near(serializers = [borsh, json])]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathNamespace {
+    // account:<agent account ID>/<path>
+    Account,
+    // measurements:<measurements ID>/<path>
+    Measurements,
+}
+
+// Whether a segment matches a pattern segment, * matches any run of characters
+// Only the last * is backtracked to, so agents cannot make matching exponential
+fn segment_matches(pattern: &[u8], segment: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // Position after the last * and the segment position it was matched up to
+    let mut star: Option<(usize, usize)> = None;
+    while s < segment.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            p += 1;
+            star = Some((p, s));
+        } else if p < pattern.len() && pattern[p] == segment[s] {
+            p += 1;
+            s += 1;
+        } else if let Some((star_p, star_s)) = star {
+            p = star_p;
+            s = star_s + 1;
+            star = Some((star_p, s));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|byte| *byte == b'*')
+}
+
+impl PathRule {
+    fn matches(&self, path: &str) -> bool {
+        match self {
+            PathRule::Exact(exact) => path == exact,
+            PathRule::Prefix(prefix) => path.starts_with(prefix.as_str()),
+            PathRule::Pattern(pattern) => {
+                let segments: Vec<&str> = path.split('/').collect();
+                let pattern_segments: Vec<&str> = pattern.split('/').collect();
+                segments.len() == pattern_segments.len()
+                    && pattern_segments
+                        .iter()
+                        .zip(segments)
+                        .all(|(pattern, segment)| {
+                            segment_matches(pattern.as_bytes(), segment.as_bytes())
+                        })
+            }
+        }
+    }
+}
+
+impl PathPolicy {
+    pub(crate) fn validate(&self) {
+        for rule in &self.allowed_paths {
+            let (PathRule::Exact(value) | PathRule::Prefix(value) | PathRule::Pattern(value)) =
+                rule;
+            require!(!value.is_empty(), "Path rules must not be empty");
+        }
+    }
+}
+
+impl Contract {
+    // Check the path an agent passed against its path policy and return the path to sign with
+    // The agent's own policy takes precedence over the policy of its measurements
+    pub(crate) fn internal_derivation_path(&self, account_id: &AccountId, path: String) -> String {
+        for namespace in [ACCOUNT_NAMESPACE, MEASUREMENTS_NAMESPACE] {
+            require!(
+                !path.starts_with(namespace),
+                format!("Paths starting with {namespace} are reserved for key namespaces")
+            );
+        }
+        let measurements_id = self
+            .agents
+            .get(account_id)
+            .expect("Agent not registered")
+            .measurements_id;
+        let Some(policy) = self
+            .agent_path_policies
+            .get(account_id)
+            .or_else(|| self.path_policies.get(&measurements_id))
+        else {
+            return path;
+        };
+
+        require!(
+            policy.allowed_paths.is_empty()
+                || policy.allowed_paths.iter().any(|rule| rule.matches(&path)),
+            format!("Path {path} is not allowed")
+        );
+        match policy.namespace {
+            Some(PathNamespace::Account) => format!("{ACCOUNT_NAMESPACE}{account_id}/{path}"),
+            Some(PathNamespace::Measurements) => {
+                format!("{MEASUREMENTS_NAMESPACE}{measurements_id}/{path}")
+            }
+            None => path,
+        }
+    }
+
+    // Set or, with None, remove the path policy of a set of measurements
+    pub(crate) fn internal_set_path_policy(
+        &mut self,
+        measurements_id: MeasurementsId,
+        policy: Option<PathPolicy>,
+    ) {
+        if let Some(policy) = &policy {
+            require!(
+                self.approved_measurements.contains_key(&measurements_id),
+                "Measurements not in approved list"
+            );
+            policy.validate();
+        }
+        Event::PathPolicySet {
+            measurements_id,
+            policy: policy.as_ref(),
+        }
+        .emit();
+        match policy {
+            Some(policy) => self.path_policies.insert(measurements_id, policy),
+            None => self.path_policies.remove(&measurements_id),
+        };
+    }
+
+    // Set or, with None, remove the path policy of an agent, which is kept when the agent is removed
+    pub(crate) fn internal_set_agent_path_policy(
+        &mut self,
+        account_id: AccountId,
+        policy: Option<PathPolicy>,
+    ) {
+        if let Some(policy) = &policy {
+            policy.validate();
+        }
+        Event::AgentPathPolicySet {
+            account_id: &account_id,
+            policy: policy.as_ref(),
+        }
+        .emit();
+        match policy {
+            Some(policy) => self.agent_path_policies.insert(account_id, policy),
+            None => self.agent_path_policies.remove(&account_id),
+        };
+    }
+}
@@ -0,0 +1,299 @@
+use crate::*;
+use internal::chainsig::FAIL_GAS;
+
+// Most budgets the rate limits can have, which bounds the counters stored for each agent
+pub(crate) const MAX_RATE_BUDGETS: usize = 4;
+
+// Asset whose transfers a structured signature request moves, in its smallest unit
+#[near(serializers = [borsh, json])]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Asset {
+    // Native coin of an EVM chain, in wei
+    Evm { chain_id: u64 },
+    // In satoshis
+    Bitcoin,
+    // In lamports
+    Solana,
+}
+
+#[near(serializers = [borsh, json])]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimits {
+    // Length of the rolling window the limits apply to
+    pub window_ms: U64,
+    // Signatures one agent and all agents together can request per window, None for no limit
+    pub max_signatures_per_agent: Option<u32>,
+    pub max_signatures: Option<u32>,
+    pub budgets: Vec<AssetBudget>,
+}
+
+// Value of an asset one agent and all agents together can move per window, None for no limit
+#[near(serializers = [borsh, json])]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssetBudget {
+    pub asset: Asset,
+    pub max_per_agent: Option<U128>,
+    pub max_total: Option<U128>,
+}
+
+// The limit a request went over
+#[near(serializers = [json])]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimit {
+    AgentSignatures,
+    Signatures,
+    AgentBudget(Asset),
+    Budget(Asset),
+}
+
+// Usage in the current and the previous window, windows are aligned to multiples of the window length
+#[near(serializers = [borsh])]
+#[derive(Debug, Clone, Default)]
+pub struct RateCounter {
+    window_start_ms: u64,
+    current: u128,
+    previous: u128,
+}
+
+impl RateCounter {
+    fn roll(&mut self, now_ms: u64, window_ms: u64) {
+        let window_start_ms = now_ms - now_ms % window_ms;
+        if window_start_ms != self.window_start_ms {
+            self.previous = if window_start_ms == self.window_start_ms + window_ms {
+                self.current
+            } else {
+                0
+            };
+            self.current = 0;
+            self.window_start_ms = window_start_ms;
+        }
+    }
+
+    // Usage over the last window_ms, the previous window counts by how much of it is still in range
+    fn usage(&self, now_ms: u64, window_ms: u64) -> u128 {
+        let mut counter = self.clone();
+        counter.roll(now_ms, window_ms);
+        let window = u128::from(window_ms);
+        let remaining = window - u128::from(now_ms - counter.window_start_ms);
+        // Split so the multiplication cannot overflow
+        let previous =
+            counter.previous / window * remaining + counter.previous % window * remaining / window;
+        counter.current.saturating_add(previous)
+    }
+}
+
+#[near(serializers = [borsh])]
+#[derive(Debug, Clone, Default)]
+pub struct RateCounters {
+    signatures: RateCounter,
+    // Only assets with a budget are counted
+    spent: Vec<(Asset, RateCounter)>,
+}
+
+impl RateCounters {
+    // Counter of an asset, added in the current window if the asset was not counted yet
+    fn spent(&mut self, asset: Asset, now_ms: u64, window_ms: u64) -> &mut RateCounter {
+        let index = match self.spent.iter().position(|(spent, _)| *spent == asset) {
+            Some(index) => index,
+            None => {
+                let mut counter = RateCounter::default();
+                counter.roll(now_ms, window_ms);
+                self.spent.push((asset, counter));
+                self.spent.len() - 1
+            }
+        };
+        &mut self.spent[index].1
+    }
+
+    // Counters with a counter for as many assets as an agent can have, to measure their storage
+    pub(crate) fn largest() -> Self {
+        Self {
+            signatures: RateCounter::default(),
+            spent: (0..MAX_RATE_BUDGETS as u64)
+                .map(|chain_id| (Asset::Evm { chain_id }, RateCounter::default()))
+                .collect(),
+        }
+    }
+
+    // Drop the counters of assets that no longer have a budget
+    fn retain_budgets(&mut self, budgets: &[AssetBudget]) {
+        self.spent
+            .retain(|(asset, _)| budgets.iter().any(|budget| budget.asset == *asset));
+    }
+
+    fn roll(&mut self, now_ms: u64, window_ms: u64) {
+        self.signatures.roll(now_ms, window_ms);
+        for (_, counter) in &mut self.spent {
+            counter.roll(now_ms, window_ms);
+        }
+    }
+
+    pub(crate) fn view(&self, window_ms: u64) -> RateUsageView {
+        let now_ms = block_timestamp_ms();
+        RateUsageView {
+            signatures: U128(self.signatures.usage(now_ms, window_ms)),
+            spent: self
+                .spent
+                .iter()
+                .map(|(asset, counter)| AssetUsageView {
+                    asset: *asset,
+                    amount: U128(counter.usage(now_ms, window_ms)),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl RateLimits {
+    pub(crate) fn validate(&self) {
+        require!(
+            self.window_ms.0 > 0,
+            "Rate limit window must be greater than zero"
+        );
+        require!(
+            self.budgets.len() <= MAX_RATE_BUDGETS,
+            format!("At most {MAX_RATE_BUDGETS} budgets are allowed")
+        );
+        for (index, budget) in self.budgets.iter().enumerate() {
+            require!(
+                !self.budgets[..index]
+                    .iter()
+                    .any(|other| other.asset == budget.asset),
+                format!("Duplicate budget for {:?}", budget.asset)
+            );
+        }
+    }
+}
+
+impl Contract {
+    // Count a signature request, and the value it moves, against the rate limits
+    // Nothing is counted if the request would go over a limit
+    // A request counts once it is sent to the MPC contract, even if the sign call then fails
+    fn internal_consume_rate_limits(
+        &mut self,
+        account_id: &AccountId,
+        spend: Option<(Asset, u128)>,
+    ) -> Result<(), RateLimit> {
+        let Some(limits) = &self.rate_limits else {
+            return Ok(());
+        };
+        let now_ms = block_timestamp_ms();
+        let window_ms = limits.window_ms.0;
+        let mut agent = match self.agent_rate_counters.get(account_id) {
+            Some(agent) => agent.clone(),
+            None => {
+                self.require_rate_counters_storage(account_id);
+                RateCounters::default()
+            }
+        };
+        let mut global = self.rate_counters.clone();
+        agent.retain_budgets(&limits.budgets);
+        global.retain_budgets(&limits.budgets);
+        agent.roll(now_ms, window_ms);
+        global.roll(now_ms, window_ms);
+
+        let exceeds = |counter: &RateCounter, amount: u128, max: Option<u128>| {
+            max.is_some_and(|max| counter.usage(now_ms, window_ms).saturating_add(amount) > max)
+        };
+        if exceeds(
+            &agent.signatures,
+            1,
+            limits.max_signatures_per_agent.map(u128::from),
+        ) {
+            return Err(RateLimit::AgentSignatures);
+        }
+        if exceeds(&global.signatures, 1, limits.max_signatures.map(u128::from)) {
+            return Err(RateLimit::Signatures);
+        }
+        agent.signatures.current += 1;
+        global.signatures.current += 1;
+
+        let budget = spend.and_then(|(asset, amount)| {
+            let budget = limits.budgets.iter().find(|budget| budget.asset == asset)?;
+            Some((budget, asset, amount))
+        });
+        if let Some((budget, asset, amount)) = budget {
+            if exceeds(
+                agent.spent(asset, now_ms, window_ms),
+                amount,
+                budget.max_per_agent.map(|max| max.0),
+            ) {
+                return Err(RateLimit::AgentBudget(asset));
+            }
+            if exceeds(
+                global.spent(asset, now_ms, window_ms),
+                amount,
+                budget.max_total.map(|max| max.0),
+            ) {
+                return Err(RateLimit::Budget(asset));
+            }
+            let agent_spent = agent.spent(asset, now_ms, window_ms);
+            agent_spent.current = agent_spent.current.saturating_add(amount);
+            let global_spent = global.spent(asset, now_ms, window_ms);
+            global_spent.current = global_spent.current.saturating_add(amount);
+        }
+
+        self.agent_rate_counters.insert(account_id.clone(), agent);
+        self.rate_counters = global;
+        Ok(())
+    }
+
+    // The first counted request of an agent stores its rate counters, which its storage balance
+    // has to cover on top of the agent storage cost, the call fails and refunds the deposit otherwise
+    fn require_rate_counters_storage(&self, account_id: &AccountId) {
+        let storage_cost = self
+            .agent_storage_cost()
+            .saturating_add(self.rate_counters_storage_cost());
+        require!(
+            self.storage_balances
+                .get(account_id)
+                .is_some_and(|balance| *balance >= storage_cost),
+            format!(
+                "Storage balance must cover storage cost {} with rate counters, call storage_deposit first",
+                storage_cost.exact_amount_display()
+            )
+        );
+    }
+
+    // Count the request against the rate limits or, if it goes over one, refund the deposit
+    // and return a promise to fail the request, so the event is kept
+    pub(crate) fn require_rate_limits(&mut self, spend: Option<(Asset, u128)>) -> Option<Promise> {
+        let account_id = env::predecessor_account_id();
+        let limit = self
+            .internal_consume_rate_limits(&account_id, spend)
+            .err()?;
+
+        Event::RateLimitExceeded {
+            account_id: &account_id,
+            limit,
+        }
+        .emit();
+        self.refund_attached_deposit();
+        Some(
+            Self::ext(env::current_account_id())
+                .with_static_gas(FAIL_GAS)
+                .fail_on_rate_limit(limit),
+        )
+    }
+
+    // Set or, with None, remove the rate limits
+    // Counters are not updated without limits, so old usage has usually rolled out when limits are set again
+    pub(crate) fn internal_set_rate_limits(&mut self, rate_limits: Option<RateLimits>) {
+        if let Some(rate_limits) = &rate_limits {
+            rate_limits.validate();
+        }
+        Event::RateLimitsUpdated {
+            rate_limits: rate_limits.as_ref(),
+        }
+        .emit();
+        self.rate_limits = rate_limits;
+    }
+}
+
+#[near]
+impl Contract {
+    #[private]
+    pub fn fail_on_rate_limit(limit: RateLimit) {
+        env::panic_str(&format!("Rate limit exceeded: {:?}", limit));
+    }
+}
@@ -0,0 +1,300 @@
+use crate::*;
+
+pub type SolanaAddress = Base58CryptoHash;
+
+// The System program, 11111111111111111111111111111111
+const SYSTEM_PROGRAM_ID: [u8; 32] = [0; 32];
+const SYSTEM_TRANSFER: u32 = 2;
+const VERSION_PREFIX: u8 = 0x80;
+// Size limit of a Solana transaction, the message is part of it
+const MAX_SOLANA_MESSAGE_LEN: usize = 1232;
+
+// What agents on a set of measurements can sign with request_solana_message_signature
+#[near(serializers = [borsh, json])]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SolanaPolicy {
+    pub fee_payers: Vec<SolanaAddress>,
+    pub allowed_programs: Vec<SolanaProgramRule>,
+    // Accounts System transfers can send lamports to
+    pub allowed_transfer_destinations: Vec<SolanaAddress>,
+    // Highest total of lamports the System transfers of a message can move
+    pub max_transfer_lamports: U64,
+}
+
+#[near(serializers = [borsh, json])]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SolanaProgramRule {
+    pub program_id: SolanaAddress,
+    // Hex prefixes instruction data has to start with, empty allows any instruction of the program
+    pub instruction_prefixes: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct SolanaInstruction {
+    pub program_id_index: u8,
+    pub accounts: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+// The parts of a legacy or v0 message the policy is checked against
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct SolanaMessage {
+    // Keys stored in the message, addresses loaded from lookup tables come after them
+    pub account_keys: Vec<[u8; 32]>,
+    pub instructions: Vec<SolanaInstruction>,
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() < len {
+            return Err("Unexpected end of message".to_string());
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    // Compact u16, 7 bits per byte with the high bit set on all but the last byte
+    fn compact_u16(&mut self) -> Result<usize, String> {
+        let mut value = 0usize;
+        for position in 0..3 {
+            let byte = self.u8()?;
+            value |= usize::from(byte & 0x7f) << (7 * position);
+            if byte & 0x80 == 0 {
+                if position > 0 && byte == 0 {
+                    return Err("Non-canonical compact u16".to_string());
+                }
+                if value > usize::from(u16::MAX) {
+                    return Err("Compact u16 is too large".to_string());
+                }
+                return Ok(value);
+            }
+        }
+        Err("Compact u16 is too large".to_string())
+    }
+
+    fn var_bytes(&mut self) -> Result<&'a [u8], String> {
+        let len = self.compact_u16()?;
+        self.take(len)
+    }
+}
+
+// Decode a legacy or v0 message, rejecting other versions and trailing bytes
+pub(crate) fn decode_solana_message(bytes: &[u8]) -> Result<SolanaMessage, String> {
+    if bytes.len() > MAX_SOLANA_MESSAGE_LEN {
+        return Err("Message is longer than a Solana transaction can be".to_string());
+    }
+    let mut reader = Reader { bytes };
+    let versioned = match bytes.first() {
+        Some(&VERSION_PREFIX) => {
+            reader.take(1)?;
+            true
+        }
+        Some(byte) if byte & VERSION_PREFIX != 0 => {
+            return Err(format!(
+                "Unsupported message version {}",
+                byte & !VERSION_PREFIX
+            ));
+        }
+        _ => false,
+    };
+
+    let header = reader.take(3)?;
+    let key_count = reader.compact_u16()?;
+    let account_keys = (0..key_count)
+        .map(|_| Ok(reader.take(32)?.try_into().unwrap()))
+        .collect::<Result<Vec<[u8; 32]>, String>>()?;
+    if header[0] == 0 || usize::from(header[0]) > account_keys.len() {
+        return Err("Invalid message header".to_string());
+    }
+    reader.take(32)?;
+
+    let instruction_count = reader.compact_u16()?;
+    let mut instructions = Vec::new();
+    for _ in 0..instruction_count {
+        let program_id_index = reader.u8()?;
+        // Programs cannot be loaded from lookup tables
+        if usize::from(program_id_index) >= account_keys.len() {
+            return Err("Invalid program ID index".to_string());
+        }
+        instructions.push(SolanaInstruction {
+            program_id_index,
+            accounts: reader.var_bytes()?.to_vec(),
+            data: reader.var_bytes()?.to_vec(),
+        });
+    }
+
+    if versioned {
+        let lookup_count = reader.compact_u16()?;
+        for _ in 0..lookup_count {
+            reader.take(32)?;
+            reader.var_bytes()?;
+            reader.var_bytes()?;
+        }
+    }
+    if !reader.bytes.is_empty() {
+        return Err("Trailing bytes after message".to_string());
+    }
+    Ok(SolanaMessage {
+        account_keys,
+        instructions,
+    })
+}
+
+// Lamports of a System transfer instruction, None for any other instruction
+// The System program ignores trailing data, so padded transfers are transfers too
+fn system_transfer_lamports(program_id: &[u8; 32], data: &[u8]) -> Option<u64> {
+    if *program_id != SYSTEM_PROGRAM_ID || data.len() < 12 {
+        return None;
+    }
+    let (instruction, lamports) = data[..12].split_at(4);
+    (u32::from_le_bytes(instruction.try_into().unwrap()) == SYSTEM_TRANSFER)
+        .then(|| u64::from_le_bytes(lamports.try_into().unwrap()))
+}
+
+impl SolanaMessage {
+    // Lamports the System transfers of the message move together
+    pub(crate) fn transfer_lamports(&self) -> u64 {
+        self.instructions
+            .iter()
+            .filter_map(|instruction| {
+                let program_id = &self.account_keys[usize::from(instruction.program_id_index)];
+                system_transfer_lamports(program_id, &instruction.data)
+            })
+            .fold(0, u64::saturating_add)
+    }
+}
+
+impl SolanaPolicy {
+    // System transfers to allowed destinations are allowed within max_transfer_lamports,
+    // every other instruction needs a rule for its program
+    pub(crate) fn check(&self, message: &SolanaMessage) -> Result<(), String> {
+        let fee_payer = SolanaAddress::from(message.account_keys[0]);
+        if !self.fee_payers.contains(&fee_payer) {
+            return Err(format!(
+                "Fee payer {} is not allowed",
+                String::from(&fee_payer)
+            ));
+        }
+
+        for (index, instruction) in message.instructions.iter().enumerate() {
+            let program_id = &message.account_keys[usize::from(instruction.program_id_index)];
+            if system_transfer_lamports(program_id, &instruction.data).is_some() {
+                // The source has to sign the transaction, so only the destination is checked
+                let destination = instruction
+                    .accounts
+                    .get(1)
+                    .and_then(|account| message.account_keys.get(usize::from(*account)))
+                    .ok_or_else(|| {
+                        format!(
+                            "Instruction {index} transfers to an account outside the message keys"
+                        )
+                    })?;
+                let destination = SolanaAddress::from(*destination);
+                if !self.allowed_transfer_destinations.contains(&destination) {
+                    return Err(format!(
+                        "Transfer destination {} is not allowed",
+                        String::from(&destination)
+                    ));
+                }
+                continue;
+            }
+            let program_id = SolanaAddress::from(*program_id);
+            let rule = self
+                .allowed_programs
+                .iter()
+                .find(|rule| rule.program_id == program_id)
+                .ok_or_else(|| format!("Program {} is not allowed", String::from(&program_id)))?;
+            let data = hex::encode(&instruction.data);
+            if !rule.instruction_prefixes.is_empty()
+                && !rule.instruction_prefixes.iter().any(|prefix| {
+                    data.get(..prefix.len())
+                        .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+                })
+            {
+                return Err(format!(
+                    "Instruction {index} data is not allowed for program {}",
+                    String::from(&program_id)
+                ));
+            }
+        }
+
+        let transfer_lamports = message.transfer_lamports();
+        if transfer_lamports > self.max_transfer_lamports.0 {
+            return Err(format!(
+                "Transfers of {transfer_lamports} lamports are more than the maximum {}",
+                self.max_transfer_lamports.0
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Contract {
+    // Check a message against the policy of the caller's measurements and sign it with Ed25519
+    // The caller has to be a valid agent
+    pub(crate) fn internal_request_solana_message_signature(
+        &mut self,
+        path: String,
+        message: String,
+    ) -> Promise {
+        let bytes = hex::decode(&message).expect("Message must be a hex string");
+        let measurements_id = self
+            .agents
+            .get(&env::predecessor_account_id())
+            .expect("Agent not registered")
+            .measurements_id;
+        let policy = self
+            .solana_policies
+            .get(&measurements_id)
+            .expect("No Solana policy for the agent's measurements");
+
+        let decoded = decode_solana_message(&bytes).unwrap_or_else(|error| env::panic_str(&error));
+        policy
+            .check(&decoded)
+            .unwrap_or_else(|error| env::panic_str(&error));
+
+        // Ed25519 signs the message itself, not a hash of it
+        let spend = (Asset::Solana, u128::from(decoded.transfer_lamports()));
+        self.internal_request_signature(path, message, SignatureScheme::Ed25519, Some(spend))
+    }
+
+    // Set or, with None, remove the Solana policy of a set of measurements
+    pub(crate) fn internal_set_solana_policy(
+        &mut self,
+        measurements_id: MeasurementsId,
+        policy: Option<SolanaPolicy>,
+    ) {
+        if let Some(policy) = &policy {
+            require!(
+                self.approved_measurements.contains_key(&measurements_id),
+                "Measurements not in approved list"
+            );
+            for rule in &policy.allowed_programs {
+                for prefix in &rule.instruction_prefixes {
+                    require!(
+                        !prefix.is_empty() && hex::decode(prefix).is_ok(),
+                        "Instruction prefix must be a non-empty hex string"
+                    );
+                }
+            }
+        }
+        Event::SolanaPolicySet {
+            measurements_id,
+            policy: policy.as_ref(),
+        }
+        .emit();
+        match policy {
+            Some(policy) => self.solana_policies.insert(measurements_id, policy),
+            None => self.solana_policies.remove(&measurements_id),
+        };
+    }
+}
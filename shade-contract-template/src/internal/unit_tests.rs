@@ -9,10 +9,14 @@ use shade_attestation::{
 
 // Only testing requires_tee = false since we cannot produce a valid attestation for a TEE in unit tests
 
+// A 32 byte hash to sign
+const MOCK_PAYLOAD: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
 // Deposit constants for tests
 const DEPOSIT_005_NEAR: NearToken = NearToken::from_yoctonear(5_000_000_000_000_000_000_000); // 0.005 NEAR
 const DEPOSIT_003_NEAR: NearToken = NearToken::from_yoctonear(3_000_000_000_000_000_000_000); // 0.003 NEAR
 const DEPOSIT_ZERO: NearToken = NearToken::from_yoctonear(0);
+const ONE_YOCTO: NearToken = NearToken::from_yoctonear(1);
 
 // Helper function to create a mock context
 fn get_context(predecessor: AccountId, is_view: bool) -> VMContextBuilder {
@@ -83,9 +87,10 @@ fn setup_contract() -> Contract {
         U64::from(100000u64), // 100 seconds in milliseconds
         owner,
         mpc_contract,
+        None,
     );
-    contract.approve_measurements(create_mock_full_measurements_hex());
-    contract.approve_ppids(vec![Ppid::default()]);
+    contract.approve_measurements(create_mock_full_measurements_hex(), None, None);
+    contract.approve_ppids(vec![Ppid::default()], None, None, None);
     contract
 }
 
@@ -104,12 +109,13 @@ fn test_new() {
         attestation_expiration_time_ms,
         owner.clone(),
         mpc_contract.clone(),
+        None,
     );
 
     let contract_info = contract.get_contract_info();
     assert_eq!(contract_info.owner_id, owner);
     assert_eq!(contract_info.mpc_contract_id, mpc_contract);
-    assert_eq!(contract_info.requires_tee, false);
+    assert!(!contract_info.requires_tee);
     assert_eq!(contract_info.attestation_expiration_time_ms.0, 100000u64);
     assert_eq!(contract.get_approved_measurements(&None, &None).len(), 0);
     assert_eq!(contract.get_approved_ppids(&None, &None).len(), 0);
@@ -123,12 +129,13 @@ fn test_approve_measurements() {
     let mut contract = setup_contract();
 
     let measurements = create_mock_full_measurements_hex();
-    contract.approve_measurements(measurements.clone());
+    contract.approve_measurements(measurements.clone(), None, None);
 
     assert!(
         contract
             .get_approved_measurements(&None, &None)
-            .contains(&measurements)
+            .iter()
+            .any(|approved| approved.measurements == measurements)
     );
     assert_eq!(contract.get_approved_measurements(&None, &None).len(), 1);
 }
@@ -142,7 +149,7 @@ fn test_approve_measurements_not_owner() {
     let context = get_context(non_owner, false);
     testing_env!(context.build());
 
-    contract.approve_measurements(create_mock_full_measurements_hex());
+    contract.approve_measurements(create_mock_full_measurements_hex(), None, None);
 }
 
 // Test that owner can remove measurements from the approved list
@@ -151,7 +158,7 @@ fn test_remove_measurements() {
     let mut contract = setup_contract();
 
     let extra = create_mock_full_measurements_hex();
-    contract.approve_measurements(extra.clone());
+    contract.approve_measurements(extra.clone(), None, None);
     let count_before = contract.get_approved_measurements(&None, &None).len();
     assert_eq!(count_before, 1);
 
@@ -159,7 +166,8 @@ fn test_remove_measurements() {
     assert!(
         !contract
             .get_approved_measurements(&None, &None)
-            .contains(&extra)
+            .iter()
+            .any(|approved| approved.measurements == extra)
     );
 }
 
@@ -169,7 +177,7 @@ fn test_remove_measurements() {
 fn test_remove_measurements_not_owner() {
     let mut contract = setup_contract();
     let non_owner = accounts(2);
-    contract.approve_measurements(create_mock_full_measurements_hex());
+    contract.approve_measurements(create_mock_full_measurements_hex(), None, None);
 
     let context = get_context(non_owner, false);
     testing_env!(context.build());
@@ -191,7 +199,7 @@ fn test_remove_measurements_not_found() {
 fn test_remove_ppids_not_found() {
     let mut contract = setup_contract();
     // Try to remove PPID that was never approved
-    contract.remove_ppids(vec![non_default_ppid()]);
+    contract.remove_ppids(vec![non_default_ppid()], None);
 }
 
 // Test that owner can whitelist an agent for local and agent appears in whitelist (not yet registered)
@@ -402,7 +410,7 @@ fn test_register_agent_not_whitelisted() {
 
 // First-time registration requires storage stake: zero attached deposit must fail
 #[test]
-#[should_panic(expected = "Attached deposit must be greater than storage cost")]
+#[should_panic(expected = "Storage balance must cover storage cost")]
 fn test_register_agent_errors_when_storage_deposit_required_but_zero_attached() {
     let mut contract = setup_contract();
     let agent = accounts(2);
@@ -418,7 +426,7 @@ fn test_register_agent_errors_when_storage_deposit_required_but_zero_attached()
 
 // First-time registration: attached deposit below storage cost must fail
 #[test]
-#[should_panic(expected = "Attached deposit must be greater than storage cost")]
+#[should_panic(expected = "Storage balance must cover storage cost")]
 fn test_register_agent_errors_when_storage_deposit_insufficient() {
     let mut contract = setup_contract();
     let agent = accounts(2);
@@ -486,7 +494,7 @@ fn test_update_attestation_expiration_time() {
     let mut contract = setup_contract();
     let new_expiration_time = U64::from(200000u64); // 200 seconds
 
-    contract.update_attestation_expiration_time(new_expiration_time.clone());
+    contract.update_attestation_expiration_time(new_expiration_time);
     let contract_info = contract.get_contract_info();
     assert_eq!(contract_info.attestation_expiration_time_ms.0, 200000u64);
 }
@@ -509,7 +517,7 @@ fn test_update_attestation_expiration_time_not_owner() {
 fn test_get_contract_info() {
     let contract = setup_contract();
     let contract_info = contract.get_contract_info();
-    assert_eq!(contract_info.requires_tee, false);
+    assert!(!contract_info.requires_tee);
     assert_eq!(contract_info.attestation_expiration_time_ms.0, 100000u64);
 }
 
@@ -519,11 +527,11 @@ fn test_get_approved_measurements() {
     let mut contract = setup_contract();
 
     let default = create_mock_full_measurements_hex();
-    contract.approve_measurements(default.clone());
+    contract.approve_measurements(default.clone(), None, None);
 
     let all = contract.get_approved_measurements(&None, &None);
     assert_eq!(all.len(), 1);
-    assert!(all.contains(&default));
+    assert_eq!(all[0].measurements, default);
 
     // Test pagination
     let first_two = contract.get_approved_measurements(&Some(0), &Some(2));
@@ -634,8 +642,8 @@ fn test_request_signature_not_whitelisted() {
     testing_env!(context.build());
     let _promise = contract.request_signature(
         "path".to_string(),
-        "payload".to_string(),
-        "Ecdsa".to_string(),
+        MOCK_PAYLOAD.to_string(),
+        SignatureScheme::Secp256k1,
     );
 
     // Agent should be removed from map
@@ -655,8 +663,8 @@ fn test_request_signature_not_registered() {
     testing_env!(context.build());
     let _ = contract.request_signature(
         "path".to_string(),
-        "payload".to_string(),
-        "Ecdsa".to_string(),
+        MOCK_PAYLOAD.to_string(),
+        SignatureScheme::Secp256k1,
     );
 }
 
@@ -709,7 +717,7 @@ fn test_require_valid_agent_removes_on_invalid_ppid() {
     // Remove PPID from approved list
     let context = get_context(accounts(0), false);
     testing_env!(context.build());
-    contract.remove_ppids(vec![Ppid::default()]);
+    contract.remove_ppids(vec![Ppid::default()], None);
 
     // Call require_valid_agent - should remove agent and emit event (not panic)
     let context = get_context(agent.clone(), false);
@@ -770,7 +778,7 @@ fn test_require_valid_agent_removes_with_multiple_reasons() {
     let context = get_context(accounts(0), false);
     testing_env!(context.build());
     contract.remove_measurements(create_mock_full_measurements_hex());
-    contract.remove_ppids(vec![Ppid::default()]);
+    contract.remove_ppids(vec![Ppid::default()], None);
     contract.remove_agent_from_whitelist_for_local(agent.clone());
 
     // Call require_valid_agent - should remove agent and emit event with multiple reasons (not panic)
@@ -794,12 +802,12 @@ fn test_request_signature_measurements_removed() {
     testing_env!(context.build());
     contract.register_agent(create_mock_dstack_attestation());
 
-    let context = get_context(agent.clone(), false);
+    let context = get_context_with_deposit(agent.clone(), false, Some(ONE_YOCTO));
     testing_env!(context.build());
     let _promise = contract.request_signature(
         "path".to_string(),
-        "payload".to_string(),
-        "Ecdsa".to_string(),
+        MOCK_PAYLOAD.to_string(),
+        SignatureScheme::Secp256k1,
     );
 
     // Remove default measurements from approved list
@@ -818,8 +826,8 @@ fn test_request_signature_measurements_removed() {
     testing_env!(context.build());
     let _promise2 = contract.request_signature(
         "path".to_string(),
-        "payload".to_string(),
-        "Ecdsa".to_string(),
+        MOCK_PAYLOAD.to_string(),
+        SignatureScheme::Secp256k1,
     );
 
     // Agent should be removed from map after require_valid_agent removed it
@@ -839,18 +847,18 @@ fn test_request_signature_ppid_removed() {
     testing_env!(context.build());
     contract.register_agent(create_mock_dstack_attestation());
 
-    let context = get_context(agent.clone(), false);
+    let context = get_context_with_deposit(agent.clone(), false, Some(ONE_YOCTO));
     testing_env!(context.build());
     let _promise = contract.request_signature(
         "path".to_string(),
-        "payload".to_string(),
-        "Ecdsa".to_string(),
+        MOCK_PAYLOAD.to_string(),
+        SignatureScheme::Secp256k1,
     );
 
     // Remove default PPID from approved list
     let context = get_context(accounts(0), false);
     testing_env!(context.build());
-    contract.remove_ppids(vec![Ppid::default()]);
+    contract.remove_ppids(vec![Ppid::default()], None);
 
     let agent_info = contract.get_agent(agent.clone()).unwrap();
     assert!(
@@ -863,8 +871,8 @@ fn test_request_signature_ppid_removed() {
     testing_env!(context.build());
     let _promise2 = contract.request_signature(
         "path".to_string(),
-        "payload".to_string(),
-        "Ecdsa".to_string(),
+        MOCK_PAYLOAD.to_string(),
+        SignatureScheme::Secp256k1,
     );
 
     // Agent should be removed from map after require_valid_agent removed it
@@ -883,12 +891,12 @@ fn test_request_signature_success() {
     testing_env!(context.build());
     contract.register_agent(create_mock_dstack_attestation());
 
-    let context = get_context(agent, false);
+    let context = get_context_with_deposit(agent, false, Some(ONE_YOCTO));
     testing_env!(context.build());
     let _promise = contract.request_signature(
         "path".to_string(),
-        "payload".to_string(),
-        "Ecdsa".to_string(),
+        MOCK_PAYLOAD.to_string(),
+        SignatureScheme::Secp256k1,
     );
 }
 
@@ -904,18 +912,18 @@ fn test_request_signature_with_eddsa() {
     testing_env!(context.build());
     contract.register_agent(create_mock_dstack_attestation());
 
-    let context = get_context(agent, false);
+    let context = get_context_with_deposit(agent, false, Some(ONE_YOCTO));
     testing_env!(context.build());
     let _promise = contract.request_signature(
         "path".to_string(),
-        "payload".to_string(),
-        "Eddsa".to_string(),
+        MOCK_PAYLOAD.to_string(),
+        SignatureScheme::Ed25519,
     );
 }
 
-// Test that request_signature panics when key_type is not exactly "Ecdsa" or "Eddsa"
+// Test that request_signature panics when no domain is configured for the key type
 #[test]
-#[should_panic(expected = "Invalid key type")]
+#[should_panic(expected = "No signature domain configured for V2Secp256k1")]
 fn test_request_signature_invalid_key_type() {
     let mut contract = setup_contract();
     let agent = accounts(2);
@@ -930,8 +938,8 @@ fn test_request_signature_invalid_key_type() {
     testing_env!(context.build());
     let _ = contract.request_signature(
         "path".to_string(),
-        "payload".to_string(),
-        "invalid".to_string(),
+        MOCK_PAYLOAD.to_string(),
+        SignatureScheme::V2Secp256k1,
     );
 }
 
@@ -962,8 +970,8 @@ fn test_request_signature_expired_attestation() {
     // Promise will fail but this can't be tested in unit tests
     let _promise = contract.request_signature(
         "path".to_string(),
-        "payload".to_string(),
-        "Ecdsa".to_string(),
+        MOCK_PAYLOAD.to_string(),
+        SignatureScheme::Secp256k1,
     );
 
     // Agent should be removed from map
@@ -1108,6 +1116,5150 @@ fn test_get_agents_expiration_fields() {
     assert!(matches!(agent2_info.validity, AgentValidity::Valid));
 }
 
+// -------- Measurements registry (labels, sources, IDs) --------
+
+// Test that approving measurements records the label, source, approver and approval time
+#[test]
+fn test_approve_measurements_with_metadata() {
+    let mut contract = setup_contract();
+    let context = get_context_with_deposit_and_timestamp(accounts(0), false, None, Some(5000u64));
+    testing_env!(context.build());
+
+    let measurements_id = contract
+        .approve_measurements(
+            non_default_measurements(),
+            Some("v1.2.0".to_string()),
+            Some("ghcr.io/example/agent@sha256:abcd".to_string()),
+        )
+        .unwrap();
+
+    let approved = contract.get_measurements(measurements_id).unwrap();
+    assert_eq!(approved.measurements_id, measurements_id);
+    assert_eq!(approved.measurements, non_default_measurements());
+    assert_eq!(approved.label, "v1.2.0");
+    assert_eq!(approved.source, "ghcr.io/example/agent@sha256:abcd");
+    assert_eq!(approved.approved_by, accounts(0));
+    assert_eq!(approved.approved_at_ms.0, 5000u64);
+}
+
+// Test that each new set of measurements gets its own ID and re-approving keeps the existing one
+#[test]
+fn test_approve_measurements_assigns_ids() {
+    let mut contract = setup_contract();
+
+    let default_id = contract
+        .approve_measurements(create_mock_full_measurements_hex(), None, None)
+        .unwrap();
+    let other_id = contract
+        .approve_measurements(non_default_measurements(), None, None)
+        .unwrap();
+    assert_ne!(default_id, other_id);
+
+    // Re-approving keeps the ID and does not overwrite the metadata
+    let again_id = contract
+        .approve_measurements(
+            non_default_measurements(),
+            Some("ignored".to_string()),
+            None,
+        )
+        .unwrap();
+    assert_eq!(again_id, other_id);
+    assert_eq!(contract.get_measurements(other_id).unwrap().label, "");
+    assert_eq!(contract.get_approved_measurements(&None, &None).len(), 2);
+
+    // Removing and re-approving creates a new approval with a new ID
+    contract.remove_measurements(non_default_measurements());
+    assert!(contract.get_measurements(other_id).is_none());
+    let new_id = contract
+        .approve_measurements(non_default_measurements(), None, None)
+        .unwrap();
+    assert_ne!(new_id, other_id);
+}
+
+// Test that the owner can update the label and source of approved measurements
+#[test]
+fn test_update_measurements_metadata() {
+    let mut contract = setup_contract();
+    let measurements_id = contract
+        .approve_measurements(
+            non_default_measurements(),
+            Some("old".to_string()),
+            Some("commit abc".to_string()),
+        )
+        .unwrap();
+
+    contract.update_measurements_metadata(measurements_id, Some("new".to_string()), None);
+
+    let approved = contract.get_measurements(measurements_id).unwrap();
+    assert_eq!(approved.label, "new");
+    assert_eq!(approved.source, "commit abc");
+}
+
+// Test that updating metadata of measurements that are not approved panics
+#[test]
+#[should_panic(expected = "Measurements not in approved list")]
+fn test_update_measurements_metadata_not_found() {
+    let mut contract = setup_contract();
+    contract.update_measurements_metadata(42, Some("label".to_string()), None);
+}
+
+// Test that non-owner cannot update measurements metadata
+#[test]
+#[should_panic(expected = "Caller is not the owner")]
+fn test_update_measurements_metadata_not_owner() {
+    let mut contract = setup_contract();
+    let context = get_context(accounts(2), false);
+    testing_env!(context.build());
+    contract.update_measurements_metadata(0, Some("label".to_string()), None);
+}
+
+// Test that an overly long label is rejected
+#[test]
+#[should_panic(expected = "Label must be at most 64 bytes")]
+fn test_approve_measurements_label_too_long() {
+    let mut contract = setup_contract();
+    contract.approve_measurements(non_default_measurements(), Some("a".repeat(65)), None);
+}
+
+// Test that agents reference their measurements by ID and the agent view includes the metadata
+#[test]
+fn test_agent_view_includes_measurements_metadata() {
+    let mut contract = setup_contract();
+    let agent = accounts(2);
+    let measurements_id = contract
+        .approve_measurements(create_mock_full_measurements_hex(), None, None)
+        .unwrap();
+    contract.update_measurements_metadata(measurements_id, Some("local".to_string()), None);
+
+    contract.whitelist_agent_for_local(agent.clone());
+    let context = get_context_with_deposit(agent.clone(), false, Some(DEPOSIT_005_NEAR));
+    testing_env!(context.build());
+    contract.register_agent(create_mock_dstack_attestation());
+
+    let agent_info = contract.get_agent(agent.clone()).unwrap();
+    assert_eq!(agent_info.measurements_id, measurements_id);
+    let measurements = agent_info.measurements.unwrap();
+    assert_eq!(measurements.label, "local");
+    assert_eq!(
+        measurements.measurements,
+        create_mock_full_measurements_hex()
+    );
+
+    // Once the measurements are removed the agent still reports the ID but no metadata
+    let context = get_context(accounts(0), false);
+    testing_env!(context.build());
+    contract.remove_measurements(create_mock_full_measurements_hex());
+    let agent_info = contract.get_agent(agent).unwrap();
+    assert_eq!(agent_info.measurements_id, measurements_id);
+    assert!(agent_info.measurements.is_none());
+}
+
+// -------- Measurements sunset --------
+
+// Registers `agent` in local mode at `timestamp_ms` (default measurements and PPID approved in setup)
+fn register_local_agent_at(contract: &mut Contract, agent: &AccountId, timestamp_ms: u64) {
+    let context = get_context(accounts(0), false);
+    testing_env!(context.build());
+    contract.whitelist_agent_for_local(agent.clone());
+    let context = get_context_with_deposit_and_timestamp(
+        agent.clone(),
+        false,
+        Some(DEPOSIT_005_NEAR),
+        Some(timestamp_ms),
+    );
+    testing_env!(context.build());
+    contract.register_agent(create_mock_dstack_attestation());
+}
+
+// Test that existing agents keep working until the sunset and become invalid with MeasurementsSunset after it
+#[test]
+fn test_measurements_sunset_lifecycle() {
+    let mut contract = setup_contract();
+    let agent = accounts(2);
+    let other_agent = accounts(3);
+    let measurements_id = contract
+        .approve_measurements(create_mock_full_measurements_hex(), None, None)
+        .unwrap();
+    register_local_agent_at(&mut contract, &agent, 1000);
+    register_local_agent_at(&mut contract, &other_agent, 1000);
+
+    let context = get_context_with_deposit_and_timestamp(accounts(0), false, None, Some(2000));
+    testing_env!(context.build());
+    contract.schedule_measurements_sunset(measurements_id, U64::from(50000u64));
+
+    let sunsets = contract.get_measurements_sunsets();
+    assert_eq!(sunsets.len(), 1);
+    assert_eq!(sunsets[0].measurements_id, measurements_id);
+    assert_eq!(sunsets[0].sunset_at_ms, Some(U64::from(50000u64)));
+
+    // Before the sunset the agent is still valid and may re-register
+    let context = get_context_with_deposit_and_timestamp(agent.clone(), false, None, Some(3000));
+    testing_env!(context.build());
+    assert!(contract.require_valid_agent().is_none());
+    assert!(contract.register_agent(create_mock_dstack_attestation()));
+
+    // After the sunset the agents are invalid with MeasurementsSunset
+    let context = get_context_with_deposit_and_timestamp(agent.clone(), false, None, Some(50001));
+    testing_env!(context.build());
+    let agent_info = contract.get_agent(agent.clone()).unwrap();
+    assert!(
+        matches!(agent_info.validity, AgentValidity::Invalid(ref r) if r == &vec![AgentRemovalReason::MeasurementsSunset])
+    );
+
+    // The first agent call after the sunset removes the agent and the measurements
+    assert!(contract.require_valid_agent().is_some());
+    assert!(contract.get_agent(agent).is_none());
+    assert!(contract.get_measurements(measurements_id).is_none());
+    assert!(contract.get_measurements_sunsets().is_empty());
+
+    // Other agents on the sunset measurements still report MeasurementsSunset rather than InvalidMeasurements
+    let agent_info = contract.get_agent(other_agent).unwrap();
+    assert!(
+        matches!(agent_info.validity, AgentValidity::Invalid(ref r) if r == &vec![AgentRemovalReason::MeasurementsSunset])
+    );
+}
+
+// Test that new agents cannot register with measurements scheduled for sunset
+#[test]
+#[should_panic(
+    expected = "Measurements are scheduled for sunset and do not accept new registrations"
+)]
+fn test_measurements_sunset_refuses_new_registrations() {
+    let mut contract = setup_contract();
+    let measurements_id = contract
+        .approve_measurements(create_mock_full_measurements_hex(), None, None)
+        .unwrap();
+    contract.schedule_measurements_sunset(measurements_id, U64::from(50000u64));
+
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+}
+
+// Test that a cancelled sunset no longer affects registrations or agent validity
+#[test]
+fn test_cancel_measurements_sunset() {
+    let mut contract = setup_contract();
+    let agent = accounts(2);
+    let measurements_id = contract
+        .approve_measurements(create_mock_full_measurements_hex(), None, None)
+        .unwrap();
+    contract.schedule_measurements_sunset(measurements_id, U64::from(50000u64));
+    contract.cancel_measurements_sunset(measurements_id);
+    assert!(contract.get_measurements_sunsets().is_empty());
+
+    register_local_agent_at(&mut contract, &agent, 60000);
+    let agent_info = contract.get_agent(agent).unwrap();
+    assert!(matches!(agent_info.validity, AgentValidity::Valid));
+}
+
+// Test that a sunset cannot be cancelled once it has taken effect
+#[test]
+#[should_panic(expected = "Measurements sunset has already taken effect")]
+fn test_cancel_measurements_sunset_after_sunset() {
+    let mut contract = setup_contract();
+    let measurements_id = contract
+        .approve_measurements(create_mock_full_measurements_hex(), None, None)
+        .unwrap();
+    contract.schedule_measurements_sunset(measurements_id, U64::from(50000u64));
+
+    let context = get_context_with_deposit_and_timestamp(accounts(0), false, None, Some(50000));
+    testing_env!(context.build());
+    contract.cancel_measurements_sunset(measurements_id);
+}
+
+// Test that a sunset must be scheduled in the future
+#[test]
+#[should_panic(expected = "Sunset time must be in the future")]
+fn test_schedule_measurements_sunset_in_past() {
+    let mut contract = setup_contract();
+    let measurements_id = contract
+        .approve_measurements(create_mock_full_measurements_hex(), None, None)
+        .unwrap();
+    let context = get_context_with_deposit_and_timestamp(accounts(0), false, None, Some(5000));
+    testing_env!(context.build());
+    contract.schedule_measurements_sunset(measurements_id, U64::from(5000u64));
+}
+
+// Test that non-owner cannot schedule a sunset
+#[test]
+#[should_panic(expected = "Caller is not the owner")]
+fn test_schedule_measurements_sunset_not_owner() {
+    let mut contract = setup_contract();
+    let measurements_id = contract
+        .approve_measurements(create_mock_full_measurements_hex(), None, None)
+        .unwrap();
+    let context = get_context(accounts(2), false);
+    testing_env!(context.build());
+    contract.schedule_measurements_sunset(measurements_id, U64::from(50000u64));
+}
+
+// Test that anyone can execute a sunset once its time has passed
+#[test]
+fn test_execute_measurements_sunset() {
+    let mut contract = setup_contract();
+    let measurements_id = contract
+        .approve_measurements(create_mock_full_measurements_hex(), None, None)
+        .unwrap();
+    contract.schedule_measurements_sunset(measurements_id, U64::from(50000u64));
+
+    let context = get_context_with_deposit_and_timestamp(accounts(3), false, None, Some(50000));
+    testing_env!(context.build());
+    contract.execute_measurements_sunset(measurements_id);
+    assert!(contract.get_measurements(measurements_id).is_none());
+}
+
+// Test that a sunset cannot be executed before its time
+#[test]
+#[should_panic(expected = "Measurements sunset time has not been reached")]
+fn test_execute_measurements_sunset_too_early() {
+    let mut contract = setup_contract();
+    let measurements_id = contract
+        .approve_measurements(create_mock_full_measurements_hex(), None, None)
+        .unwrap();
+    contract.schedule_measurements_sunset(measurements_id, U64::from(50000u64));
+
+    let context = get_context_with_deposit_and_timestamp(accounts(3), false, None, Some(49999));
+    testing_env!(context.build());
+    contract.execute_measurements_sunset(measurements_id);
+}
+
+// -------- Measurements agent quotas --------
+
+// Test that registrations beyond the quota are rejected
+#[test]
+#[should_panic(expected = "Agent quota reached for measurements 0: 1 of 1 agents registered")]
+fn test_measurements_agent_quota_rejects_registration() {
+    let mut contract = setup_contract();
+    contract.set_measurements_agent_quota(0, Some(1));
+
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+    register_local_agent_at(&mut contract, &accounts(3), 1000);
+}
+
+// Test that active agent counts track registrations, re-registrations and removals
+#[test]
+fn test_measurements_agent_counts() {
+    let mut contract = setup_contract();
+    let agent1 = accounts(2);
+    let agent2 = accounts(3);
+    contract.set_measurements_agent_quota(0, Some(2));
+
+    register_local_agent_at(&mut contract, &agent1, 1000);
+    register_local_agent_at(&mut contract, &agent2, 1000);
+    let approved = contract.get_measurements(0).unwrap();
+    assert_eq!(approved.active_agents, 2);
+    assert_eq!(approved.max_agents, Some(2));
+
+    // Re-registering does not take another slot
+    register_local_agent_at(&mut contract, &agent1, 2000);
+    assert_eq!(contract.get_measurements(0).unwrap().active_agents, 2);
+
+    // Removing an agent frees its slot so another agent can register
+    let context = get_context(accounts(0), false);
+    testing_env!(context.build());
+    contract.remove_agent(agent1.clone());
+    assert_eq!(contract.get_measurements(0).unwrap().active_agents, 1);
+    register_local_agent_at(&mut contract, &agent1, 3000);
+    assert_eq!(contract.get_measurements(0).unwrap().active_agents, 2);
+
+    // Agents removed by require_valid_agent free their slot too
+    let context = get_context(accounts(0), false);
+    testing_env!(context.build());
+    contract.remove_agent_from_whitelist_for_local(agent2.clone());
+    let context = get_context(agent2, false);
+    testing_env!(context.build());
+    assert!(contract.require_valid_agent().is_some());
+    assert_eq!(contract.get_measurements(0).unwrap().active_agents, 1);
+}
+
+// Test that an expired agent keeps its slot until it is pruned
+#[test]
+fn test_expired_agent_holds_quota_until_pruned() {
+    let mut contract = setup_contract();
+    contract.set_measurements_agent_quota(0, Some(1));
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+
+    // The first agent's attestation expired at 101000
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        register_local_agent_at(&mut contract, &accounts(3), 101001)
+    }));
+    assert!(panic_message(result).contains("Agent quota reached for measurements 0"));
+    assert_eq!(contract.get_measurements(0).unwrap().active_agents, 1);
+
+    set_caller_at(accounts(5), 101001);
+    assert_eq!(contract.prune_agents(10), 1);
+    register_local_agent_at(&mut contract, &accounts(3), 101001);
+    assert_eq!(contract.get_measurements(0).unwrap().active_agents, 1);
+}
+
+// Test that raising or removing the quota allows more agents to register
+#[test]
+fn test_raise_measurements_agent_quota() {
+    let mut contract = setup_contract();
+    contract.set_measurements_agent_quota(0, Some(1));
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+
+    let context = get_context(accounts(0), false);
+    testing_env!(context.build());
+    contract.set_measurements_agent_quota(0, None);
+    register_local_agent_at(&mut contract, &accounts(3), 1000);
+    assert_eq!(contract.get_measurements(0).unwrap().active_agents, 2);
+}
+
+// Test that an agent re-registering with newly approved measurements moves to the new count
+#[test]
+fn test_measurements_agent_counts_move_on_reregistration() {
+    let mut contract = setup_contract();
+    let agent = accounts(2);
+    register_local_agent_at(&mut contract, &agent, 1000);
+
+    let context = get_context(accounts(0), false);
+    testing_env!(context.build());
+    contract.remove_measurements(create_mock_full_measurements_hex());
+    let new_id = contract
+        .approve_measurements(create_mock_full_measurements_hex(), None, None)
+        .unwrap();
+
+    register_local_agent_at(&mut contract, &agent, 2000);
+    assert_eq!(contract.get_agent(agent).unwrap().measurements_id, new_id);
+    assert_eq!(contract.get_measurements(new_id).unwrap().active_agents, 1);
+}
+
+// Test that non-owner cannot set a quota
+#[test]
+#[should_panic(expected = "Caller is not the owner")]
+fn test_set_measurements_agent_quota_not_owner() {
+    let mut contract = setup_contract();
+    let context = get_context(accounts(2), false);
+    testing_env!(context.build());
+    contract.set_measurements_agent_quota(0, Some(1));
+}
+
+// -------- Measurements and PPID scoping --------
+
+// Test that measurements limited to a PPID group only register on PPIDs in the group
+#[test]
+fn test_measurements_ppid_group_allows_member() {
+    let mut contract = setup_contract();
+    contract.set_ppid_group("production".to_string(), vec![Ppid::default()]);
+    contract.set_measurements_ppid_group(0, Some("production".to_string()));
+
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+    let agent_info = contract.get_agent(accounts(2)).unwrap();
+    assert!(matches!(agent_info.validity, AgentValidity::Valid));
+    assert_eq!(
+        agent_info.measurements.unwrap().ppid_group,
+        Some("production".to_string())
+    );
+}
+
+// Test that registration fails when the PPID is not in the measurements' PPID group
+#[test]
+#[should_panic(expected = "Measurements are not allowed to run on this PPID")]
+fn test_measurements_ppid_group_rejects_non_member() {
+    let mut contract = setup_contract();
+    contract.set_ppid_group("staging".to_string(), vec![non_default_ppid()]);
+    contract.set_measurements_ppid_group(0, Some("staging".to_string()));
+
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+}
+
+// Test that registration fails when the PPID is limited to other measurements
+#[test]
+#[should_panic(expected = "Measurements are not allowed to run on this PPID")]
+fn test_ppid_measurements_scope_rejects_other_measurements() {
+    let mut contract = setup_contract();
+    let other_id = contract
+        .approve_measurements(non_default_measurements(), None, None)
+        .unwrap();
+    contract.set_ppid_measurements_scope(Ppid::default(), Some(vec![other_id]));
+
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+}
+
+// Test that agents become invalid when their pairing is no longer allowed, and valid again when it is
+#[test]
+fn test_measurements_ppid_pairing_checked_for_registered_agents() {
+    let mut contract = setup_contract();
+    let agent = accounts(2);
+    register_local_agent_at(&mut contract, &agent, 1000);
+
+    let context = get_context(accounts(0), false);
+    testing_env!(context.build());
+    contract.set_ppid_measurements_scope(Ppid::default(), Some(vec![42]));
+    let agent_info = contract.get_agent(agent.clone()).unwrap();
+    assert!(
+        matches!(agent_info.validity, AgentValidity::Invalid(ref r) if r == &vec![AgentRemovalReason::InvalidMeasurementsPpidPairing])
+    );
+
+    contract.set_ppid_measurements_scope(Ppid::default(), None);
+    let agent_info = contract.get_agent(agent.clone()).unwrap();
+    assert!(matches!(agent_info.validity, AgentValidity::Valid));
+
+    // Changing the group's members also affects registered agents
+    contract.set_ppid_group("production".to_string(), vec![Ppid::default()]);
+    contract.set_measurements_ppid_group(0, Some("production".to_string()));
+    assert!(matches!(
+        contract.get_agent(agent.clone()).unwrap().validity,
+        AgentValidity::Valid
+    ));
+    contract.set_ppid_group("production".to_string(), vec![non_default_ppid()]);
+    let agent_info = contract.get_agent(agent).unwrap();
+    assert!(
+        matches!(agent_info.validity, AgentValidity::Invalid(ref r) if r == &vec![AgentRemovalReason::InvalidMeasurementsPpidPairing])
+    );
+}
+
+// Test that the PPID group and scope views list the configured rules
+#[test]
+fn test_ppid_scoping_views() {
+    let mut contract = setup_contract();
+    contract.set_ppid_group("production".to_string(), vec![non_default_ppid()]);
+    contract.set_ppid_measurements_scope(non_default_ppid(), Some(vec![0]));
+
+    let groups = contract.get_ppid_groups();
+    assert_eq!(groups.len(), 1);
+    assert_eq!(groups[0].name, "production");
+    assert_eq!(groups[0].ppids, vec![non_default_ppid()]);
+
+    let scopes = contract.get_ppid_measurements_scopes();
+    assert_eq!(scopes.len(), 1);
+    assert_eq!(scopes[0].ppid, non_default_ppid());
+    assert_eq!(scopes[0].measurements_ids, vec![0]);
+}
+
+// Test that measurements cannot be limited to a group that does not exist
+#[test]
+#[should_panic(expected = "PPID group not found")]
+fn test_set_measurements_ppid_group_unknown_group() {
+    let mut contract = setup_contract();
+    contract.set_measurements_ppid_group(0, Some("missing".to_string()));
+}
+
+// Test that a PPID group used by measurements cannot be removed
+#[test]
+#[should_panic(expected = "PPID group is still used by approved measurements")]
+fn test_remove_ppid_group_in_use() {
+    let mut contract = setup_contract();
+    contract.set_ppid_group("production".to_string(), vec![Ppid::default()]);
+    contract.set_measurements_ppid_group(0, Some("production".to_string()));
+    contract.remove_ppid_group("production".to_string());
+}
+
+// Test that non-owner cannot change PPID groups
+#[test]
+#[should_panic(expected = "Caller is not the owner")]
+fn test_set_ppid_group_not_owner() {
+    let mut contract = setup_contract();
+    let context = get_context(accounts(2), false);
+    testing_env!(context.build());
+    contract.set_ppid_group("production".to_string(), vec![Ppid::default()]);
+}
+
+// -------- PPID registry --------
+
+// Test that approved PPIDs record their operator, region and expiry
+#[test]
+fn test_approve_ppids_with_metadata() {
+    let mut contract = setup_contract();
+    let context = get_context_with_deposit_and_timestamp(accounts(0), false, None, Some(1000));
+    testing_env!(context.build());
+    contract.approve_ppids(
+        vec![non_default_ppid()],
+        Some("provider-a".to_string()),
+        Some("eu-west".to_string()),
+        Some(U64::from(5000)),
+    );
+
+    let ppids = contract.get_approved_ppids(&None, &None);
+    let approved = ppids.iter().find(|p| p.ppid == non_default_ppid()).unwrap();
+    assert_eq!(approved.operator, "provider-a");
+    assert_eq!(approved.region, "eu-west");
+    assert_eq!(approved.added_at_ms, U64::from(1000));
+    assert_eq!(approved.expires_at_ms, Some(U64::from(5000)));
+}
+
+// Test that approving an already approved PPID keeps its existing metadata
+#[test]
+fn test_approve_ppids_keeps_existing_entry() {
+    let mut contract = setup_contract();
+    contract.approve_ppids(
+        vec![Ppid::default()],
+        Some("provider-b".to_string()),
+        None,
+        None,
+    );
+    let ppids = contract.get_approved_ppids(&None, &None);
+    assert_eq!(ppids.len(), 1);
+    assert_eq!(ppids[0].operator, "");
+}
+
+// Test that the owner can update the metadata of an approved PPID
+#[test]
+fn test_update_ppid_metadata() {
+    let mut contract = setup_contract();
+    contract.update_ppid_metadata(
+        Ppid::default(),
+        "provider-a".to_string(),
+        "us-east".to_string(),
+        Some(U64::from(5000)),
+    );
+    let ppids = contract.get_approved_ppids(&None, &None);
+    assert_eq!(ppids[0].operator, "provider-a");
+    assert_eq!(ppids[0].region, "us-east");
+    assert_eq!(ppids[0].expires_at_ms, Some(U64::from(5000)));
+}
+
+// Test that updating the metadata of a PPID that is not approved fails
+#[test]
+#[should_panic(expected = "PPID not in approved list")]
+fn test_update_ppid_metadata_not_found() {
+    let mut contract = setup_contract();
+    contract.update_ppid_metadata(non_default_ppid(), String::new(), String::new(), None);
+}
+
+// Test that an operator longer than the limit is rejected
+#[test]
+#[should_panic(expected = "Operator must be at most 64 bytes")]
+fn test_approve_ppids_operator_too_long() {
+    let mut contract = setup_contract();
+    contract.approve_ppids(vec![non_default_ppid()], Some("a".repeat(65)), None, None);
+}
+
+// Test that agents on a revoked PPID carry the revocation reason, and re-approval clears it
+#[test]
+fn test_remove_ppids_records_revocation_reason() {
+    let mut contract = setup_contract();
+    let agent = accounts(2);
+    register_local_agent_at(&mut contract, &agent, 1000);
+
+    let context = get_context(accounts(0), false);
+    testing_env!(context.build());
+    contract.remove_ppids(
+        vec![Ppid::default()],
+        Some(PpidRevocationReason::Compromised),
+    );
+    let agent_info = contract.get_agent(agent.clone()).unwrap();
+    assert!(
+        matches!(agent_info.validity, AgentValidity::Invalid(ref r) if r == &vec![AgentRemovalReason::RevokedPpid(PpidRevocationReason::Compromised)])
+    );
+
+    contract.approve_ppids(vec![Ppid::default()], None, None, None);
+    contract.remove_ppids(vec![Ppid::default()], None);
+    let agent_info = contract.get_agent(agent).unwrap();
+    assert!(
+        matches!(agent_info.validity, AgentValidity::Invalid(ref r) if r == &vec![AgentRemovalReason::InvalidPpid])
+    );
+}
+
+// Test that agents become invalid once their PPID expires
+#[test]
+fn test_expired_ppid_invalidates_agent() {
+    let mut contract = setup_contract();
+    let agent = accounts(2);
+    contract.update_ppid_metadata(
+        Ppid::default(),
+        String::new(),
+        String::new(),
+        Some(U64::from(2000)),
+    );
+    register_local_agent_at(&mut contract, &agent, 1000);
+    assert!(matches!(
+        contract.get_agent(agent.clone()).unwrap().validity,
+        AgentValidity::Valid
+    ));
+
+    let context = get_context_with_deposit_and_timestamp(accounts(0), false, None, Some(2000));
+    testing_env!(context.build());
+    let agent_info = contract.get_agent(agent).unwrap();
+    assert!(
+        matches!(agent_info.validity, AgentValidity::Invalid(ref r) if r == &vec![AgentRemovalReason::ExpiredPpid])
+    );
+}
+
+// Test that registration fails once the PPID has expired
+#[test]
+#[should_panic(expected = "Default PPID must be approved for local mode")]
+fn test_expired_ppid_refuses_registration() {
+    let mut contract = setup_contract();
+    contract.update_ppid_metadata(
+        Ppid::default(),
+        String::new(),
+        String::new(),
+        Some(U64::from(2000)),
+    );
+    register_local_agent_at(&mut contract, &accounts(2), 3000);
+}
+
+// Test that a PPID cannot be approved with an expiry that already passed
+#[test]
+#[should_panic(expected = "PPID expiry must be in the future")]
+fn test_approve_ppids_past_expiry() {
+    let mut contract = setup_contract();
+    set_caller(accounts(0));
+    contract.approve_ppids(vec![non_default_ppid()], None, None, Some(U64::from(1000)));
+}
+
+// Test that non-owner cannot update PPID metadata
+#[test]
+#[should_panic(expected = "Caller is not the owner")]
+fn test_update_ppid_metadata_not_owner() {
+    let mut contract = setup_contract();
+    let context = get_context(accounts(2), false);
+    testing_env!(context.build());
+    contract.update_ppid_metadata(Ppid::default(), String::new(), String::new(), None);
+}
+
+// -------- Multisig owner --------
+
+// Helper to switch the contract to a 2-of-3 multisig of accounts 2, 3 and 4 at timestamp 1000
+fn setup_multisig_contract() -> Contract {
+    let mut contract = setup_contract();
+    let context = get_context_with_deposit_and_timestamp(accounts(0), false, None, Some(1000));
+    testing_env!(context.build());
+    contract.enable_multisig(
+        vec![accounts(2), accounts(3), accounts(4)],
+        2,
+        U64::from(10000),
+    );
+    contract
+}
+
+// Helper to call as an account at timestamp 1000
+fn set_caller(account_id: AccountId) {
+    let context = get_context_with_deposit_and_timestamp(account_id, false, None, Some(1000));
+    testing_env!(context.build());
+}
+
+// Test that a proposal executes once the threshold of owners has confirmed
+#[test]
+fn test_multisig_proposal_executes_at_threshold() {
+    let mut contract = setup_multisig_contract();
+    set_caller(accounts(2));
+    let proposal_id = contract.propose(OwnerAction::UpdateMpcContractId {
+        mpc_contract_id: accounts(5),
+    });
+    assert_eq!(contract.get_contract_info().mpc_contract_id, accounts(1));
+    let proposal = contract.get_proposal(proposal_id).unwrap();
+    assert_eq!(proposal.confirmations, vec![accounts(2)]);
+    assert_eq!(proposal.expires_at_ms, U64::from(11000));
+
+    set_caller(accounts(3));
+    assert!(contract.confirm_proposal(proposal_id));
+    assert_eq!(contract.get_contract_info().mpc_contract_id, accounts(5));
+    assert!(contract.get_proposal(proposal_id).is_none());
+}
+
+// Test that privileged actions are applied through proposals the same way as direct calls
+#[test]
+fn test_multisig_proposal_approves_measurements() {
+    let mut contract = setup_multisig_contract();
+    set_caller(accounts(2));
+    let proposal_id = contract.propose(OwnerAction::ApproveMeasurements {
+        measurements: non_default_measurements(),
+        label: Some("v2".to_string()),
+        source: None,
+    });
+    set_caller(accounts(4));
+    contract.confirm_proposal(proposal_id);
+
+    let approved = contract.get_measurements(1).unwrap();
+    assert_eq!(approved.measurements, non_default_measurements());
+    assert_eq!(approved.label, "v2");
+}
+
+// Test that owner methods cannot be called directly in multisig mode
+#[test]
+#[should_panic(expected = "Owner methods are disabled in multisig mode, submit a proposal instead")]
+fn test_multisig_disables_owner_methods() {
+    let mut contract = setup_multisig_contract();
+    contract.approve_measurements(non_default_measurements(), None, None);
+}
+
+// Test that accounts outside the multisig cannot propose
+#[test]
+#[should_panic(expected = "Caller is not a multisig owner")]
+fn test_multisig_propose_not_owner() {
+    let mut contract = setup_multisig_contract();
+    set_caller(accounts(0));
+    contract.propose(OwnerAction::DisableMultisig);
+}
+
+// Test that an owner cannot confirm the same proposal twice
+#[test]
+#[should_panic(expected = "Proposal already confirmed by this owner")]
+fn test_multisig_confirm_twice() {
+    let mut contract = setup_multisig_contract();
+    set_caller(accounts(2));
+    let proposal_id = contract.propose(OwnerAction::DisableMultisig);
+    contract.confirm_proposal(proposal_id);
+}
+
+// Test that expired proposals cannot be confirmed
+#[test]
+#[should_panic(expected = "Proposal has expired")]
+fn test_multisig_confirm_expired() {
+    let mut contract = setup_multisig_contract();
+    set_caller(accounts(2));
+    let proposal_id = contract.propose(OwnerAction::DisableMultisig);
+
+    let context = get_context_with_deposit_and_timestamp(accounts(3), false, None, Some(11000));
+    testing_env!(context.build());
+    contract.confirm_proposal(proposal_id);
+}
+
+// Test that the proposer can cancel a proposal and anyone can clear it once expired
+#[test]
+fn test_multisig_cancel_proposal() {
+    let mut contract = setup_multisig_contract();
+    set_caller(accounts(2));
+    let first = contract.propose(OwnerAction::DisableMultisig);
+    let second = contract.propose(OwnerAction::DisableMultisig);
+    assert_eq!(contract.get_proposals(&None, &None).len(), 2);
+
+    contract.cancel_proposal(first);
+    assert!(contract.get_proposal(first).is_none());
+
+    let context = get_context_with_deposit_and_timestamp(accounts(5), false, None, Some(11000));
+    testing_env!(context.build());
+    contract.cancel_proposal(second);
+    assert!(contract.get_proposals(&None, &None).is_empty());
+}
+
+// Test that other owners cannot cancel a proposal before it expires
+#[test]
+#[should_panic(expected = "Only the proposer can cancel a proposal before it expires")]
+fn test_multisig_cancel_proposal_not_proposer() {
+    let mut contract = setup_multisig_contract();
+    set_caller(accounts(2));
+    let proposal_id = contract.propose(OwnerAction::DisableMultisig);
+    set_caller(accounts(3));
+    contract.cancel_proposal(proposal_id);
+}
+
+// Test that a failing action reverts the final confirmation
+#[test]
+#[should_panic(expected = "PPID not in approved list")]
+fn test_multisig_failing_action() {
+    let mut contract = setup_multisig_contract();
+    set_caller(accounts(2));
+    let proposal_id = contract.propose(OwnerAction::RemovePpids {
+        ppids: vec![non_default_ppid()],
+        reason: None,
+    });
+    set_caller(accounts(3));
+    contract.confirm_proposal(proposal_id);
+}
+
+// Test that the owners can change the multisig and hand control back to the single owner
+#[test]
+fn test_multisig_update_and_disable() {
+    let mut contract = setup_multisig_contract();
+    set_caller(accounts(2));
+    let proposal_id = contract.propose(OwnerAction::SetMultisig {
+        owners: vec![accounts(2), accounts(3)],
+        threshold: 1,
+        proposal_lifetime_ms: U64::from(5000),
+    });
+    set_caller(accounts(3));
+    contract.confirm_proposal(proposal_id);
+    let multisig = contract.get_multisig().unwrap();
+    assert_eq!(multisig.owners, vec![accounts(2), accounts(3)]);
+    assert_eq!(multisig.threshold, 1);
+
+    // With a threshold of 1 the proposal executes straight away
+    contract.propose(OwnerAction::DisableMultisig);
+    assert!(contract.get_multisig().is_none());
+
+    set_caller(accounts(0));
+    contract.approve_ppids(vec![non_default_ppid()], None, None, None);
+    assert_eq!(contract.get_approved_ppids(&None, &None).len(), 2);
+}
+
+// Test that confirmations from removed owners no longer count
+#[test]
+fn test_multisig_removed_owner_confirmations_ignored() {
+    let mut contract = setup_multisig_contract();
+    set_caller(accounts(4));
+    let pending = contract.propose(OwnerAction::DisableMultisig);
+
+    set_caller(accounts(2));
+    let proposal_id = contract.propose(OwnerAction::SetMultisig {
+        owners: vec![accounts(2), accounts(3), accounts(5)],
+        threshold: 2,
+        proposal_lifetime_ms: U64::from(10000),
+    });
+    set_caller(accounts(3));
+    contract.confirm_proposal(proposal_id);
+
+    // accounts(4) proposed but is no longer an owner, so one more confirmation is not enough
+    assert!(!contract.confirm_proposal(pending));
+    assert!(contract.get_multisig().is_some());
+}
+
+// Test that the threshold cannot exceed the number of owners
+#[test]
+#[should_panic(expected = "Threshold must be between 1 and the number of owners")]
+fn test_enable_multisig_invalid_threshold() {
+    let mut contract = setup_contract();
+    contract.enable_multisig(vec![accounts(2), accounts(3)], 3, U64::from(10000));
+}
+
+// Test that non-owner cannot enable multisig mode
+#[test]
+#[should_panic(expected = "Caller is not the owner")]
+fn test_enable_multisig_not_owner() {
+    let mut contract = setup_contract();
+    set_caller(accounts(2));
+    contract.enable_multisig(vec![accounts(2), accounts(3)], 2, U64::from(10000));
+}
+
+// -------- Timelock --------
+
+// Helper to enable a 5 second timelock at timestamp 1000
+fn setup_timelock_contract() -> Contract {
+    let mut contract = setup_contract();
+    set_caller(accounts(0));
+    contract.set_timelock_delay(U64::from(5000));
+    contract
+}
+
+// Test that timelocked actions are queued and anyone can execute them after the delay
+#[test]
+fn test_timelock_queues_and_executes_action() {
+    let mut contract = setup_timelock_contract();
+    assert_eq!(
+        contract.get_contract_info().timelock_delay_ms,
+        U64::from(5000)
+    );
+
+    assert!(
+        contract
+            .approve_measurements(non_default_measurements(), None, None)
+            .is_none()
+    );
+    assert!(contract.get_measurements(1).is_none());
+    let queued = contract.get_queued_actions(&None, &None);
+    assert_eq!(queued.len(), 1);
+    assert_eq!(queued[0].queued_by, accounts(0));
+    assert_eq!(queued[0].executable_at_ms, U64::from(6000));
+
+    let context = get_context_with_deposit_and_timestamp(accounts(5), false, None, Some(6000));
+    testing_env!(context.build());
+    contract.execute_queued_action(queued[0].action_id);
+    let approved = contract.get_measurements(1).unwrap();
+    assert_eq!(approved.measurements, non_default_measurements());
+    assert_eq!(approved.approved_by, accounts(0));
+    assert!(contract.get_queued_actions(&None, &None).is_empty());
+}
+
+// Test that queued actions cannot be executed before the delay
+#[test]
+#[should_panic(expected = "Timelock delay has not passed")]
+fn test_timelock_execute_too_early() {
+    let mut contract = setup_timelock_contract();
+    contract.update_mpc_contract_id(accounts(5));
+
+    let context = get_context_with_deposit_and_timestamp(accounts(5), false, None, Some(5999));
+    testing_env!(context.build());
+    contract.execute_queued_action(U64::from(0));
+}
+
+// Test that the owner can cancel a queued action
+#[test]
+#[should_panic(expected = "Queued action not found")]
+fn test_timelock_cancel_queued_action() {
+    let mut contract = setup_timelock_contract();
+    contract.approve_ppids(vec![non_default_ppid()], None, None, None);
+    contract.cancel_queued_action(U64::from(0));
+    assert!(contract.get_queued_action(U64::from(0)).is_none());
+
+    let context = get_context_with_deposit_and_timestamp(accounts(5), false, None, Some(6000));
+    testing_env!(context.build());
+    contract.execute_queued_action(U64::from(0));
+}
+
+// Test that non-owner cannot cancel a queued action
+#[test]
+#[should_panic(expected = "Caller is not the owner")]
+fn test_timelock_cancel_queued_action_not_owner() {
+    let mut contract = setup_timelock_contract();
+    contract.approve_ppids(vec![non_default_ppid()], None, None, None);
+    set_caller(accounts(2));
+    contract.cancel_queued_action(U64::from(0));
+}
+
+// Test that removals skip the timelock
+#[test]
+fn test_timelock_removals_are_immediate() {
+    let mut contract = setup_timelock_contract();
+    contract.remove_ppids(vec![Ppid::default()], None);
+    contract.remove_measurements(create_mock_full_measurements_hex());
+    assert!(contract.get_approved_ppids(&None, &None).is_empty());
+    assert!(contract.get_approved_measurements(&None, &None).is_empty());
+    assert!(contract.get_queued_actions(&None, &None).is_empty());
+}
+
+// Test that changing the timelock delay is itself timelocked
+#[test]
+fn test_timelock_delay_change_is_timelocked() {
+    let mut contract = setup_timelock_contract();
+    contract.set_timelock_delay(U64::from(0));
+    assert_eq!(
+        contract.get_contract_info().timelock_delay_ms,
+        U64::from(5000)
+    );
+
+    let context = get_context_with_deposit_and_timestamp(accounts(0), false, None, Some(6000));
+    testing_env!(context.build());
+    contract.execute_queued_action(U64::from(0));
+    assert_eq!(contract.get_contract_info().timelock_delay_ms, U64::from(0));
+}
+
+// Test that unpausing waits for the timelock while pausing does not
+#[test]
+fn test_timelock_unpause_is_queued() {
+    let mut contract = setup_timelock_contract();
+    contract.pause(PausableFeature::Signing);
+    assert!(contract.get_contract_info().signing_paused);
+
+    contract.unpause(PausableFeature::Signing);
+    assert!(contract.get_contract_info().signing_paused);
+    let context = get_context_with_deposit_and_timestamp(accounts(5), false, None, Some(6000));
+    testing_env!(context.build());
+    contract.execute_queued_action(U64::from(0));
+    assert!(!contract.get_contract_info().signing_paused);
+}
+
+// Test that multisig proposals for timelocked actions are queued once confirmed
+#[test]
+fn test_timelock_multisig_proposal_is_queued() {
+    let mut contract = setup_timelock_contract();
+    contract.enable_multisig(vec![accounts(2), accounts(3)], 1, U64::from(10000));
+    assert!(contract.get_multisig().is_none());
+
+    let context = get_context_with_deposit_and_timestamp(accounts(0), false, None, Some(6000));
+    testing_env!(context.build());
+    contract.execute_queued_action(U64::from(0));
+    assert!(contract.get_multisig().is_some());
+
+    set_caller(accounts(2));
+    contract.propose(OwnerAction::UpdateMpcContractId {
+        mpc_contract_id: accounts(5),
+    });
+    assert_eq!(contract.get_contract_info().mpc_contract_id, accounts(1));
+    let queued = contract.get_queued_actions(&None, &None);
+    assert_eq!(queued.len(), 1);
+    assert_eq!(queued[0].queued_by, accounts(2));
+}
+
+// -------- Roles --------
+
+// Test that an account with a role can run the methods of that role
+#[test]
+fn test_role_allows_matching_methods() {
+    let mut contract = setup_contract();
+    contract.grant_role(accounts(2), Role::MeasurementAdmin);
+    assert_eq!(
+        contract.get_account_roles(accounts(2)),
+        vec![Role::MeasurementAdmin]
+    );
+    assert_eq!(
+        contract.get_role_members(Role::MeasurementAdmin),
+        vec![accounts(2)]
+    );
+
+    set_caller(accounts(2));
+    let measurements_id = contract
+        .approve_measurements(non_default_measurements(), None, None)
+        .unwrap();
+    assert_eq!(
+        contract
+            .get_measurements(measurements_id)
+            .unwrap()
+            .approved_by,
+        accounts(2)
+    );
+    contract.set_measurements_agent_quota(measurements_id, Some(1));
+}
+
+// Test that a role does not allow methods of other roles
+#[test]
+#[should_panic(expected = "Caller is not the owner and does not have the Upgrader role")]
+fn test_role_rejects_other_methods() {
+    let mut contract = setup_contract();
+    contract.grant_role(accounts(2), Role::MeasurementAdmin);
+    set_caller(accounts(2));
+    contract.approve_code_hash(Base58CryptoHash::from([1; 32]), 30);
+}
+
+// Test that roles do not allow owner-only methods
+#[test]
+#[should_panic(expected = "Caller is not the owner")]
+fn test_role_rejects_owner_only_methods() {
+    let mut contract = setup_contract();
+    contract.grant_role(accounts(2), Role::MeasurementAdmin);
+    set_caller(accounts(2));
+    contract.update_mpc_contract_id(accounts(5));
+}
+
+// Test that revoking a role removes access
+#[test]
+#[should_panic(expected = "Caller is not the owner and does not have the PpidAdmin role")]
+fn test_revoke_role() {
+    let mut contract = setup_contract();
+    contract.grant_role(accounts(2), Role::PpidAdmin);
+    contract.grant_role(accounts(2), Role::AgentAdmin);
+    contract.revoke_role(accounts(2), Role::PpidAdmin);
+    assert_eq!(
+        contract.get_account_roles(accounts(2)),
+        vec![Role::AgentAdmin]
+    );
+    assert_eq!(contract.get_roles(&None, &None).len(), 1);
+
+    set_caller(accounts(2));
+    contract.approve_ppids(vec![non_default_ppid()], None, None, None);
+}
+
+// Test that revoking a role cancels the actions queued through it, but not the owner's
+#[test]
+fn test_revoke_role_cancels_queued_actions() {
+    let mut contract = setup_timelock_contract();
+    contract.grant_role(accounts(2), Role::PpidAdmin);
+    let context = get_context_with_deposit_and_timestamp(accounts(0), false, None, Some(6000));
+    testing_env!(context.build());
+    contract.execute_queued_action(U64::from(0));
+
+    let context = get_context_with_deposit_and_timestamp(accounts(2), false, None, Some(6000));
+    testing_env!(context.build());
+    contract.approve_ppids(vec![non_default_ppid()], None, None, None);
+    let context = get_context_with_deposit_and_timestamp(accounts(0), false, None, Some(6000));
+    testing_env!(context.build());
+    contract.approve_ppids(vec![non_default_ppid()], None, None, None);
+
+    contract.revoke_role(accounts(2), Role::PpidAdmin);
+    assert!(
+        logged_events().contains(&ContractEvent::QueuedActionCancelled {
+            action_id: 1,
+            action: "approve_ppids".to_string(),
+        })
+    );
+    let queued = contract.get_queued_actions(&None, &None);
+    assert_eq!(queued.len(), 1);
+    assert_eq!(queued[0].queued_by, accounts(0));
+}
+
+// Test that revoking a role the account does not have fails
+#[test]
+#[should_panic(expected = "Account does not have this role")]
+fn test_revoke_role_not_granted() {
+    let mut contract = setup_contract();
+    contract.revoke_role(accounts(2), Role::PpidAdmin);
+}
+
+// Test that only the owner can grant roles
+#[test]
+#[should_panic(expected = "Caller is not the owner")]
+fn test_grant_role_not_owner() {
+    let mut contract = setup_contract();
+    contract.grant_role(accounts(2), Role::AgentAdmin);
+    set_caller(accounts(2));
+    contract.grant_role(accounts(3), Role::AgentAdmin);
+}
+
+// Test that role holders cannot act alone in multisig mode, except for pausing
+#[test]
+fn test_role_in_multisig_mode() {
+    let mut contract = setup_contract();
+    contract.grant_role(accounts(5), Role::MeasurementAdmin);
+    contract.grant_role(accounts(5), Role::Upgrader);
+    contract.grant_role(accounts(5), Role::Pauser);
+    contract.enable_multisig(vec![accounts(2), accounts(3)], 2, U64::from(10000));
+
+    set_caller(accounts(5));
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        contract.approve_measurements(non_default_measurements(), None, None);
+    }));
+    assert_eq!(
+        panic_message(result),
+        "Role methods are disabled in multisig mode, submit a proposal instead"
+    );
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        contract.approve_code_hash(Base58CryptoHash::from([1; 32]), 30);
+    }));
+    assert_eq!(
+        panic_message(result),
+        "Role methods are disabled in multisig mode, submit a proposal instead"
+    );
+    assert_eq!(contract.get_approved_measurements(&None, &None).len(), 1);
+
+    // Pausing stays available to pausers, unpausing needs a proposal
+    contract.pause(PausableFeature::Signing);
+    assert!(contract.get_contract_info().signing_paused);
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        contract.unpause(PausableFeature::Signing);
+    }));
+    assert_eq!(
+        panic_message(result),
+        "Role methods are disabled in multisig mode, submit a proposal instead"
+    );
+
+    // Accounts without the role cannot pause either
+    set_caller(accounts(4));
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        contract.pause(PausableFeature::Registration);
+    }));
+    assert_eq!(
+        panic_message(result),
+        "Caller does not have the Pauser role"
+    );
+
+    // The multisig owners can still approve through a proposal
+    set_caller(accounts(2));
+    let proposal_id = contract.propose(OwnerAction::ApproveMeasurements {
+        measurements: non_default_measurements(),
+        label: None,
+        source: None,
+    });
+    set_caller(accounts(3));
+    assert!(contract.confirm_proposal(proposal_id));
+    assert_eq!(contract.get_approved_measurements(&None, &None).len(), 2);
+}
+
+// Test that cancelling a queued action requires the role of the queued action
+#[test]
+fn test_cancel_queued_action_with_role() {
+    let mut contract = setup_timelock_contract();
+    contract.grant_role(accounts(2), Role::PpidAdmin);
+    let context = get_context_with_deposit_and_timestamp(accounts(0), false, None, Some(6000));
+    testing_env!(context.build());
+    contract.execute_queued_action(U64::from(0));
+
+    let context = get_context_with_deposit_and_timestamp(accounts(2), false, None, Some(6000));
+    testing_env!(context.build());
+    contract.approve_ppids(vec![non_default_ppid()], None, None, None);
+    contract.cancel_queued_action(U64::from(1));
+    assert!(contract.get_queued_actions(&None, &None).is_empty());
+}
+
+// -------- Pause --------
+
+// Test that paused registration refuses new agents and is shown in the contract info
+#[test]
+#[should_panic(expected = "Agent registration is paused")]
+fn test_pause_registration() {
+    let mut contract = setup_contract();
+    contract.pause(PausableFeature::Registration);
+    let info = contract.get_contract_info();
+    assert!(info.registration_paused);
+    assert!(!info.signing_paused);
+
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+}
+
+// Test that paused signing fails before the agent is checked, keeping invalid agents in place
+#[test]
+fn test_pause_signing_keeps_agents() {
+    let mut contract = setup_contract();
+    let agent = accounts(2);
+    register_local_agent_at(&mut contract, &agent, 1000);
+    set_caller(accounts(0));
+    contract.remove_measurements(create_mock_full_measurements_hex());
+    contract.pause(PausableFeature::Signing);
+
+    set_caller(agent.clone());
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        contract
+            .request_signature(
+                "path".to_string(),
+                MOCK_PAYLOAD.to_string(),
+                SignatureScheme::Secp256k1,
+            )
+            .detach();
+    }));
+    assert!(result.is_err());
+    assert!(contract.get_agent(agent).is_some());
+}
+
+// Test that unpausing resumes registration
+#[test]
+fn test_unpause_registration() {
+    let mut contract = setup_contract();
+    contract.pause(PausableFeature::Registration);
+    contract.unpause(PausableFeature::Registration);
+    assert!(!contract.get_contract_info().registration_paused);
+
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+    assert!(contract.get_agent(accounts(2)).is_some());
+}
+
+// Test that pausing twice fails
+#[test]
+#[should_panic(expected = "Signing is already paused")]
+fn test_pause_twice() {
+    let mut contract = setup_contract();
+    contract.pause(PausableFeature::Signing);
+    contract.pause(PausableFeature::Signing);
+}
+
+// Test that the pauser role can pause, and pausing is not delayed by the timelock
+#[test]
+fn test_pauser_role() {
+    let mut contract = setup_timelock_contract();
+    contract.grant_role(accounts(2), Role::Pauser);
+    let context = get_context_with_deposit_and_timestamp(accounts(0), false, None, Some(6000));
+    testing_env!(context.build());
+    contract.execute_queued_action(U64::from(0));
+
+    let context = get_context_with_deposit_and_timestamp(accounts(2), false, None, Some(6000));
+    testing_env!(context.build());
+    contract.pause(PausableFeature::Signing);
+    assert!(contract.get_contract_info().signing_paused);
+}
+
+// Test that accounts without the pauser role cannot pause
+#[test]
+#[should_panic(expected = "Caller is not the owner and does not have the Pauser role")]
+fn test_pause_not_pauser() {
+    let mut contract = setup_contract();
+    set_caller(accounts(2));
+    contract.pause(PausableFeature::Registration);
+}
+
+// -------- State migration --------
+
+use crate::migration::ContractV1;
+use near_sdk::store::{IterableMap, IterableSet};
+
+fn legacy_agent_id(i: usize) -> AccountId {
+    format!("agent{i}.near").parse().unwrap()
+}
+
+// Writes a contract state in the version 1 layout with the default measurements and PPID approved
+// and num_agents whitelisted agents registered on them
+fn write_v1_state(num_agents: usize) {
+    let context = get_context_with_deposit_and_timestamp(accounts(0), false, None, Some(1000));
+    testing_env!(context.build());
+
+    let mut approved_measurements = IterableSet::new(StorageKey::LegacyApprovedMeasurements);
+    approved_measurements.insert(create_mock_full_measurements_hex());
+    approved_measurements.insert(non_default_measurements());
+    let mut approved_ppids = IterableSet::new(StorageKey::LegacyApprovedPpids);
+    approved_ppids.insert(Ppid::default());
+    let mut agents = IterableMap::new(StorageKey::LegacyAgents);
+    let mut whitelisted_agents_for_local = IterableSet::new(StorageKey::WhitelistedAgentsForLocal);
+    whitelisted_agents_for_local.insert(accounts(2));
+    for i in 0..num_agents {
+        whitelisted_agents_for_local.insert(legacy_agent_id(i));
+        agents.insert(
+            legacy_agent_id(i),
+            AgentV1 {
+                measurements: create_mock_full_measurements_hex(),
+                ppid: Ppid::default(),
+                valid_until_ms: 50_000,
+            },
+        );
+    }
+
+    let old = ContractV1 {
+        requires_tee: false,
+        attestation_expiration_time_ms: 100000,
+        owner_id: accounts(0),
+        mpc_contract_id: accounts(1),
+        approved_measurements,
+        approved_ppids,
+        agents,
+        whitelisted_agents_for_local,
+    };
+    env::state_write(&old);
+}
+
+// Test that migrate upgrades the version 1 layout, giving measurements IDs and moving the agents
+#[test]
+fn test_migrate_from_v1() {
+    write_v1_state(3);
+    let contract = Contract::migrate();
+
+    let info = contract.get_contract_info();
+    assert_eq!(info.state_version, 2);
+    assert_eq!(info.agents_pending_migration, 0);
+    assert_eq!(info.owner_id, accounts(0));
+    assert_eq!(info.attestation_expiration_time_ms.0, 100000);
+
+    let measurements = contract.get_approved_measurements(&None, &None);
+    assert_eq!(measurements.len(), 2);
+    assert_eq!(measurements[0].measurements_id, 0);
+    assert_eq!(
+        measurements[0].measurements,
+        create_mock_full_measurements_hex()
+    );
+    assert_eq!(measurements[0].approved_by, accounts(0));
+    assert_eq!(measurements[0].active_agents, 3);
+    assert_eq!(measurements[1].active_agents, 0);
+
+    let ppids = contract.get_approved_ppids(&None, &None);
+    assert_eq!(ppids.len(), 1);
+    assert_eq!(ppids[0].ppid, Ppid::default());
+    let whitelisted = contract.get_whitelisted_agents_for_local();
+    assert_eq!(whitelisted.len(), 4);
+    assert!(whitelisted.contains(&accounts(2)));
+
+    let agents = contract.get_agents(&None, &None);
+    assert_eq!(agents.len(), 3);
+    assert!(agents.iter().all(|agent| agent.measurements_id == 0
+        && agent.valid_until_ms.0 == 50_000
+        && matches!(agent.validity, AgentValidity::Valid)));
+}
+
+// Test that agents whose measurements were removed before the upgrade are dropped
+#[test]
+fn test_migrate_from_v1_drops_agents_on_removed_measurements() {
+    write_v1_state(1);
+    let mut old: ContractV1 = env::state_read().unwrap();
+    old.agents.insert(
+        accounts(3),
+        AgentV1 {
+            measurements: create_mock_full_measurements_hex(),
+            ppid: Ppid::default(),
+            valid_until_ms: 50_000,
+        },
+    );
+    old.approved_measurements
+        .remove(&create_mock_full_measurements_hex());
+    env::state_write(&old);
+    drop(old);
+
+    let contract = Contract::migrate();
+    assert_eq!(contract.get_approved_measurements(&None, &None).len(), 1);
+    assert!(contract.get_agents(&None, &None).is_empty());
+    assert_eq!(contract.get_contract_info().agents_pending_migration, 0);
+}
+
+// Test that agents beyond the first batch are migrated by migrate_agents and still show up meanwhile
+#[test]
+fn test_migrate_agents_in_batches() {
+    write_v1_state(30);
+    let mut contract = Contract::migrate();
+    assert_eq!(contract.get_agents(&None, &None).len(), 25);
+    assert_eq!(contract.get_contract_info().agents_pending_migration, 5);
+
+    // Agents waiting for migration are shown as they will be once migrated
+    let pending = legacy_agent_id(29);
+    let view = contract.get_agent(pending.clone()).unwrap();
+    assert_eq!(view.measurements_id, 0);
+    assert!(matches!(view.validity, AgentValidity::Valid));
+
+    assert_eq!(contract.migrate_agents(3), 2);
+    assert_eq!(contract.migrate_agents(10), 0);
+    assert_eq!(contract.get_agents(&None, &None).len(), 30);
+    assert_eq!(
+        contract.get_approved_measurements(&None, &None)[0].active_agents,
+        30
+    );
+    assert!(contract.legacy_agents.is_none());
+}
+
+// Test that an agent waiting for migration is migrated when it registers again, without a new deposit
+#[test]
+fn test_register_migrates_pending_agent() {
+    write_v1_state(30);
+    let mut contract = Contract::migrate();
+    let pending = legacy_agent_id(29);
+    set_caller(pending.clone());
+    contract.register_agent(create_mock_dstack_attestation());
+    assert_eq!(contract.get_contract_info().agents_pending_migration, 4);
+    assert_eq!(
+        contract.get_agent(pending).unwrap().valid_until_ms.0,
+        101000
+    );
+    assert_eq!(
+        contract.get_approved_measurements(&None, &None)[0].active_agents,
+        26
+    );
+}
+
+// Test that agents waiting for migration are exempt from a quota set after the upgrade
+#[test]
+fn test_migrated_agents_exempt_from_quota() {
+    write_v1_state(30);
+    let mut contract = Contract::migrate();
+    set_caller(accounts(0));
+    contract.set_measurements_agent_quota(0, Some(25));
+
+    assert_eq!(contract.migrate_agents(10), 0);
+    assert_eq!(
+        contract.get_approved_measurements(&None, &None)[0].active_agents,
+        30
+    );
+
+    // New agents are refused until the count is back under the quota
+    testing_env!(get_context_with_deposit(accounts(2), false, Some(DEPOSIT_005_NEAR)).build());
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        contract.register_agent(create_mock_dstack_attestation())
+    }))
+    .map(|_| ());
+    assert!(
+        panic_message(result)
+            .contains("Agent quota reached for measurements 0: 30 of 25 agents registered")
+    );
+}
+
+// Test that the owner can remove an agent that has not been migrated yet
+#[test]
+fn test_remove_pending_agent() {
+    write_v1_state(30);
+    let mut contract = Contract::migrate();
+    let pending = legacy_agent_id(29);
+    set_caller(accounts(0));
+    contract.remove_agent(pending.clone());
+    assert!(contract.get_agent(pending).is_none());
+    assert_eq!(contract.get_contract_info().agents_pending_migration, 4);
+    assert_eq!(
+        contract.get_approved_measurements(&None, &None)[0].active_agents,
+        25
+    );
+}
+
+// Test that migrate_agents fails once every agent has been migrated
+#[test]
+#[should_panic(expected = "There are no agents to migrate")]
+fn test_migrate_agents_nothing_left() {
+    write_v1_state(3);
+    let mut contract = Contract::migrate();
+    contract.migrate_agents(10);
+}
+
+// Test that migrating state that is already current keeps it unchanged
+#[test]
+fn test_migrate_current_state() {
+    let mut contract = setup_contract();
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+    env::state_write(&contract);
+    drop(contract);
+
+    let contract = Contract::migrate();
+    assert_eq!(contract.get_contract_info().state_version, 2);
+    assert_eq!(contract.get_approved_measurements(&None, &None).len(), 1);
+    assert!(contract.get_agent(accounts(2)).is_some());
+}
+
+// Test that state written by newer code is not downgraded
+#[test]
+#[should_panic(expected = "State version 3 is newer than the contract code")]
+fn test_migrate_newer_state_version() {
+    let contract = setup_contract();
+    env::state_write(&contract);
+    crate::migration::write_state_version(3);
+    Contract::migrate();
+}
+
+// -------- Staged upgrades --------
+
+use near_sdk::{PromiseResult, RuntimeFeesConfig, json_types::Base58CryptoHash, test_vm_config};
+
+const UPGRADE_CODE: &[u8] = b"new contract code";
+
+fn upgrade_code_hash() -> Base58CryptoHash {
+    Base58CryptoHash::from(env::sha256_array(UPGRADE_CODE))
+}
+
+// Calls upload_code with the code as the raw input
+fn upload_code_as(contract: &mut Contract, account_id: AccountId, code: &[u8]) {
+    let mut context =
+        get_context_with_deposit_and_timestamp(account_id, false, None, Some(1000)).build();
+    context.input = code.into();
+    testing_env!(context);
+    contract.upload_code();
+}
+
+// Calls on_code_deployed as the callback of a deploy that succeeded or failed
+fn on_code_deployed_with(contract: &mut Contract, code_hash: Base58CryptoHash, success: bool) {
+    let context = get_context(accounts(0), false).build();
+    let result = if success {
+        PromiseResult::Successful(vec![])
+    } else {
+        PromiseResult::Failed
+    };
+    testing_env!(
+        context,
+        test_vm_config(),
+        RuntimeFeesConfig::test(),
+        Default::default(),
+        vec![result]
+    );
+    contract.on_code_deployed(code_hash);
+}
+
+// Test that an approved code hash shows up as the pending upgrade
+#[test]
+fn test_approve_code_hash() {
+    let mut contract = setup_contract();
+    contract.approve_code_hash(upgrade_code_hash(), 30);
+
+    let code_upgrade = contract.get_code_upgrade();
+    assert!(code_upgrade.code_hash.is_none());
+    let pending_upgrade = code_upgrade.pending_upgrade.unwrap();
+    assert_eq!(pending_upgrade.code_hash, upgrade_code_hash());
+    assert_eq!(pending_upgrade.migrate_gas_tgas, 30);
+    assert_eq!(pending_upgrade.approved_by, accounts(0));
+}
+
+// Test that anyone can upload code matching the approved hash and that the deploy is recorded
+#[test]
+fn test_upload_code_and_record_upgrade() {
+    let mut contract = setup_contract();
+    contract.approve_code_hash(upgrade_code_hash(), 30);
+    upload_code_as(&mut contract, accounts(3), UPGRADE_CODE);
+    // The upgrade stays pending until the deploy is confirmed
+    assert!(contract.get_code_upgrade().pending_upgrade.is_some());
+
+    on_code_deployed_with(&mut contract, upgrade_code_hash(), true);
+    let code_upgrade = contract.get_code_upgrade();
+    assert_eq!(code_upgrade.code_hash, Some(upgrade_code_hash()));
+    assert!(code_upgrade.previous_code_hash.is_none());
+    assert!(code_upgrade.pending_upgrade.is_none());
+}
+
+// Test that a failed deploy or migrate leaves the upgrade pending
+#[test]
+fn test_failed_upgrade_stays_pending() {
+    let mut contract = setup_contract();
+    contract.approve_code_hash(upgrade_code_hash(), 30);
+    upload_code_as(&mut contract, accounts(3), UPGRADE_CODE);
+    on_code_deployed_with(&mut contract, upgrade_code_hash(), false);
+
+    let code_upgrade = contract.get_code_upgrade();
+    assert!(code_upgrade.code_hash.is_none());
+    assert_eq!(
+        code_upgrade.pending_upgrade.unwrap().code_hash,
+        upgrade_code_hash()
+    );
+}
+
+// Test that code not matching the approved hash is rejected
+#[test]
+#[should_panic(expected = "Code does not match the approved code hash")]
+fn test_upload_code_wrong_hash() {
+    let mut contract = setup_contract();
+    contract.approve_code_hash(upgrade_code_hash(), 30);
+    upload_code_as(&mut contract, accounts(3), b"other contract code");
+}
+
+// Test that code cannot be uploaded without an approved hash
+#[test]
+#[should_panic(expected = "There is no pending upgrade")]
+fn test_upload_code_without_approval() {
+    let mut contract = setup_contract();
+    upload_code_as(&mut contract, accounts(3), UPGRADE_CODE);
+}
+
+// Test that a cancelled upgrade can no longer be uploaded
+#[test]
+#[should_panic(expected = "There is no pending upgrade")]
+fn test_cancel_code_upgrade() {
+    let mut contract = setup_contract();
+    contract.approve_code_hash(upgrade_code_hash(), 30);
+    contract.cancel_code_upgrade();
+    assert!(contract.get_code_upgrade().pending_upgrade.is_none());
+    upload_code_as(&mut contract, accounts(3), UPGRADE_CODE);
+}
+
+// Test that rolling back approves the code hash deployed before the current one
+#[test]
+fn test_rollback_code() {
+    let mut contract = setup_contract();
+    let first_hash = Base58CryptoHash::from(env::sha256_array(b"first contract code"));
+    contract.approve_code_hash(first_hash, 30);
+    on_code_deployed_with(&mut contract, first_hash, true);
+    set_caller(accounts(0));
+    contract.approve_code_hash(upgrade_code_hash(), 30);
+    on_code_deployed_with(&mut contract, upgrade_code_hash(), true);
+
+    set_caller(accounts(0));
+    contract.rollback_code(20);
+    let code_upgrade = contract.get_code_upgrade();
+    assert_eq!(code_upgrade.code_hash, Some(upgrade_code_hash()));
+    assert_eq!(code_upgrade.previous_code_hash, Some(first_hash));
+    let pending_upgrade = code_upgrade.pending_upgrade.unwrap();
+    assert_eq!(pending_upgrade.code_hash, first_hash);
+    assert_eq!(pending_upgrade.migrate_gas_tgas, 20);
+
+    upload_code_as(&mut contract, accounts(3), b"first contract code");
+    on_code_deployed_with(&mut contract, first_hash, true);
+    let code_upgrade = contract.get_code_upgrade();
+    assert_eq!(code_upgrade.code_hash, Some(first_hash));
+    assert_eq!(code_upgrade.previous_code_hash, Some(upgrade_code_hash()));
+}
+
+// Test that the code hash given on init can be rolled back to after one upgrade
+#[test]
+fn test_rollback_to_initial_code() {
+    testing_env!(get_context(accounts(0), false).build());
+    let initial_code: &[u8] = b"initial contract code";
+    let initial_hash = Base58CryptoHash::from(env::sha256_array(initial_code));
+    let mut contract = Contract::new(
+        false,
+        U64::from(100000u64),
+        accounts(0),
+        accounts(1),
+        Some(initial_hash),
+    );
+    assert_eq!(contract.get_code_upgrade().code_hash, Some(initial_hash));
+
+    contract.approve_code_hash(upgrade_code_hash(), 30);
+    upload_code_as(&mut contract, accounts(3), UPGRADE_CODE);
+    on_code_deployed_with(&mut contract, upgrade_code_hash(), true);
+    assert_eq!(
+        contract.get_code_upgrade().previous_code_hash,
+        Some(initial_hash)
+    );
+
+    set_caller(accounts(0));
+    contract.rollback_code(20);
+    upload_code_as(&mut contract, accounts(3), initial_code);
+    on_code_deployed_with(&mut contract, initial_hash, true);
+    let code_upgrade = contract.get_code_upgrade();
+    assert_eq!(code_upgrade.code_hash, Some(initial_hash));
+    assert_eq!(code_upgrade.previous_code_hash, Some(upgrade_code_hash()));
+    assert!(code_upgrade.pending_upgrade.is_none());
+}
+
+// Test that rolling back is refused when the previous code writes an older state version
+#[test]
+#[should_panic(expected = "The previous code cannot read the current state version")]
+fn test_rollback_code_across_state_version() {
+    testing_env!(get_context(accounts(0), false).build());
+    let initial_hash = Base58CryptoHash::from(env::sha256_array(b"initial contract code"));
+    let mut contract = Contract::new(
+        false,
+        U64::from(100000u64),
+        accounts(0),
+        accounts(1),
+        Some(initial_hash),
+    );
+    // As if the initial code wrote the version 1 layout and the upgrade migrated it
+    contract.code_state_version = 1;
+    on_code_deployed_with(&mut contract, upgrade_code_hash(), true);
+    assert_eq!(
+        contract.get_code_upgrade().previous_code_state_version,
+        Some(1)
+    );
+
+    set_caller(accounts(0));
+    contract.rollback_code(20);
+}
+
+// Test that there is nothing to roll back to without an initial code hash before a second upgrade
+#[test]
+#[should_panic(expected = "There is no previous code hash to roll back to")]
+fn test_rollback_code_without_previous() {
+    let mut contract = setup_contract();
+    contract.rollback_code(20);
+}
+
+// Test that approving a code hash is queued by the timelock
+#[test]
+fn test_timelock_code_hash_approval() {
+    let mut contract = setup_timelock_contract();
+    contract.approve_code_hash(upgrade_code_hash(), 30);
+    assert!(contract.get_code_upgrade().pending_upgrade.is_none());
+    let queued = contract.get_queued_actions(&None, &None);
+    assert_eq!(queued.len(), 1);
+    assert!(matches!(
+        queued[0].action,
+        OwnerAction::ApproveCodeHash {
+            migrate_gas_tgas: 30,
+            ..
+        }
+    ));
+}
+
+// Test that rolling back is queued by the timelock like any other code change
+#[test]
+fn test_timelock_rollback_is_queued() {
+    let mut contract = setup_timelock_contract();
+    let first_hash = Base58CryptoHash::from(env::sha256_array(b"first contract code"));
+    on_code_deployed_with(&mut contract, first_hash, true);
+    on_code_deployed_with(&mut contract, upgrade_code_hash(), true);
+
+    set_caller(accounts(0));
+    contract.rollback_code(20);
+    assert!(contract.get_code_upgrade().pending_upgrade.is_none());
+    let queued = contract.get_queued_actions(&None, &None);
+    assert_eq!(queued.len(), 1);
+    assert!(matches!(
+        queued[0].action,
+        OwnerAction::RollbackCode {
+            migrate_gas_tgas: 20
+        }
+    ));
+
+    let context = get_context_with_deposit_and_timestamp(accounts(5), false, None, Some(6000));
+    testing_env!(context.build());
+    contract.execute_queued_action(queued[0].action_id);
+    assert_eq!(
+        contract
+            .get_code_upgrade()
+            .pending_upgrade
+            .unwrap()
+            .code_hash,
+        first_hash
+    );
+}
+
+// Test that the upgrader role can approve code hashes
+#[test]
+fn test_upgrader_role_approves_code_hash() {
+    let mut contract = setup_contract();
+    contract.grant_role(accounts(2), Role::Upgrader);
+    set_caller(accounts(2));
+    contract.approve_code_hash(upgrade_code_hash(), 30);
+    assert_eq!(
+        contract
+            .get_code_upgrade()
+            .pending_upgrade
+            .unwrap()
+            .approved_by,
+        accounts(2)
+    );
+}
+
+// -------- Administrative events --------
+
+use near_sdk::test_utils::get_logs;
+use shade_contract_events::{ContractEvent, parse_logs};
+
+// Parse the logs of the current context with shade-contract-events, panicking if any event does not match it
+fn logged_events() -> Vec<ContractEvent> {
+    parse_logs(get_logs()).expect("Event does not match shade-contract-events")
+}
+
+// Test that the owner settings emit events with the old and new values
+#[test]
+fn test_owner_settings_emit_events() {
+    let mut contract = setup_contract();
+    set_caller(accounts(0));
+
+    contract.update_attestation_expiration_time(U64::from(200000));
+    contract.update_mpc_contract_id(accounts(2));
+    contract.update_owner_id(accounts(3));
+
+    assert_eq!(
+        logged_events(),
+        vec![
+            ContractEvent::AttestationExpirationTimeUpdated {
+                old_attestation_expiration_time_ms: 100000,
+                new_attestation_expiration_time_ms: 200000,
+            },
+            ContractEvent::MpcContractUpdated {
+                old_mpc_contract_id: accounts(1),
+                new_mpc_contract_id: accounts(2),
+            },
+            ContractEvent::OwnerUpdated {
+                old_owner_id: accounts(0),
+                new_owner_id: accounts(3),
+            },
+        ]
+    );
+
+    // set_caller starts a new context without the earlier logs
+    set_caller(accounts(3));
+    contract.set_timelock_delay(U64::from(5000));
+    assert_eq!(
+        logged_events(),
+        vec![ContractEvent::TimelockDelayUpdated {
+            old_timelock_delay_ms: 0,
+            new_timelock_delay_ms: 5000,
+        }]
+    );
+}
+
+// Test that the measurements registry emits an event for each change and none for a repeated approval
+#[test]
+fn test_measurements_changes_emit_events() {
+    let mut contract = setup_contract();
+    set_caller(accounts(0));
+
+    let measurements_id = contract
+        .approve_measurements(non_default_measurements(), Some("v2".to_string()), None)
+        .unwrap();
+    contract.approve_measurements(non_default_measurements(), None, None);
+    contract.update_measurements_metadata(measurements_id, None, Some("commit abc".to_string()));
+    contract.set_measurements_agent_quota(measurements_id, Some(3));
+    contract.set_ppid_group("group".to_string(), vec![Ppid::default()]);
+    contract.set_measurements_ppid_group(measurements_id, Some("group".to_string()));
+    contract.set_measurements_ppid_group(measurements_id, None);
+    contract.remove_ppid_group("group".to_string());
+    contract.remove_measurements(non_default_measurements());
+
+    assert_eq!(
+        logged_events(),
+        vec![
+            ContractEvent::MeasurementsApproved {
+                measurements_id,
+                measurements: non_default_measurements(),
+                label: "v2".to_string(),
+                source: String::new(),
+                approved_by: accounts(0),
+            },
+            ContractEvent::MeasurementsMetadataUpdated {
+                measurements_id,
+                label: "v2".to_string(),
+                source: "commit abc".to_string(),
+            },
+            ContractEvent::MeasurementsAgentQuotaSet {
+                measurements_id,
+                max_agents: Some(3),
+            },
+            ContractEvent::PpidGroupSet {
+                name: "group".to_string(),
+                ppids: vec![Ppid::default()],
+            },
+            ContractEvent::MeasurementsPpidGroupSet {
+                measurements_id,
+                ppid_group: Some("group".to_string()),
+            },
+            ContractEvent::MeasurementsPpidGroupSet {
+                measurements_id,
+                ppid_group: None,
+            },
+            ContractEvent::PpidGroupRemoved {
+                name: "group".to_string(),
+            },
+            ContractEvent::MeasurementsRemoved {
+                measurements_id,
+                measurements: non_default_measurements(),
+            },
+        ]
+    );
+}
+
+// Test that approving PPIDs only reports the ones that were not approved already
+#[test]
+fn test_ppid_changes_emit_events() {
+    let mut contract = setup_contract();
+    set_caller(accounts(0));
+
+    contract.approve_ppids(
+        vec![Ppid::default(), non_default_ppid()],
+        Some("operator".to_string()),
+        None,
+        Some(U64::from(5000)),
+    );
+    contract.approve_ppids(vec![Ppid::default()], None, None, None);
+    contract.update_ppid_metadata(
+        non_default_ppid(),
+        "operator".to_string(),
+        "eu".to_string(),
+        None,
+    );
+    contract.set_ppid_measurements_scope(non_default_ppid(), Some(vec![0]));
+
+    assert_eq!(
+        logged_events(),
+        vec![
+            ContractEvent::PpidsApproved {
+                ppids: vec![non_default_ppid()],
+                operator: "operator".to_string(),
+                region: String::new(),
+                expires_at_ms: Some(5000),
+            },
+            ContractEvent::PpidMetadataUpdated {
+                ppid: non_default_ppid(),
+                operator: "operator".to_string(),
+                region: "eu".to_string(),
+                expires_at_ms: None,
+            },
+            ContractEvent::PpidMeasurementsScopeSet {
+                ppid: non_default_ppid(),
+                measurements_ids: Some(vec![0]),
+            },
+        ]
+    );
+}
+
+// Test that the local whitelist only emits when it changes
+#[test]
+fn test_whitelist_changes_emit_events() {
+    let mut contract = setup_contract();
+    set_caller(accounts(0));
+
+    contract.whitelist_agent_for_local(accounts(2));
+    contract.whitelist_agent_for_local(accounts(2));
+    contract.remove_agent_from_whitelist_for_local(accounts(2));
+
+    assert_eq!(
+        logged_events(),
+        vec![
+            ContractEvent::AgentWhitelistedForLocal {
+                account_id: accounts(2),
+            },
+            ContractEvent::AgentRemovedFromWhitelistForLocal {
+                account_id: accounts(2),
+            },
+        ]
+    );
+}
+
+// Test that enabling and disabling multisig emit events alongside the proposal events
+#[test]
+fn test_multisig_changes_emit_events() {
+    let mut contract = setup_multisig_contract();
+    assert_eq!(
+        logged_events(),
+        vec![ContractEvent::MultisigUpdated {
+            owners: vec![accounts(2), accounts(3), accounts(4)],
+            threshold: 2,
+            proposal_lifetime_ms: 10000,
+        }]
+    );
+
+    set_caller(accounts(2));
+    let proposal_id = contract.propose(OwnerAction::DisableMultisig);
+    set_caller(accounts(3));
+    contract.confirm_proposal(proposal_id);
+
+    assert_eq!(
+        logged_events().last(),
+        Some(&ContractEvent::MultisigDisabled {
+            account_id: accounts(2),
+        })
+    );
+}
+
+// -------- Allowlist history --------
+
+// Helper to call as an account at a given timestamp
+fn set_caller_at(account_id: AccountId, timestamp_ms: u64) {
+    let context =
+        get_context_with_deposit_and_timestamp(account_id, false, None, Some(timestamp_ms));
+    testing_env!(context.build());
+}
+
+// Test that approvals and removals of measurements and PPIDs are appended to the history
+#[test]
+fn test_allowlist_history_records_changes() {
+    let mut contract = setup_contract();
+    set_caller_at(accounts(0), 1000);
+    let measurements_id = contract
+        .approve_measurements(non_default_measurements(), None, None)
+        .unwrap();
+    // Approving again changes nothing and is not recorded
+    contract.approve_measurements(non_default_measurements(), None, None);
+    contract.grant_role(accounts(2), Role::PpidAdmin);
+    set_caller_at(accounts(2), 2000);
+    contract.approve_ppids(vec![non_default_ppid()], None, None, None);
+    contract.remove_ppids(
+        vec![non_default_ppid()],
+        Some(PpidRevocationReason::Compromised),
+    );
+    set_caller_at(accounts(0), 3000);
+    contract.remove_measurements(non_default_measurements());
+
+    let history = contract.get_allowlist_history(&None, &None);
+    assert_eq!(history.len(), 6);
+    assert!(
+        history
+            .iter()
+            .enumerate()
+            .all(|(i, entry)| entry.index == i as u32)
+    );
+    // The first two entries come from setup_contract
+    assert!(matches!(
+        history[0].change,
+        AllowlistChange::MeasurementsApproved {
+            measurements_id: 0,
+            ..
+        }
+    ));
+    assert!(matches!(
+        history[1].change,
+        AllowlistChange::PpidApproved { .. }
+    ));
+    assert!(matches!(
+        &history[2].change,
+        AllowlistChange::MeasurementsApproved { measurements_id: id, measurements }
+            if *id == measurements_id && *measurements == non_default_measurements()
+    ));
+    assert_eq!(history[2].changed_by, accounts(0));
+    assert_eq!(history[2].changed_at_ms, U64::from(1000));
+    assert!(matches!(
+        &history[3].change,
+        AllowlistChange::PpidApproved { ppid, expires_at_ms: None } if *ppid == non_default_ppid()
+    ));
+    assert_eq!(history[3].changed_by, accounts(2));
+    assert!(matches!(
+        &history[4].change,
+        AllowlistChange::PpidRevoked {
+            reason: Some(PpidRevocationReason::Compromised),
+            ..
+        }
+    ));
+    assert!(matches!(
+        &history[5].change,
+        AllowlistChange::MeasurementsRemoved { measurements_id: id, .. } if *id == measurements_id
+    ));
+    assert_eq!(history[5].changed_at_ms, U64::from(3000));
+
+    // Pagination and the per-key histories
+    let page = contract.get_allowlist_history(&Some(2), &Some(2));
+    assert_eq!(
+        page.iter().map(|entry| entry.index).collect::<Vec<_>>(),
+        vec![2, 3]
+    );
+    let measurements_history = contract.get_measurements_history(non_default_measurements());
+    assert_eq!(
+        measurements_history
+            .iter()
+            .map(|entry| entry.index)
+            .collect::<Vec<_>>(),
+        vec![2, 5]
+    );
+    let ppid_history = contract.get_ppid_history(non_default_ppid());
+    assert_eq!(
+        ppid_history
+            .iter()
+            .map(|entry| entry.index)
+            .collect::<Vec<_>>(),
+        vec![3, 4]
+    );
+    assert!(
+        contract
+            .get_ppid_history(HexBytes::from([9; 16]))
+            .is_empty()
+    );
+}
+
+// Test whether measurements were approved at points in time across removal and re-approval
+#[test]
+fn test_was_measurements_approved_at() {
+    let mut contract = setup_contract();
+    set_caller_at(accounts(0), 1000);
+    contract.approve_measurements(non_default_measurements(), None, None);
+    set_caller_at(accounts(0), 3000);
+    contract.remove_measurements(non_default_measurements());
+    set_caller_at(accounts(0), 5000);
+    contract.approve_measurements(non_default_measurements(), None, None);
+
+    let approved_at = |timestamp_ms: u64| {
+        contract.was_measurements_approved_at(non_default_measurements(), U64::from(timestamp_ms))
+    };
+    assert!(!approved_at(999));
+    assert!(approved_at(1000));
+    assert!(approved_at(2999));
+    assert!(!approved_at(3000));
+    assert!(!approved_at(4999));
+    assert!(approved_at(5000));
+    assert!(approved_at(100000));
+}
+
+// Test that measurements count as unapproved from their sunset time, before and after the sunset is executed
+#[test]
+fn test_was_measurements_approved_at_with_sunset() {
+    let mut contract = setup_contract();
+    set_caller_at(accounts(0), 1000);
+    let measurements_id = contract
+        .approve_measurements(non_default_measurements(), None, None)
+        .unwrap();
+    contract.schedule_measurements_sunset(measurements_id, U64::from(3000));
+
+    set_caller_at(accounts(2), 4000);
+    assert!(contract.was_measurements_approved_at(non_default_measurements(), U64::from(2999)));
+    assert!(!contract.was_measurements_approved_at(non_default_measurements(), U64::from(3000)));
+
+    contract.execute_measurements_sunset(measurements_id);
+    let history = contract.get_measurements_history(non_default_measurements());
+    assert_eq!(history.len(), 2);
+    assert!(matches!(
+        history[1].change,
+        AllowlistChange::MeasurementsSunset { sunset_at_ms, .. } if sunset_at_ms == U64::from(3000)
+    ));
+    assert_eq!(history[1].changed_by, accounts(2));
+    assert_eq!(history[1].changed_at_ms, U64::from(4000));
+    assert!(contract.was_measurements_approved_at(non_default_measurements(), U64::from(2999)));
+    assert!(!contract.was_measurements_approved_at(non_default_measurements(), U64::from(3000)));
+}
+
+// Test whether a PPID was approved at points in time across expiry, expiry updates and revocation
+#[test]
+fn test_was_ppid_approved_at() {
+    let mut contract = setup_contract();
+    set_caller_at(accounts(0), 1000);
+    contract.approve_ppids(vec![non_default_ppid()], None, None, Some(U64::from(3000)));
+    set_caller_at(accounts(0), 4000);
+    contract.update_ppid_metadata(
+        non_default_ppid(),
+        "operator".to_string(),
+        String::new(),
+        Some(U64::from(8000)),
+    );
+    // Metadata changes that keep the expiry are not recorded
+    contract.update_ppid_metadata(
+        non_default_ppid(),
+        "other operator".to_string(),
+        String::new(),
+        Some(U64::from(8000)),
+    );
+    set_caller_at(accounts(0), 6000);
+    contract.remove_ppids(vec![non_default_ppid()], None);
+
+    assert_eq!(contract.get_ppid_history(non_default_ppid()).len(), 3);
+    let approved_at = |timestamp_ms: u64| {
+        contract.was_ppid_approved_at(non_default_ppid(), U64::from(timestamp_ms))
+    };
+    assert!(!approved_at(999));
+    assert!(approved_at(1000));
+    assert!(approved_at(2999));
+    // Expired until the expiry was extended
+    assert!(!approved_at(3000));
+    assert!(!approved_at(3999));
+    assert!(approved_at(4000));
+    assert!(approved_at(5999));
+    assert!(!approved_at(6000));
+}
+
+// -------- Pruning agents --------
+
+// Test that prune_agents removes only invalid agents and refunds their storage deposits
+#[test]
+fn test_prune_agents_removes_invalid_agents() {
+    let mut contract = setup_contract();
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+    register_local_agent_at(&mut contract, &accounts(3), 1000);
+    register_local_agent_at(&mut contract, &accounts(4), 60000);
+    assert_eq!(
+        contract.storage_balance_of(accounts(2)).unwrap().total,
+        DEPOSIT_005_NEAR
+    );
+
+    // Agents registered at 1000 expire at 101000, the last one stays valid
+    set_caller_at(accounts(5), 101001);
+    assert_eq!(contract.prune_agents(10), 2);
+
+    let agents = contract.get_agents(&None, &None);
+    assert_eq!(agents.len(), 1);
+    assert_eq!(agents[0].account_id, accounts(4));
+    assert_eq!(contract.get_measurements(0).unwrap().active_agents, 1);
+
+    let events = logged_events();
+    for account_id in [accounts(2), accounts(3)] {
+        assert!(events.contains(&ContractEvent::AgentRemoved {
+            account_id: account_id.clone(),
+            reasons: vec![shade_contract_events::AgentRemovalReason::ExpiredAttestation],
+        }));
+        assert!(events.contains(&ContractEvent::StorageDepositRefunded {
+            account_id,
+            amount: DEPOSIT_005_NEAR.as_yoctonear(),
+        }));
+    }
+    assert_eq!(
+        events.last(),
+        Some(&ContractEvent::AgentsPruned {
+            account_id: accounts(5),
+            removed: 2,
+            reward: 0,
+        })
+    );
+}
+
+// Test that the prune reward is taken from the storage cost freed by the removed agents
+// and the rest of their deposits is refunded
+#[test]
+fn test_prune_agents_pays_reward() {
+    let mut contract = setup_contract();
+    contract.set_prune_reward(500);
+    assert_eq!(contract.get_contract_info().prune_reward_bps, 500);
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+
+    set_caller_at(accounts(5), 101001);
+    assert_eq!(contract.prune_agents(10), 1);
+
+    let reward = contract.agent_storage_cost().as_yoctonear() / 20;
+    let events = logged_events();
+    assert!(events.contains(&ContractEvent::StorageDepositRefunded {
+        account_id: accounts(2),
+        amount: DEPOSIT_005_NEAR.as_yoctonear() - reward,
+    }));
+    assert_eq!(
+        events.last(),
+        Some(&ContractEvent::AgentsPruned {
+            account_id: accounts(5),
+            removed: 1,
+            reward,
+        })
+    );
+}
+
+// Test that each call checks at most limit agents and continues where the previous one stopped
+#[test]
+fn test_prune_agents_limit_and_cursor() {
+    let mut contract = setup_contract();
+    register_local_agent_at(&mut contract, &accounts(2), 60000);
+    register_local_agent_at(&mut contract, &accounts(3), 1000);
+    register_local_agent_at(&mut contract, &accounts(4), 1000);
+
+    set_caller_at(accounts(5), 101001);
+    // The first agent is still valid
+    assert_eq!(contract.prune_agents(1), 0);
+    assert_eq!(contract.get_agents(&None, &None).len(), 3);
+    // The last agent takes the place of the removed one and is checked by the next call
+    assert_eq!(contract.prune_agents(1), 1);
+    assert_eq!(contract.prune_agents(1), 1);
+    // Only the remaining agent is checked, however high the limit
+    assert_eq!(contract.prune_agents(10), 0);
+
+    let agents = contract.get_agents(&None, &None);
+    assert_eq!(agents.len(), 1);
+    assert_eq!(agents[0].account_id, accounts(2));
+}
+
+// Test that an agent removed by the owner gets its storage deposit back
+#[test]
+fn test_remove_agent_refunds_deposit() {
+    let mut contract = setup_contract();
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+
+    set_caller(accounts(0));
+    contract.remove_agent(accounts(2));
+
+    assert_eq!(
+        logged_events().last(),
+        Some(&ContractEvent::StorageDepositRefunded {
+            account_id: accounts(2),
+            amount: DEPOSIT_005_NEAR.as_yoctonear(),
+        })
+    );
+    assert!(contract.storage_balance_of(accounts(2)).is_none());
+}
+
+// Test that the prune reward is capped
+#[test]
+#[should_panic(expected = "Prune reward must be at most 1000 basis points")]
+fn test_set_prune_reward_too_high() {
+    let mut contract = setup_contract();
+    contract.set_prune_reward(1001);
+}
+
+// Test that prune_agents needs a limit
+#[test]
+#[should_panic(expected = "Limit must be greater than zero")]
+fn test_prune_agents_zero_limit() {
+    let mut contract = setup_contract();
+    contract.prune_agents(0);
+}
+
+// -------- Storage management --------
+
+// Test that the storage cost is measured and covers an agent with the longest account ID,
+// and that the rate counters of every budget are measured separately
+#[test]
+fn test_storage_balance_bounds_cover_longest_agent() {
+    let mut contract = setup_contract();
+    let bounds = contract.storage_balance_bounds();
+    assert!(contract.agent_storage_usage > 0);
+    assert_eq!(bounds.min, contract.agent_storage_cost());
+    assert_eq!(bounds.max, None);
+
+    let agent: AccountId = "a".repeat(64).parse().unwrap();
+    contract.whitelist_agent_for_local(agent.clone());
+    contract.whitelisted_agents_for_local.flush();
+
+    // testing_env resets the storage usage, so measure from the registration's own context
+    testing_env!(get_context_with_deposit(agent.clone(), false, Some(DEPOSIT_005_NEAR)).build());
+    let initial_storage_usage = env::storage_usage();
+    contract.register_agent(create_mock_dstack_attestation());
+    contract.agents.flush();
+    contract.storage_balances.flush();
+    assert_eq!(
+        env::storage_usage() - initial_storage_usage,
+        contract.agent_storage_usage
+    );
+
+    let initial_storage_usage = env::storage_usage();
+    contract
+        .agent_rate_counters
+        .insert(agent, RateCounters::largest());
+    contract.agent_rate_counters.flush();
+    assert!(contract.rate_counters_storage_usage > 0);
+    assert_eq!(
+        env::storage_usage() - initial_storage_usage,
+        contract.rate_counters_storage_usage
+    );
+}
+
+// Test that an agent can deposit for storage first and then register without a deposit
+#[test]
+fn test_storage_deposit_then_register() {
+    let mut contract = setup_contract();
+    let agent = accounts(2);
+    contract.whitelist_agent_for_local(agent.clone());
+
+    testing_env!(get_context_with_deposit(agent.clone(), false, Some(DEPOSIT_005_NEAR)).build());
+    let balance = contract.storage_deposit(None, None);
+    assert_eq!(balance.total, DEPOSIT_005_NEAR);
+    assert_eq!(
+        balance.available,
+        DEPOSIT_005_NEAR.saturating_sub(contract.agent_storage_cost())
+    );
+    assert_eq!(
+        logged_events(),
+        vec![ContractEvent::StorageDeposited {
+            account_id: agent.clone(),
+            amount: DEPOSIT_005_NEAR.as_yoctonear(),
+        }]
+    );
+
+    testing_env!(get_context_with_deposit(agent.clone(), false, Some(DEPOSIT_ZERO)).build());
+    assert!(contract.register_agent(create_mock_dstack_attestation()));
+    assert_eq!(
+        contract.storage_balance_of(agent).unwrap().total,
+        DEPOSIT_005_NEAR
+    );
+}
+
+// Test that storage can be deposited for another account and registration_only keeps only the storage cost
+#[test]
+fn test_storage_deposit_registration_only() {
+    let mut contract = setup_contract();
+    let storage_cost = contract.agent_storage_cost();
+
+    testing_env!(get_context_with_deposit(accounts(3), false, Some(DEPOSIT_005_NEAR)).build());
+    let balance = contract.storage_deposit(Some(accounts(2)), Some(true));
+    assert_eq!(balance.total, storage_cost);
+    assert_eq!(balance.available, NearToken::from_yoctonear(0));
+    assert!(contract.storage_balance_of(accounts(3)).is_none());
+
+    // An account that is already registered gets the whole deposit back
+    let balance = contract.storage_deposit(Some(accounts(2)), Some(true));
+    assert_eq!(balance.total, storage_cost);
+}
+
+// Test that a new account has to deposit at least the storage cost
+#[test]
+#[should_panic(expected = "Attached deposit must be at least the storage cost")]
+fn test_storage_deposit_below_storage_cost() {
+    let mut contract = setup_contract();
+    testing_env!(get_context_with_deposit(accounts(2), false, Some(ONE_YOCTO)).build());
+    contract.storage_deposit(None, None);
+}
+
+// Test that an agent can withdraw its balance above the storage cost
+#[test]
+fn test_storage_withdraw() {
+    let mut contract = setup_contract();
+    let agent = accounts(2);
+    register_local_agent_at(&mut contract, &agent, 1000);
+    let storage_cost = contract.agent_storage_cost();
+
+    testing_env!(get_context_with_deposit(agent.clone(), false, Some(ONE_YOCTO)).build());
+    let balance = contract.storage_withdraw(None);
+    assert_eq!(balance.total, storage_cost);
+    assert_eq!(balance.available, NearToken::from_yoctonear(0));
+    assert_eq!(
+        logged_events(),
+        vec![ContractEvent::StorageWithdrawn {
+            account_id: agent,
+            amount: DEPOSIT_005_NEAR.saturating_sub(storage_cost).as_yoctonear(),
+        }]
+    );
+}
+
+// Test that the storage cost cannot be withdrawn
+#[test]
+#[should_panic(expected = "Amount is more than the available storage balance")]
+fn test_storage_withdraw_more_than_available() {
+    let mut contract = setup_contract();
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+
+    testing_env!(get_context_with_deposit(accounts(2), false, Some(ONE_YOCTO)).build());
+    contract.storage_withdraw(Some(DEPOSIT_005_NEAR));
+}
+
+// Test that storage_withdraw requires one yoctoNEAR
+#[test]
+#[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+fn test_storage_withdraw_requires_one_yocto() {
+    let mut contract = setup_contract();
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+
+    set_caller(accounts(2));
+    contract.storage_withdraw(None);
+}
+
+// Test that a registered agent has to force storage_unregister, which removes it and refunds its balance
+#[test]
+fn test_storage_unregister_agent() {
+    let mut contract = setup_contract();
+    let agent = accounts(2);
+    register_local_agent_at(&mut contract, &agent, 1000);
+
+    testing_env!(get_context_with_deposit(agent.clone(), false, Some(ONE_YOCTO)).build());
+    assert!(contract.storage_unregister(Some(true)));
+    assert!(contract.get_agent(agent.clone()).is_none());
+    assert!(contract.storage_balance_of(agent.clone()).is_none());
+    assert_eq!(contract.get_measurements(0).unwrap().active_agents, 0);
+    assert_eq!(
+        logged_events(),
+        vec![
+            ContractEvent::AgentRemoved {
+                account_id: agent.clone(),
+                reasons: vec![shade_contract_events::AgentRemovalReason::StorageUnregistered],
+            },
+            ContractEvent::StorageDepositRefunded {
+                account_id: agent.clone(),
+                amount: DEPOSIT_005_NEAR.as_yoctonear(),
+            },
+        ]
+    );
+
+    // Nothing left to unregister
+    assert!(!contract.storage_unregister(None));
+}
+
+// Test that storage_unregister without force keeps a registered agent
+#[test]
+#[should_panic(
+    expected = "Account is a registered agent, pass force to remove the agent and unregister"
+)]
+fn test_storage_unregister_agent_without_force() {
+    let mut contract = setup_contract();
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+
+    testing_env!(get_context_with_deposit(accounts(2), false, Some(ONE_YOCTO)).build());
+    contract.storage_unregister(None);
+}
+
+// -------- Signature callback --------
+
+use near_sdk::PromiseOrValue;
+
+// Call on_signature as the contract itself with the result of the MPC sign call
+fn call_on_signature(
+    result: PromiseResult,
+    payload_format: PayloadFormat,
+) -> PromiseOrValue<SignatureResponse> {
+    call_on_signature_with_deposit(result, payload_format, ONE_YOCTO, ONE_YOCTO)
+}
+
+fn call_on_signature_with_deposit(
+    result: PromiseResult,
+    payload_format: PayloadFormat,
+    deposit: NearToken,
+    sign_deposit: NearToken,
+) -> PromiseOrValue<SignatureResponse> {
+    let contract = setup_contract();
+    testing_env!(
+        get_context(accounts(0), false).build(),
+        test_vm_config(),
+        RuntimeFeesConfig::test(),
+        Default::default(),
+        vec![result],
+    );
+    contract.on_signature(
+        accounts(2),
+        "path".to_string(),
+        "payload-hash".to_string(),
+        payload_format,
+        deposit,
+        sign_deposit,
+    )
+}
+
+fn mpc_ecdsa_response() -> serde_json::Value {
+    serde_json::json!({
+        "scheme": "Secp256k1",
+        "big_r": { "affine_point": format!("02{}", "AB".repeat(32)) },
+        "s": { "scalar": "CD".repeat(32) },
+        "recovery_id": 1
+    })
+}
+
+// Test that an ECDSA signature is returned in the MPC contract's JSON and logged
+#[test]
+fn test_on_signature_ecdsa() {
+    let response = mpc_ecdsa_response();
+    let result = call_on_signature(
+        PromiseResult::Successful(serde_json::to_vec(&response).unwrap()),
+        PayloadFormat::Ecdsa,
+    );
+    let PromiseOrValue::Value(signature) = result else {
+        panic!("Expected a signature");
+    };
+    assert_eq!(serde_json::to_value(&signature).unwrap(), response);
+    assert_eq!(
+        logged_events(),
+        vec![ContractEvent::SignatureProduced {
+            account_id: accounts(2),
+            path: "path".to_string(),
+            payload_hash: "payload-hash".to_string(),
+            signature: serde_json::from_value(response).unwrap(),
+        }]
+    );
+}
+
+// Test that an EdDSA signature is returned in the MPC contract's JSON
+#[test]
+fn test_on_signature_eddsa() {
+    let response = serde_json::json!({ "scheme": "Ed25519", "signature": vec![7u8; 64] });
+    let result = call_on_signature(
+        PromiseResult::Successful(serde_json::to_vec(&response).unwrap()),
+        PayloadFormat::Eddsa,
+    );
+    let PromiseOrValue::Value(signature) = result else {
+        panic!("Expected a signature");
+    };
+    assert_eq!(
+        signature,
+        SignatureResponse::Ed25519 {
+            signature: vec![7; 64]
+        }
+    );
+    assert_eq!(serde_json::to_value(&signature).unwrap(), response);
+}
+
+// Test that a failed or timed out sign call is logged and fails the request
+#[test]
+fn test_on_signature_mpc_call_failed() {
+    let result = call_on_signature(PromiseResult::Failed, PayloadFormat::Ecdsa);
+    assert!(matches!(result, PromiseOrValue::Promise(_)));
+    assert_eq!(
+        logged_events(),
+        vec![ContractEvent::SignatureFailed {
+            account_id: accounts(2),
+            path: "path".to_string(),
+            payload_hash: "payload-hash".to_string(),
+            reason: shade_contract_events::SignatureFailureReason::MpcCallFailed,
+        }]
+    );
+}
+
+// Test that responses that are not a signature for the key type are rejected
+#[test]
+fn test_on_signature_invalid_response() {
+    let mut short_big_r = mpc_ecdsa_response();
+    short_big_r["big_r"]["affine_point"] = serde_json::json!("02AB");
+    let responses = [
+        (b"not json".to_vec(), PayloadFormat::Ecdsa),
+        (
+            serde_json::to_vec(&mpc_ecdsa_response()).unwrap(),
+            PayloadFormat::Eddsa,
+        ),
+        (
+            serde_json::to_vec(&short_big_r).unwrap(),
+            PayloadFormat::Ecdsa,
+        ),
+        (
+            serde_json::to_vec(&serde_json::json!({ "scheme": "Ed25519", "signature": [1, 2] }))
+                .unwrap(),
+            PayloadFormat::Eddsa,
+        ),
+    ];
+    for (response, payload_format) in responses {
+        let result = call_on_signature(PromiseResult::Successful(response), payload_format);
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+        assert!(matches!(
+            logged_events().as_slice(),
+            [ContractEvent::SignatureFailed {
+                reason: shade_contract_events::SignatureFailureReason::InvalidResponse(_),
+                ..
+            }]
+        ));
+    }
+}
+
+// Test that payloads are forwarded as passed and events identify them by the SHA-256 of the string
+#[test]
+fn test_request_signature_payload_hash() {
+    let mut contract = setup_contract();
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+
+    testing_env!(get_context_with_deposit(accounts(2), false, Some(ONE_YOCTO)).build());
+    contract
+        .request_signature(
+            "path".to_string(),
+            "test_payload".to_string(),
+            SignatureScheme::Secp256k1,
+        )
+        .detach();
+    assert_eq!(
+        sign_call_args(&contract)["request"]["payload_v2"]["Ecdsa"],
+        "test_payload"
+    );
+    let callback_args: serde_json::Value = near_sdk::test_utils::get_created_receipts()
+        .into_iter()
+        .flat_map(|receipt| receipt.actions)
+        .find_map(|action| match action {
+            MockAction::FunctionCallWeight {
+                method_name, args, ..
+            } if method_name == b"on_signature" => Some(serde_json::from_slice(&args).unwrap()),
+            _ => None,
+        })
+        .expect("Callback not found");
+    assert_eq!(
+        callback_args["payload_hash"],
+        hex::encode(env::sha256(b"test_payload"))
+    );
+}
+
+// -------- Sign config --------
+
+use near_sdk::mock::MockAction;
+
+// Total transferred to account_id by the receipts created so far
+fn transferred_to(account_id: &AccountId) -> NearToken {
+    near_sdk::test_utils::get_created_receipts()
+        .into_iter()
+        .filter(|receipt| &receipt.receiver_id == account_id)
+        .flat_map(|receipt| receipt.actions)
+        .filter_map(|action| match action {
+            MockAction::Transfer { deposit, .. } => Some(deposit),
+            _ => None,
+        })
+        .fold(DEPOSIT_ZERO, NearToken::saturating_add)
+}
+
+// Test that the owner can set the sign gas and deposit and the change is logged
+#[test]
+fn test_set_sign_config() {
+    let mut contract = setup_contract();
+    let info = contract.get_contract_info();
+    assert_eq!(info.sign_gas_tgas, 15);
+    assert_eq!(info.sign_deposit, ONE_YOCTO);
+
+    set_caller(accounts(0));
+    contract.set_sign_config(30, DEPOSIT_003_NEAR);
+    let info = contract.get_contract_info();
+    assert_eq!(info.sign_gas_tgas, 30);
+    assert_eq!(info.sign_deposit, DEPOSIT_003_NEAR);
+    assert_eq!(
+        logged_events(),
+        vec![ContractEvent::SignConfigUpdated {
+            old_sign_gas_tgas: 15,
+            new_sign_gas_tgas: 30,
+            old_sign_deposit: 1,
+            new_sign_deposit: DEPOSIT_003_NEAR.as_yoctonear(),
+        }]
+    );
+}
+
+// Test that the sign config waits for the timelock, agents have to attach the new deposit
+#[test]
+fn test_set_sign_config_is_timelocked() {
+    let mut contract = setup_timelock_contract();
+    contract.set_sign_config(30, DEPOSIT_003_NEAR);
+    assert_eq!(contract.get_contract_info().sign_gas_tgas, 15);
+
+    let context = get_context_with_deposit_and_timestamp(accounts(5), false, None, Some(6000));
+    testing_env!(context.build());
+    contract.execute_queued_action(U64::from(0));
+    assert_eq!(contract.get_contract_info().sign_gas_tgas, 30);
+}
+
+// Test that only the owner can set the sign config
+#[test]
+#[should_panic(expected = "Caller is not the owner")]
+fn test_set_sign_config_not_owner() {
+    let mut contract = setup_contract();
+    set_caller(accounts(2));
+    contract.set_sign_config(30, DEPOSIT_003_NEAR);
+}
+
+// Test that the sign gas has to leave room for the rest of the request
+#[test]
+fn test_set_sign_config_invalid_gas() {
+    for sign_gas_tgas in [0, 251] {
+        let mut contract = setup_contract();
+        set_caller(accounts(0));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            contract.set_sign_config(sign_gas_tgas, ONE_YOCTO);
+        }));
+        assert!(result.is_err());
+    }
+}
+
+// Test that the sign call forwards the configured gas and deposit to the MPC contract
+#[test]
+fn test_request_signature_forwards_sign_config() {
+    let mut contract = setup_contract();
+    let agent = accounts(2);
+    register_local_agent_at(&mut contract, &agent, 1000);
+    set_caller(accounts(0));
+    contract.set_sign_config(30, DEPOSIT_003_NEAR);
+
+    testing_env!(get_context_with_deposit(agent, false, Some(DEPOSIT_005_NEAR)).build());
+    contract
+        .request_signature(
+            "path".to_string(),
+            MOCK_PAYLOAD.to_string(),
+            SignatureScheme::Secp256k1,
+        )
+        .detach();
+
+    let receipts = near_sdk::test_utils::get_created_receipts();
+    let sign_call = receipts
+        .iter()
+        .find(|receipt| receipt.receiver_id == contract.mpc_contract_id)
+        .expect("Sign call not found");
+    assert!(matches!(
+        sign_call.actions.as_slice(),
+        [MockAction::FunctionCallWeight { attached_deposit, prepaid_gas, .. }]
+            if *attached_deposit == DEPOSIT_003_NEAR && *prepaid_gas == Gas::from_tgas(30)
+    ));
+}
+
+// Test that request_signature requires the sign deposit
+#[test]
+#[should_panic(expected = "Attached deposit must cover the sign deposit")]
+fn test_request_signature_deposit_too_small() {
+    let mut contract = setup_contract();
+    let agent = accounts(2);
+    register_local_agent_at(&mut contract, &agent, 1000);
+
+    testing_env!(get_context_with_deposit(agent, false, Some(DEPOSIT_ZERO)).build());
+    let _ = contract.request_signature(
+        "path".to_string(),
+        MOCK_PAYLOAD.to_string(),
+        SignatureScheme::Secp256k1,
+    );
+}
+
+// Test that an invalid agent gets its attached deposit back along with the failure
+#[test]
+fn test_request_signature_invalid_agent_refunds_deposit() {
+    let mut contract = setup_contract();
+    let agent = accounts(2);
+    register_local_agent_at(&mut contract, &agent, 1000);
+    set_caller(accounts(0));
+    contract.remove_measurements(create_mock_full_measurements_hex());
+
+    testing_env!(get_context_with_deposit(agent.clone(), false, Some(DEPOSIT_003_NEAR)).build());
+    contract
+        .request_signature(
+            "path".to_string(),
+            MOCK_PAYLOAD.to_string(),
+            SignatureScheme::Secp256k1,
+        )
+        .detach();
+    assert!(contract.get_agent(agent.clone()).is_none());
+    // The storage balance is refunded too
+    assert_eq!(
+        transferred_to(&agent),
+        DEPOSIT_003_NEAR.saturating_add(DEPOSIT_005_NEAR)
+    );
+}
+
+// Test that the deposit above the sign deposit is refunded once the signature is produced
+#[test]
+fn test_on_signature_refunds_unused_deposit() {
+    let result = call_on_signature_with_deposit(
+        PromiseResult::Successful(serde_json::to_vec(&mpc_ecdsa_response()).unwrap()),
+        PayloadFormat::Ecdsa,
+        DEPOSIT_005_NEAR,
+        DEPOSIT_003_NEAR,
+    );
+    assert!(matches!(result, PromiseOrValue::Value(_)));
+    assert_eq!(
+        transferred_to(&accounts(2)),
+        DEPOSIT_005_NEAR.saturating_sub(DEPOSIT_003_NEAR)
+    );
+}
+
+// Test that the whole deposit is refunded when the sign call failed, the MPC contract did not keep any of it
+#[test]
+fn test_on_signature_refunds_deposit_on_failed_call() {
+    let result = call_on_signature_with_deposit(
+        PromiseResult::Failed,
+        PayloadFormat::Ecdsa,
+        DEPOSIT_005_NEAR,
+        DEPOSIT_003_NEAR,
+    );
+    assert!(matches!(result, PromiseOrValue::Promise(_)));
+    assert_eq!(transferred_to(&accounts(2)), DEPOSIT_005_NEAR);
+}
+
+// Test that nothing is refunded when the agent attached exactly the sign deposit
+#[test]
+fn test_on_signature_exact_deposit_no_refund() {
+    let _ = call_on_signature(
+        PromiseResult::Successful(serde_json::to_vec(&mpc_ecdsa_response()).unwrap()),
+        PayloadFormat::Ecdsa,
+    );
+    assert_eq!(transferred_to(&accounts(2)), DEPOSIT_ZERO);
+}
+
+// -------- Signature domains --------
+
+use super::chainsig::parse_mpc_domains;
+
+// Arguments of the sign call made to the MPC contract
+fn sign_call_args(contract: &Contract) -> serde_json::Value {
+    near_sdk::test_utils::get_created_receipts()
+        .into_iter()
+        .filter(|receipt| receipt.receiver_id == contract.mpc_contract_id)
+        .flat_map(|receipt| receipt.actions)
+        .find_map(|action| match action {
+            MockAction::FunctionCallWeight {
+                method_name, args, ..
+            } if method_name == b"sign" => Some(serde_json::from_slice(&args).unwrap()),
+            _ => None,
+        })
+        .expect("Sign call not found")
+}
+
+fn request_signature_with(contract: &mut Contract, scheme: SignatureScheme) {
+    testing_env!(get_context_with_deposit(accounts(2), false, Some(ONE_YOCTO)).build());
+    contract
+        .request_signature("path".to_string(), MOCK_PAYLOAD.to_string(), scheme)
+        .detach();
+}
+
+fn mpc_state(domains: serde_json::Value) -> Vec<u8> {
+    serde_json::to_vec(&serde_json::json!({
+        "Running": {
+            "domains": { "domains": domains, "next_domain_id": 9 },
+            "keyset": { "epoch_id": 1, "domains": [] }
+        }
+    }))
+    .unwrap()
+}
+
+// Call on_mpc_state as the contract itself with the result of the state call
+fn call_on_mpc_state(contract: &mut Contract, result: PromiseResult) -> Vec<SignatureDomainView> {
+    testing_env!(
+        get_context(accounts(0), false).build(),
+        test_vm_config(),
+        RuntimeFeesConfig::test(),
+        Default::default(),
+        vec![result],
+    );
+    contract.on_mpc_state(accounts(0))
+}
+
+// Test that new contracts sign Secp256k1 with domain 0 and Ed25519 with domain 1
+#[test]
+fn test_default_signature_domains() {
+    let contract = setup_contract();
+    let domains: Vec<_> = contract
+        .get_signature_domains()
+        .into_iter()
+        .map(|domain| (domain.scheme, domain.domain_id, domain.payload_format))
+        .collect();
+    assert_eq!(
+        domains,
+        vec![
+            (SignatureScheme::Secp256k1, 0, PayloadFormat::Ecdsa),
+            (SignatureScheme::Ed25519, 1, PayloadFormat::Eddsa),
+        ]
+    );
+}
+
+// Test that agents can keep passing the key types Ecdsa and Eddsa
+#[test]
+fn test_signature_scheme_accepts_key_types() {
+    for (key_type, scheme) in [
+        ("Ecdsa", SignatureScheme::Secp256k1),
+        ("Eddsa", SignatureScheme::Ed25519),
+        ("Secp256k1", SignatureScheme::Secp256k1),
+        ("V2Secp256k1", SignatureScheme::V2Secp256k1),
+    ] {
+        assert_eq!(
+            serde_json::from_value::<SignatureScheme>(serde_json::json!(key_type)).unwrap(),
+            scheme
+        );
+    }
+    assert!(serde_json::from_value::<SignatureScheme>(serde_json::json!("invalid")).is_err());
+}
+
+// Test that the sign call uses the configured domain and payload format
+#[test]
+fn test_request_signature_uses_signature_domain() {
+    let mut contract = setup_contract();
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+
+    request_signature_with(&mut contract, SignatureScheme::Ed25519);
+    let args = sign_call_args(&contract);
+    assert_eq!(args["request"]["domain_id"], 1);
+    assert_eq!(args["request"]["payload_v2"]["Eddsa"], MOCK_PAYLOAD);
+
+    set_caller(accounts(0));
+    contract.set_signature_domain(SignatureScheme::V2Secp256k1, 4, PayloadFormat::Ecdsa);
+    assert_eq!(
+        logged_events(),
+        vec![ContractEvent::SignatureDomainSet {
+            scheme: shade_contract_events::SignatureScheme::V2Secp256k1,
+            domain: Some(shade_contract_events::SignatureDomain {
+                domain_id: 4,
+                payload_format: shade_contract_events::PayloadFormat::Ecdsa,
+            }),
+        }]
+    );
+
+    request_signature_with(&mut contract, SignatureScheme::V2Secp256k1);
+    let args = sign_call_args(&contract);
+    assert_eq!(args["request"]["domain_id"], 4);
+    assert_eq!(args["request"]["payload_v2"]["Ecdsa"], MOCK_PAYLOAD);
+}
+
+// Test that a removed scheme can no longer be signed with
+#[test]
+#[should_panic(expected = "No signature domain configured for Ed25519")]
+fn test_remove_signature_domain() {
+    let mut contract = setup_contract();
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+    set_caller(accounts(0));
+    contract.remove_signature_domain(SignatureScheme::Ed25519);
+    assert_eq!(contract.get_signature_domains().len(), 1);
+
+    request_signature_with(&mut contract, SignatureScheme::Ed25519);
+}
+
+// Test that setting a domain waits for the timelock but removing one does not
+#[test]
+fn test_signature_domain_timelock() {
+    let mut contract = setup_contract();
+    set_caller(accounts(0));
+    contract.set_timelock_delay(U64(1000));
+
+    contract.set_signature_domain(SignatureScheme::V2Secp256k1, 4, PayloadFormat::Ecdsa);
+    assert_eq!(contract.get_queued_actions(&None, &None).len(), 1);
+    assert_eq!(contract.get_signature_domains().len(), 2);
+
+    contract.remove_signature_domain(SignatureScheme::Ed25519);
+    assert_eq!(contract.get_signature_domains().len(), 1);
+}
+
+// Test that only the owner can change the signature domains
+#[test]
+#[should_panic(expected = "Caller is not the owner")]
+fn test_set_signature_domain_not_owner() {
+    let mut contract = setup_contract();
+    set_caller(accounts(2));
+    contract.set_signature_domain(SignatureScheme::Secp256k1, 4, PayloadFormat::Ecdsa);
+}
+
+// Test that the domain list is read from the running or resharing MPC state
+#[test]
+fn test_parse_mpc_domains() {
+    let domains = serde_json::json!([
+        { "id": 0, "scheme": "Secp256k1" },
+        { "id": 1, "scheme": "Ed25519" },
+        { "id": 2, "scheme": "Bls12381" },
+        { "id": 3, "scheme": "V2Secp256k1" }
+    ]);
+    let expected: Vec<(u64, String)> = vec![
+        (0, "Secp256k1".to_string()),
+        (1, "Ed25519".to_string()),
+        (2, "Bls12381".to_string()),
+        (3, "V2Secp256k1".to_string()),
+    ];
+    assert_eq!(
+        parse_mpc_domains(&mpc_state(domains.clone())).unwrap(),
+        expected
+    );
+
+    let resharing = serde_json::json!({
+        "Resharing": {
+            "previous_running_state": { "domains": { "domains": domains, "next_domain_id": 4 } }
+        }
+    });
+    assert_eq!(
+        parse_mpc_domains(&serde_json::to_vec(&resharing).unwrap()).unwrap(),
+        expected
+    );
+
+    assert!(parse_mpc_domains(b"\"NotInitialized\"").is_err());
+    assert!(parse_mpc_domains(b"not json").is_err());
+}
+
+// Test that syncing follows the MPC contract's domains, keeping domains it still lists
+#[test]
+fn test_sync_signature_domains() {
+    let mut contract = setup_contract();
+    set_caller(accounts(0));
+    contract.set_signature_domain(SignatureScheme::Secp256k1, 5, PayloadFormat::Ecdsa);
+
+    let state = mpc_state(serde_json::json!([
+        { "id": 2, "scheme": "Secp256k1" },
+        { "id": 5, "scheme": "Secp256k1" },
+        { "id": 7, "scheme": "V2Secp256k1" }
+    ]));
+    let domains: Vec<_> = call_on_mpc_state(&mut contract, PromiseResult::Successful(state))
+        .into_iter()
+        .map(|domain| (domain.scheme, domain.domain_id, domain.payload_format))
+        .collect();
+    assert_eq!(
+        domains,
+        vec![
+            (SignatureScheme::Secp256k1, 5, PayloadFormat::Ecdsa),
+            (SignatureScheme::V2Secp256k1, 7, PayloadFormat::Ecdsa),
+        ]
+    );
+    // Only the changes are logged
+    assert_eq!(
+        logged_events(),
+        vec![
+            ContractEvent::SignatureDomainSet {
+                scheme: shade_contract_events::SignatureScheme::Ed25519,
+                domain: None,
+            },
+            ContractEvent::SignatureDomainSet {
+                scheme: shade_contract_events::SignatureScheme::V2Secp256k1,
+                domain: Some(shade_contract_events::SignatureDomain {
+                    domain_id: 7,
+                    payload_format: shade_contract_events::PayloadFormat::Ecdsa,
+                }),
+            },
+        ]
+    );
+}
+
+// Test that a domain the MPC contract remapped is moved, keeping the configured payload format
+#[test]
+fn test_sync_signature_domains_remapped() {
+    let mut contract = setup_contract();
+    set_caller(accounts(0));
+    contract.set_signature_domain(SignatureScheme::Secp256k1, 0, PayloadFormat::Eddsa);
+
+    let state = mpc_state(serde_json::json!([
+        { "id": 3, "scheme": "Secp256k1" },
+        { "id": 1, "scheme": "Ed25519" }
+    ]));
+    call_on_mpc_state(&mut contract, PromiseResult::Successful(state));
+    let domain = contract.signature_domains[&SignatureScheme::Secp256k1];
+    assert_eq!(domain.domain_id, 3);
+    assert_eq!(domain.payload_format, PayloadFormat::Eddsa);
+    assert_eq!(
+        contract.signature_domains[&SignatureScheme::Ed25519].domain_id,
+        1
+    );
+}
+
+// Test that a failed state call leaves the domains as they are
+#[test]
+#[should_panic(expected = "Failed to read the MPC contract state")]
+fn test_sync_signature_domains_failed_call() {
+    let mut contract = setup_contract();
+    call_on_mpc_state(&mut contract, PromiseResult::Failed);
+}
+
+// Test that only the owner can sync the signature domains
+#[test]
+#[should_panic(expected = "Caller is not the owner")]
+fn test_sync_signature_domains_not_owner() {
+    let mut contract = setup_contract();
+    set_caller(accounts(2));
+    let _ = contract.sync_signature_domains();
+}
+
+// Test that synced domains wait for the timelock but synced removals do not
+#[test]
+fn test_sync_signature_domains_timelock() {
+    let mut contract = setup_timelock_contract();
+    let state = mpc_state(serde_json::json!([
+        { "id": 0, "scheme": "Secp256k1" },
+        { "id": 7, "scheme": "V2Secp256k1" }
+    ]));
+    call_on_mpc_state(&mut contract, PromiseResult::Successful(state));
+    assert!(
+        !contract
+            .signature_domains
+            .contains_key(&SignatureScheme::Ed25519)
+    );
+    assert!(
+        !contract
+            .signature_domains
+            .contains_key(&SignatureScheme::V2Secp256k1)
+    );
+    let queued = contract.get_queued_actions(&None, &None);
+    assert_eq!(queued.len(), 1);
+    assert_eq!(queued[0].queued_by, accounts(0));
+    assert!(matches!(
+        queued[0].action,
+        OwnerAction::SetSignatureDomain {
+            scheme: SignatureScheme::V2Secp256k1,
+            domain: Some(SignatureDomain { domain_id: 7, .. }),
+        }
+    ));
+}
+
+// -------- App private keys --------
+
+use near_sdk::bs58;
+
+fn g1_point(byte: u8) -> String {
+    format!("bls12381g1:{}", bs58::encode([byte; 48]).into_string())
+}
+
+fn request_app_private_key_with(
+    contract: &mut Contract,
+    app_public_key: String,
+    deposit: NearToken,
+) {
+    testing_env!(get_context_with_deposit(accounts(2), false, Some(deposit)).build());
+    contract.request_app_private_key(app_public_key).detach();
+}
+
+// Call on_app_private_key as the contract itself with the result of the MPC CKD call
+fn call_on_app_private_key(
+    result: PromiseResult,
+    deposit: NearToken,
+) -> PromiseOrValue<CkdResponse> {
+    let contract = setup_contract();
+    testing_env!(
+        get_context(accounts(0), false).build(),
+        test_vm_config(),
+        RuntimeFeesConfig::test(),
+        Default::default(),
+        vec![result],
+    );
+    contract.on_app_private_key(accounts(2), g1_point(1), deposit, ONE_YOCTO)
+}
+
+// Test that a CKD request is forwarded to the configured domain with the sign deposit
+#[test]
+fn test_request_app_private_key() {
+    let mut contract = setup_contract();
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+    set_caller(accounts(0));
+    contract.set_ckd_domain(2);
+    assert_eq!(
+        logged_events(),
+        vec![ContractEvent::CkdDomainSet { domain_id: Some(2) }]
+    );
+    assert_eq!(contract.get_contract_info().ckd_domain_id, Some(2));
+
+    request_app_private_key_with(&mut contract, g1_point(1), ONE_YOCTO);
+    let ckd_call = near_sdk::test_utils::get_created_receipts()
+        .into_iter()
+        .filter(|receipt| receipt.receiver_id == contract.mpc_contract_id)
+        .flat_map(|receipt| receipt.actions)
+        .find_map(|action| match action {
+            MockAction::FunctionCallWeight {
+                method_name,
+                args,
+                attached_deposit,
+                ..
+            } if method_name == b"request_app_private_key" => Some((
+                serde_json::from_slice::<serde_json::Value>(&args).unwrap(),
+                attached_deposit,
+            )),
+            _ => None,
+        })
+        .expect("CKD call not found");
+    assert_eq!(
+        ckd_call.0,
+        serde_json::json!({ "request": { "app_public_key": g1_point(1), "domain_id": 2 } })
+    );
+    assert_eq!(ckd_call.1, ONE_YOCTO);
+}
+
+// Test that keys cannot be requested before a CKD domain is set
+#[test]
+#[should_panic(expected = "No CKD domain configured, call sync_signature_domains")]
+fn test_request_app_private_key_without_domain() {
+    let mut contract = setup_contract();
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+    request_app_private_key_with(&mut contract, g1_point(1), ONE_YOCTO);
+}
+
+// Test that the app public key has to be a BLS12-381 G1 point
+#[test]
+fn test_request_app_private_key_invalid_public_key() {
+    for app_public_key in [
+        format!("ed25519:{}", bs58::encode([1u8; 48]).into_string()),
+        format!("bls12381g1:{}", bs58::encode([1u8; 32]).into_string()),
+        "bls12381g1:not-base58!".to_string(),
+    ] {
+        let mut contract = setup_contract();
+        register_local_agent_at(&mut contract, &accounts(2), 1000);
+        set_caller(accounts(0));
+        contract.set_ckd_domain(2);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            request_app_private_key_with(&mut contract, app_public_key, ONE_YOCTO);
+        }));
+        assert!(result.is_err());
+    }
+}
+
+// Test that a CKD request requires the sign deposit
+#[test]
+#[should_panic(expected = "Attached deposit must cover the sign deposit")]
+fn test_request_app_private_key_deposit_too_small() {
+    let mut contract = setup_contract();
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+    set_caller(accounts(0));
+    contract.set_ckd_domain(2);
+    request_app_private_key_with(&mut contract, g1_point(1), DEPOSIT_ZERO);
+}
+
+// Test that agents whose measurements are no longer approved are removed instead of getting the key
+#[test]
+fn test_request_app_private_key_invalid_agent() {
+    let mut contract = setup_contract();
+    let agent = accounts(2);
+    register_local_agent_at(&mut contract, &agent, 1000);
+    set_caller(accounts(0));
+    contract.set_ckd_domain(2);
+    contract.remove_measurements(create_mock_full_measurements_hex());
+
+    request_app_private_key_with(&mut contract, g1_point(1), DEPOSIT_003_NEAR);
+    assert!(contract.get_agent(agent.clone()).is_none());
+    let receipts = near_sdk::test_utils::get_created_receipts();
+    assert!(
+        receipts
+            .iter()
+            .all(|receipt| receipt.receiver_id != contract.mpc_contract_id)
+    );
+    assert_eq!(
+        transferred_to(&agent),
+        DEPOSIT_003_NEAR.saturating_add(DEPOSIT_005_NEAR)
+    );
+}
+
+// Test that paused signing also stops key requests
+#[test]
+#[should_panic(expected = "Signing is paused")]
+fn test_request_app_private_key_paused() {
+    let mut contract = setup_contract();
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+    set_caller(accounts(0));
+    contract.set_ckd_domain(2);
+    contract.pause(PausableFeature::Signing);
+    request_app_private_key_with(&mut contract, g1_point(1), ONE_YOCTO);
+}
+
+// Test that the encrypted key is returned in the MPC contract's JSON and the unused deposit refunded
+#[test]
+fn test_on_app_private_key() {
+    let response = serde_json::json!({ "big_y": g1_point(2), "big_c": g1_point(3) });
+    let result = call_on_app_private_key(
+        PromiseResult::Successful(serde_json::to_vec(&response).unwrap()),
+        DEPOSIT_003_NEAR,
+    );
+    let PromiseOrValue::Value(key) = result else {
+        panic!("Expected a key");
+    };
+    assert_eq!(serde_json::to_value(&key).unwrap(), response);
+    assert_eq!(
+        logged_events(),
+        vec![ContractEvent::AppPrivateKeyProduced {
+            account_id: accounts(2),
+            app_public_key: g1_point(1),
+        }]
+    );
+    assert_eq!(
+        transferred_to(&accounts(2)),
+        DEPOSIT_003_NEAR.saturating_sub(ONE_YOCTO)
+    );
+}
+
+// Test that a failed CKD call is logged, fails the request and refunds the whole deposit
+#[test]
+fn test_on_app_private_key_mpc_call_failed() {
+    let result = call_on_app_private_key(PromiseResult::Failed, DEPOSIT_003_NEAR);
+    assert!(matches!(result, PromiseOrValue::Promise(_)));
+    assert_eq!(
+        logged_events(),
+        vec![ContractEvent::AppPrivateKeyFailed {
+            account_id: accounts(2),
+            app_public_key: g1_point(1),
+            reason: shade_contract_events::SignatureFailureReason::MpcCallFailed,
+        }]
+    );
+    assert_eq!(transferred_to(&accounts(2)), DEPOSIT_003_NEAR);
+}
+
+// Test that responses that are not two G1 points are rejected
+#[test]
+fn test_on_app_private_key_invalid_response() {
+    for response in [
+        b"not json".to_vec(),
+        serde_json::to_vec(&serde_json::json!({ "big_y": g1_point(2) })).unwrap(),
+        serde_json::to_vec(&serde_json::json!({ "big_y": g1_point(2), "big_c": "bls12381g1:1" }))
+            .unwrap(),
+    ] {
+        let result = call_on_app_private_key(PromiseResult::Successful(response), ONE_YOCTO);
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+        assert!(matches!(
+            logged_events().as_slice(),
+            [ContractEvent::AppPrivateKeyFailed {
+                reason: shade_contract_events::SignatureFailureReason::InvalidResponse(_),
+                ..
+            }]
+        ));
+    }
+}
+
+// Test that syncing picks up the MPC contract's BLS12-381 domain and keeps it while it is listed
+#[test]
+fn test_sync_ckd_domain() {
+    let mut contract = setup_contract();
+    let state = mpc_state(serde_json::json!([
+        { "id": 0, "scheme": "Secp256k1" },
+        { "id": 1, "scheme": "Ed25519" },
+        { "id": 2, "scheme": "Bls12381" },
+        { "id": 4, "scheme": "Bls12381" }
+    ]));
+    call_on_mpc_state(&mut contract, PromiseResult::Successful(state.clone()));
+    assert_eq!(contract.ckd_domain_id, Some(2));
+    assert_eq!(
+        logged_events(),
+        vec![ContractEvent::CkdDomainSet { domain_id: Some(2) }]
+    );
+
+    set_caller(accounts(0));
+    contract.set_ckd_domain(4);
+    call_on_mpc_state(&mut contract, PromiseResult::Successful(state));
+    assert_eq!(contract.ckd_domain_id, Some(4));
+    assert!(logged_events().is_empty());
+
+    let state = mpc_state(serde_json::json!([{ "id": 0, "scheme": "Secp256k1" }]));
+    call_on_mpc_state(&mut contract, PromiseResult::Successful(state));
+    assert_eq!(contract.ckd_domain_id, None);
+}
+
+// Test that setting the CKD domain waits for the timelock but removing it does not
+#[test]
+fn test_ckd_domain_timelock() {
+    let mut contract = setup_contract();
+    set_caller(accounts(0));
+    contract.set_ckd_domain(2);
+    contract.set_timelock_delay(U64(1000));
+
+    contract.set_ckd_domain(4);
+    assert_eq!(contract.ckd_domain_id, Some(2));
+    contract.remove_ckd_domain();
+    assert_eq!(contract.ckd_domain_id, None);
+}
+
+// Test that only the owner can change the CKD domain
+#[test]
+#[should_panic(expected = "Caller is not the owner")]
+fn test_set_ckd_domain_not_owner() {
+    let mut contract = setup_contract();
+    set_caller(accounts(2));
+    contract.set_ckd_domain(2);
+}
+
+// -------- EVM transaction policies --------
+
+use super::evm::{EvmTransaction, decode_evm_transaction};
+
+const EVM_DESTINATION: [u8; 20] = [0x35; 20];
+const TRANSFER_SELECTOR: [u8; 4] = [0xa9, 0x05, 0x9c, 0xbb];
+
+fn rlp_bytes(bytes: &[u8]) -> Vec<u8> {
+    match bytes {
+        [byte] if *byte < 0x80 => vec![*byte],
+        _ => [rlp_length(bytes.len(), 0x80), bytes.to_vec()].concat(),
+    }
+}
+
+fn rlp_uint(value: u128) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let first = bytes.iter().position(|byte| *byte != 0).unwrap_or(16);
+    rlp_bytes(&bytes[first..])
+}
+
+fn rlp_list(items: &[Vec<u8>]) -> Vec<u8> {
+    let payload = items.concat();
+    [rlp_length(payload.len(), 0xc0), payload].concat()
+}
+
+fn rlp_length(len: usize, offset: u8) -> Vec<u8> {
+    if len <= 55 {
+        return vec![offset + len as u8];
+    }
+    let len_bytes = len.to_be_bytes();
+    let first = len_bytes.iter().position(|byte| *byte != 0).unwrap();
+    [
+        vec![offset + 55 + (len_bytes.len() - first) as u8],
+        len_bytes[first..].to_vec(),
+    ]
+    .concat()
+}
+
+fn eip1559_transaction(chain_id: u64, to: &[u8], value: u128, data: &[u8]) -> Vec<u8> {
+    let fields = rlp_list(&[
+        rlp_uint(chain_id.into()),
+        rlp_uint(3),
+        rlp_uint(1_000_000_000),
+        rlp_uint(30_000_000_000),
+        rlp_uint(100_000),
+        rlp_bytes(to),
+        rlp_uint(value),
+        rlp_bytes(data),
+        rlp_list(&[]),
+    ]);
+    [vec![0x02], fields].concat()
+}
+
+fn mock_evm_policy() -> EvmPolicy {
+    EvmPolicy {
+        chain_ids: vec![1, 8453],
+        allowed_destinations: vec![EVM_DESTINATION.into()],
+        max_value: U128(1_000_000_000_000_000_000),
+        allowed_selectors: vec![TRANSFER_SELECTOR.into()],
+    }
+}
+
+fn request_evm_transaction_signature_with(contract: &mut Contract, transaction: &[u8]) {
+    testing_env!(get_context_with_deposit(accounts(2), false, Some(ONE_YOCTO)).build());
+    contract
+        .request_evm_transaction_signature("path".to_string(), hex::encode(transaction))
+        .detach();
+}
+
+// Test decoding the unsigned transaction from the EIP-155 example and its signing hash
+#[test]
+fn test_decode_eip155_legacy_transaction() {
+    let transaction = hex::decode(
+        "ec098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a764000080018080",
+    )
+    .unwrap();
+    assert_eq!(
+        decode_evm_transaction(&transaction).unwrap(),
+        EvmTransaction {
+            chain_id: 1,
+            to: Some(EVM_DESTINATION.into()),
+            value: 1_000_000_000_000_000_000,
+            data: Vec::new(),
+        }
+    );
+    assert_eq!(
+        hex::encode(env::keccak256(&transaction)),
+        "daf5a779ae972f972197303d7b574746c7ef83eadac0f2791ad23db92e4c8e53"
+    );
+}
+
+// Test decoding an EIP-1559 transaction with calldata long enough for the long string form
+#[test]
+fn test_decode_eip1559_transaction() {
+    let data = [TRANSFER_SELECTOR.to_vec(), vec![7; 64]].concat();
+    let transaction = eip1559_transaction(8453, &EVM_DESTINATION, 5, &data);
+    assert_eq!(
+        decode_evm_transaction(&transaction).unwrap(),
+        EvmTransaction {
+            chain_id: 8453,
+            to: Some(EVM_DESTINATION.into()),
+            value: 5,
+            data,
+        }
+    );
+
+    let creation = eip1559_transaction(1, &[], 0, &[]);
+    assert_eq!(decode_evm_transaction(&creation).unwrap().to, None);
+}
+
+// Test that anything but a canonical unsigned EIP-1559 or EIP-155 transaction is rejected
+#[test]
+fn test_decode_evm_transaction_rejects_malformed() {
+    let valid = eip1559_transaction(1, &EVM_DESTINATION, 5, &[]);
+    let legacy_field = |chain_id: Vec<u8>, r: Vec<u8>| {
+        rlp_list(&[
+            rlp_uint(0),
+            rlp_uint(1),
+            rlp_uint(21000),
+            rlp_bytes(&EVM_DESTINATION),
+            rlp_uint(5),
+            rlp_bytes(&[]),
+            chain_id,
+            r,
+            rlp_uint(0),
+        ])
+    };
+    let cases = [
+        (
+            [valid.clone(), vec![0x80]].concat(),
+            "Trailing bytes after RLP item",
+        ),
+        (valid[..valid.len() - 1].to_vec(), "Unexpected end of RLP"),
+        (
+            [vec![0x01], valid[1..].to_vec()].concat(),
+            "Unsupported transaction type",
+        ),
+        (Vec::new(), "Unsupported transaction type"),
+        (
+            legacy_field(rlp_bytes(&[0, 1]), rlp_uint(0)),
+            "Non-canonical integer chain_id",
+        ),
+        (
+            legacy_field(vec![0x81, 0x01], rlp_uint(0)),
+            "Non-canonical RLP string",
+        ),
+        (
+            legacy_field(rlp_uint(1), rlp_uint(27)),
+            "Expected empty r and s in an unsigned transaction",
+        ),
+        (
+            legacy_field(rlp_bytes(&[1; 9]), rlp_uint(0)),
+            "chain_id is too large",
+        ),
+        (
+            rlp_list(&[
+                rlp_uint(0),
+                rlp_uint(1),
+                rlp_uint(21000),
+                rlp_bytes(&EVM_DESTINATION),
+                rlp_uint(5),
+                rlp_bytes(&[]),
+            ]),
+            "Legacy transactions must include the chain ID (EIP-155)",
+        ),
+        (
+            eip1559_transaction(1, &[0x35; 19], 5, &[]),
+            "Expected to to be 20 bytes",
+        ),
+        (
+            [
+                vec![0x02],
+                rlp_list(&[rlp_list(&[rlp_list(&[rlp_list(&[rlp_list(&[])])])])]),
+            ]
+            .concat(),
+            "RLP is nested too deeply",
+        ),
+        (
+            [vec![0x02, 0xb8, 0x01], vec![0x80]].concat(),
+            "Non-canonical RLP length",
+        ),
+    ];
+    for (transaction, error) in cases {
+        assert_eq!(
+            decode_evm_transaction(&transaction).unwrap_err(),
+            error,
+            "{}",
+            hex::encode(&transaction)
+        );
+    }
+}
+
+// Test each rule of an EVM policy
+#[test]
+fn test_evm_policy_check() {
+    let policy = mock_evm_policy();
+    let check = |chain_id, to: &[u8], value, data: &[u8]| {
+        let transaction = eip1559_transaction(chain_id, to, value, data);
+        policy.check(&decode_evm_transaction(&transaction).unwrap())
+    };
+    let max_value = 1_000_000_000_000_000_000;
+
+    assert_eq!(check(1, &EVM_DESTINATION, max_value, &[]), Ok(()));
+    assert_eq!(
+        check(
+            8453,
+            &EVM_DESTINATION,
+            0,
+            &[TRANSFER_SELECTOR.to_vec(), vec![7; 64]].concat()
+        ),
+        Ok(())
+    );
+    assert_eq!(
+        check(10, &EVM_DESTINATION, 0, &[]),
+        Err("Chain ID 10 is not allowed".to_string())
+    );
+    assert_eq!(
+        check(1, &[], 0, &[]),
+        Err("Contract creation is not allowed".to_string())
+    );
+    assert_eq!(
+        check(1, &[0x36; 20], 0, &[]),
+        Err(format!("Destination {} is not allowed", "36".repeat(20)))
+    );
+    assert_eq!(
+        check(1, &EVM_DESTINATION, max_value + 1, &[]),
+        Err(format!(
+            "Value {} is more than the maximum {max_value}",
+            max_value + 1
+        ))
+    );
+    assert_eq!(
+        check(1, &EVM_DESTINATION, 0, &TRANSFER_SELECTOR[..3]),
+        Err("Data is too short for a function selector".to_string())
+    );
+    assert_eq!(
+        check(1, &EVM_DESTINATION, 0, &[0x09, 0x5e, 0xa7, 0xb3, 0]),
+        Err("Function selector 095ea7b3 is not allowed".to_string())
+    );
+}
+
+// Test that a transaction meeting the policy is signed by its keccak256 hash on the secp256k1 domain
+#[test]
+fn test_request_evm_transaction_signature() {
+    let mut contract = setup_contract();
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+    set_caller(accounts(0));
+    contract.set_evm_policy(0, mock_evm_policy());
+    assert_eq!(
+        logged_events(),
+        vec![ContractEvent::EvmPolicySet {
+            measurements_id: 0,
+            policy: Some(shade_contract_events::EvmPolicy {
+                chain_ids: vec![1, 8453],
+                allowed_destinations: vec![EVM_DESTINATION.into()],
+                max_value: 1_000_000_000_000_000_000,
+                allowed_selectors: vec![TRANSFER_SELECTOR.into()],
+            }),
+        }]
+    );
+    assert_eq!(contract.get_evm_policy(0), Some(mock_evm_policy()));
+
+    let transaction = eip1559_transaction(1, &EVM_DESTINATION, 5, &TRANSFER_SELECTOR);
+    request_evm_transaction_signature_with(&mut contract, &transaction);
+    let args = sign_call_args(&contract);
+    assert_eq!(args["request"]["domain_id"], 0);
+    assert_eq!(
+        args["request"]["payload_v2"]["Ecdsa"],
+        hex::encode(env::keccak256(&transaction))
+    );
+}
+
+// Test that agents cannot sign EVM transactions before their measurements have a policy
+#[test]
+#[should_panic(expected = "No EVM policy for the agent's measurements")]
+fn test_request_evm_transaction_signature_without_policy() {
+    let mut contract = setup_contract();
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+    let transaction = eip1559_transaction(1, &EVM_DESTINATION, 5, &[]);
+    request_evm_transaction_signature_with(&mut contract, &transaction);
+}
+
+// Test that a transaction breaking the policy is not sent for signing
+#[test]
+#[should_panic(expected = "Chain ID 10 is not allowed")]
+fn test_request_evm_transaction_signature_rejects_policy_violation() {
+    let mut contract = setup_contract();
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+    set_caller(accounts(0));
+    contract.set_evm_policy(0, mock_evm_policy());
+    let transaction = eip1559_transaction(10, &EVM_DESTINATION, 5, &[]);
+    request_evm_transaction_signature_with(&mut contract, &transaction);
+}
+
+// Test that policies can only be set for approved measurements and go away with them
+#[test]
+fn test_evm_policy_lifecycle() {
+    let mut contract = setup_contract();
+    set_caller(accounts(0));
+    contract.set_evm_policy(0, mock_evm_policy());
+    contract.remove_evm_policy(0);
+    assert_eq!(contract.get_evm_policy(0), None);
+    assert_eq!(
+        logged_events().last(),
+        Some(&ContractEvent::EvmPolicySet {
+            measurements_id: 0,
+            policy: None,
+        })
+    );
+
+    contract.set_evm_policy(0, mock_evm_policy());
+    contract.remove_measurements(create_mock_full_measurements_hex());
+    assert_eq!(contract.get_evm_policy(0), None);
+}
+
+// Test that a policy cannot be set for measurements that are not approved
+#[test]
+#[should_panic(expected = "Measurements not in approved list")]
+fn test_set_evm_policy_unknown_measurements() {
+    let mut contract = setup_contract();
+    set_caller(accounts(0));
+    contract.set_evm_policy(7, mock_evm_policy());
+}
+
+// Test that agents under an EVM policy cannot sign raw payloads with the policy's key
+#[test]
+fn test_evm_policy_disables_raw_signing() {
+    let mut contract = setup_contract();
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+    set_caller(accounts(0));
+    contract.set_evm_policy(0, mock_evm_policy());
+
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        request_signature_with(&mut contract, SignatureScheme::Secp256k1)
+    }));
+    assert_eq!(
+        panic_message(result),
+        "Raw Secp256k1 signing is disabled by the EVM policy of the agent's measurements"
+    );
+
+    // Other keys can still be signed with
+    request_signature_with(&mut contract, SignatureScheme::Ed25519);
+    assert_eq!(sign_call_args(&contract)["request"]["domain_id"], 1);
+
+    // Including a scheme mapped to the policy's domain
+    set_caller(accounts(0));
+    contract.set_signature_domain(SignatureScheme::V2Secp256k1, 0, PayloadFormat::Ecdsa);
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        request_signature_with(&mut contract, SignatureScheme::V2Secp256k1)
+    }));
+    assert_eq!(
+        panic_message(result),
+        "Raw V2Secp256k1 signing is disabled by the EVM policy of the agent's measurements"
+    );
+}
+
+// -------- Bitcoin transaction policies --------
+
+use super::bitcoin::{bip143_sighash, decode_bitcoin_transaction};
+
+// The native P2WPKH example of BIP-143, its second input spends a P2WPKH output of 6 BTC
+const BIP143_TRANSACTION: &str = "0100000002fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f0000000000eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac11000000";
+const BIP143_SPENT_SCRIPT: &str = "00141d0f172a0ecb48aee1be1f2687d2963ae33f71a1";
+const BIP143_SIGHASH: &str = "c37af31116d1b27caf68aae9e3ac82f1477929014d5b917657d0eb49478cb670";
+const BIP143_OUTPUT_SCRIPTS: [&str; 2] = [
+    "76a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac",
+    "76a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac",
+];
+
+fn mock_bitcoin_policy() -> BitcoinPolicy {
+    BitcoinPolicy {
+        allowed_outputs: vec![
+            BitcoinOutputRule {
+                script_pubkey: BIP143_OUTPUT_SCRIPTS[0].to_string(),
+                max_amount: U64(200_000_000),
+            },
+            BitcoinOutputRule {
+                script_pubkey: BIP143_OUTPUT_SCRIPTS[1].to_uppercase(),
+                max_amount: U64(300_000_000),
+            },
+        ],
+    }
+}
+
+fn bip143_spent_output() -> BitcoinSpentOutput {
+    BitcoinSpentOutput {
+        script_pubkey: BIP143_SPENT_SCRIPT.to_string(),
+        amount: U64(600_000_000),
+    }
+}
+
+fn request_bitcoin_transaction_signature_with(contract: &mut Contract, transaction: &str) {
+    testing_env!(get_context_with_deposit(accounts(2), false, Some(ONE_YOCTO)).build());
+    contract
+        .request_bitcoin_transaction_signature(
+            "path".to_string(),
+            transaction.to_string(),
+            1,
+            bip143_spent_output(),
+        )
+        .detach();
+}
+
+// Test decoding the BIP-143 example and computing the sighash of its P2WPKH input
+#[test]
+fn test_bip143_sighash_p2wpkh_example() {
+    let transaction =
+        decode_bitcoin_transaction(&hex::decode(BIP143_TRANSACTION).unwrap()).unwrap();
+    assert_eq!(transaction.version, 1);
+    assert_eq!(transaction.inputs.len(), 2);
+    assert_eq!(transaction.inputs[0].sequence, 0xffffffee);
+    assert_eq!(transaction.outputs.len(), 2);
+    assert_eq!(transaction.outputs[0].amount, 112_340_000);
+    assert_eq!(
+        hex::encode(&transaction.outputs[1].script_pubkey),
+        BIP143_OUTPUT_SCRIPTS[1]
+    );
+    assert_eq!(transaction.lock_time, 17);
+
+    let spent_script = hex::decode(BIP143_SPENT_SCRIPT).unwrap();
+    let sighash = bip143_sighash(&transaction, 1, &spent_script, 600_000_000).unwrap();
+    assert_eq!(hex::encode(sighash), BIP143_SIGHASH);
+}
+
+// Test that only P2WPKH inputs of the transaction can be signed
+#[test]
+fn test_bip143_sighash_rejects_other_inputs() {
+    let transaction =
+        decode_bitcoin_transaction(&hex::decode(BIP143_TRANSACTION).unwrap()).unwrap();
+    let spent_script = hex::decode(BIP143_SPENT_SCRIPT).unwrap();
+    let taproot_script = [vec![0x51, 0x20], vec![7; 32]].concat();
+    let p2wsh_script = [vec![0x00, 0x20], vec![7; 32]].concat();
+    let p2pk_script =
+        hex::decode("2103c9f4836b9a4f77fc0d81f7bcb01b7f1b35916864b9476c241ce9fc198bd25432ac")
+            .unwrap();
+    for (input_index, script, error) in [
+        (2, &spent_script, "Transaction has no input 2"),
+        (
+            1,
+            &taproot_script,
+            "Taproot inputs need Schnorr signatures, which the MPC network does not produce",
+        ),
+        (1, &p2wsh_script, "Only P2WPKH inputs can be signed"),
+        (0, &p2pk_script, "Only P2WPKH inputs can be signed"),
+    ] {
+        assert_eq!(
+            bip143_sighash(&transaction, input_index, script, 1).unwrap_err(),
+            error
+        );
+    }
+}
+
+// Test that anything but a canonical unsigned transaction without witnesses is rejected
+#[test]
+fn test_decode_bitcoin_transaction_rejects_malformed() {
+    let valid = BIP143_TRANSACTION;
+    // Outpoint of a single input, followed by its scriptSig and sequence in each case
+    let outpoint = format!("{}00000000", "11".repeat(32));
+    let cases = [
+        (format!("{valid}00"), "Trailing bytes after transaction"),
+        (
+            valid[..valid.len() - 2].to_string(),
+            "Unexpected end of transaction",
+        ),
+        (
+            format!("010000000001{}", &valid[10..]),
+            "Expected an unsigned transaction without witnesses",
+        ),
+        (
+            format!("0100000001{outpoint}0151ffffffff0000000000"),
+            "Expected empty scriptSigs in an unsigned transaction",
+        ),
+        (
+            format!("0100000001{outpoint}00fffffffffd010000000000"),
+            "Non-canonical CompactSize",
+        ),
+    ];
+    for (transaction, error) in cases {
+        assert_eq!(
+            decode_bitcoin_transaction(&hex::decode(&transaction).unwrap()).unwrap_err(),
+            error,
+            "{transaction}"
+        );
+    }
+}
+
+// Test that outputs have to go to allowed scripts within their maximum or back to the spent script
+#[test]
+fn test_bitcoin_policy_check() {
+    let transaction =
+        decode_bitcoin_transaction(&hex::decode(BIP143_TRANSACTION).unwrap()).unwrap();
+    let spent_script = hex::decode(BIP143_SPENT_SCRIPT).unwrap();
+    let mut policy = mock_bitcoin_policy();
+    assert_eq!(policy.check(&transaction, &spent_script), Ok(()));
+
+    policy.allowed_outputs[1].max_amount = U64(200_000_000);
+    assert_eq!(
+        policy.check(&transaction, &spent_script),
+        Err("Output 1 amount 223450000 is more than the maximum 200000000".to_string())
+    );
+
+    // Change back to the script being spent needs no rule
+    policy.allowed_outputs.pop();
+    assert_eq!(
+        policy.check(
+            &transaction,
+            &hex::decode(BIP143_OUTPUT_SCRIPTS[1]).unwrap()
+        ),
+        Ok(())
+    );
+    assert_eq!(
+        policy.check(&transaction, &spent_script),
+        Err(format!(
+            "Output 1 to {} is not allowed",
+            BIP143_OUTPUT_SCRIPTS[1]
+        ))
+    );
+}
+
+// Test that a transaction meeting the policy is signed by its BIP-143 sighash on the secp256k1 domain
+#[test]
+fn test_request_bitcoin_transaction_signature() {
+    let mut contract = setup_contract();
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+    set_caller(accounts(0));
+    contract.set_bitcoin_policy(0, mock_bitcoin_policy());
+    assert_eq!(
+        logged_events(),
+        vec![ContractEvent::BitcoinPolicySet {
+            measurements_id: 0,
+            policy: Some(shade_contract_events::BitcoinPolicy {
+                allowed_outputs: vec![
+                    shade_contract_events::BitcoinOutputRule {
+                        script_pubkey: BIP143_OUTPUT_SCRIPTS[0].to_string(),
+                        max_amount: 200_000_000,
+                    },
+                    shade_contract_events::BitcoinOutputRule {
+                        script_pubkey: BIP143_OUTPUT_SCRIPTS[1].to_uppercase(),
+                        max_amount: 300_000_000,
+                    },
+                ],
+            }),
+        }]
+    );
+    assert_eq!(contract.get_bitcoin_policy(0), Some(mock_bitcoin_policy()));
+
+    request_bitcoin_transaction_signature_with(&mut contract, BIP143_TRANSACTION);
+    let args = sign_call_args(&contract);
+    assert_eq!(args["request"]["domain_id"], 0);
+    assert_eq!(args["request"]["payload_v2"]["Ecdsa"], BIP143_SIGHASH);
+}
+
+// Test that agents cannot sign Bitcoin transactions before their measurements have a policy
+#[test]
+#[should_panic(expected = "No Bitcoin policy for the agent's measurements")]
+fn test_request_bitcoin_transaction_signature_without_policy() {
+    let mut contract = setup_contract();
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+    request_bitcoin_transaction_signature_with(&mut contract, BIP143_TRANSACTION);
+}
+
+// Test that a transaction paying a script outside the policy is not sent for signing
+#[test]
+#[should_panic(
+    expected = "Output 0 to 76a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac is not allowed"
+)]
+fn test_request_bitcoin_transaction_signature_rejects_policy_violation() {
+    let mut contract = setup_contract();
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+    set_caller(accounts(0));
+    let mut policy = mock_bitcoin_policy();
+    policy.allowed_outputs.remove(0);
+    contract.set_bitcoin_policy(0, policy);
+    request_bitcoin_transaction_signature_with(&mut contract, BIP143_TRANSACTION);
+}
+
+// Test that policies go away with their measurements and need hex scripts
+#[test]
+fn test_bitcoin_policy_lifecycle() {
+    let mut contract = setup_contract();
+    set_caller(accounts(0));
+    contract.set_bitcoin_policy(0, mock_bitcoin_policy());
+    contract.remove_bitcoin_policy(0);
+    assert_eq!(contract.get_bitcoin_policy(0), None);
+    assert_eq!(
+        logged_events().last(),
+        Some(&ContractEvent::BitcoinPolicySet {
+            measurements_id: 0,
+            policy: None,
+        })
+    );
+
+    contract.set_bitcoin_policy(0, mock_bitcoin_policy());
+    contract.remove_measurements(create_mock_full_measurements_hex());
+    assert_eq!(contract.get_bitcoin_policy(0), None);
+}
+
+// Test that output scripts in a policy have to be hex
+#[test]
+#[should_panic(expected = "Script pubkey must be a hex string")]
+fn test_set_bitcoin_policy_invalid_script() {
+    let mut contract = setup_contract();
+    set_caller(accounts(0));
+    let mut policy = mock_bitcoin_policy();
+    policy.allowed_outputs[0].script_pubkey = "not hex".to_string();
+    contract.set_bitcoin_policy(0, policy);
+}
+
+// Test that agents under a Bitcoin policy cannot sign arbitrary 32 byte hashes
+#[test]
+#[should_panic(
+    expected = "Raw Secp256k1 signing is disabled by the Bitcoin policy of the agent's measurements"
+)]
+fn test_bitcoin_policy_disables_raw_signing() {
+    let mut contract = setup_contract();
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+    set_caller(accounts(0));
+    contract.set_bitcoin_policy(0, mock_bitcoin_policy());
+    request_signature_with(&mut contract, SignatureScheme::Secp256k1);
+}
+
+// -------- Solana message policies --------
+
+use super::solana::{SolanaInstruction, decode_solana_message};
+
+const SOLANA_FEE_PAYER: [u8; 32] = [1; 32];
+const SOLANA_DESTINATION: [u8; 32] = [2; 32];
+const SOLANA_PROGRAM: [u8; 32] = [3; 32];
+const SYSTEM_PROGRAM: &str = "11111111111111111111111111111111";
+
+fn compact_u16(mut value: usize) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return bytes;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+// A message signed by the fee payer with the fee payer, destination, System program and
+// SOLANA_PROGRAM as account keys, v0 messages get one lookup table
+fn solana_message(versioned: bool, instructions: &[(u8, Vec<u8>)]) -> Vec<u8> {
+    let mut message = Vec::new();
+    if versioned {
+        message.push(0x80);
+    }
+    message.extend_from_slice(&[1, 0, 2]);
+    message.extend(compact_u16(4));
+    for key in [
+        SOLANA_FEE_PAYER,
+        SOLANA_DESTINATION,
+        [0; 32],
+        SOLANA_PROGRAM,
+    ] {
+        message.extend_from_slice(&key);
+    }
+    message.extend_from_slice(&[9; 32]);
+    message.extend(compact_u16(instructions.len()));
+    for (program_id_index, data) in instructions {
+        message.push(*program_id_index);
+        message.extend([compact_u16(3), vec![0, 1, 4]].concat());
+        message.extend(compact_u16(data.len()));
+        message.extend_from_slice(data);
+    }
+    if versioned {
+        message.extend(compact_u16(1));
+        message.extend_from_slice(&[8; 32]);
+        message.extend([compact_u16(1), vec![0]].concat());
+        message.extend(compact_u16(0));
+    }
+    message
+}
+
+fn system_transfer(lamports: u64) -> (u8, Vec<u8>) {
+    (
+        2,
+        [2u32.to_le_bytes().to_vec(), lamports.to_le_bytes().to_vec()].concat(),
+    )
+}
+
+fn mock_solana_policy() -> SolanaPolicy {
+    SolanaPolicy {
+        fee_payers: vec![SolanaAddress::from(SOLANA_FEE_PAYER)],
+        allowed_programs: vec![SolanaProgramRule {
+            program_id: SolanaAddress::from(SOLANA_PROGRAM),
+            instruction_prefixes: vec!["f8c69e91".to_string(), "AA".to_string()],
+        }],
+        allowed_transfer_destinations: vec![SolanaAddress::from(SOLANA_DESTINATION)],
+        max_transfer_lamports: U64(1_000_000_000),
+    }
+}
+
+fn request_solana_message_signature_with(contract: &mut Contract, message: &[u8]) {
+    testing_env!(get_context_with_deposit(accounts(2), false, Some(ONE_YOCTO)).build());
+    contract
+        .request_solana_message_signature("path".to_string(), hex::encode(message))
+        .detach();
+}
+
+// Test decoding legacy and v0 messages, instructions can use accounts from lookup tables
+#[test]
+fn test_decode_solana_messages() {
+    let instructions = [system_transfer(5), (3, vec![0xaa; 200])];
+    for versioned in [false, true] {
+        let message = decode_solana_message(&solana_message(versioned, &instructions)).unwrap();
+        assert_eq!(
+            message.account_keys,
+            vec![
+                SOLANA_FEE_PAYER,
+                SOLANA_DESTINATION,
+                [0; 32],
+                SOLANA_PROGRAM
+            ]
+        );
+        assert_eq!(
+            message.instructions,
+            vec![
+                SolanaInstruction {
+                    program_id_index: 2,
+                    accounts: vec![0, 1, 4],
+                    data: system_transfer(5).1,
+                },
+                SolanaInstruction {
+                    program_id_index: 3,
+                    accounts: vec![0, 1, 4],
+                    data: vec![0xaa; 200],
+                },
+            ]
+        );
+    }
+}
+
+// Test that anything but a canonical legacy or v0 message is rejected
+#[test]
+fn test_decode_solana_message_rejects_malformed() {
+    let legacy = solana_message(false, &[system_transfer(5)]);
+    let versioned = solana_message(true, &[system_transfer(5)]);
+    let mut no_signers = legacy.clone();
+    no_signers[0] = 0;
+    let mut non_canonical = legacy.clone();
+    non_canonical.splice(3..4, [0x84, 0x00]);
+    let cases = [
+        (
+            [legacy.clone(), vec![0]].concat(),
+            "Trailing bytes after message",
+        ),
+        // A legacy message read as v0 misses its lookup tables
+        (
+            [vec![0x80], legacy.clone()].concat(),
+            "Unexpected end of message",
+        ),
+        (
+            versioned[..versioned.len() - 1].to_vec(),
+            "Unexpected end of message",
+        ),
+        (
+            [vec![0x81], versioned[1..].to_vec()].concat(),
+            "Unsupported message version 1",
+        ),
+        (no_signers, "Invalid message header"),
+        (
+            solana_message(false, &[(4, Vec::new())]),
+            "Invalid program ID index",
+        ),
+        (non_canonical, "Non-canonical compact u16"),
+        (
+            solana_message(false, &[(3, vec![0; 1100])]),
+            "Message is longer than a Solana transaction can be",
+        ),
+    ];
+    for (message, error) in cases {
+        assert_eq!(
+            decode_solana_message(&message).unwrap_err(),
+            error,
+            "{}",
+            hex::encode(&message)
+        );
+    }
+}
+
+// Test the fee payer, program, instruction data and transfer rules of a Solana policy
+#[test]
+fn test_solana_policy_check() {
+    let policy = mock_solana_policy();
+    let check = |instructions: &[(u8, Vec<u8>)]| {
+        policy.check(&decode_solana_message(&solana_message(true, instructions)).unwrap())
+    };
+    let program = String::from(&SolanaAddress::from(SOLANA_PROGRAM));
+
+    assert_eq!(
+        check(&[
+            system_transfer(400_000_000),
+            (3, vec![0xf8, 0xc6, 0x9e, 0x91, 7]),
+            (3, vec![0xaa]),
+            system_transfer(600_000_000),
+        ]),
+        Ok(())
+    );
+    assert_eq!(
+        check(&[system_transfer(600_000_000), system_transfer(400_000_001)]),
+        Err("Transfers of 1000000001 lamports are more than the maximum 1000000000".to_string())
+    );
+    assert_eq!(
+        check(&[(3, vec![0xf8, 0xc6, 0x9e])]),
+        Err(format!(
+            "Instruction 0 data is not allowed for program {program}"
+        ))
+    );
+    // Transfers only go to allowed destinations that are keys of the message
+    let mut policy_without_destinations = mock_solana_policy();
+    policy_without_destinations
+        .allowed_transfer_destinations
+        .clear();
+    assert_eq!(
+        policy_without_destinations
+            .check(&decode_solana_message(&solana_message(true, &[system_transfer(1)])).unwrap()),
+        Err(format!(
+            "Transfer destination {} is not allowed",
+            String::from(&SolanaAddress::from(SOLANA_DESTINATION))
+        ))
+    );
+    // Trailing data does not make a transfer another System instruction
+    let (program_id_index, mut data) = system_transfer(1);
+    data.push(0);
+    assert_eq!(
+        policy_without_destinations.check(
+            &decode_solana_message(&solana_message(true, &[(program_id_index, data)])).unwrap()
+        ),
+        Err(format!(
+            "Transfer destination {} is not allowed",
+            String::from(&SolanaAddress::from(SOLANA_DESTINATION))
+        ))
+    );
+    let mut message = solana_message(true, &[system_transfer(1)]);
+    let accounts = message
+        .windows(5)
+        .position(|window| window == [2, 3, 0, 1, 4])
+        .unwrap();
+    // The destination is the address loaded from the lookup table
+    message[accounts + 3] = 4;
+    assert_eq!(
+        policy.check(&decode_solana_message(&message).unwrap()),
+        Err("Instruction 0 transfers to an account outside the message keys".to_string())
+    );
+    // Other System instructions, here CreateAccount, need a rule for the System program
+    assert_eq!(
+        check(&[(2, [vec![0; 4], vec![1; 48]].concat())]),
+        Err(format!("Program {SYSTEM_PROGRAM} is not allowed"))
+    );
+    assert_eq!(
+        check(&[(1, Vec::new())]),
+        Err(format!(
+            "Program {} is not allowed",
+            String::from(&SolanaAddress::from(SOLANA_DESTINATION))
+        ))
+    );
+
+    let mut policy = mock_solana_policy();
+    policy.allowed_programs[0].instruction_prefixes.clear();
+    policy.fee_payers = vec![SolanaAddress::from(SOLANA_DESTINATION)];
+    let message = decode_solana_message(&solana_message(false, &[(3, vec![1])])).unwrap();
+    assert_eq!(
+        policy.check(&message),
+        Err(format!(
+            "Fee payer {} is not allowed",
+            String::from(&SolanaAddress::from(SOLANA_FEE_PAYER))
+        ))
+    );
+    policy
+        .fee_payers
+        .push(SolanaAddress::from(SOLANA_FEE_PAYER));
+    assert_eq!(policy.check(&message), Ok(()));
+}
+
+// Test that a message meeting the policy is signed as is on the Ed25519 domain
+#[test]
+fn test_request_solana_message_signature() {
+    let mut contract = setup_contract();
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+    set_caller(accounts(0));
+    let policy: SolanaPolicy = serde_json::from_value(serde_json::json!({
+        "fee_payers": [String::from(&SolanaAddress::from(SOLANA_FEE_PAYER))],
+        "allowed_programs": [{ "program_id": SYSTEM_PROGRAM, "instruction_prefixes": ["00000000"] }],
+        "allowed_transfer_destinations": [String::from(&SolanaAddress::from(SOLANA_DESTINATION))],
+        "max_transfer_lamports": "5",
+    }))
+    .unwrap();
+    assert_eq!(
+        policy.allowed_programs[0].program_id,
+        SolanaAddress::from([0; 32])
+    );
+    contract.set_solana_policy(0, policy.clone());
+    assert_eq!(
+        logged_events(),
+        vec![ContractEvent::SolanaPolicySet {
+            measurements_id: 0,
+            policy: Some(shade_contract_events::SolanaPolicy {
+                fee_payers: vec![String::from(&SolanaAddress::from(SOLANA_FEE_PAYER))],
+                allowed_programs: vec![shade_contract_events::SolanaProgramRule {
+                    program_id: SYSTEM_PROGRAM.to_string(),
+                    instruction_prefixes: vec!["00000000".to_string()],
+                }],
+                allowed_transfer_destinations: vec![String::from(&SolanaAddress::from(
+                    SOLANA_DESTINATION
+                ))],
+                max_transfer_lamports: 5,
+            }),
+        }]
+    );
+    assert_eq!(contract.get_solana_policy(0), Some(policy));
+
+    let message = solana_message(false, &[system_transfer(5)]);
+    request_solana_message_signature_with(&mut contract, &message);
+    let args = sign_call_args(&contract);
+    assert_eq!(args["request"]["domain_id"], 1);
+    assert_eq!(
+        args["request"]["payload_v2"]["Eddsa"],
+        hex::encode(&message)
+    );
+}
+
+// Test that agents cannot sign Solana messages before their measurements have a policy
+#[test]
+#[should_panic(expected = "No Solana policy for the agent's measurements")]
+fn test_request_solana_message_signature_without_policy() {
+    let mut contract = setup_contract();
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+    request_solana_message_signature_with(&mut contract, &solana_message(false, &[]));
+}
+
+// Test that a message breaking the policy is not sent for signing
+#[test]
+#[should_panic(expected = "Transfers of 1000000001 lamports are more than the maximum 1000000000")]
+fn test_request_solana_message_signature_rejects_policy_violation() {
+    let mut contract = setup_contract();
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+    set_caller(accounts(0));
+    contract.set_solana_policy(0, mock_solana_policy());
+    let message = solana_message(false, &[system_transfer(1_000_000_001)]);
+    request_solana_message_signature_with(&mut contract, &message);
+}
+
+// Test that policies go away with their measurements
+#[test]
+fn test_solana_policy_lifecycle() {
+    let mut contract = setup_contract();
+    set_caller(accounts(0));
+    contract.set_solana_policy(0, mock_solana_policy());
+    contract.remove_solana_policy(0);
+    assert_eq!(contract.get_solana_policy(0), None);
+    assert_eq!(
+        logged_events().last(),
+        Some(&ContractEvent::SolanaPolicySet {
+            measurements_id: 0,
+            policy: None,
+        })
+    );
+
+    contract.set_solana_policy(0, mock_solana_policy());
+    contract.remove_measurements(create_mock_full_measurements_hex());
+    assert_eq!(contract.get_solana_policy(0), None);
+}
+
+// Test that instruction prefixes have to be non-empty hex
+#[test]
+fn test_set_solana_policy_invalid_prefix() {
+    for prefix in ["", "zz"] {
+        let mut contract = setup_contract();
+        set_caller(accounts(0));
+        let mut policy = mock_solana_policy();
+        policy.allowed_programs[0].instruction_prefixes = vec![prefix.to_string()];
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            contract.set_solana_policy(0, policy)
+        }));
+        assert!(result.is_err(), "{prefix}");
+    }
+}
+
+// Test that agents under a Solana policy cannot sign raw messages with Ed25519
+#[test]
+fn test_solana_policy_disables_raw_signing() {
+    let mut contract = setup_contract();
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+    set_caller(accounts(0));
+    contract.set_solana_policy(0, mock_solana_policy());
+
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        request_signature_with(&mut contract, SignatureScheme::Ed25519)
+    }));
+    assert_eq!(
+        panic_message(result),
+        "Raw Ed25519 signing is disabled by the Solana policy of the agent's measurements"
+    );
+
+    // The Secp256k1 key is not covered by the policy
+    request_signature_with(&mut contract, SignatureScheme::Secp256k1);
+    assert_eq!(sign_call_args(&contract)["request"]["domain_id"], 0);
+}
+
+// -------- Rate limits --------
+
+const RATE_WINDOW_MS: u64 = 10_000;
+
+fn mock_rate_limits() -> RateLimits {
+    RateLimits {
+        window_ms: U64(RATE_WINDOW_MS),
+        max_signatures_per_agent: Some(2),
+        max_signatures: None,
+        budgets: Vec::new(),
+    }
+}
+
+// Register an agent that also deposited for the rate counters its first counted request stores
+fn register_rate_limited_agent_at(contract: &mut Contract, agent: &AccountId, timestamp_ms: u64) {
+    register_local_agent_at(contract, agent, timestamp_ms);
+    let deposit = contract.rate_counters_storage_cost();
+    testing_env!(get_context_with_deposit(agent.clone(), false, Some(deposit)).build());
+    contract.storage_deposit(None, None);
+}
+
+fn request_signature_at(contract: &mut Contract, agent: &AccountId, timestamp_ms: u64) {
+    let context = get_context_with_deposit_and_timestamp(
+        agent.clone(),
+        false,
+        Some(ONE_YOCTO),
+        Some(timestamp_ms),
+    );
+    testing_env!(context.build());
+    contract
+        .request_signature(
+            "path".to_string(),
+            MOCK_PAYLOAD.to_string(),
+            SignatureScheme::Secp256k1,
+        )
+        .detach();
+}
+
+fn request_evm_transfer_at(
+    contract: &mut Contract,
+    agent: &AccountId,
+    chain_id: u64,
+    value: u128,
+    timestamp_ms: u64,
+) {
+    let context = get_context_with_deposit_and_timestamp(
+        agent.clone(),
+        false,
+        Some(ONE_YOCTO),
+        Some(timestamp_ms),
+    );
+    testing_env!(context.build());
+    let transaction = eip1559_transaction(chain_id, &EVM_DESTINATION, value, &[]);
+    contract
+        .request_evm_transaction_signature("path".to_string(), hex::encode(transaction))
+        .detach();
+}
+
+// Whether the last request was sent to the MPC contract, requests over a limit are not
+fn sign_call_made(contract: &Contract) -> bool {
+    near_sdk::test_utils::get_created_receipts()
+        .iter()
+        .any(|receipt| receipt.receiver_id == contract.mpc_contract_id)
+}
+
+fn usage(contract: &Contract, account_id: Option<AccountId>) -> (u128, Vec<(Asset, u128)>) {
+    let usage = contract.get_rate_usage(account_id).unwrap();
+    (
+        usage.signatures.0,
+        usage
+            .spent
+            .into_iter()
+            .map(|spent| (spent.asset, spent.amount.0))
+            .collect(),
+    )
+}
+
+// Test that an agent over its signature limit gets a dedicated event, a refund and a failed request
+#[test]
+fn test_rate_limit_agent_signatures() {
+    let mut contract = setup_contract();
+    let agent = accounts(2);
+    register_rate_limited_agent_at(&mut contract, &agent, 1000);
+    set_caller(accounts(0));
+    contract.set_rate_limits(mock_rate_limits());
+    assert_eq!(
+        logged_events(),
+        vec![ContractEvent::RateLimitsUpdated {
+            rate_limits: Some(shade_contract_events::RateLimits {
+                window_ms: RATE_WINDOW_MS,
+                max_signatures_per_agent: Some(2),
+                max_signatures: None,
+                budgets: Vec::new(),
+            }),
+        }]
+    );
+    assert_eq!(contract.get_rate_limits(), Some(mock_rate_limits()));
+
+    request_signature_at(&mut contract, &agent, 20_000);
+    assert!(sign_call_made(&contract));
+    request_signature_at(&mut contract, &agent, 21_000);
+    assert!(sign_call_made(&contract));
+    assert_eq!(usage(&contract, Some(agent.clone())), (2, Vec::new()));
+    assert_eq!(usage(&contract, None), (2, Vec::new()));
+
+    request_signature_at(&mut contract, &agent, 22_000);
+    assert!(!sign_call_made(&contract));
+    assert_eq!(
+        logged_events(),
+        vec![ContractEvent::RateLimitExceeded {
+            account_id: agent.clone(),
+            limit: shade_contract_events::RateLimit::AgentSignatures,
+        }]
+    );
+    assert_eq!(transferred_to(&agent), ONE_YOCTO);
+    assert!(
+        near_sdk::test_utils::get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .any(|action| matches!(
+                action,
+                MockAction::FunctionCallWeight { method_name, .. }
+                    if method_name == b"fail_on_rate_limit"
+            ))
+    );
+    // The rejected request is not counted
+    assert_eq!(usage(&contract, Some(agent)), (2, Vec::new()));
+}
+
+// Test that the previous window counts by how much of it is still within the rolling window
+#[test]
+fn test_rate_limit_rolling_window() {
+    let mut contract = setup_contract();
+    let agent = accounts(2);
+    register_rate_limited_agent_at(&mut contract, &agent, 1000);
+    set_caller(accounts(0));
+    contract.set_rate_limits(mock_rate_limits());
+    request_signature_at(&mut contract, &agent, 29_000);
+    request_signature_at(&mut contract, &agent, 29_500);
+
+    // At 32_500, 70% of the previous window is still in range: 2 * 0.7 = 1.4
+    request_signature_at(&mut contract, &agent, 32_500);
+    assert!(sign_call_made(&contract));
+    set_caller_at(accounts(0), 33_000);
+    assert_eq!(usage(&contract, Some(agent.clone())).0, 2);
+    request_signature_at(&mut contract, &agent, 33_000);
+    assert!(!sign_call_made(&contract));
+
+    // Once the previous window is out of range only the current one counts
+    set_caller_at(accounts(0), 40_000);
+    assert_eq!(usage(&contract, Some(agent.clone())).0, 1);
+    set_caller_at(accounts(0), 50_000);
+    assert_eq!(usage(&contract, Some(agent.clone())).0, 0);
+    request_signature_at(&mut contract, &agent, 50_000);
+    request_signature_at(&mut contract, &agent, 50_000);
+    assert!(sign_call_made(&contract));
+}
+
+// Test the limit on signatures of all agents together
+#[test]
+fn test_rate_limit_global_signatures() {
+    let mut contract = setup_contract();
+    register_rate_limited_agent_at(&mut contract, &accounts(2), 1000);
+    register_rate_limited_agent_at(&mut contract, &accounts(3), 1000);
+    set_caller(accounts(0));
+    contract.set_rate_limits(RateLimits {
+        max_signatures_per_agent: None,
+        max_signatures: Some(1),
+        ..mock_rate_limits()
+    });
+
+    request_signature_at(&mut contract, &accounts(2), 20_000);
+    assert!(sign_call_made(&contract));
+    request_signature_at(&mut contract, &accounts(3), 20_000);
+    assert!(!sign_call_made(&contract));
+    assert_eq!(
+        logged_events(),
+        vec![ContractEvent::RateLimitExceeded {
+            account_id: accounts(3),
+            limit: shade_contract_events::RateLimit::Signatures,
+        }]
+    );
+}
+
+// Test per-agent and total budgets on the value structured transactions move
+#[test]
+fn test_rate_limit_budgets() {
+    let mut contract = setup_contract();
+    let agent = accounts(2);
+    let other_agent = accounts(3);
+    register_rate_limited_agent_at(&mut contract, &agent, 1000);
+    register_rate_limited_agent_at(&mut contract, &other_agent, 1000);
+    set_caller(accounts(0));
+    contract.set_evm_policy(0, mock_evm_policy());
+    contract.set_rate_limits(RateLimits {
+        max_signatures_per_agent: None,
+        budgets: vec![AssetBudget {
+            asset: Asset::Evm { chain_id: 1 },
+            max_per_agent: Some(U128(10)),
+            max_total: Some(U128(15)),
+        }],
+        ..mock_rate_limits()
+    });
+
+    request_evm_transfer_at(&mut contract, &agent, 1, 6, 20_000);
+    assert!(sign_call_made(&contract));
+    request_evm_transfer_at(&mut contract, &agent, 1, 5, 20_000);
+    assert!(!sign_call_made(&contract));
+    assert_eq!(
+        logged_events(),
+        vec![ContractEvent::RateLimitExceeded {
+            account_id: agent.clone(),
+            limit: shade_contract_events::RateLimit::AgentBudget(
+                shade_contract_events::Asset::Evm { chain_id: 1 }
+            ),
+        }]
+    );
+
+    request_evm_transfer_at(&mut contract, &other_agent, 1, 10, 20_000);
+    assert!(!sign_call_made(&contract));
+    assert_eq!(
+        logged_events(),
+        vec![ContractEvent::RateLimitExceeded {
+            account_id: other_agent.clone(),
+            limit: shade_contract_events::RateLimit::Budget(shade_contract_events::Asset::Evm {
+                chain_id: 1
+            }),
+        }]
+    );
+    request_evm_transfer_at(&mut contract, &other_agent, 1, 9, 20_000);
+    assert!(sign_call_made(&contract));
+
+    // Assets without a budget are not counted
+    request_evm_transfer_at(&mut contract, &agent, 8453, 100, 20_000);
+    assert!(sign_call_made(&contract));
+    assert_eq!(
+        usage(&contract, Some(agent.clone())),
+        (2, vec![(Asset::Evm { chain_id: 1 }, 6)])
+    );
+    assert_eq!(
+        usage(&contract, None),
+        (3, vec![(Asset::Evm { chain_id: 1 }, 15)])
+    );
+
+    // Counters go away with the agent
+    set_caller_at(accounts(0), 20_000);
+    contract.remove_agent(agent.clone());
+    assert_eq!(usage(&contract, Some(agent)), (0, Vec::new()));
+}
+
+// Test that Bitcoin and Solana requests count the value they send
+#[test]
+fn test_rate_limit_bitcoin_and_solana_spend() {
+    let mut contract = setup_contract();
+    register_rate_limited_agent_at(&mut contract, &accounts(2), 1000);
+    set_caller(accounts(0));
+    contract.set_bitcoin_policy(0, mock_bitcoin_policy());
+    contract.set_solana_policy(0, mock_solana_policy());
+    contract.set_rate_limits(RateLimits {
+        max_signatures_per_agent: None,
+        budgets: vec![
+            AssetBudget {
+                asset: Asset::Bitcoin,
+                max_per_agent: None,
+                max_total: Some(U128(1_000_000_000)),
+            },
+            AssetBudget {
+                asset: Asset::Solana,
+                max_per_agent: Some(U128(1_000_000_000)),
+                max_total: None,
+            },
+        ],
+        ..mock_rate_limits()
+    });
+
+    request_bitcoin_transaction_signature_with(&mut contract, BIP143_TRANSACTION);
+    assert!(sign_call_made(&contract));
+    let message = solana_message(false, &[system_transfer(7), system_transfer(8)]);
+    request_solana_message_signature_with(&mut contract, &message);
+    assert!(sign_call_made(&contract));
+
+    set_caller(accounts(0));
+    let (signatures, spent) = usage(&contract, Some(accounts(2)));
+    assert_eq!(signatures, 2);
+    assert_eq!(
+        spent,
+        vec![
+            (Asset::Bitcoin, 112_340_000 + 223_450_000),
+            (Asset::Solana, 15)
+        ]
+    );
+}
+
+// Test that removing the limits stops counting and is timelocked like setting them
+#[test]
+fn test_remove_rate_limits() {
+    let mut contract = setup_contract();
+    let agent = accounts(2);
+    register_rate_limited_agent_at(&mut contract, &agent, 1000);
+    set_caller(accounts(0));
+    contract.set_rate_limits(mock_rate_limits());
+    contract.remove_rate_limits();
+    assert_eq!(
+        logged_events().last(),
+        Some(&ContractEvent::RateLimitsUpdated { rate_limits: None })
+    );
+    assert_eq!(contract.get_rate_limits(), None);
+    assert!(contract.get_rate_usage(None).is_none());
+    for _ in 0..3 {
+        request_signature_at(&mut contract, &agent, 20_000);
+        assert!(sign_call_made(&contract));
+    }
+
+    assert!(OwnerAction::SetRateLimits { rate_limits: None }.requires_timelock());
+    assert_eq!(
+        OwnerAction::SetRateLimits { rate_limits: None }.required_role(),
+        None
+    );
+}
+
+// Test that an agent without rate limits is not charged for counters, and that its first counted
+// request needs a storage balance that covers them
+#[test]
+#[should_panic(expected = "with rate counters, call storage_deposit first")]
+fn test_rate_counters_need_storage_balance() {
+    let mut contract = setup_contract();
+    let agent = accounts(2);
+    register_local_agent_at(&mut contract, &agent, 1000);
+    request_signature_at(&mut contract, &agent, 1000);
+    assert!(!contract.agent_rate_counters.contains_key(&agent));
+    assert_eq!(
+        contract
+            .storage_balance_of(agent.clone())
+            .unwrap()
+            .available,
+        DEPOSIT_005_NEAR.saturating_sub(contract.agent_storage_cost())
+    );
+
+    set_caller(accounts(0));
+    contract.set_rate_limits(mock_rate_limits());
+    request_signature_at(&mut contract, &agent, 2000);
+}
+
+// Test that the rate counters' storage cost stays locked once they are stored and is refunded with the agent
+#[test]
+fn test_rate_counters_storage_cost() {
+    let mut contract = setup_contract();
+    let agent = accounts(2);
+    register_rate_limited_agent_at(&mut contract, &agent, 1000);
+    set_caller(accounts(0));
+    contract.set_rate_limits(mock_rate_limits());
+    request_signature_at(&mut contract, &agent, 2000);
+    assert!(sign_call_made(&contract));
+
+    let balance = contract.storage_balance_of(agent.clone()).unwrap();
+    assert_eq!(
+        balance.total,
+        DEPOSIT_005_NEAR.saturating_add(contract.rate_counters_storage_cost())
+    );
+    assert_eq!(
+        balance.available,
+        DEPOSIT_005_NEAR.saturating_sub(contract.agent_storage_cost())
+    );
+
+    testing_env!(get_context_with_deposit(agent.clone(), false, Some(ONE_YOCTO)).build());
+    assert!(contract.storage_unregister(Some(true)));
+    assert!(!contract.agent_rate_counters.contains_key(&agent));
+    assert_eq!(
+        logged_events().last(),
+        Some(&ContractEvent::StorageDepositRefunded {
+            account_id: agent,
+            amount: balance.total.as_yoctonear(),
+        })
+    );
+}
+
+// Test that rate limits need a window, at most one budget per asset and a bounded number of budgets
+#[test]
+fn test_set_rate_limits_validation() {
+    for (rate_limits, error) in [
+        (
+            RateLimits {
+                window_ms: U64(0),
+                ..mock_rate_limits()
+            },
+            "Rate limit window must be greater than zero",
+        ),
+        (
+            RateLimits {
+                budgets: vec![
+                    AssetBudget {
+                        asset: Asset::Solana,
+                        max_per_agent: None,
+                        max_total: None,
+                    };
+                    2
+                ],
+                ..mock_rate_limits()
+            },
+            "Duplicate budget for Solana",
+        ),
+        (
+            RateLimits {
+                budgets: (0..=internal::rate_limits::MAX_RATE_BUDGETS as u64)
+                    .map(|chain_id| AssetBudget {
+                        asset: Asset::Evm { chain_id },
+                        max_per_agent: None,
+                        max_total: None,
+                    })
+                    .collect(),
+                ..mock_rate_limits()
+            },
+            "At most 4 budgets are allowed",
+        ),
+    ] {
+        let mut contract = setup_contract();
+        set_caller(accounts(0));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            contract.set_rate_limits(rate_limits)
+        }));
+        let panic = result.unwrap_err();
+        let message = panic
+            .downcast_ref::<String>()
+            .map(String::as_str)
+            .or_else(|| panic.downcast_ref::<&str>().copied())
+            .unwrap();
+        assert!(message.contains(error), "{message}");
+    }
+}
+
+// -------- Path policies --------
+
+fn request_signature_for_path(contract: &mut Contract, agent: &AccountId, path: &str) {
+    testing_env!(get_context_with_deposit(agent.clone(), false, Some(ONE_YOCTO)).build());
+    contract
+        .request_signature(
+            path.to_string(),
+            MOCK_PAYLOAD.to_string(),
+            SignatureScheme::Secp256k1,
+        )
+        .detach();
+}
+
+fn panic_message(result: std::thread::Result<()>) -> String {
+    let panic = result.unwrap_err();
+    panic
+        .downcast_ref::<String>()
+        .cloned()
+        .or_else(|| {
+            panic
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+        })
+        .unwrap()
+}
+
+fn mock_path_policy() -> PathPolicy {
+    PathPolicy {
+        allowed_paths: vec![
+            PathRule::Exact("wallet".to_string()),
+            PathRule::Prefix("trading/".to_string()),
+            PathRule::Pattern("vault/*-hot/key*".to_string()),
+        ],
+        namespace: None,
+    }
+}
+
+// Test that agents can only sign with paths matching a rule of their measurements' policy
+#[test]
+fn test_path_policy_rules() {
+    let mut contract = setup_contract();
+    let agent = accounts(2);
+    register_local_agent_at(&mut contract, &agent, 1000);
+    set_caller(accounts(0));
+    contract.set_path_policy(0, mock_path_policy());
+    assert_eq!(
+        logged_events(),
+        vec![ContractEvent::PathPolicySet {
+            measurements_id: 0,
+            policy: Some(shade_contract_events::PathPolicy {
+                allowed_paths: vec![
+                    shade_contract_events::PathRule::Exact("wallet".to_string()),
+                    shade_contract_events::PathRule::Prefix("trading/".to_string()),
+                    shade_contract_events::PathRule::Pattern("vault/*-hot/key*".to_string()),
+                ],
+                namespace: None,
+            }),
+        }]
+    );
+    assert_eq!(contract.get_path_policy(0), Some(mock_path_policy()));
+
+    for path in [
+        "wallet",
+        "trading/",
+        "trading/eth/1",
+        "vault/eu-hot/key",
+        "vault/us-west-hot/key-2",
+    ] {
+        request_signature_for_path(&mut contract, &agent, path);
+        assert_eq!(sign_call_args(&contract)["request"]["path"], path);
+    }
+    for path in [
+        "",
+        "wallet/1",
+        "Wallet",
+        "trading",
+        "vault/eu-cold/key",
+        "vault/eu-hot/other",
+        "vault/eu/hot/key",
+        "vault/eu-hot/key/1",
+    ] {
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            request_signature_for_path(&mut contract, &agent, path)
+        }));
+        assert!(
+            panic_message(result).contains(&format!("Path {path} is not allowed")),
+            "{path}"
+        );
+    }
+}
+
+// Test that namespaces prefix the path with the agent's account or measurements ID
+#[test]
+fn test_path_policy_namespaces() {
+    let mut contract = setup_contract();
+    let agent = accounts(2);
+    register_local_agent_at(&mut contract, &agent, 1000);
+    set_caller(accounts(0));
+    contract.set_path_policy(
+        0,
+        PathPolicy {
+            allowed_paths: Vec::new(),
+            namespace: Some(PathNamespace::Account),
+        },
+    );
+    request_signature_for_path(&mut contract, &agent, "eth/1");
+    assert_eq!(
+        sign_call_args(&contract)["request"]["path"],
+        "account:charlie/eth/1"
+    );
+    assert_eq!(
+        contract.get_derivation_path(agent.clone(), "eth/1".to_string()),
+        "account:charlie/eth/1"
+    );
+
+    set_caller(accounts(0));
+    contract.set_path_policy(
+        0,
+        PathPolicy {
+            namespace: Some(PathNamespace::Measurements),
+            ..mock_path_policy()
+        },
+    );
+    request_signature_for_path(&mut contract, &agent, "wallet");
+    assert_eq!(
+        sign_call_args(&contract)["request"]["path"],
+        "measurements:0/wallet"
+    );
+    // Rules apply to the path the agent passes
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        request_signature_for_path(&mut contract, &agent, "measurements:0/wallet")
+    }));
+    assert!(panic_message(result).contains("reserved for key namespaces"));
+}
+
+// Test that namespace prefixes cannot be passed as paths, even without a policy
+#[test]
+fn test_reserved_paths() {
+    let mut contract = setup_contract();
+    let agent = accounts(2);
+    register_local_agent_at(&mut contract, &agent, 1000);
+    request_signature_for_path(&mut contract, &agent, "account/1");
+    assert_eq!(sign_call_args(&contract)["request"]["path"], "account/1");
+    for (path, error) in [
+        (
+            "account:bob/eth/1",
+            "Paths starting with account: are reserved for key namespaces",
+        ),
+        (
+            "measurements:0/wallet",
+            "Paths starting with measurements: are reserved for key namespaces",
+        ),
+    ] {
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            request_signature_for_path(&mut contract, &agent, path)
+        }));
+        assert!(panic_message(result).contains(error), "{path}");
+    }
+}
+
+// Test that an agent's own policy replaces the policy of its measurements and outlives its registration
+#[test]
+fn test_agent_path_policy() {
+    let mut contract = setup_contract();
+    let agent = accounts(2);
+    let other_agent = accounts(3);
+    register_local_agent_at(&mut contract, &agent, 1000);
+    register_local_agent_at(&mut contract, &other_agent, 1000);
+    set_caller(accounts(0));
+    contract.set_path_policy(0, mock_path_policy());
+    let agent_policy = PathPolicy {
+        allowed_paths: vec![PathRule::Prefix("btc/".to_string())],
+        namespace: Some(PathNamespace::Account),
+    };
+    contract.set_agent_path_policy(agent.clone(), agent_policy.clone());
+    assert_eq!(
+        logged_events()[1..],
+        [ContractEvent::AgentPathPolicySet {
+            account_id: agent.clone(),
+            policy: Some(shade_contract_events::PathPolicy {
+                allowed_paths: vec![shade_contract_events::PathRule::Prefix("btc/".to_string())],
+                namespace: Some(shade_contract_events::PathNamespace::Account),
+            }),
+        }]
+    );
+
+    request_signature_for_path(&mut contract, &agent, "btc/0");
+    assert_eq!(
+        sign_call_args(&contract)["request"]["path"],
+        "account:charlie/btc/0"
+    );
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        request_signature_for_path(&mut contract, &agent, "wallet")
+    }));
+    assert!(panic_message(result).contains("Path wallet is not allowed"));
+    request_signature_for_path(&mut contract, &other_agent, "wallet");
+    assert_eq!(sign_call_args(&contract)["request"]["path"], "wallet");
+
+    set_caller(accounts(0));
+    contract.remove_agent(agent.clone());
+    assert_eq!(
+        contract.get_agent_path_policy(agent.clone()),
+        Some(agent_policy)
+    );
+    contract.remove_agent_path_policy(agent.clone());
+    assert_eq!(
+        logged_events().last(),
+        Some(&ContractEvent::AgentPathPolicySet {
+            account_id: agent.clone(),
+            policy: None,
+        })
+    );
+    assert_eq!(contract.get_agent_path_policy(agent.clone()), None);
+
+    register_local_agent_at(&mut contract, &agent, 1000);
+    request_signature_for_path(&mut contract, &agent, "wallet");
+    assert_eq!(sign_call_args(&contract)["request"]["path"], "wallet");
+}
+
+// Test that structured requests are signed with the namespaced path too
+#[test]
+fn test_path_policy_structured_requests() {
+    let mut contract = setup_contract();
+    register_local_agent_at(&mut contract, &accounts(2), 1000);
+    set_caller(accounts(0));
+    contract.set_evm_policy(0, mock_evm_policy());
+    contract.set_path_policy(
+        0,
+        PathPolicy {
+            allowed_paths: vec![PathRule::Exact("path".to_string())],
+            namespace: Some(PathNamespace::Measurements),
+        },
+    );
+    request_evm_transaction_signature_with(
+        &mut contract,
+        &eip1559_transaction(1, &EVM_DESTINATION, 1, &[]),
+    );
+    assert_eq!(
+        sign_call_args(&contract)["request"]["path"],
+        "measurements:0/path"
+    );
+}
+
+// Test validation, roles, the timelock, and removal with the measurements
+#[test]
+fn test_path_policy_management() {
+    let mut contract = setup_contract();
+    set_caller(accounts(0));
+    for policy in [
+        PathPolicy {
+            allowed_paths: vec![PathRule::Prefix(String::new())],
+            namespace: None,
+        },
+        PathPolicy {
+            allowed_paths: vec![PathRule::Pattern(String::new())],
+            namespace: Some(PathNamespace::Account),
+        },
+    ] {
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            contract.set_agent_path_policy(accounts(2), policy)
+        }));
+        assert!(panic_message(result).contains("Path rules must not be empty"));
+    }
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        contract.set_path_policy(7, mock_path_policy())
+    }));
+    assert!(panic_message(result).contains("Measurements not in approved list"));
+
+    let set = OwnerAction::SetPathPolicy {
+        measurements_id: 0,
+        policy: Some(mock_path_policy()),
+    };
+    let remove = OwnerAction::SetAgentPathPolicy {
+        account_id: accounts(2),
+        policy: None,
+    };
+    // Removing a policy lets agents sign with any path again, so it waits for the timelock too
+    assert!(set.requires_timelock() && remove.requires_timelock());
+    assert_eq!(set.required_role(), Some(Role::MeasurementAdmin));
+    assert_eq!(remove.required_role(), Some(Role::AgentAdmin));
+    assert_eq!(remove.name(), "remove_agent_path_policy");
+
+    contract.set_path_policy(0, mock_path_policy());
+    contract.remove_measurements(create_mock_full_measurements_hex());
+    assert_eq!(contract.get_path_policy(0), None);
+}
+
 // -------- summarize_advisory_ids (AgentRegistered event payload) --------
 
 use super::events::{MAX_ADVISORY_IDS, summarize_advisory_ids};
@@ -1,13 +1,12 @@
-use hex;
 use near_sdk::{
-    AccountId, BorshStorageKey, Gas, NearToken, PanicOnDefault, Promise,
+    AccountId, BorshStorageKey, CryptoHash, Gas, NearToken, PanicOnDefault, Promise, StorageUsage,
     env::{self, block_timestamp_ms},
     ext_contract,
-    json_types::U64,
+    json_types::{Base58CryptoHash, U64, U128},
     log, near, require,
     serde::Serialize,
     serde_json,
-    store::{IterableMap, IterableSet},
+    store::{IterableMap, IterableSet, LookupMap, Vector},
 };
 use shade_attestation::{
     attestation::{AcceptedDstackAttestation, DstackAttestation},
@@ -16,17 +15,47 @@ use shade_attestation::{
     tcb_info::HexBytes,
 };
 
+pub use internal::bitcoin::{BitcoinOutputRule, BitcoinPolicy, BitcoinSpentOutput};
+pub use internal::chainsig::{
+    AffinePoint, EcdsaSignature, PayloadFormat, Scalar, SignatureDomain, SignatureFailureReason,
+    SignatureResponse, SignatureScheme,
+};
+pub use internal::ckd::CkdResponse;
 pub use internal::events::Event;
+pub use internal::evm::{EvmAddress, EvmPolicy, FunctionSelector};
 pub use internal::helpers::AgentRemovalReason;
-pub use views::{AgentValidity, AgentView, ContractInfo};
+pub use internal::owner_actions::OwnerAction;
+pub use internal::paths::{PathNamespace, PathPolicy, PathRule};
+pub use internal::rate_limits::{Asset, AssetBudget, RateCounters, RateLimit, RateLimits};
+pub use internal::solana::{SolanaAddress, SolanaPolicy, SolanaProgramRule};
+pub use storage::{StorageBalance, StorageBalanceBounds};
+pub use views::{
+    AccountRolesView, AgentValidity, AgentView, AllowlistHistoryEntryView,
+    ApprovedMeasurementsView, ApprovedPpidView, AssetUsageView, CodeUpgradeView, ContractInfo,
+    MultisigView, PendingUpgradeView, PpidGroupView, PpidMeasurementsScopeView, ProposalView,
+    QueuedActionView, RateUsageView, SignatureDomainView,
+};
 
+mod history;
 mod internal;
+mod migration;
+mod multisig;
 mod owner;
+mod storage;
+mod timelock;
+mod upgrade;
 pub mod views;
 mod your_functions;
 
 pub type Ppid = HexBytes<16>;
 
+/// Compact identifier assigned to a set of measurements when it is approved
+pub type MeasurementsId = u32;
+
+pub type ProposalId = u64;
+
+pub type QueuedActionId = u64;
+
 #[near(contract_state)]
 #[derive(PanicOnDefault)]
 pub struct Contract {
@@ -34,32 +63,271 @@ pub struct Contract {
     pub attestation_expiration_time_ms: u64,
     pub owner_id: AccountId,
     pub mpc_contract_id: AccountId,
-    pub approved_measurements: IterableSet<FullMeasurementsHex>,
-    pub approved_ppids: IterableSet<Ppid>,
+    pub approved_measurements: IterableMap<MeasurementsId, ApprovedMeasurements>,
+    pub measurements_ids: LookupMap<FullMeasurementsHex, MeasurementsId>,
+    pub next_measurements_id: MeasurementsId,
+    pub sunset_measurements: LookupMap<MeasurementsId, u64>,
+    pub approved_ppids: IterableMap<Ppid, ApprovedPpid>,
+    pub revoked_ppids: LookupMap<Ppid, PpidRevocation>,
+    pub ppid_groups: IterableMap<String, Vec<Ppid>>,
+    pub ppid_measurements_scopes: IterableMap<Ppid, Vec<MeasurementsId>>,
     pub agents: IterableMap<AccountId, Agent>,
+    // Agents stored in the version 1 layout that have not been migrated yet, None once all are
+    pub legacy_agents: Option<IterableMap<AccountId, AgentV1>>,
     pub whitelisted_agents_for_local: IterableSet<AccountId>,
+    // When set, owner methods are disabled and privileged actions go through proposals
+    pub multisig: Option<Multisig>,
+    pub proposals: IterableMap<ProposalId, Proposal>,
+    pub next_proposal_id: ProposalId,
+    // Delay before queued security-critical actions can be executed, 0 disables the timelock
+    pub timelock_delay_ms: u64,
+    pub queued_actions: IterableMap<QueuedActionId, QueuedAction>,
+    pub next_queued_action_id: QueuedActionId,
+    // Roles granted to accounts other than the owner
+    pub roles: IterableMap<AccountId, Vec<Role>>,
+    pub registration_paused: bool,
+    pub signing_paused: bool,
+    // SHA-256 of the current code, given on init or deployed through upload_code, and of the code before it
+    pub code_hash: Option<CryptoHash>,
+    pub previous_code_hash: Option<CryptoHash>,
+    // State version written by the current code and by the code before it
+    pub code_state_version: u32,
+    pub previous_code_state_version: Option<u32>,
+    pub pending_upgrade: Option<PendingUpgrade>,
+    // Append-only record of changes to the approved measurements and PPIDs
+    pub allowlist_history: Vector<AllowlistHistoryEntry>,
+    // Positions in allowlist_history of the changes to each set of measurements and each PPID
+    pub measurements_history: LookupMap<FullMeasurementsHex, Vec<u32>>,
+    pub ppid_history: LookupMap<Ppid, Vec<u32>>,
+    // Share of each pruned agent's storage deposit paid to the caller of prune_agents, in basis points
+    pub prune_reward_bps: u16,
+    // Position in agents where the next prune_agents call starts checking
+    pub prune_cursor: u32,
+    // NEP-145 storage balance of each account, refunded when the account unregisters or its agent is removed
+    pub storage_balances: LookupMap<AccountId, NearToken>,
+    // Bytes an agent and its storage balance take up, measured for the longest account ID
+    pub agent_storage_usage: StorageUsage,
+    // Bytes the rate counters of an agent take up with every budget, paid from its storage balance
+    // once its first request is counted
+    pub rate_counters_storage_usage: StorageUsage,
+    // Gas and deposit forwarded to the MPC contract with each sign call, follow the MPC contract's requirements
+    pub sign_gas_tgas: u64,
+    pub sign_deposit: NearToken,
+    // MPC domain and payload format each signature scheme is signed with
+    pub signature_domains: IterableMap<SignatureScheme, SignatureDomain>,
+    // MPC domain confidential keys are derived with, None until it is set or synced
+    pub ckd_domain_id: Option<u64>,
+    // What agents on each set of measurements can sign with request_evm_transaction_signature
+    pub evm_policies: LookupMap<MeasurementsId, EvmPolicy>,
+    // Where agents on each set of measurements can send bitcoin with request_bitcoin_transaction_signature
+    pub bitcoin_policies: LookupMap<MeasurementsId, BitcoinPolicy>,
+    // What agents on each set of measurements can sign with request_solana_message_signature
+    pub solana_policies: LookupMap<MeasurementsId, SolanaPolicy>,
+    // Limits on signature requests per rolling window, None for no limits
+    pub rate_limits: Option<RateLimits>,
+    // Signatures and value counted against the rate limits for all agents together and for each agent
+    pub rate_counters: RateCounters,
+    pub agent_rate_counters: LookupMap<AccountId, RateCounters>,
+    // Derivation paths agents on each set of measurements can sign with, and overrides for single agents
+    pub path_policies: LookupMap<MeasurementsId, PathPolicy>,
+    pub agent_path_policies: LookupMap<AccountId, PathPolicy>,
 }
 
 #[near(serializers = [borsh])]
 pub struct Agent {
+    pub measurements_id: MeasurementsId,
+    pub ppid: Ppid,
+    pub valid_until_ms: u64,
+}
+
+// An agent as stored in the version 1 layout, before measurements had IDs
+#[near(serializers = [borsh])]
+pub struct AgentV1 {
     pub measurements: FullMeasurementsHex,
     pub ppid: Ppid,
     pub valid_until_ms: u64,
 }
 
+// An approved set of measurements together with where it came from and who approved it
+#[near(serializers = [borsh])]
+#[derive(Clone)]
+pub struct ApprovedMeasurements {
+    pub measurements: FullMeasurementsHex,
+    pub label: String,
+    // Free-form reference to the release, e.g. a git commit, image digest or build URL
+    pub source: String,
+    pub approved_by: AccountId,
+    pub approved_at_ms: u64,
+    // Once set, new registrations are refused and existing agents become invalid at this time
+    pub sunset_at_ms: Option<u64>,
+    // Maximum number of agents registered with these measurements at once, None for no limit
+    pub max_agents: Option<u32>,
+    // Number of agents currently registered with these measurements
+    pub active_agents: u32,
+    // Named PPID group the measurements are limited to, None to allow any approved PPID
+    pub ppid_group: Option<String>,
+}
+
+// An approved PPID together with who operates the machine and until when it is approved
+#[near(serializers = [borsh])]
+#[derive(Clone)]
+pub struct ApprovedPpid {
+    // Operator or provider running the machine
+    pub operator: String,
+    pub region: String,
+    pub added_at_ms: u64,
+    // Agents on this PPID become invalid after this time, None for no expiry
+    pub expires_at_ms: Option<u64>,
+}
+
+#[near(serializers = [borsh, json])]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PpidRevocationReason {
+    Compromised,
+    Decommissioned,
+    ProviderChange,
+}
+
+// Why and when a PPID was removed from the approved list
+#[near(serializers = [borsh])]
+pub struct PpidRevocation {
+    pub reason: Option<PpidRevocationReason>,
+    pub revoked_at_ms: u64,
+}
+
+// Permission to run a group of owner methods without being the owner
+#[near(serializers = [borsh, json])]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    MeasurementAdmin,
+    PpidAdmin,
+    AgentAdmin,
+    Upgrader,
+    Pauser,
+}
+
+// Parts of the contract that can be paused independently during an incident
+#[near(serializers = [borsh, json])]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PausableFeature {
+    Registration,
+    Signing,
+}
+
+// Set of owners that confirm privileged actions, threshold confirmations are needed to execute one
+#[near(serializers = [borsh])]
+#[derive(Clone)]
+pub struct Multisig {
+    pub owners: Vec<AccountId>,
+    pub threshold: u32,
+    // How long a proposal can collect confirmations before it expires
+    pub proposal_lifetime_ms: u64,
+}
+
+// A privileged action waiting for confirmations from the multisig owners
+#[near(serializers = [borsh])]
+pub struct Proposal {
+    pub action: OwnerAction,
+    pub proposer: AccountId,
+    pub confirmations: Vec<AccountId>,
+    pub created_at_ms: u64,
+    pub expires_at_ms: u64,
+}
+
+// A security-critical action waiting for the timelock delay to pass
+#[near(serializers = [borsh])]
+pub struct QueuedAction {
+    pub action: OwnerAction,
+    pub queued_by: AccountId,
+    pub queued_at_ms: u64,
+    pub executable_at_ms: u64,
+}
+
+// An approved code hash waiting for its code to be uploaded
+#[near(serializers = [borsh])]
+pub struct PendingUpgrade {
+    pub code_hash: CryptoHash,
+    pub migrate_gas_tgas: u64,
+    pub approved_by: AccountId,
+    pub approved_at_ms: u64,
+}
+
+// A change to the approved measurements or PPIDs
+#[near(serializers = [borsh, json])]
+#[derive(Clone)]
+pub enum AllowlistChange {
+    MeasurementsApproved {
+        measurements_id: MeasurementsId,
+        measurements: FullMeasurementsHex,
+    },
+    MeasurementsRemoved {
+        measurements_id: MeasurementsId,
+        measurements: FullMeasurementsHex,
+    },
+    // Recorded when the sunset is executed, the measurements stopped being valid at sunset_at_ms
+    MeasurementsSunset {
+        measurements_id: MeasurementsId,
+        measurements: FullMeasurementsHex,
+        sunset_at_ms: U64,
+    },
+    PpidApproved {
+        ppid: Ppid,
+        expires_at_ms: Option<U64>,
+    },
+    PpidExpiryUpdated {
+        ppid: Ppid,
+        expires_at_ms: Option<U64>,
+    },
+    PpidRevoked {
+        ppid: Ppid,
+        reason: Option<PpidRevocationReason>,
+    },
+}
+
+// A change in the allowlist history together with who made it and when
+#[near(serializers = [borsh])]
+pub struct AllowlistHistoryEntry {
+    pub change: AllowlistChange,
+    pub changed_by: AccountId,
+    pub changed_at_ms: u64,
+}
+
 #[derive(BorshStorageKey)]
 #[near]
 pub enum StorageKey {
+    // The first three prefixes belong to the version 1 layout and stay reserved for migrate
+    LegacyApprovedMeasurements,
+    LegacyApprovedPpids,
+    LegacyAgents,
+    WhitelistedAgentsForLocal,
     ApprovedMeasurements,
+    MeasurementsIds,
+    SunsetMeasurements,
     ApprovedPpids,
+    RevokedPpids,
+    PpidGroups,
+    PpidMeasurementsScopes,
     Agents,
-    WhitelistedAgentsForLocal,
+    Proposals,
+    QueuedActions,
+    Roles,
+    AllowlistHistory,
+    MeasurementsHistory,
+    PpidHistory,
+    StorageBalances,
+    SignatureDomains,
+    EvmPolicies,
+    BitcoinPolicies,
+    SolanaPolicies,
+    AgentRateCounters,
+    PathPolicies,
+    AgentPathPolicies,
 }
 
-const STORAGE_BYTES_TO_REGISTER: u128 = 486;
-
 #[near]
 impl Contract {
+    // code_hash is the SHA-256 of the code being initialized, so it can be rolled back to after an upgrade
+    // The contract cannot hash its own code, so it is taken on trust from the deployer
     #[init]
     #[private]
     pub fn new(
@@ -67,42 +335,130 @@ impl Contract {
         attestation_expiration_time_ms: U64,
         owner_id: AccountId,
         mpc_contract_id: AccountId,
+        code_hash: Option<Base58CryptoHash>,
     ) -> Self {
-        Self {
+        migration::write_state_version(migration::STATE_VERSION);
+        let mut contract = Self {
             requires_tee,
             attestation_expiration_time_ms: attestation_expiration_time_ms.into(),
             owner_id,
             mpc_contract_id, // Set to v1.signer-prod.testnet for testnet, v1.signer for mainnet
-            approved_measurements: IterableSet::new(StorageKey::ApprovedMeasurements),
-            approved_ppids: IterableSet::new(StorageKey::ApprovedPpids),
+            approved_measurements: IterableMap::new(StorageKey::ApprovedMeasurements),
+            measurements_ids: LookupMap::new(StorageKey::MeasurementsIds),
+            next_measurements_id: 0,
+            sunset_measurements: LookupMap::new(StorageKey::SunsetMeasurements),
+            approved_ppids: IterableMap::new(StorageKey::ApprovedPpids),
+            revoked_ppids: LookupMap::new(StorageKey::RevokedPpids),
+            ppid_groups: IterableMap::new(StorageKey::PpidGroups),
+            ppid_measurements_scopes: IterableMap::new(StorageKey::PpidMeasurementsScopes),
             agents: IterableMap::new(StorageKey::Agents),
+            legacy_agents: None,
             whitelisted_agents_for_local: IterableSet::new(StorageKey::WhitelistedAgentsForLocal),
-        }
+            multisig: None,
+            proposals: IterableMap::new(StorageKey::Proposals),
+            next_proposal_id: 0,
+            timelock_delay_ms: 0,
+            queued_actions: IterableMap::new(StorageKey::QueuedActions),
+            next_queued_action_id: 0,
+            roles: IterableMap::new(StorageKey::Roles),
+            registration_paused: false,
+            signing_paused: false,
+            code_hash: code_hash.map(CryptoHash::from),
+            previous_code_hash: None,
+            code_state_version: migration::STATE_VERSION,
+            previous_code_state_version: None,
+            pending_upgrade: None,
+            allowlist_history: Vector::new(StorageKey::AllowlistHistory),
+            measurements_history: LookupMap::new(StorageKey::MeasurementsHistory),
+            ppid_history: LookupMap::new(StorageKey::PpidHistory),
+            prune_reward_bps: 0,
+            prune_cursor: 0,
+            storage_balances: LookupMap::new(StorageKey::StorageBalances),
+            agent_storage_usage: 0,
+            rate_counters_storage_usage: 0,
+            // What the MPC contract required at the time of writing
+            sign_gas_tgas: 15,
+            sign_deposit: NearToken::from_yoctonear(1),
+            signature_domains: IterableMap::new(StorageKey::SignatureDomains),
+            ckd_domain_id: None,
+            evm_policies: LookupMap::new(StorageKey::EvmPolicies),
+            bitcoin_policies: LookupMap::new(StorageKey::BitcoinPolicies),
+            solana_policies: LookupMap::new(StorageKey::SolanaPolicies),
+            rate_limits: None,
+            rate_counters: RateCounters::default(),
+            agent_rate_counters: LookupMap::new(StorageKey::AgentRateCounters),
+            path_policies: LookupMap::new(StorageKey::PathPolicies),
+            agent_path_policies: LookupMap::new(StorageKey::AgentPathPolicies),
+        };
+        // The domains the MPC contract started with, sync_signature_domains picks up later changes
+        contract.signature_domains.insert(
+            SignatureScheme::Secp256k1,
+            SignatureDomain {
+                domain_id: 0,
+                payload_format: PayloadFormat::Ecdsa,
+            },
+        );
+        contract.signature_domains.insert(
+            SignatureScheme::Ed25519,
+            SignatureDomain {
+                domain_id: 1,
+                payload_format: PayloadFormat::Eddsa,
+            },
+        );
+        contract.measure_agent_storage_usage();
+        contract
     }
 
     // Register an agent, this needs to be called by the agent itself
     #[payable]
     pub fn register_agent(&mut self, attestation: DstackAttestation) -> bool {
+        require!(!self.registration_paused, "Agent registration is paused");
         let predecessor = env::predecessor_account_id();
-        let already_registered = self.agents.get(&predecessor).is_some();
+        self.migrate_legacy_agent(&predecessor);
+
+        // The attached deposit is added to the agent's storage balance
+        let deposit = env::attached_deposit();
+        if !deposit.is_zero() {
+            self.internal_storage_deposit(&predecessor, deposit);
+        }
 
         // New agents must cover storage; re-registration only updates existing state (no extra storage)
-        if !already_registered {
-            // You should update the STORAGE_BYTES_TO_REGISTER const if you store more data
-            let storage_cost = env::storage_byte_cost()
-                .checked_mul(STORAGE_BYTES_TO_REGISTER)
-                .unwrap();
+        if !self.agents.contains_key(&predecessor) {
+            let storage_cost = self.agent_storage_cost();
             require!(
-                env::attached_deposit() >= storage_cost,
+                self.storage_balances
+                    .get(&predecessor)
+                    .is_some_and(|balance| *balance >= storage_cost),
                 &format!(
-                    "Attached deposit must be greater than storage cost {:?}",
+                    "Storage balance must cover storage cost {:?}, attach a deposit or call storage_deposit first",
                     storage_cost.exact_amount_display()
                 )
             );
         }
 
         // Verify the attestation and get the measurements and PPID for the agent
-        let (measurements, ppid, advisory_ids) = self.verify_attestation(attestation);
+        let (measurements_id, ppid, advisory_ids) = self.verify_attestation(attestation);
+
+        // Measurements scheduled for sunset only keep the agents already running them
+        let previous_measurements_id = self
+            .agents
+            .get(&predecessor)
+            .map(|agent| agent.measurements_id);
+        require!(
+            self.approved_measurements[&measurements_id]
+                .sunset_at_ms
+                .is_none()
+                || previous_measurements_id == Some(measurements_id),
+            "Measurements are scheduled for sunset and do not accept new registrations"
+        );
+
+        // Agents moving onto these measurements count towards their quota
+        if previous_measurements_id != Some(measurements_id) {
+            self.add_agent_to_measurements(measurements_id);
+            if let Some(previous_measurements_id) = previous_measurements_id {
+                self.release_agent_from_measurements(previous_measurements_id);
+            }
+        }
 
         let valid_until_ms = block_timestamp_ms() + self.attestation_expiration_time_ms;
         let (advisory_ids_truncated, number_of_advisory_ids) =
@@ -110,7 +466,8 @@ impl Contract {
 
         Event::AgentRegistered {
             account_id: &predecessor,
-            measurements: &measurements,
+            measurements_id,
+            measurements: &self.approved_measurements[&measurements_id].measurements,
             ppid: &ppid,
             advisory_ids_truncated,
             number_of_advisory_ids,
@@ -123,7 +480,7 @@ impl Contract {
         self.agents.insert(
             predecessor,
             Agent {
-                measurements,
+                measurements_id,
                 ppid,
                 valid_until_ms,
             },
@@ -131,4 +488,39 @@ impl Contract {
 
         true
     }
+
+    // Remove measurements whose sunset time has passed, anyone can call this
+    // Agents on these measurements are reported as MeasurementsSunset from then on
+    pub fn execute_measurements_sunset(&mut self, measurements_id: MeasurementsId) {
+        let sunset_at_ms = self
+            .approved_measurements
+            .get(&measurements_id)
+            .expect("Measurements not in approved list")
+            .sunset_at_ms
+            .expect("Measurements are not scheduled for sunset");
+        require!(
+            sunset_at_ms <= block_timestamp_ms(),
+            "Measurements sunset time has not been reached"
+        );
+        self.internal_execute_measurements_sunset(measurements_id);
+    }
+
+    // Check up to limit agents and remove the invalid ones, anyone can call this
+    // Each removed agent gets its storage balance back, less the prune reward which goes to the caller
+    // Checking continues where the previous call stopped, returns the number of agents removed
+    pub fn prune_agents(&mut self, limit: u32) -> u32 {
+        require!(limit > 0, "Limit must be greater than zero");
+        let (removed, reward) = self.internal_prune_agents(limit);
+        let account_id = env::predecessor_account_id();
+        if !reward.is_zero() {
+            Promise::new(account_id.clone()).transfer(reward).detach();
+        }
+        Event::AgentsPruned {
+            account_id: &account_id,
+            removed,
+            reward,
+        }
+        .emit();
+        removed
+    }
 }
@@ -0,0 +1,231 @@
+use crate::*;
+
+// Version of the state layout written by this code
+// When the layout changes, bump it, keep the old layout below and add a step to migrate
+// Bumping it rules out rolling back to older code, whose migrate refuses the newer state
+pub(crate) const STATE_VERSION: u32 = 2;
+
+// Stored outside of the contract state so it can be read before the state is deserialized
+// State without this key was written before versioning and is version 1
+const STATE_VERSION_KEY: &[u8] = b"STATE_VERSION";
+
+// Number of agents migrate moves itself, the rest are moved by calling migrate_agents
+const MIGRATE_AGENTS_BATCH: u32 = 25;
+
+// Storage version 1 charged each agent for on registration
+const V1_STORAGE_BYTES_TO_REGISTER: u128 = 486;
+
+// The contract state in the version 1 layout
+#[near(serializers = [borsh])]
+pub(crate) struct ContractV1 {
+    pub requires_tee: bool,
+    pub attestation_expiration_time_ms: u64,
+    pub owner_id: AccountId,
+    pub mpc_contract_id: AccountId,
+    pub approved_measurements: IterableSet<FullMeasurementsHex>,
+    pub approved_ppids: IterableSet<Ppid>,
+    pub agents: IterableMap<AccountId, AgentV1>,
+    pub whitelisted_agents_for_local: IterableSet<AccountId>,
+}
+
+#[near]
+impl Contract {
+    // Upgrade the stored state to the current layout one version at a time
+    // Called by upload_code after the new code is deployed
+    // Agents are moved in batches, those left after the first batch are moved by migrate_agents
+    #[init(ignore_state)]
+    #[private]
+    pub fn migrate() -> Self {
+        let from_version = read_state_version();
+        require!(
+            from_version <= STATE_VERSION,
+            format!("State version {from_version} is newer than the contract code")
+        );
+
+        let mut version = from_version;
+        while version < STATE_VERSION {
+            match version {
+                1 => migrate_v1_to_v2(),
+                _ => env::panic_str(&format!("No migration from state version {version}")),
+            }
+            version += 1;
+        }
+        write_state_version(STATE_VERSION);
+
+        let mut contract: Contract = env::state_read().expect("Contract state not found");
+        // The new code may store agents differently, new agents pay for the new size
+        contract.measure_agent_storage_usage();
+        if from_version < STATE_VERSION {
+            Event::StateMigrated {
+                from_version,
+                to_version: STATE_VERSION,
+            }
+            .emit();
+        }
+        if contract.legacy_agents.is_some() {
+            contract.internal_migrate_agents(MIGRATE_AGENTS_BATCH);
+        }
+        contract
+    }
+
+    // Move up to limit agents from the version 1 layout, anyone can call this until none are left
+    // Returns the number of agents still waiting to be migrated
+    pub fn migrate_agents(&mut self, limit: u32) -> u32 {
+        require!(
+            self.legacy_agents.is_some(),
+            "There are no agents to migrate"
+        );
+        require!(limit > 0, "Limit must be greater than zero");
+        self.internal_migrate_agents(limit)
+    }
+}
+
+impl Contract {
+    // Move an agent out of the version 1 layout if it has not been migrated yet
+    // Agents whose measurements are no longer approved cannot be given an ID and are removed
+    // Version 1 did not record deposits, agents had to attach the version 1 storage cost
+    // which becomes their storage balance
+    pub(crate) fn migrate_legacy_agent(&mut self, account_id: &AccountId) {
+        let Some(agent) = self
+            .legacy_agents
+            .as_mut()
+            .and_then(|legacy_agents| legacy_agents.remove(account_id))
+        else {
+            return;
+        };
+
+        match self.measurements_ids.get(&agent.measurements).copied() {
+            Some(measurements_id) => {
+                // Agents registered before the upgrade are exempt from quotas, a quota set since then
+                // can be exceeded by the agents still waiting for migration
+                self.approved_measurements
+                    .get_mut(&measurements_id)
+                    .expect("Measurements not in approved list")
+                    .active_agents += 1;
+                self.agents.insert(
+                    account_id.clone(),
+                    Agent {
+                        measurements_id,
+                        ppid: agent.ppid,
+                        valid_until_ms: agent.valid_until_ms,
+                    },
+                );
+                self.internal_storage_deposit(account_id, v1_storage_cost());
+            }
+            None => {
+                Event::AgentRemoved {
+                    account_id,
+                    reasons: vec![AgentRemovalReason::InvalidMeasurements],
+                }
+                .emit();
+                self.refund_storage_deposit(account_id, v1_storage_cost());
+            }
+        }
+    }
+
+    // An agent that has not been migrated yet as it will look once it is
+    pub(crate) fn legacy_agent(&self, account_id: &AccountId) -> Option<Agent> {
+        let agent = self.legacy_agents.as_ref()?.get(account_id)?;
+        Some(Agent {
+            measurements_id: *self.measurements_ids.get(&agent.measurements)?,
+            ppid: agent.ppid.clone(),
+            valid_until_ms: agent.valid_until_ms,
+        })
+    }
+
+    // Migrate up to limit agents and return how many are left
+    fn internal_migrate_agents(&mut self, limit: u32) -> u32 {
+        let account_ids: Vec<AccountId> = self
+            .legacy_agents
+            .as_ref()
+            .map(|legacy_agents| legacy_agents.keys().take(limit as usize).cloned().collect())
+            .unwrap_or_default();
+        for account_id in &account_ids {
+            self.migrate_legacy_agent(account_id);
+        }
+
+        let remaining = self
+            .legacy_agents
+            .as_ref()
+            .map_or(0, |legacy_agents| legacy_agents.len());
+        if remaining == 0 {
+            self.legacy_agents = None;
+        }
+
+        Event::AgentsMigrated {
+            migrated: account_ids.len() as u32,
+            remaining,
+        }
+        .emit();
+        remaining
+    }
+
+    // Number of agents still stored in the version 1 layout
+    pub(crate) fn agents_pending_migration(&self) -> u32 {
+        self.legacy_agents
+            .as_ref()
+            .map_or(0, |legacy_agents| legacy_agents.len())
+    }
+}
+
+pub(crate) fn read_state_version() -> u32 {
+    env::storage_read(STATE_VERSION_KEY).map_or(1, |bytes| {
+        u32::from_le_bytes(bytes.try_into().expect("Invalid state version"))
+    })
+}
+
+pub(crate) fn write_state_version(version: u32) {
+    env::storage_write(STATE_VERSION_KEY, &version.to_le_bytes());
+}
+
+// Version 2 gives measurements IDs and metadata, adds PPID metadata and the owner features
+// Approved measurements and PPIDs are moved here, agents stay under their old prefix until migrated
+// Migrated entries have empty metadata and are recorded as approved by the owner
+fn migrate_v1_to_v2() {
+    let mut old: ContractV1 = env::state_read().expect("Contract state not found");
+
+    let mut contract = Contract::new(
+        old.requires_tee,
+        U64::from(old.attestation_expiration_time_ms),
+        old.owner_id.clone(),
+        old.mpc_contract_id.clone(),
+        // The version 1 code can't read this state, so there is nothing to roll back to
+        None,
+    );
+
+    for measurements in old.approved_measurements.iter() {
+        contract.internal_approve_measurements(
+            measurements.clone(),
+            None,
+            None,
+            old.owner_id.clone(),
+        );
+    }
+    old.approved_measurements.clear();
+
+    contract.apply_owner_action(
+        OwnerAction::ApprovePpids {
+            ppids: old.approved_ppids.iter().cloned().collect(),
+            operator: None,
+            region: None,
+            expires_at_ms: None,
+        },
+        old.owner_id.clone(),
+    );
+    old.approved_ppids.clear();
+
+    // The whitelist kept its prefix and layout
+    contract.whitelisted_agents_for_local = old.whitelisted_agents_for_local;
+    if !old.agents.is_empty() {
+        contract.legacy_agents = Some(old.agents);
+    }
+
+    env::state_write(&contract);
+}
+
+// Deposit an agent attached to register in version 1
+fn v1_storage_cost() -> NearToken {
+    env::storage_byte_cost()
+        .checked_mul(V1_STORAGE_BYTES_TO_REGISTER)
+        .expect("Storage cost overflow")
+}
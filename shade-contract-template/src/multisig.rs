@@ -0,0 +1,148 @@
+use crate::*;
+
+// Multisig owner mode, privileged actions become proposals that execute once enough owners confirm
+#[near]
+impl Contract {
+    // Switch from the single owner to a set of owners with an M-of-N threshold
+    // Later changes to the owners or threshold have to go through a SetMultisig proposal
+    pub fn enable_multisig(
+        &mut self,
+        owners: Vec<AccountId>,
+        threshold: u32,
+        proposal_lifetime_ms: U64,
+    ) {
+        self.require_owner();
+        self.run_owner_action(
+            OwnerAction::SetMultisig {
+                owners,
+                threshold,
+                proposal_lifetime_ms,
+            },
+            env::predecessor_account_id(),
+        );
+    }
+
+    // Propose a privileged action, the proposer's confirmation is counted straight away
+    pub fn propose(&mut self, action: OwnerAction) -> U64 {
+        let proposer = env::predecessor_account_id();
+        let multisig = self.require_multisig_owner(&proposer);
+
+        let proposal_id = self.next_proposal_id;
+        self.next_proposal_id += 1;
+        let expires_at_ms = block_timestamp_ms() + multisig.proposal_lifetime_ms;
+
+        Event::ProposalCreated {
+            proposal_id: U64::from(proposal_id),
+            proposer: &proposer,
+            action: action.name(),
+            expires_at_ms: U64::from(expires_at_ms),
+        }
+        .emit();
+
+        self.proposals.insert(
+            proposal_id,
+            Proposal {
+                action,
+                proposer: proposer.clone(),
+                confirmations: vec![],
+                created_at_ms: block_timestamp_ms(),
+                expires_at_ms,
+            },
+        );
+        self.internal_confirm_proposal(proposal_id, proposer);
+        U64::from(proposal_id)
+    }
+
+    // Confirm a proposal, the action executes once the threshold is reached
+    // Returns true if the proposal was executed
+    pub fn confirm_proposal(&mut self, proposal_id: U64) -> bool {
+        let account_id = env::predecessor_account_id();
+        self.require_multisig_owner(&account_id);
+        self.internal_confirm_proposal(proposal_id.0, account_id)
+    }
+
+    // Cancel a proposal, the proposer can cancel at any time and anyone can clear an expired one
+    pub fn cancel_proposal(&mut self, proposal_id: U64) {
+        let proposal = self
+            .proposals
+            .get(&proposal_id.0)
+            .expect("Proposal not found");
+        require!(
+            proposal.proposer == env::predecessor_account_id()
+                || proposal.expires_at_ms <= block_timestamp_ms(),
+            "Only the proposer can cancel a proposal before it expires"
+        );
+        self.proposals.remove(&proposal_id.0);
+
+        Event::ProposalCancelled {
+            proposal_id,
+            account_id: &env::predecessor_account_id(),
+        }
+        .emit();
+    }
+}
+
+impl Contract {
+    // Require the account to be one of the multisig owners and return the multisig config
+    fn require_multisig_owner(&self, account_id: &AccountId) -> Multisig {
+        let multisig = self.multisig.clone().expect("Multisig is not enabled");
+        require!(
+            multisig.owners.contains(account_id),
+            "Caller is not a multisig owner"
+        );
+        multisig
+    }
+
+    // Add a confirmation and execute the proposal if enough current owners have confirmed
+    fn internal_confirm_proposal(
+        &mut self,
+        proposal_id: ProposalId,
+        account_id: AccountId,
+    ) -> bool {
+        let multisig = self.multisig.clone().expect("Multisig is not enabled");
+        let proposal = self
+            .proposals
+            .get_mut(&proposal_id)
+            .expect("Proposal not found");
+        require!(
+            proposal.expires_at_ms > block_timestamp_ms(),
+            "Proposal has expired"
+        );
+        require!(
+            !proposal.confirmations.contains(&account_id),
+            "Proposal already confirmed by this owner"
+        );
+        proposal.confirmations.push(account_id.clone());
+
+        // Owners removed since they confirmed no longer count
+        let confirmations = proposal
+            .confirmations
+            .iter()
+            .filter(|confirmation| multisig.owners.contains(confirmation))
+            .count() as u32;
+
+        Event::ProposalConfirmed {
+            proposal_id: U64::from(proposal_id),
+            account_id: &account_id,
+            confirmations,
+            threshold: multisig.threshold,
+        }
+        .emit();
+
+        if confirmations < multisig.threshold {
+            return false;
+        }
+
+        // Executed proposals are removed
+        let proposal = self.proposals.remove(&proposal_id).unwrap();
+        Event::ProposalExecuted {
+            proposal_id: U64::from(proposal_id),
+            action: proposal.action.name(),
+        }
+        .emit();
+
+        // A failing action panics, reverting the confirmation so the proposal stays pending
+        self.run_owner_action(proposal.action, proposal.proposer);
+        true
+    }
+}
@@ -1,101 +1,375 @@
 use crate::*;
 
+// Each method runs the matching OwnerAction and requires the owner or the action's role
+// In multisig mode the owner's actions go through proposals instead
+// Security-critical actions are queued instead of applied while the timelock is enabled
 #[near]
 impl Contract {
     // Update the attestation expiration time
     pub fn update_attestation_expiration_time(&mut self, attestation_expiration_time_ms: U64) {
-        self.require_owner();
-        self.attestation_expiration_time_ms = attestation_expiration_time_ms.into();
+        self.run_caller_action(OwnerAction::UpdateAttestationExpirationTime {
+            attestation_expiration_time_ms,
+        });
     }
 
     // Update the owner account ID
     pub fn update_owner_id(&mut self, owner_id: AccountId) {
-        self.require_owner();
-        self.owner_id = owner_id;
+        self.run_caller_action(OwnerAction::UpdateOwnerId { owner_id });
     }
 
     // Update the MPC contract ID
     pub fn update_mpc_contract_id(&mut self, mpc_contract_id: AccountId) {
-        self.require_owner();
-        self.mpc_contract_id = mpc_contract_id;
+        self.run_caller_action(OwnerAction::UpdateMpcContractId { mpc_contract_id });
     }
 
-    // Add a new set of measurements to the approved list
-    pub fn approve_measurements(&mut self, measurements: FullMeasurementsHex) {
-        self.require_owner();
-        self.approved_measurements.insert(measurements);
+    // Set the gas in TGas and the deposit forwarded to the MPC contract with each sign call
+    // Agents have to attach at least sign_deposit to request_signature
+    pub fn set_sign_config(&mut self, sign_gas_tgas: u64, sign_deposit: NearToken) {
+        self.run_caller_action(OwnerAction::SetSignConfig {
+            sign_gas_tgas,
+            sign_deposit,
+        });
+    }
+
+    // Sign scheme with the MPC domain domain_id, passing payloads in payload_format
+    // sync_signature_domains runs the same action for the domains the MPC contract lists
+    pub fn set_signature_domain(
+        &mut self,
+        scheme: SignatureScheme,
+        domain_id: u64,
+        payload_format: PayloadFormat,
+    ) {
+        self.run_caller_action(OwnerAction::SetSignatureDomain {
+            scheme,
+            domain: Some(SignatureDomain {
+                domain_id,
+                payload_format,
+            }),
+        });
+    }
+
+    // Stop signing with scheme
+    pub fn remove_signature_domain(&mut self, scheme: SignatureScheme) {
+        self.run_caller_action(OwnerAction::SetSignatureDomain {
+            scheme,
+            domain: None,
+        });
+    }
+
+    // Limit how many signatures and how much value agents can request per rolling window
+    pub fn set_rate_limits(&mut self, rate_limits: RateLimits) {
+        self.run_caller_action(OwnerAction::SetRateLimits {
+            rate_limits: Some(rate_limits),
+        });
+    }
+
+    // Remove the limits on signature requests
+    pub fn remove_rate_limits(&mut self) {
+        self.run_caller_action(OwnerAction::SetRateLimits { rate_limits: None });
+    }
+
+    // Restrict the derivation paths agents on a set of measurements can sign with, or namespace them
+    pub fn set_path_policy(&mut self, measurements_id: MeasurementsId, policy: PathPolicy) {
+        self.run_caller_action(OwnerAction::SetPathPolicy {
+            measurements_id,
+            policy: Some(policy),
+        });
+    }
+
+    // Let agents on a set of measurements sign with any derivation path again
+    pub fn remove_path_policy(&mut self, measurements_id: MeasurementsId) {
+        self.run_caller_action(OwnerAction::SetPathPolicy {
+            measurements_id,
+            policy: None,
+        });
+    }
+
+    // Set the path policy of a single agent, which replaces the policy of its measurements
+    pub fn set_agent_path_policy(&mut self, account_id: AccountId, policy: PathPolicy) {
+        self.run_caller_action(OwnerAction::SetAgentPathPolicy {
+            account_id,
+            policy: Some(policy),
+        });
+    }
+
+    // Go back to the path policy of the agent's measurements
+    pub fn remove_agent_path_policy(&mut self, account_id: AccountId) {
+        self.run_caller_action(OwnerAction::SetAgentPathPolicy {
+            account_id,
+            policy: None,
+        });
+    }
+
+    // Derive confidential keys with the MPC domain domain_id
+    pub fn set_ckd_domain(&mut self, domain_id: u64) {
+        self.run_caller_action(OwnerAction::SetCkdDomain {
+            domain_id: Some(domain_id),
+        });
+    }
+
+    // Stop confidential key derivation
+    pub fn remove_ckd_domain(&mut self) {
+        self.run_caller_action(OwnerAction::SetCkdDomain { domain_id: None });
+    }
+
+    // Add a new set of measurements to the approved list and return its ID
+    // Approving measurements that are already approved keeps the existing entry and ID
+    // Returns None if the approval was queued behind the timelock
+    pub fn approve_measurements(
+        &mut self,
+        measurements: FullMeasurementsHex,
+        label: Option<String>,
+        source: Option<String>,
+    ) -> Option<MeasurementsId> {
+        let applied = self.run_caller_action(OwnerAction::ApproveMeasurements {
+            measurements: measurements.clone(),
+            label,
+            source,
+        });
+        // The ID is only given once the approval is applied
+        applied.then(|| {
+            *self
+                .measurements_ids
+                .get(&measurements)
+                .expect("Measurements not in approved list")
+        })
+    }
+
+    // Update the label and source of an approved set of measurements
+    pub fn update_measurements_metadata(
+        &mut self,
+        measurements_id: MeasurementsId,
+        label: Option<String>,
+        source: Option<String>,
+    ) {
+        self.run_caller_action(OwnerAction::UpdateMeasurementsMetadata {
+            measurements_id,
+            label,
+            source,
+        });
     }
 
     // Remove a set of measurements from the approved list
     pub fn remove_measurements(&mut self, measurements: FullMeasurementsHex) {
-        self.require_owner();
-        require!(
-            self.approved_measurements.remove(&measurements),
-            "Measurements not in approved list"
-        );
-    }
-
-    // Add an array of PPIDs to the approved list
-    pub fn approve_ppids(&mut self, ppids: Vec<Ppid>) {
-        self.require_owner();
-        for id in ppids {
-            self.approved_ppids.insert(id);
-        }
+        self.run_caller_action(OwnerAction::RemoveMeasurements { measurements });
     }
 
-    // Remove an array of PPIDs from the approved list.
-    pub fn remove_ppids(&mut self, ppids: Vec<Ppid>) {
-        self.require_owner();
-        for id in ppids {
-            require!(self.approved_ppids.remove(&id), "PPID not in approved list");
-        }
+    // Schedule a set of measurements to be retired at sunset_at_ms
+    // Until then agents already on them keep working but new agents cannot register with them
+    pub fn schedule_measurements_sunset(
+        &mut self,
+        measurements_id: MeasurementsId,
+        sunset_at_ms: U64,
+    ) {
+        self.run_caller_action(OwnerAction::ScheduleMeasurementsSunset {
+            measurements_id,
+            sunset_at_ms,
+        });
+    }
+
+    // Cancel a scheduled sunset that has not taken effect yet
+    pub fn cancel_measurements_sunset(&mut self, measurements_id: MeasurementsId) {
+        self.run_caller_action(OwnerAction::CancelMeasurementsSunset { measurements_id });
+    }
+
+    // Limit how many agents can be registered with a set of measurements at once, None removes the limit
+    // Lowering the quota below the current count keeps existing agents but refuses new ones
+    pub fn set_measurements_agent_quota(
+        &mut self,
+        measurements_id: MeasurementsId,
+        max_agents: Option<u32>,
+    ) {
+        self.run_caller_action(OwnerAction::SetMeasurementsAgentQuota {
+            measurements_id,
+            max_agents,
+        });
+    }
+
+    // Limit a set of measurements to the PPIDs of a named group, None allows any approved PPID
+    pub fn set_measurements_ppid_group(
+        &mut self,
+        measurements_id: MeasurementsId,
+        ppid_group: Option<String>,
+    ) {
+        self.run_caller_action(OwnerAction::SetMeasurementsPpidGroup {
+            measurements_id,
+            ppid_group,
+        });
+    }
+
+    // Allow agents on a set of measurements to sign EVM transactions that meet the policy
+    pub fn set_evm_policy(&mut self, measurements_id: MeasurementsId, policy: EvmPolicy) {
+        self.run_caller_action(OwnerAction::SetEvmPolicy {
+            measurements_id,
+            policy: Some(policy),
+        });
+    }
+
+    // Stop agents on a set of measurements from signing EVM transactions
+    pub fn remove_evm_policy(&mut self, measurements_id: MeasurementsId) {
+        self.run_caller_action(OwnerAction::SetEvmPolicy {
+            measurements_id,
+            policy: None,
+        });
+    }
+
+    // Allow agents on a set of measurements to sign Bitcoin transactions whose outputs meet the policy
+    pub fn set_bitcoin_policy(&mut self, measurements_id: MeasurementsId, policy: BitcoinPolicy) {
+        self.run_caller_action(OwnerAction::SetBitcoinPolicy {
+            measurements_id,
+            policy: Some(policy),
+        });
+    }
+
+    // Stop agents on a set of measurements from signing Bitcoin transactions
+    pub fn remove_bitcoin_policy(&mut self, measurements_id: MeasurementsId) {
+        self.run_caller_action(OwnerAction::SetBitcoinPolicy {
+            measurements_id,
+            policy: None,
+        });
+    }
+
+    // Allow agents on a set of measurements to sign Solana messages that meet the policy
+    pub fn set_solana_policy(&mut self, measurements_id: MeasurementsId, policy: SolanaPolicy) {
+        self.run_caller_action(OwnerAction::SetSolanaPolicy {
+            measurements_id,
+            policy: Some(policy),
+        });
+    }
+
+    // Stop agents on a set of measurements from signing Solana messages
+    pub fn remove_solana_policy(&mut self, measurements_id: MeasurementsId) {
+        self.run_caller_action(OwnerAction::SetSolanaPolicy {
+            measurements_id,
+            policy: None,
+        });
+    }
+
+    // Create or replace a named group of PPIDs
+    pub fn set_ppid_group(&mut self, name: String, ppids: Vec<Ppid>) {
+        self.run_caller_action(OwnerAction::SetPpidGroup { name, ppids });
+    }
+
+    // Remove a named group of PPIDs that no measurements are limited to
+    pub fn remove_ppid_group(&mut self, name: String) {
+        self.run_caller_action(OwnerAction::RemovePpidGroup { name });
+    }
+
+    // Limit a PPID to certain sets of measurements, None allows any approved measurements
+    pub fn set_ppid_measurements_scope(
+        &mut self,
+        ppid: Ppid,
+        measurements_ids: Option<Vec<MeasurementsId>>,
+    ) {
+        self.run_caller_action(OwnerAction::SetPpidMeasurementsScope {
+            ppid,
+            measurements_ids,
+        });
+    }
+
+    // Add an array of PPIDs to the approved list with the operator, region and optional expiry
+    // PPIDs that are already approved keep their existing entry
+    pub fn approve_ppids(
+        &mut self,
+        ppids: Vec<Ppid>,
+        operator: Option<String>,
+        region: Option<String>,
+        expires_at_ms: Option<U64>,
+    ) {
+        self.run_caller_action(OwnerAction::ApprovePpids {
+            ppids,
+            operator,
+            region,
+            expires_at_ms,
+        });
+    }
+
+    // Replace the operator, region and expiry of an approved PPID
+    pub fn update_ppid_metadata(
+        &mut self,
+        ppid: Ppid,
+        operator: String,
+        region: String,
+        expires_at_ms: Option<U64>,
+    ) {
+        self.run_caller_action(OwnerAction::UpdatePpidMetadata {
+            ppid,
+            operator,
+            region,
+            expires_at_ms,
+        });
+    }
+
+    // Remove an array of PPIDs from the approved list, recording why they were revoked
+    pub fn remove_ppids(&mut self, ppids: Vec<Ppid>, reason: Option<PpidRevocationReason>) {
+        self.run_caller_action(OwnerAction::RemovePpids { ppids, reason });
     }
 
     // Remove an agent from the registered list
     pub fn remove_agent(&mut self, account_id: AccountId) {
-        self.require_owner();
-        require!(
-            self.agents.remove(&account_id).is_some(),
-            "Agent not registered"
-        );
-        Event::AgentRemoved {
-            account_id: &account_id,
-            reasons: vec![AgentRemovalReason::ManualRemoval],
-        }
-        .emit();
+        self.run_caller_action(OwnerAction::RemoveAgent { account_id });
     }
 
-    // Function to update the contract code
-    // Input format: [gas_bytes (8 bytes u64 little-endian)] + [wasm_code_bytes...]
-    // See tests/update_contract_tests.rs for an example of how to call this function
-    pub fn update_contract(&mut self) -> Promise {
-        self.require_owner();
+    // Set the share of a pruned agent's storage deposit paid to the caller of prune_agents, in basis points
+    pub fn set_prune_reward(&mut self, prune_reward_bps: u16) {
+        self.run_caller_action(OwnerAction::SetPruneReward { prune_reward_bps });
+    }
 
-        let input = env::input().expect("Error: No input").to_vec();
+    // Set how long security-critical actions wait in the queue before anyone can execute them, 0 disables the timelock
+    pub fn set_timelock_delay(&mut self, timelock_delay_ms: U64) {
+        self.run_caller_action(OwnerAction::SetTimelockDelay { timelock_delay_ms });
+    }
 
-        require!(
-            input.len() >= 8,
-            "Input must be at least 8 bytes: first 8 bytes are gas (u64), followed by WASM code"
-        );
+    // Cancel a queued action before it is executed, this requires the role of the queued action
+    pub fn cancel_queued_action(&mut self, action_id: U64) {
+        self.run_caller_action(OwnerAction::CancelQueuedAction { action_id });
+    }
 
-        // First 8 bytes are gas (u64 in little-endian)
-        let gas_bytes: [u8; 8] = input[0..8].try_into().unwrap();
-        let gas_tgas = u64::from_le_bytes(gas_bytes);
+    // Pause agent registration or signing, agent records are kept
+    pub fn pause(&mut self, feature: PausableFeature) {
+        self.run_caller_action(OwnerAction::SetPaused {
+            feature,
+            paused: true,
+        });
+    }
 
-        // Rest is the WASM code
-        let code = input[8..].to_vec();
+    // Resume agent registration or signing
+    pub fn unpause(&mut self, feature: PausableFeature) {
+        self.run_caller_action(OwnerAction::SetPaused {
+            feature,
+            paused: false,
+        });
+    }
+
+    // Grant a role to an account
+    pub fn grant_role(&mut self, account_id: AccountId, role: Role) {
+        self.run_caller_action(OwnerAction::GrantRole { account_id, role });
+    }
+
+    // Revoke a role from an account
+    pub fn revoke_role(&mut self, account_id: AccountId, role: Role) {
+        self.run_caller_action(OwnerAction::RevokeRole { account_id, role });
+    }
+
+    // Approve the SHA-256 of new contract code, for example from a reproducible build
+    // Anyone can then deploy the code with upload_code, migrate is called with migrate_gas_tgas
+    pub fn approve_code_hash(&mut self, code_hash: Base58CryptoHash, migrate_gas_tgas: u64) {
+        self.run_caller_action(OwnerAction::ApproveCodeHash {
+            code_hash,
+            migrate_gas_tgas,
+        });
+    }
 
-        require!(!code.is_empty(), "WASM code cannot be empty");
+    // Approve the code that was deployed before the current one so it can be uploaded again
+    pub fn rollback_code(&mut self, migrate_gas_tgas: u64) {
+        // Refuse before queuing a rollback that could never be deployed
+        self.require_rollback_possible();
+        self.run_caller_action(OwnerAction::RollbackCode { migrate_gas_tgas });
+    }
 
-        Promise::new(env::current_account_id())
-            .deploy_contract(code)
-            .function_call(
-                "migrate".to_string(),
-                b"".to_vec(),
-                NearToken::from_near(0),
-                Gas::from_tgas(gas_tgas),
-            )
-            .as_return()
+    // Cancel the pending upgrade before its code is uploaded
+    pub fn cancel_code_upgrade(&mut self) {
+        self.run_caller_action(OwnerAction::CancelCodeUpgrade);
     }
 
     // Local only functions
@@ -105,9 +379,7 @@ impl Contract {
         if self.requires_tee {
             panic!("Whitelisting agents is not supported for TEE");
         }
-        self.require_owner();
-        // Only insert if not already whitelisted
-        self.whitelisted_agents_for_local.insert(account_id);
+        self.run_caller_action(OwnerAction::WhitelistAgentForLocal { account_id });
     }
 
     // Remove an agent from the list of whitelisted agents
@@ -115,10 +387,6 @@ impl Contract {
         if self.requires_tee {
             panic!("Removing agents from the whitelist is not supported for TEE");
         }
-        self.require_owner();
-        require!(
-            self.whitelisted_agents_for_local.remove(&account_id),
-            "Agent not in whitelist for local"
-        );
+        self.run_caller_action(OwnerAction::RemoveAgentFromWhitelistForLocal { account_id });
     }
 }
@@ -0,0 +1,261 @@
+use crate::*;
+use internal::helpers::BPS_DENOMINATOR;
+
+// Longest valid account ID, used to measure the storage an agent can take up
+const MAX_ACCOUNT_ID_LEN: usize = 64;
+
+// NEP-145 storage balance of an account
+#[near(serializers = [json])]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StorageBalance {
+    pub total: NearToken,
+    // Amount that can be withdrawn, the agent storage cost stays locked while the account is registered
+    pub available: NearToken,
+}
+
+#[near(serializers = [json])]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StorageBalanceBounds {
+    pub min: NearToken,
+    pub max: Option<NearToken>,
+}
+
+// NEP-145 storage management, agents pay for their own storage and get their balance back when they leave
+#[near]
+impl Contract {
+    // Add the attached deposit to the storage balance of account_id, or of the caller
+    // With registration_only, only the storage cost is kept for a new account and the rest is refunded
+    #[payable]
+    pub fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance {
+        let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        let mut deposit = env::attached_deposit();
+        let registered = self.storage_balances.contains_key(&account_id);
+
+        if !registered {
+            let storage_cost = self.agent_storage_cost();
+            require!(
+                deposit >= storage_cost,
+                format!(
+                    "Attached deposit must be at least the storage cost {}",
+                    storage_cost.exact_amount_display()
+                )
+            );
+        }
+
+        if registration_only.unwrap_or(false) {
+            let kept = if registered {
+                NearToken::from_yoctonear(0)
+            } else {
+                self.agent_storage_cost()
+            };
+            let refund = deposit.saturating_sub(kept);
+            if !refund.is_zero() {
+                Promise::new(env::predecessor_account_id())
+                    .transfer(refund)
+                    .detach();
+            }
+            deposit = kept;
+        }
+
+        if !deposit.is_zero() {
+            self.internal_storage_deposit(&account_id, deposit);
+        }
+        self.storage_balance_of(account_id)
+            .expect("Storage balance not found")
+    }
+
+    // Withdraw amount, or everything available, from the caller's storage balance
+    #[payable]
+    pub fn storage_withdraw(&mut self, amount: Option<NearToken>) -> StorageBalance {
+        require!(
+            env::attached_deposit() == NearToken::from_yoctonear(1),
+            "Requires attached deposit of exactly 1 yoctoNEAR"
+        );
+        let account_id = env::predecessor_account_id();
+        let balance = self
+            .storage_balance_of(account_id.clone())
+            .expect("Account is not registered for storage");
+        let amount = amount.unwrap_or(balance.available);
+        require!(
+            amount <= balance.available,
+            format!(
+                "Amount is more than the available storage balance {}",
+                balance.available.exact_amount_display()
+            )
+        );
+
+        if !amount.is_zero() {
+            self.storage_balances
+                .insert(account_id.clone(), balance.total.saturating_sub(amount));
+            Promise::new(account_id.clone()).transfer(amount).detach();
+            Event::StorageWithdrawn {
+                account_id: &account_id,
+                amount,
+            }
+            .emit();
+        }
+        self.storage_balance_of(account_id)
+            .expect("Storage balance not found")
+    }
+
+    // Close the caller's storage balance and refund it, returns false if there was none
+    // A registered agent has to pass force, which removes the agent as well
+    #[payable]
+    pub fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        require!(
+            env::attached_deposit() == NearToken::from_yoctonear(1),
+            "Requires attached deposit of exactly 1 yoctoNEAR"
+        );
+        let account_id = env::predecessor_account_id();
+        self.migrate_legacy_agent(&account_id);
+        if !self.storage_balances.contains_key(&account_id) {
+            log!("The account {} is not registered for storage", account_id);
+            return false;
+        }
+
+        if self.agents.contains_key(&account_id) {
+            require!(
+                force.unwrap_or(false),
+                "Account is a registered agent, pass force to remove the agent and unregister"
+            );
+            self.internal_remove_agent(&account_id)
+                .expect("Agent not registered");
+            Event::AgentRemoved {
+                account_id: &account_id,
+                reasons: vec![AgentRemovalReason::StorageUnregistered],
+            }
+            .emit();
+        }
+
+        self.internal_unregister_storage(&account_id, 0);
+        true
+    }
+
+    pub fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        StorageBalanceBounds {
+            min: self.agent_storage_cost(),
+            max: None,
+        }
+    }
+
+    pub fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        let total = *self.storage_balances.get(&account_id)?;
+        Some(StorageBalance {
+            total,
+            available: total.saturating_sub(self.account_storage_cost(&account_id)),
+        })
+    }
+}
+
+impl Contract {
+    // Deposit an agent and its storage balance need to cover their storage
+    pub(crate) fn agent_storage_cost(&self) -> NearToken {
+        env::storage_byte_cost()
+            .checked_mul(u128::from(self.agent_storage_usage))
+            .expect("Storage cost overflow")
+    }
+
+    // Storage cost locked in the storage balance of an account, including its rate counters once it has any
+    pub(crate) fn account_storage_cost(&self, account_id: &AccountId) -> NearToken {
+        if self.agent_rate_counters.contains_key(account_id) {
+            self.agent_storage_cost()
+                .saturating_add(self.rate_counters_storage_cost())
+        } else {
+            self.agent_storage_cost()
+        }
+    }
+
+    // Deposit the rate counters of an agent need on top of the agent storage cost
+    pub(crate) fn rate_counters_storage_cost(&self) -> NearToken {
+        env::storage_byte_cost()
+            .checked_mul(u128::from(self.rate_counters_storage_usage))
+            .expect("Storage cost overflow")
+    }
+
+    // Measure the storage of an agent with the longest account ID and its storage balance, and
+    // separately of its largest rate counters, by writing them and removing them again
+    // Called on init and migration so the cost follows the Agent struct without a hard-coded size
+    pub(crate) fn measure_agent_storage_usage(&mut self) {
+        let account_id: AccountId = "a"
+            .repeat(MAX_ACCOUNT_ID_LEN)
+            .parse()
+            .expect("Invalid account ID");
+        let initial_storage_usage = env::storage_usage();
+
+        self.storage_balances
+            .insert(account_id.clone(), NearToken::from_yoctonear(u128::MAX));
+        self.agents.insert(
+            account_id.clone(),
+            Agent {
+                measurements_id: MeasurementsId::MAX,
+                ppid: Ppid::default(),
+                valid_until_ms: u64::MAX,
+            },
+        );
+        self.storage_balances.flush();
+        self.agents.flush();
+        self.agent_storage_usage = env::storage_usage() - initial_storage_usage;
+
+        // Rate counters for every budget, the most an agent's requests can store
+        self.agent_rate_counters
+            .insert(account_id.clone(), RateCounters::largest());
+        self.agent_rate_counters.flush();
+        self.rate_counters_storage_usage =
+            env::storage_usage() - initial_storage_usage - self.agent_storage_usage;
+
+        self.storage_balances.remove(&account_id);
+        self.agents.remove(&account_id);
+        self.agent_rate_counters.remove(&account_id);
+        self.storage_balances.flush();
+        self.agents.flush();
+        self.agent_rate_counters.flush();
+    }
+
+    // Add to the storage balance of an account, registering it if it has none
+    pub(crate) fn internal_storage_deposit(&mut self, account_id: &AccountId, amount: NearToken) {
+        let total = self
+            .storage_balances
+            .get(account_id)
+            .map_or(amount, |balance| balance.saturating_add(amount));
+        self.storage_balances.insert(account_id.clone(), total);
+        Event::StorageDeposited { account_id, amount }.emit();
+    }
+
+    // Close the storage balance of an account that left and refund it, keeping reward_bps of the
+    // storage cost it freed back, the excess it deposited is refunded in full
+    // Its rate counters are removed here so the storage they freed counts towards the reward
+    // Returns the amount kept back
+    pub(crate) fn internal_unregister_storage(
+        &mut self,
+        account_id: &AccountId,
+        reward_bps: u16,
+    ) -> NearToken {
+        let balance = self
+            .storage_balances
+            .remove(account_id)
+            .unwrap_or(NearToken::from_yoctonear(0));
+        let freed_storage_cost = balance.min(self.account_storage_cost(account_id));
+        self.agent_rate_counters.remove(account_id);
+        let reward = NearToken::from_yoctonear(
+            freed_storage_cost.as_yoctonear() * u128::from(reward_bps) / BPS_DENOMINATOR,
+        );
+        self.refund_storage_deposit(account_id, balance.saturating_sub(reward));
+        reward
+    }
+
+    // Send a storage deposit back to an account
+    pub(crate) fn refund_storage_deposit(&self, account_id: &AccountId, refund: NearToken) {
+        if !refund.is_zero() {
+            Promise::new(account_id.clone()).transfer(refund).detach();
+            Event::StorageDepositRefunded {
+                account_id,
+                amount: refund,
+            }
+            .emit();
+        }
+    }
+}
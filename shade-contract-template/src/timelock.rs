@@ -0,0 +1,53 @@
+use crate::*;
+
+// Timelock for security-critical actions, they are queued with an event and anyone can execute them after the delay
+#[near]
+impl Contract {
+    // Execute a queued action once its delay has passed, anyone can call this
+    pub fn execute_queued_action(&mut self, action_id: U64) {
+        let queued = self
+            .queued_actions
+            .remove(&action_id.0)
+            .expect("Queued action not found");
+        require!(
+            queued.executable_at_ms <= block_timestamp_ms(),
+            "Timelock delay has not passed"
+        );
+
+        Event::QueuedActionExecuted {
+            action_id,
+            action: queued.action.name(),
+        }
+        .emit();
+
+        // A failing action panics, leaving it in the queue
+        self.apply_owner_action(queued.action, queued.queued_by);
+    }
+}
+
+impl Contract {
+    // Queue an action to be executable after the current timelock delay
+    pub(crate) fn internal_queue_action(&mut self, action: OwnerAction, queued_by: AccountId) {
+        let action_id = self.next_queued_action_id;
+        self.next_queued_action_id += 1;
+        let executable_at_ms = block_timestamp_ms() + self.timelock_delay_ms;
+
+        Event::ActionQueued {
+            action_id: U64::from(action_id),
+            action: action.name(),
+            queued_by: &queued_by,
+            executable_at_ms: U64::from(executable_at_ms),
+        }
+        .emit();
+
+        self.queued_actions.insert(
+            action_id,
+            QueuedAction {
+                action,
+                queued_by,
+                queued_at_ms: block_timestamp_ms(),
+                executable_at_ms,
+            },
+        );
+    }
+}
@@ -0,0 +1,81 @@
+use crate::*;
+use near_sdk::PromiseError;
+
+// Staged upgrades, the code hash is approved first and the code can then be deployed by anyone
+#[near]
+impl Contract {
+    // Deploy the code of the pending upgrade and call migrate on it, anyone can call this
+    // Input format: the raw WASM code, its SHA-256 must match the approved code hash
+    // See tests/update_contract_tests.rs for an example of how to call this function
+    pub fn upload_code(&mut self) {
+        let code = env::input().expect("Error: No input");
+        let pending_upgrade = self
+            .pending_upgrade
+            .as_ref()
+            .expect("There is no pending upgrade");
+        let code_hash = env::sha256_array(&code);
+        require!(
+            code_hash == pending_upgrade.code_hash,
+            "Code does not match the approved code hash"
+        );
+
+        // The callback runs on the new code if the upgrade succeeded and on this code if it failed
+        Promise::new(env::current_account_id())
+            .deploy_contract(code)
+            .function_call(
+                "migrate".to_string(),
+                vec![],
+                NearToken::from_near(0),
+                Gas::from_tgas(pending_upgrade.migrate_gas_tgas),
+            )
+            .then(
+                Promise::new(env::current_account_id()).function_call(
+                    "on_code_deployed".to_string(),
+                    serde_json::to_vec(&serde_json::json!({
+                        "code_hash": Base58CryptoHash::from(code_hash),
+                    }))
+                    .unwrap(),
+                    NearToken::from_near(0),
+                    Gas::from_tgas(10),
+                ),
+            )
+            .detach();
+    }
+
+    // Record the deployed code hash, a failed deploy or migrate leaves the upgrade pending
+    // New code has to keep this method for the upgrade to be recorded
+    #[private]
+    pub fn on_code_deployed(&mut self, code_hash: Base58CryptoHash) {
+        let code_hash = CryptoHash::from(code_hash);
+        if matches!(env::promise_result_checked(0, 0), Err(PromiseError::Failed)) {
+            Event::ContractUpgradeFailed {
+                code_hash: code_hash.into(),
+            }
+            .emit();
+            return;
+        }
+
+        // The same code may have been uploaded twice
+        if self.code_hash == Some(code_hash) {
+            return;
+        }
+        if self
+            .pending_upgrade
+            .as_ref()
+            .is_some_and(|pending_upgrade| pending_upgrade.code_hash == code_hash)
+        {
+            self.pending_upgrade = None;
+        }
+        self.previous_code_hash = self.code_hash.replace(code_hash);
+        self.previous_code_state_version = Some(std::mem::replace(
+            &mut self.code_state_version,
+            migration::STATE_VERSION,
+        ));
+
+        Event::ContractUpgraded {
+            code_hash: code_hash.into(),
+            previous_code_hash: self.previous_code_hash.map(Base58CryptoHash::from),
+        }
+        .emit();
+    }
+}
@@ -6,6 +6,16 @@ pub struct ContractInfo {
     pub attestation_expiration_time_ms: U64,
     pub owner_id: AccountId,
     pub mpc_contract_id: AccountId,
+    pub timelock_delay_ms: U64,
+    pub registration_paused: bool,
+    pub signing_paused: bool,
+    pub state_version: u32,
+    // Agents from an older state layout still waiting for migrate_agents
+    pub agents_pending_migration: u32,
+    pub prune_reward_bps: u16,
+    pub sign_gas_tgas: u64,
+    pub sign_deposit: NearToken,
+    pub ckd_domain_id: Option<u64>,
 }
 
 #[near(serializers = [json])]
@@ -14,10 +24,126 @@ pub enum AgentValidity {
     Invalid(Vec<AgentRemovalReason>),
 }
 
+#[near(serializers = [json])]
+pub struct ApprovedMeasurementsView {
+    pub measurements_id: MeasurementsId,
+    pub measurements: FullMeasurementsHex,
+    pub label: String,
+    pub source: String,
+    pub approved_by: AccountId,
+    pub approved_at_ms: U64,
+    pub sunset_at_ms: Option<U64>,
+    pub max_agents: Option<u32>,
+    pub active_agents: u32,
+    pub ppid_group: Option<String>,
+}
+
+#[near(serializers = [json])]
+pub struct ApprovedPpidView {
+    pub ppid: Ppid,
+    pub operator: String,
+    pub region: String,
+    pub added_at_ms: U64,
+    pub expires_at_ms: Option<U64>,
+}
+
+#[near(serializers = [json])]
+pub struct PpidGroupView {
+    pub name: String,
+    pub ppids: Vec<Ppid>,
+}
+
+#[near(serializers = [json])]
+pub struct PpidMeasurementsScopeView {
+    pub ppid: Ppid,
+    pub measurements_ids: Vec<MeasurementsId>,
+}
+
+#[near(serializers = [json])]
+pub struct SignatureDomainView {
+    pub scheme: SignatureScheme,
+    pub domain_id: u64,
+    pub payload_format: PayloadFormat,
+}
+
+// Usage over the rolling window of the rate limits
+#[near(serializers = [json])]
+pub struct RateUsageView {
+    pub signatures: U128,
+    pub spent: Vec<AssetUsageView>,
+}
+
+#[near(serializers = [json])]
+pub struct AssetUsageView {
+    pub asset: Asset,
+    pub amount: U128,
+}
+
+#[near(serializers = [json])]
+pub struct MultisigView {
+    pub owners: Vec<AccountId>,
+    pub threshold: u32,
+    pub proposal_lifetime_ms: U64,
+}
+
+#[near(serializers = [json])]
+pub struct ProposalView {
+    pub proposal_id: U64,
+    pub action: OwnerAction,
+    pub proposer: AccountId,
+    pub confirmations: Vec<AccountId>,
+    pub created_at_ms: U64,
+    pub expires_at_ms: U64,
+}
+
+#[near(serializers = [json])]
+pub struct QueuedActionView {
+    pub action_id: U64,
+    pub action: OwnerAction,
+    pub queued_by: AccountId,
+    pub queued_at_ms: U64,
+    pub executable_at_ms: U64,
+}
+
+#[near(serializers = [json])]
+pub struct AccountRolesView {
+    pub account_id: AccountId,
+    pub roles: Vec<Role>,
+}
+
+#[near(serializers = [json])]
+pub struct PendingUpgradeView {
+    pub code_hash: Base58CryptoHash,
+    pub migrate_gas_tgas: u64,
+    pub approved_by: AccountId,
+    pub approved_at_ms: U64,
+}
+
+#[near(serializers = [json])]
+pub struct CodeUpgradeView {
+    // The hash given on init, unchecked against the deployed code, until the first upgrade through upload_code
+    pub code_hash: Option<Base58CryptoHash>,
+    pub previous_code_hash: Option<Base58CryptoHash>,
+    // State version the previous code writes, it can only be rolled back to if it is the current one
+    pub previous_code_state_version: Option<u32>,
+    pub pending_upgrade: Option<PendingUpgradeView>,
+}
+
+#[near(serializers = [json])]
+pub struct AllowlistHistoryEntryView {
+    // Position in the allowlist history
+    pub index: u32,
+    pub change: AllowlistChange,
+    pub changed_by: AccountId,
+    pub changed_at_ms: U64,
+}
+
 #[near(serializers = [json])]
 pub struct AgentView {
     pub account_id: AccountId,
-    pub measurements: FullMeasurementsHex,
+    pub measurements_id: MeasurementsId,
+    // None if the measurements the agent registered with are no longer approved
+    pub measurements: Option<ApprovedMeasurementsView>,
     pub ppid: Ppid,
     pub valid_until_ms: U64,
     pub validity: AgentValidity,
@@ -32,62 +158,191 @@ impl Contract {
             attestation_expiration_time_ms: U64::from(self.attestation_expiration_time_ms),
             owner_id: self.owner_id.clone(),
             mpc_contract_id: self.mpc_contract_id.clone(),
+            timelock_delay_ms: U64::from(self.timelock_delay_ms),
+            registration_paused: self.registration_paused,
+            signing_paused: self.signing_paused,
+            state_version: migration::read_state_version(),
+            agents_pending_migration: self.agents_pending_migration(),
+            prune_reward_bps: self.prune_reward_bps,
+            sign_gas_tgas: self.sign_gas_tgas,
+            sign_deposit: self.sign_deposit,
+            ckd_domain_id: self.ckd_domain_id,
         }
     }
 
-    // Get the list of approved PPIDs (paginated via from_index and limit)
-    pub fn get_approved_ppids(&self, from_index: &Option<u32>, limit: &Option<u32>) -> Vec<Ppid> {
+    // Get the list of approved PPIDs and their metadata (paginated via from_index and limit)
+    pub fn get_approved_ppids(
+        &self,
+        from_index: &Option<u32>,
+        limit: &Option<u32>,
+    ) -> Vec<ApprovedPpidView> {
         let from = from_index.unwrap_or(0);
-        let limit = limit.unwrap_or(self.approved_ppids.len() as u32);
+        let limit = limit.unwrap_or(self.approved_ppids.len());
 
         self.approved_ppids
             .iter()
             .skip(from as usize)
             .take(limit as usize)
-            .cloned()
+            .map(|(ppid, approved)| ApprovedPpidView {
+                ppid: ppid.clone(),
+                operator: approved.operator.clone(),
+                region: approved.region.clone(),
+                added_at_ms: U64::from(approved.added_at_ms),
+                expires_at_ms: approved.expires_at_ms.map(U64::from),
+            })
             .collect()
     }
 
-    // Get the list of approved measurements
+    // Get the list of approved measurements and their metadata (paginated via from_index and limit)
     pub fn get_approved_measurements(
         &self,
         from_index: &Option<u32>,
         limit: &Option<u32>,
-    ) -> Vec<FullMeasurementsHex> {
+    ) -> Vec<ApprovedMeasurementsView> {
         let from = from_index.unwrap_or(0);
-        let limit = limit.unwrap_or(self.approved_measurements.len() as u32);
+        let limit = limit.unwrap_or(self.approved_measurements.len());
 
         self.approved_measurements
             .iter()
             .skip(from as usize)
             .take(limit as usize)
-            .cloned()
+            .map(|(measurements_id, approved)| {
+                ApprovedMeasurementsView::new(*measurements_id, approved)
+            })
+            .collect()
+    }
+
+    // Get an approved set of measurements and its metadata by ID
+    pub fn get_measurements(
+        &self,
+        measurements_id: MeasurementsId,
+    ) -> Option<ApprovedMeasurementsView> {
+        self.approved_measurements
+            .get(&measurements_id)
+            .map(|approved| ApprovedMeasurementsView::new(measurements_id, approved))
+    }
+
+    // Get the named PPID groups that measurements can be limited to
+    pub fn get_ppid_groups(&self) -> Vec<PpidGroupView> {
+        self.ppid_groups
+            .iter()
+            .map(|(name, ppids)| PpidGroupView {
+                name: name.clone(),
+                ppids: ppids.clone(),
+            })
+            .collect()
+    }
+
+    // Get the PPIDs that are limited to certain sets of measurements
+    pub fn get_ppid_measurements_scopes(&self) -> Vec<PpidMeasurementsScopeView> {
+        self.ppid_measurements_scopes
+            .iter()
+            .map(|(ppid, measurements_ids)| PpidMeasurementsScopeView {
+                ppid: ppid.clone(),
+                measurements_ids: measurements_ids.clone(),
+            })
+            .collect()
+    }
+
+    // Get the MPC domain and payload format each signature scheme is signed with
+    pub fn get_signature_domains(&self) -> Vec<SignatureDomainView> {
+        self.signature_domains
+            .iter()
+            .map(|(scheme, domain)| SignatureDomainView {
+                scheme: *scheme,
+                domain_id: domain.domain_id,
+                payload_format: domain.payload_format,
+            })
             .collect()
     }
 
+    // Get the limits on signature requests, None if there are none
+    pub fn get_rate_limits(&self) -> Option<RateLimits> {
+        self.rate_limits.clone()
+    }
+
+    // Get the usage of an agent, or of all agents together without account_id, over the rate limit window
+    // None if there are no rate limits
+    pub fn get_rate_usage(&self, account_id: Option<AccountId>) -> Option<RateUsageView> {
+        let window_ms = self.rate_limits.as_ref()?.window_ms.0;
+        Some(match account_id {
+            Some(account_id) => self
+                .agent_rate_counters
+                .get(&account_id)
+                .cloned()
+                .unwrap_or_default()
+                .view(window_ms),
+            None => self.rate_counters.view(window_ms),
+        })
+    }
+
+    // Get the path policy of a set of measurements, None if its agents can sign with any path
+    pub fn get_path_policy(&self, measurements_id: MeasurementsId) -> Option<PathPolicy> {
+        self.path_policies.get(&measurements_id).cloned()
+    }
+
+    // Get the path policy of an agent, None if the policy of its measurements applies
+    pub fn get_agent_path_policy(&self, account_id: AccountId) -> Option<PathPolicy> {
+        self.agent_path_policies.get(&account_id).cloned()
+    }
+
+    // Get the path a registered agent's request with path is signed with, to derive its public key
+    // Panics if the path is not allowed
+    pub fn get_derivation_path(&self, account_id: AccountId, path: String) -> String {
+        self.internal_derivation_path(&account_id, path)
+    }
+
+    // Get the EVM policy of a set of measurements, None if its agents cannot sign EVM transactions
+    pub fn get_evm_policy(&self, measurements_id: MeasurementsId) -> Option<EvmPolicy> {
+        self.evm_policies.get(&measurements_id).cloned()
+    }
+
+    // Get the Bitcoin policy of a set of measurements, None if its agents cannot sign Bitcoin transactions
+    pub fn get_bitcoin_policy(&self, measurements_id: MeasurementsId) -> Option<BitcoinPolicy> {
+        self.bitcoin_policies.get(&measurements_id).cloned()
+    }
+
+    // Get the Solana policy of a set of measurements, None if its agents cannot sign Solana messages
+    pub fn get_solana_policy(&self, measurements_id: MeasurementsId) -> Option<SolanaPolicy> {
+        self.solana_policies.get(&measurements_id).cloned()
+    }
+
+    // Get the approved measurements that are scheduled for sunset, soonest first
+    pub fn get_measurements_sunsets(&self) -> Vec<ApprovedMeasurementsView> {
+        let mut sunsets: Vec<ApprovedMeasurementsView> = self
+            .approved_measurements
+            .iter()
+            .filter(|(_, approved)| approved.sunset_at_ms.is_some())
+            .map(|(measurements_id, approved)| {
+                ApprovedMeasurementsView::new(*measurements_id, approved)
+            })
+            .collect();
+        sunsets.sort_by_key(|approved| approved.sunset_at_ms.map(|sunset_at_ms| sunset_at_ms.0));
+        sunsets
+    }
+
     // Get the details of a registered agent
+    // Agents that have not been migrated yet are shown as they will be once they are
     pub fn get_agent(&self, account_id: AccountId) -> Option<AgentView> {
-        self.agents.get(&account_id).map(|agent| {
+        let view = |agent: &Agent| {
             let reasons = self.check_invalid_reasons(&account_id, agent);
             let validity = if reasons.is_empty() {
                 AgentValidity::Valid
             } else {
                 AgentValidity::Invalid(reasons)
             };
-            AgentView {
-                account_id: account_id.clone(),
-                measurements: agent.measurements.clone(),
-                ppid: agent.ppid.clone(),
-                valid_until_ms: U64::from(agent.valid_until_ms),
-                validity,
-            }
-        })
+            self.agent_view(&account_id, agent, validity)
+        };
+        match self.agents.get(&account_id) {
+            Some(agent) => Some(view(agent)),
+            None => self.legacy_agent(&account_id).as_ref().map(view),
+        }
     }
 
     // Get the list of registered agents and their details
     pub fn get_agents(&self, from_index: &Option<u32>, limit: &Option<u32>) -> Vec<AgentView> {
         let from = from_index.unwrap_or(0);
-        let limit = limit.unwrap_or(self.agents.len() as u32);
+        let limit = limit.unwrap_or(self.agents.len());
 
         self.agents
             .iter()
@@ -100,17 +355,168 @@ impl Contract {
                 } else {
                     AgentValidity::Invalid(reasons)
                 };
-                AgentView {
-                    account_id: account_id.clone(),
-                    measurements: agent.measurements.clone(),
-                    ppid: agent.ppid.clone(),
-                    valid_until_ms: U64::from(agent.valid_until_ms),
-                    validity,
+                self.agent_view(account_id, agent, validity)
+            })
+            .collect()
+    }
+
+    // Get the hash of the deployed code, the one before it and the pending upgrade
+    pub fn get_code_upgrade(&self) -> CodeUpgradeView {
+        CodeUpgradeView {
+            code_hash: self.code_hash.map(Base58CryptoHash::from),
+            previous_code_hash: self.previous_code_hash.map(Base58CryptoHash::from),
+            previous_code_state_version: self.previous_code_state_version,
+            pending_upgrade: self.pending_upgrade.as_ref().map(|pending_upgrade| {
+                PendingUpgradeView {
+                    code_hash: pending_upgrade.code_hash.into(),
+                    migrate_gas_tgas: pending_upgrade.migrate_gas_tgas,
+                    approved_by: pending_upgrade.approved_by.clone(),
+                    approved_at_ms: U64::from(pending_upgrade.approved_at_ms),
                 }
+            }),
+        }
+    }
+
+    // Get the multisig owners and threshold, None if the contract has a single owner
+    pub fn get_multisig(&self) -> Option<MultisigView> {
+        self.multisig.as_ref().map(|multisig| MultisigView {
+            owners: multisig.owners.clone(),
+            threshold: multisig.threshold,
+            proposal_lifetime_ms: U64::from(multisig.proposal_lifetime_ms),
+        })
+    }
+
+    // Get a pending proposal by ID
+    pub fn get_proposal(&self, proposal_id: U64) -> Option<ProposalView> {
+        self.proposals
+            .get(&proposal_id.0)
+            .map(|proposal| proposal_view(proposal_id.0, proposal))
+    }
+
+    // Get the list of pending proposals (paginated via from_index and limit)
+    pub fn get_proposals(
+        &self,
+        from_index: &Option<u32>,
+        limit: &Option<u32>,
+    ) -> Vec<ProposalView> {
+        let from = from_index.unwrap_or(0);
+        let limit = limit.unwrap_or(self.proposals.len());
+
+        self.proposals
+            .iter()
+            .skip(from as usize)
+            .take(limit as usize)
+            .map(|(proposal_id, proposal)| proposal_view(*proposal_id, proposal))
+            .collect()
+    }
+
+    // Get a queued action by ID
+    pub fn get_queued_action(&self, action_id: U64) -> Option<QueuedActionView> {
+        self.queued_actions
+            .get(&action_id.0)
+            .map(|queued| queued_action_view(action_id.0, queued))
+    }
+
+    // Get the list of actions waiting for the timelock (paginated via from_index and limit)
+    pub fn get_queued_actions(
+        &self,
+        from_index: &Option<u32>,
+        limit: &Option<u32>,
+    ) -> Vec<QueuedActionView> {
+        let from = from_index.unwrap_or(0);
+        let limit = limit.unwrap_or(self.queued_actions.len());
+
+        self.queued_actions
+            .iter()
+            .skip(from as usize)
+            .take(limit as usize)
+            .map(|(action_id, queued)| queued_action_view(*action_id, queued))
+            .collect()
+    }
+
+    // Get the roles granted to an account, the owner implicitly has every role
+    pub fn get_account_roles(&self, account_id: AccountId) -> Vec<Role> {
+        self.roles.get(&account_id).cloned().unwrap_or_default()
+    }
+
+    // Get the accounts that have been granted a role
+    pub fn get_role_members(&self, role: Role) -> Vec<AccountId> {
+        self.roles
+            .iter()
+            .filter(|(_, roles)| roles.contains(&role))
+            .map(|(account_id, _)| account_id.clone())
+            .collect()
+    }
+
+    // Get every account with granted roles (paginated via from_index and limit)
+    pub fn get_roles(
+        &self,
+        from_index: &Option<u32>,
+        limit: &Option<u32>,
+    ) -> Vec<AccountRolesView> {
+        let from = from_index.unwrap_or(0);
+        let limit = limit.unwrap_or(self.roles.len());
+
+        self.roles
+            .iter()
+            .skip(from as usize)
+            .take(limit as usize)
+            .map(|(account_id, roles)| AccountRolesView {
+                account_id: account_id.clone(),
+                roles: roles.clone(),
             })
             .collect()
     }
 
+    // Get the changes to the approved measurements and PPIDs, oldest first (paginated via from_index and limit)
+    pub fn get_allowlist_history(
+        &self,
+        from_index: &Option<u32>,
+        limit: &Option<u32>,
+    ) -> Vec<AllowlistHistoryEntryView> {
+        let from = from_index.unwrap_or(0);
+        let limit = limit.unwrap_or(self.allowlist_history.len());
+
+        self.allowlist_history
+            .iter()
+            .enumerate()
+            .skip(from as usize)
+            .take(limit as usize)
+            .map(|(index, entry)| allowlist_history_entry_view(index as u32, entry))
+            .collect()
+    }
+
+    // Get the changes to a set of measurements, oldest first
+    pub fn get_measurements_history(
+        &self,
+        measurements: FullMeasurementsHex,
+    ) -> Vec<AllowlistHistoryEntryView> {
+        self.allowlist_history_entries(self.measurements_history.get(&measurements))
+            .map(|(index, entry)| allowlist_history_entry_view(index, entry))
+            .collect()
+    }
+
+    // Get the changes to a PPID, oldest first
+    pub fn get_ppid_history(&self, ppid: Ppid) -> Vec<AllowlistHistoryEntryView> {
+        self.allowlist_history_entries(self.ppid_history.get(&ppid))
+            .map(|(index, entry)| allowlist_history_entry_view(index, entry))
+            .collect()
+    }
+
+    // Whether a set of measurements was approved, and not sunset, at timestamp_ms
+    pub fn was_measurements_approved_at(
+        &self,
+        measurements: FullMeasurementsHex,
+        timestamp_ms: U64,
+    ) -> bool {
+        self.measurements_approved_at(&measurements, timestamp_ms.0)
+    }
+
+    // Whether a PPID was approved, and not expired, at timestamp_ms
+    pub fn was_ppid_approved_at(&self, ppid: Ppid, timestamp_ms: U64) -> bool {
+        self.ppid_approved_at(&ppid, timestamp_ms.0)
+    }
+
     // Local only functions
 
     // Get the list of whitelisted agents for local mode
@@ -121,3 +527,71 @@ impl Contract {
         self.whitelisted_agents_for_local.iter().cloned().collect()
     }
 }
+
+impl Contract {
+    fn agent_view(
+        &self,
+        account_id: &AccountId,
+        agent: &Agent,
+        validity: AgentValidity,
+    ) -> AgentView {
+        AgentView {
+            account_id: account_id.clone(),
+            measurements_id: agent.measurements_id,
+            measurements: self.get_measurements(agent.measurements_id),
+            ppid: agent.ppid.clone(),
+            valid_until_ms: U64::from(agent.valid_until_ms),
+            validity,
+        }
+    }
+}
+
+impl ApprovedMeasurementsView {
+    fn new(measurements_id: MeasurementsId, approved: &ApprovedMeasurements) -> Self {
+        Self {
+            measurements_id,
+            measurements: approved.measurements.clone(),
+            label: approved.label.clone(),
+            source: approved.source.clone(),
+            approved_by: approved.approved_by.clone(),
+            approved_at_ms: U64::from(approved.approved_at_ms),
+            sunset_at_ms: approved.sunset_at_ms.map(U64::from),
+            max_agents: approved.max_agents,
+            active_agents: approved.active_agents,
+            ppid_group: approved.ppid_group.clone(),
+        }
+    }
+}
+
+fn allowlist_history_entry_view(
+    index: u32,
+    entry: &AllowlistHistoryEntry,
+) -> AllowlistHistoryEntryView {
+    AllowlistHistoryEntryView {
+        index,
+        change: entry.change.clone(),
+        changed_by: entry.changed_by.clone(),
+        changed_at_ms: U64::from(entry.changed_at_ms),
+    }
+}
+
+fn proposal_view(proposal_id: ProposalId, proposal: &Proposal) -> ProposalView {
+    ProposalView {
+        proposal_id: U64::from(proposal_id),
+        action: proposal.action.clone(),
+        proposer: proposal.proposer.clone(),
+        confirmations: proposal.confirmations.clone(),
+        created_at_ms: U64::from(proposal.created_at_ms),
+        expires_at_ms: U64::from(proposal.expires_at_ms),
+    }
+}
+
+fn queued_action_view(action_id: QueuedActionId, queued: &QueuedAction) -> QueuedActionView {
+    QueuedActionView {
+        action_id: U64::from(action_id),
+        action: queued.action.clone(),
+        queued_by: queued.queued_by.clone(),
+        queued_at_ms: U64::from(queued.queued_at_ms),
+        executable_at_ms: U64::from(queued.executable_at_ms),
+    }
+}
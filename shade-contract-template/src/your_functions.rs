@@ -3,19 +3,103 @@ use crate::*;
 // Write your own functions here
 
 // Request a signature for a transaction payload if its a valid agent
+// The attached deposit has to cover the sign deposit, anything above it is refunded
 #[near]
 impl Contract {
+    #[payable]
     pub fn request_signature(
         &mut self,
         path: String,
         payload: String,
-        key_type: String,
+        key_type: SignatureScheme,
     ) -> Promise {
+        // Fail before touching the agent so its record stays in place while signing is paused
+        require!(!self.signing_paused, "Signing is paused");
+
         // Require the caller to be a valid agent, if not, execute a promise to panic
         if let Some(failure_promise) = self.require_valid_agent() {
+            self.refund_attached_deposit();
+            return failure_promise;
+        }
+
+        // Agents under a transaction policy have to sign through the policy's function
+        self.require_raw_signing_allowed(key_type);
+
+        self.internal_request_signature(path, payload, key_type, None)
+    }
+
+    // Request the app private key the MPC network derives for this contract, encrypted to app_public_key
+    // app_public_key is an ephemeral BLS12-381 G1 public key in the bls12381g1:<base58> format
+    // Every valid agent of this contract gets the same key, so it survives moving the agent to new hardware
+    #[payable]
+    pub fn request_app_private_key(&mut self, app_public_key: String) -> Promise {
+        // The MPC network is paused for keys the same way as for signatures
+        require!(!self.signing_paused, "Signing is paused");
+
+        // Only valid agents, on approved measurements, can get the key
+        if let Some(failure_promise) = self.require_valid_agent() {
+            self.refund_attached_deposit();
+            return failure_promise;
+        }
+
+        self.internal_request_app_private_key(app_public_key)
+    }
+
+    // Request a signature for an unsigned EVM transaction, the hex of an EIP-1559 or EIP-155 legacy transaction
+    // The transaction has to meet the EVM policy of the agent's measurements, only its hash is sent for signing
+    #[payable]
+    pub fn request_evm_transaction_signature(
+        &mut self,
+        path: String,
+        transaction: String,
+    ) -> Promise {
+        require!(!self.signing_paused, "Signing is paused");
+
+        if let Some(failure_promise) = self.require_valid_agent() {
+            self.refund_attached_deposit();
+            return failure_promise;
+        }
+
+        self.internal_request_evm_transaction_signature(path, transaction)
+    }
+
+    // Request a signature for input input_index of an unsigned Bitcoin transaction, serialized without witnesses
+    // spent_output is the P2WPKH output the input spends, the outputs have to meet the Bitcoin policy
+    // of the agent's measurements and only the BIP-143 sighash is sent for signing
+    #[payable]
+    pub fn request_bitcoin_transaction_signature(
+        &mut self,
+        path: String,
+        transaction: String,
+        input_index: u32,
+        spent_output: BitcoinSpentOutput,
+    ) -> Promise {
+        require!(!self.signing_paused, "Signing is paused");
+
+        if let Some(failure_promise) = self.require_valid_agent() {
+            self.refund_attached_deposit();
+            return failure_promise;
+        }
+
+        self.internal_request_bitcoin_transaction_signature(
+            path,
+            transaction,
+            input_index,
+            spent_output,
+        )
+    }
+
+    // Request an Ed25519 signature for a Solana message, the hex of a serialized legacy or v0 message
+    // The message has to meet the Solana policy of the agent's measurements
+    #[payable]
+    pub fn request_solana_message_signature(&mut self, path: String, message: String) -> Promise {
+        require!(!self.signing_paused, "Signing is paused");
+
+        if let Some(failure_promise) = self.require_valid_agent() {
+            self.refund_attached_deposit();
             return failure_promise;
         }
 
-        self.internal_request_signature(path, payload, key_type)
+        self.internal_request_solana_message_signature(path, message)
     }
 }
@@ -1,10 +1,10 @@
 mod helpers;
 
 use helpers::*;
-use near_api::Data;
+use near_api::{Data, NearToken};
 use serde_json::json;
 use shade_attestation::attestation::create_mock_dstack_attestation;
-use shade_contract_template::{AgentRemovalReason, AgentValidity, AgentView};
+use shade_contract_template::{AgentRemovalReason, AgentValidity, AgentView, StorageBalance};
 use tokio::time::{Duration, sleep};
 
 /// Tests measurements/PPID lifecycle with multiple agents:
@@ -502,7 +502,7 @@ async fn test_measurements_and_ppid_lifecycle()
     assert!(
         reasons
             .as_array()
-            .map_or(false, |r| r.contains(&json!("InvalidPpid"))),
+            .is_some_and(|r| r.contains(&json!("InvalidPpid"))),
         "Event should contain 'InvalidPpid' reason, got: {:?}",
         reasons
     );
@@ -881,7 +881,7 @@ async fn test_attestation_expiration() -> Result<(), Box<dyn std::error::Error +
     assert!(
         reasons
             .as_array()
-            .map_or(false, |r| r.contains(&json!("ExpiredAttestation"))),
+            .is_some_and(|r| r.contains(&json!("ExpiredAttestation"))),
         "Event should contain 'ExpiredAttestation' reason, got: {:?}",
         reasons
     );
@@ -1120,3 +1120,120 @@ async fn test_register_agent_reregister_without_storage_deposit_integration()
 
     Ok(())
 }
+
+/// NEP-145 storage: deposit first, register without a deposit, withdraw the excess, then
+/// `storage_unregister` with force removes the agent and closes its balance.
+#[tokio::test]
+async fn test_storage_management_integration()
+-> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let sandbox = near_sandbox::Sandbox::start_sandbox().await?;
+    let network_config = create_network_config(&sandbox);
+    let (genesis_account_id, genesis_signer) = setup_genesis_account().await;
+
+    let contract_id =
+        deploy_contract_default(&network_config, &genesis_account_id, &genesis_signer).await?;
+
+    sleep(Duration::from_millis(200)).await;
+
+    let (agent_id, agent_signer) = create_user_account(
+        &network_config,
+        &genesis_account_id,
+        &genesis_signer,
+        "storage_agent",
+    )
+    .await?;
+
+    let _ = call_transaction(
+        &contract_id,
+        "whitelist_agent_for_local",
+        json!({ "account_id": agent_id }),
+        &genesis_account_id,
+        &genesis_signer,
+        &network_config,
+        None,
+    )
+    .await?
+    .assert_success();
+
+    let _ = call_transaction(
+        &contract_id,
+        "storage_deposit",
+        json!({}),
+        &agent_id,
+        &agent_signer,
+        &network_config,
+        Some(helpers::DEPOSIT_005_NEAR),
+    )
+    .await?
+    .assert_success();
+
+    // The storage balance covers the registration
+    let _ = call_transaction(
+        &contract_id,
+        "register_agent",
+        json!({
+            "attestation": serde_json::to_value(create_mock_dstack_attestation()).unwrap()
+        }),
+        &agent_id,
+        &agent_signer,
+        &network_config,
+        None,
+    )
+    .await?
+    .assert_success();
+
+    let _ = call_transaction(
+        &contract_id,
+        "storage_withdraw",
+        json!({}),
+        &agent_id,
+        &agent_signer,
+        &network_config,
+        Some(NearToken::from_yoctonear(1)),
+    )
+    .await?
+    .assert_success();
+
+    sleep(Duration::from_millis(200)).await;
+
+    let balance: Data<Option<StorageBalance>> = call_view(
+        &contract_id,
+        "storage_balance_of",
+        json!({ "account_id": agent_id }),
+        &network_config,
+    )
+    .await?;
+    let balance = balance.data.expect("agent should have a storage balance");
+    assert!(
+        balance.available.is_zero(),
+        "Only the storage cost should be left after withdrawing"
+    );
+
+    let _ = call_transaction(
+        &contract_id,
+        "storage_unregister",
+        json!({ "force": true }),
+        &agent_id,
+        &agent_signer,
+        &network_config,
+        Some(NearToken::from_yoctonear(1)),
+    )
+    .await?
+    .assert_success();
+
+    sleep(Duration::from_millis(200)).await;
+
+    let agent_info: Data<Option<AgentView>> = call_view(
+        &contract_id,
+        "get_agent",
+        json!({ "account_id": agent_id }),
+        &network_config,
+    )
+    .await?;
+    assert!(
+        agent_info.data.is_none(),
+        "Agent should be removed after storage_unregister"
+    );
+
+    Ok(())
+}
@@ -1,10 +1,11 @@
 mod helpers;
 
 use helpers::*;
-use near_api::{AccountId, Data};
+use near_api::{AccountId, Data, NearToken};
 use serde_json::json;
 use shade_attestation::attestation::create_mock_dstack_attestation;
-use shade_contract_template::ContractInfo;
+use shade_contract_events::ContractEvent;
+use shade_contract_template::{ContractInfo, SignatureResponse};
 use tokio::time::{Duration, sleep};
 
 /// Tests that request_signature makes correct cross-contract call to MPC contract
@@ -109,7 +110,7 @@ async fn test_cross_contract_call_to_mpc() -> Result<(), Box<dyn std::error::Err
     sleep(Duration::from_millis(200)).await;
 
     // Request signature - this should call the mock MPC contract
-    let _ = call_transaction(
+    let result = call_transaction(
         &contract_id,
         "request_signature",
         json!({
@@ -120,10 +121,24 @@ async fn test_cross_contract_call_to_mpc() -> Result<(), Box<dyn std::error::Err
         &agent_id,
         &agent_signer,
         &network_config,
-        None,
+        Some(NearToken::from_yoctonear(1)),
     )
-    .await?
-    .assert_success();
+    .await?;
+    let events = extract_events(&result)?;
+    // The callback returns the signature in the MPC contract's format
+    let signature: SignatureResponse = result.json()?;
+    assert!(
+        matches!(signature, SignatureResponse::Secp256k1(_)),
+        "Ecdsa request should return a Secp256k1 signature"
+    );
+    assert!(
+        events.iter().any(|event| matches!(
+            event,
+            ContractEvent::SignatureProduced { account_id, path, .. }
+                if *account_id == agent_id && path == "path"
+        )),
+        "Callback should emit SignatureProduced"
+    );
 
     // Request signature - this should call the mock MPC contract (Eddsa key type)
     let _ = call_transaction(
@@ -137,11 +152,79 @@ async fn test_cross_contract_call_to_mpc() -> Result<(), Box<dyn std::error::Err
         &agent_id,
         &agent_signer,
         &network_config,
+        Some(NearToken::from_yoctonear(1)),
+    )
+    .await?
+    .assert_success();
+
+    // Raise the sign deposit, a request has to attach at least the new deposit
+    let _ = call_transaction(
+        &contract_id,
+        "set_sign_config",
+        json!({
+            "sign_gas_tgas": 20,
+            "sign_deposit": "2"
+        }),
+        &genesis_account_id,
+        &genesis_signer,
+        &network_config,
         None,
     )
     .await?
     .assert_success();
 
+    let contract_info: Data<ContractInfo> = call_view(
+        &contract_id,
+        "get_contract_info",
+        json!({}),
+        &network_config,
+    )
+    .await?;
+    assert_eq!(contract_info.data.sign_gas_tgas, 20);
+    assert_eq!(
+        contract_info.data.sign_deposit,
+        NearToken::from_yoctonear(2)
+    );
+
+    let result = call_transaction(
+        &contract_id,
+        "request_signature",
+        json!({
+            "path": "path",
+            "payload": "0000000000000000000000000000000000000000000000000000000000000000",
+            "key_type": "Ecdsa"
+        }),
+        &agent_id,
+        &agent_signer,
+        &network_config,
+        Some(NearToken::from_yoctonear(1)),
+    )
+    .await?
+    .into_result();
+    let error = result.expect_err("Request below the sign deposit should fail");
+    assert!(
+        format!("{:?}", error).contains("Attached deposit must cover the sign deposit"),
+        "Expected the sign deposit error, got: {:?}",
+        error
+    );
+
+    // A larger deposit is accepted, the callback refunds what the MPC contract did not take
+    let _ = call_transaction(
+        &contract_id,
+        "request_signature",
+        json!({
+            "path": "path",
+            "payload": "0000000000000000000000000000000000000000000000000000000000000000",
+            "key_type": "Ecdsa"
+        }),
+        &agent_id,
+        &agent_signer,
+        &network_config,
+        Some(NearToken::from_millinear(1)),
+    )
+    .await?
+    .assert_success();
+
     // Verify initial MPC contract ID
     let contract_info: Data<ContractInfo> = call_view(
         &contract_id,
@@ -198,7 +281,7 @@ async fn test_cross_contract_call_to_mpc() -> Result<(), Box<dyn std::error::Err
         &agent_id,
         &agent_signer,
         &network_config,
-        None,
+        Some(NearToken::from_yoctonear(2)),
     )
     .await?
     .into_result();
@@ -6,6 +6,7 @@ use near_api_types::transaction::result::{ExecutionFinalResult, TransactionResul
 use near_sandbox::{GenesisAccount, Sandbox};
 use serde_json::json;
 use shade_attestation::measurements::create_mock_full_measurements_hex;
+use shade_contract_events::{ContractEvent, parse_logs};
 use std::sync::Arc;
 use tokio::time::{Duration, sleep};
 
@@ -325,7 +326,7 @@ pub async fn call_transaction(
         .send_to(network_config)
         .await?;
 
-    Ok(unwrap_full(tx_result)?)
+    unwrap_full(tx_result)
 }
 
 #[allow(dead_code)]
@@ -347,7 +348,7 @@ pub async fn call_transaction_raw(
         .send_to(network_config)
         .await?;
 
-    Ok(unwrap_full(tx_result)?)
+    unwrap_full(tx_result)
 }
 
 #[allow(dead_code)]
@@ -386,6 +387,15 @@ pub async fn deploy_mock_mpc_contract(
     Ok(mpc_contract_id)
 }
 
+/// Extract this contract's events from a transaction result as typed events
+/// Fails if one of them does not match shade-contract-events
+#[allow(dead_code)]
+pub fn extract_events(
+    result: &ExecutionFinalResult,
+) -> Result<Vec<ContractEvent>, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(parse_logs(result.logs())?)
+}
+
 /// Extract and parse EVENT_JSON logs from a transaction result
 /// Returns a vector of parsed event JSON objects
 #[allow(dead_code)]
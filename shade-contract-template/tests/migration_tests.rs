@@ -0,0 +1,319 @@
+mod helpers;
+
+use helpers::*;
+use near_api::{Account, AccountId, Contract, Data, NearToken, Signer, signer};
+use near_sdk::{
+    BorshStorageKey, env,
+    json_types::{Base58CryptoHash, Base64VecU8},
+    near,
+    store::{IterableMap, IterableSet},
+    test_utils::VMContextBuilder,
+    testing_env,
+};
+use serde_json::json;
+use shade_attestation::{
+    attestation::create_mock_dstack_attestation,
+    measurements::{FullMeasurementsHex, create_mock_full_measurements_hex},
+    tcb_info::HexBytes,
+};
+use shade_contract_template::AgentView;
+use std::sync::Arc;
+use tokio::time::{Duration, sleep};
+
+// Contract state as laid out before state versioning (version 1)
+#[near(serializers = [borsh])]
+struct ContractV1 {
+    requires_tee: bool,
+    attestation_expiration_time_ms: u64,
+    owner_id: AccountId,
+    mpc_contract_id: AccountId,
+    approved_measurements: IterableSet<FullMeasurementsHex>,
+    approved_ppids: IterableSet<HexBytes<16>>,
+    agents: IterableMap<AccountId, AgentV1>,
+    whitelisted_agents_for_local: IterableSet<AccountId>,
+}
+
+#[near(serializers = [borsh])]
+struct AgentV1 {
+    measurements: FullMeasurementsHex,
+    ppid: HexBytes<16>,
+    valid_until_ms: u64,
+}
+
+#[derive(BorshStorageKey)]
+#[near]
+enum StorageKeyV1 {
+    ApprovedMeasurements,
+    ApprovedPpids,
+    Agents,
+    WhitelistedAgentsForLocal,
+}
+
+// Number of agents the migrate call moves itself
+const MIGRATE_AGENTS_BATCH: u32 = 25;
+
+// Builds version 1 state in the mocked blockchain and returns its storage as base64 key-value pairs
+fn v1_state_entries(owner_id: &AccountId, agents: &[AccountId]) -> Vec<(String, String)> {
+    testing_env!(VMContextBuilder::new().build());
+    {
+        let mut state = ContractV1 {
+            requires_tee: false,
+            attestation_expiration_time_ms: 100000,
+            owner_id: owner_id.clone(),
+            mpc_contract_id: "mpc-contract".parse().unwrap(),
+            approved_measurements: IterableSet::new(StorageKeyV1::ApprovedMeasurements),
+            approved_ppids: IterableSet::new(StorageKeyV1::ApprovedPpids),
+            agents: IterableMap::new(StorageKeyV1::Agents),
+            whitelisted_agents_for_local: IterableSet::new(StorageKeyV1::WhitelistedAgentsForLocal),
+        };
+        state
+            .approved_measurements
+            .insert(create_mock_full_measurements_hex());
+        state.approved_ppids.insert(HexBytes::from([0; 16]));
+        for account_id in agents {
+            state
+                .whitelisted_agents_for_local
+                .insert(account_id.clone());
+            state.agents.insert(
+                account_id.clone(),
+                AgentV1 {
+                    measurements: create_mock_full_measurements_hex(),
+                    ppid: HexBytes::from([0; 16]),
+                    valid_until_ms: u64::MAX,
+                },
+            );
+        }
+        env::state_write(&state);
+        // Dropping the collections flushes their entries to storage
+    }
+
+    let to_base64 = |bytes: Vec<u8>| {
+        serde_json::to_value(Base64VecU8::from(bytes))
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string()
+    };
+    near_sdk::mock::with_mocked_blockchain(|blockchain| blockchain.take_storage())
+        .into_iter()
+        .map(|(key, value)| (to_base64(key), to_base64(value)))
+        .collect()
+}
+
+#[tokio::test]
+async fn test_migrate_from_v1_state() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let sandbox = near_sandbox::Sandbox::start_sandbox().await?;
+    let network_config = create_network_config(&sandbox);
+    let (genesis_account_id, genesis_signer) = setup_genesis_account().await;
+
+    // The last agent is a real account so it can register again while it is waiting for migration
+    let (agent_id, agent_signer) = create_user_account(
+        &network_config,
+        &genesis_account_id,
+        &genesis_signer,
+        "agent",
+    )
+    .await?;
+    let mut agents: Vec<AccountId> = (0..29)
+        .map(|i| format!("legacy{i}.{genesis_account_id}").parse().unwrap())
+        .collect();
+    agents.push(agent_id.clone());
+
+    // Create the contract account and give it version 1 state
+    let contract_id: AccountId = format!("contract.{genesis_account_id}").parse()?;
+    let contract_secret_key = signer::generate_secret_key()?;
+    let contract_signer: Arc<Signer> = Signer::from_secret_key(contract_secret_key.clone())?;
+    let _ = Account::create_account(contract_id.clone())
+        .fund_myself(genesis_account_id.clone(), NearToken::from_near(10))
+        .with_public_key(contract_secret_key.public_key())
+        .with_signer(genesis_signer.clone())
+        .send_to(&network_config)
+        .await?;
+    sandbox
+        .patch_state(contract_id.clone())
+        .storage_entries(v1_state_entries(&genesis_account_id, &agents))
+        .send()
+        .await?;
+
+    // Deploy the current code and call migrate, as upload_code does
+    let wasm_bytes = std::fs::read(CONTRACT_WASM_PATH)?;
+    let result = Contract::deploy(contract_id.clone())
+        .use_code(wasm_bytes.clone())
+        .with_init_call("migrate", json!({}))?
+        .max_gas()
+        .with_signer(contract_signer.clone())
+        .send_to(&network_config)
+        .await?;
+    if let Err(e) = result.into_result() {
+        return Err(format!("migrate failed: {:?}", e).into());
+    }
+
+    sleep(Duration::from_millis(200)).await;
+
+    let contract_info: Data<serde_json::Value> = call_view(
+        &contract_id,
+        "get_contract_info",
+        json!({}),
+        &network_config,
+    )
+    .await?;
+    assert_eq!(contract_info.data["state_version"], 2);
+    assert_eq!(
+        contract_info.data["owner_id"],
+        genesis_account_id.to_string()
+    );
+    assert_eq!(
+        contract_info.data["agents_pending_migration"],
+        agents.len() as u32 - MIGRATE_AGENTS_BATCH
+    );
+
+    // Approved measurements and PPIDs are moved over with IDs and empty metadata
+    let measurements: Data<serde_json::Value> = call_view(
+        &contract_id,
+        "get_approved_measurements",
+        json!({}),
+        &network_config,
+    )
+    .await?;
+    assert_eq!(measurements.data.as_array().unwrap().len(), 1);
+    assert_eq!(measurements.data[0]["measurements_id"], 0);
+    assert_eq!(
+        measurements.data[0]["approved_by"],
+        genesis_account_id.to_string()
+    );
+    assert_eq!(measurements.data[0]["active_agents"], MIGRATE_AGENTS_BATCH);
+
+    let ppids: Data<serde_json::Value> = call_view(
+        &contract_id,
+        "get_approved_ppids",
+        json!({}),
+        &network_config,
+    )
+    .await?;
+    assert_eq!(ppids.data.as_array().unwrap().len(), 1);
+
+    // The moved entries start the allowlist history
+    let history: Data<serde_json::Value> = call_view(
+        &contract_id,
+        "get_allowlist_history",
+        json!({}),
+        &network_config,
+    )
+    .await?;
+    let history = history.data.as_array().unwrap();
+    assert_eq!(history.len(), 2);
+    assert!(history[0]["change"]["MeasurementsApproved"].is_object());
+    assert!(history[1]["change"]["PpidApproved"].is_object());
+    assert_eq!(history[0]["changed_by"], genesis_account_id.to_string());
+
+    // An agent waiting for migration is already reported as valid
+    let agent_info: Data<Option<AgentView>> = call_view(
+        &contract_id,
+        "get_agent",
+        json!({ "account_id": agent_id }),
+        &network_config,
+    )
+    .await?;
+    assert!(agent_info.data.is_some());
+
+    // Registering again migrates the agent and needs no new storage deposit
+    let _ = call_transaction(
+        &contract_id,
+        "register_agent",
+        json!({
+            "attestation": serde_json::to_value(create_mock_dstack_attestation()).unwrap()
+        }),
+        &agent_id,
+        &agent_signer,
+        &network_config,
+        None,
+    )
+    .await?
+    .assert_success();
+
+    // Anyone can migrate the remaining agents
+    let result = call_transaction(
+        &contract_id,
+        "migrate_agents",
+        json!({ "limit": 10 }),
+        &agent_id,
+        &agent_signer,
+        &network_config,
+        None,
+    )
+    .await?;
+    let events = extract_event_logs(&result)?;
+    let remaining: u32 = result.clone().into_result()?.json()?;
+    assert_eq!(remaining, 0);
+    assert!(
+        events
+            .iter()
+            .any(|event| event["event"] == "agents_migrated"
+                && event["data"]["migrated"] == agents.len() as u32 - MIGRATE_AGENTS_BATCH - 1
+                && event["data"]["remaining"] == 0)
+    );
+
+    sleep(Duration::from_millis(200)).await;
+
+    let all_agents: Data<Vec<AgentView>> =
+        call_view(&contract_id, "get_agents", json!({}), &network_config).await?;
+    assert_eq!(all_agents.data.len(), agents.len());
+    assert!(
+        all_agents
+            .data
+            .iter()
+            .all(|agent| agent.measurements_id == 0)
+    );
+
+    // Upgrading state that is already current keeps it as it is
+    let code_hash = Base58CryptoHash::from(env::sha256_array(&wasm_bytes));
+    call_transaction(
+        &contract_id,
+        "approve_code_hash",
+        json!({ "code_hash": code_hash, "migrate_gas_tgas": 30 }),
+        &genesis_account_id,
+        &genesis_signer,
+        &network_config,
+        None,
+    )
+    .await?
+    .assert_success();
+    let result = call_transaction_raw(
+        &contract_id,
+        "upload_code",
+        wasm_bytes,
+        &agent_id,
+        &agent_signer,
+        &network_config,
+    )
+    .await?;
+    let events = extract_event_logs(&result)?;
+    result.into_result().expect("upload_code should succeed");
+    assert!(
+        events
+            .iter()
+            .any(|event| event["event"] == "contract_upgraded"
+                && event["data"]["code_hash"] == json!(code_hash))
+    );
+
+    sleep(Duration::from_millis(200)).await;
+
+    let contract_info: Data<serde_json::Value> = call_view(
+        &contract_id,
+        "get_contract_info",
+        json!({}),
+        &network_config,
+    )
+    .await?;
+    assert_eq!(contract_info.data["state_version"], 2);
+    assert_eq!(contract_info.data["agents_pending_migration"], 0);
+    let code_upgrade: Data<serde_json::Value> =
+        call_view(&contract_id, "get_code_upgrade", json!({}), &network_config).await?;
+    assert_eq!(code_upgrade.data["code_hash"], json!(code_hash));
+    assert_eq!(code_upgrade.data["pending_upgrade"], json!(null));
+    let all_agents: Data<Vec<AgentView>> =
+        call_view(&contract_id, "get_agents", json!({}), &network_config).await?;
+    assert_eq!(all_agents.data.len(), agents.len());
+
+    Ok(())
+}
@@ -3,6 +3,7 @@ mod helpers;
 use helpers::*;
 use near_api::Data;
 use serde_json::json;
+use shade_contract_events::ContractEvent;
 use shade_contract_template::ContractInfo;
 use tokio::time::{Duration, sleep};
 
@@ -42,7 +43,7 @@ async fn test_owner_transfer_and_new_owner_operations()
     );
 
     // Transfer ownership
-    let _ = call_transaction(
+    let result = call_transaction(
         &contract_id,
         "update_owner_id",
         json!({
@@ -53,8 +54,16 @@ async fn test_owner_transfer_and_new_owner_operations()
         &network_config,
         None,
     )
-    .await?
-    .assert_success();
+    .await?;
+    let events = extract_events(&result)?;
+    result.assert_success();
+    assert_eq!(
+        events,
+        vec![ContractEvent::OwnerUpdated {
+            old_owner_id: genesis_account_id.clone(),
+            new_owner_id: new_owner_id.clone(),
+        }]
+    );
 
     sleep(Duration::from_millis(500)).await;
 
@@ -112,7 +121,7 @@ async fn test_owner_transfer_and_new_owner_operations()
     .await?;
 
     assert!(
-        approved_measurements.data.len() >= 1,
+        !approved_measurements.data.is_empty(),
         "Default measurements should be in approved list"
     );
 
@@ -2,6 +2,7 @@ mod helpers;
 
 use helpers::*;
 use near_api::Data;
+use near_sdk::{env, json_types::Base58CryptoHash};
 use serde_json::json;
 use tokio::time::{Duration, sleep};
 
@@ -22,23 +23,39 @@ async fn test_update_contract() -> Result<(), Box<dyn std::error::Error + Send +
         "/tests/contracts/contract_update.wasm"
     ))?;
 
-    // Pass gas (10 TGas) + WASM bytes concatenated
-    // First 8 bytes: gas as u64 little-endian, then WASM code
-    let gas_value: u64 = 10;
-    let mut input = gas_value.to_le_bytes().to_vec();
-    input.extend_from_slice(&wasm_bytes);
-
-    call_transaction_raw(
+    // The owner approves the SHA-256 of the code with 10 TGas for migrate
+    let code_hash = Base58CryptoHash::from(env::sha256_array(&wasm_bytes));
+    call_transaction(
         &contract_id,
-        "update_contract",
-        input,
+        "approve_code_hash",
+        json!({ "code_hash": code_hash, "migrate_gas_tgas": 10 }),
         &genesis_account_id,
         &genesis_signer,
         &network_config,
+        None,
+    )
+    .await?
+    .assert_success();
+
+    // Anyone can then upload the code as the raw input
+    let (user_id, user_signer) = create_user_account(
+        &network_config,
+        &genesis_account_id,
+        &genesis_signer,
+        "user",
+    )
+    .await?;
+    call_transaction_raw(
+        &contract_id,
+        "upload_code",
+        wasm_bytes,
+        &user_id,
+        &user_signer,
+        &network_config,
     )
     .await?
     .into_result()
-    .expect("update_contract should succeed");
+    .expect("upload_code should succeed");
 
     sleep(Duration::from_millis(200)).await;
 
@@ -94,3 +111,67 @@ async fn test_update_contract() -> Result<(), Box<dyn std::error::Error + Send +
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_upload_code_requires_approved_hash()
+-> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let sandbox = near_sandbox::Sandbox::start_sandbox().await?;
+    let network_config = create_network_config(&sandbox);
+    let (genesis_account_id, genesis_signer) = setup_genesis_account().await;
+
+    let contract_id =
+        deploy_contract_default(&network_config, &genesis_account_id, &genesis_signer).await?;
+
+    let wasm_bytes = std::fs::read(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/contracts/contract_update.wasm"
+    ))?;
+
+    // Nothing is approved yet
+    call_transaction_raw(
+        &contract_id,
+        "upload_code",
+        wasm_bytes.clone(),
+        &genesis_account_id,
+        &genesis_signer,
+        &network_config,
+    )
+    .await?
+    .assert_failure();
+
+    // Approve a different build, the uploaded code no longer matches
+    let code_hash = Base58CryptoHash::from(env::sha256_array(b"other build"));
+    call_transaction(
+        &contract_id,
+        "approve_code_hash",
+        json!({ "code_hash": code_hash, "migrate_gas_tgas": 10 }),
+        &genesis_account_id,
+        &genesis_signer,
+        &network_config,
+        None,
+    )
+    .await?
+    .assert_success();
+
+    call_transaction_raw(
+        &contract_id,
+        "upload_code",
+        wasm_bytes,
+        &genesis_account_id,
+        &genesis_signer,
+        &network_config,
+    )
+    .await?
+    .assert_failure();
+
+    // The pending upgrade is still there and the contract still runs the old code
+    let code_upgrade: Data<serde_json::Value> =
+        call_view(&contract_id, "get_code_upgrade", json!({}), &network_config).await?;
+    assert_eq!(
+        code_upgrade.data["pending_upgrade"]["code_hash"],
+        json!(code_hash)
+    );
+    assert_eq!(code_upgrade.data["code_hash"], json!(null));
+
+    Ok(())
+}
@@ -0,0 +1,111 @@
+use crate::{AccountId, DerivationError, derive_tweak};
+use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
+use curve25519_dalek::scalar::Scalar;
+use sha2::{Digest, Sha512};
+
+const NEAR_KEY_PREFIX: &str = "ed25519:";
+
+/// An Ed25519 public key of the MPC network, a root key or a key derived from one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ed25519PublicKey([u8; 32]);
+
+/// A 64 byte Ed25519 signature, `R` followed by `s`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ed25519Signature([u8; 64]);
+
+impl Ed25519PublicKey {
+    /// Parse a key in the NEAR format, `ed25519:` and the base58 of the 32 byte key, which the
+    /// MPC contract's `public_key` and `derived_public_key` views return.
+    pub fn from_near_string(key: &str) -> Result<Self, DerivationError> {
+        let encoded = key.strip_prefix(NEAR_KEY_PREFIX).ok_or_else(|| {
+            DerivationError::InvalidPublicKey(format!("expected the {NEAR_KEY_PREFIX} prefix"))
+        })?;
+        let bytes = bs58::decode(encoded)
+            .into_vec()
+            .map_err(|error| DerivationError::InvalidPublicKey(error.to_string()))?;
+        let bytes: [u8; 32] = bytes.try_into().map_err(|bytes: Vec<u8>| {
+            DerivationError::InvalidPublicKey(format!("expected 32 bytes, got {}", bytes.len()))
+        })?;
+        Self::from_bytes(bytes)
+    }
+
+    /// A key from its 32 byte encoding, which has to be a point on the curve.
+    pub fn from_bytes(bytes: [u8; 32]) -> Result<Self, DerivationError> {
+        decompress(&bytes)?;
+        Ok(Self(bytes))
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0
+    }
+
+    /// The key in the NEAR format the MPC contract uses.
+    pub fn to_near_string(&self) -> String {
+        format!("{NEAR_KEY_PREFIX}{}", bs58::encode(self.0).into_string())
+    }
+
+    /// The key the MPC network signs with for `predecessor_id` and `path`: the root key plus
+    /// the tweak times the base point.
+    pub fn derive(&self, predecessor_id: &AccountId, path: &str) -> Result<Self, DerivationError> {
+        let tweak = Scalar::from_bytes_mod_order(derive_tweak(predecessor_id, path));
+        let point = decompress(&self.0)? + EdwardsPoint::mul_base(&tweak);
+        Ok(Self(point.compress().to_bytes()))
+    }
+
+    /// The Solana address of the key, its base58 encoding.
+    pub fn solana_address(&self) -> String {
+        bs58::encode(self.0).into_string()
+    }
+
+    /// The NEAR implicit account of the key, its lowercase hex encoding.
+    pub fn near_implicit_account_id(&self) -> AccountId {
+        hex::encode(self.0).parse().unwrap()
+    }
+}
+
+fn decompress(bytes: &[u8; 32]) -> Result<EdwardsPoint, DerivationError> {
+    CompressedEdwardsY(*bytes)
+        .decompress()
+        .ok_or_else(|| DerivationError::InvalidPublicKey("not a point on ed25519".to_string()))
+}
+
+impl Ed25519Signature {
+    pub fn from_bytes(bytes: [u8; 64]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn to_bytes(&self) -> [u8; 64] {
+        self.0
+    }
+
+    /// The base58 encoding Solana uses for transaction signatures.
+    pub fn to_solana_string(&self) -> String {
+        bs58::encode(self.0).into_string()
+    }
+
+    /// The signature in the NEAR format, `ed25519:` and its base58 encoding.
+    pub fn to_near_string(&self) -> String {
+        format!("{NEAR_KEY_PREFIX}{}", bs58::encode(self.0).into_string())
+    }
+
+    /// Check the signature of `message` against `public_key`, as RFC 8032 without the
+    /// cofactor, so a signature from the MPC network can be checked against the derived key.
+    pub fn verify(&self, public_key: &Ed25519PublicKey, message: &[u8]) -> bool {
+        let (big_r, s) = self.0.split_at(32);
+        let Some(s) = Option::<Scalar>::from(Scalar::from_canonical_bytes(s.try_into().unwrap()))
+        else {
+            return false;
+        };
+        let Ok(a) = decompress(&public_key.0) else {
+            return false;
+        };
+        let k = Scalar::from_hash(
+            Sha512::new()
+                .chain_update(big_r)
+                .chain_update(public_key.0)
+                .chain_update(message),
+        );
+        let expected_r = EdwardsPoint::vartime_double_scalar_mul_basepoint(&-k, &a, &s);
+        expected_r.compress().as_bytes() == big_r
+    }
+}
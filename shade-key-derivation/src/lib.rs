@@ -0,0 +1,59 @@
+//! Offline derivation of the keys and addresses the MPC network signs with for the Shade Agent contract.
+//!
+//! The MPC network derives a child key for every account that calls `sign` and every `path`
+//! it passes. For the agent contract that account is the contract itself, and the path is the
+//! one it signs with (see `get_derivation_path` on the contract when path namespaces are on).
+//! This crate computes those child public keys from the MPC root keys without a network call,
+//! turns them into EVM, Bitcoin, Solana, and NEAR addresses, and converts the signatures the
+//! contract returns into the encodings each chain expects.
+//!
+//! ```
+//! use shade_key_derivation::{BitcoinNetwork, Secp256k1PublicKey};
+//!
+//! # fn main() -> Result<(), shade_key_derivation::DerivationError> {
+//! // The Secp256k1 root key, as returned by the MPC contract's public_key view
+//! let root = Secp256k1PublicKey::from_near_string(
+//!     "secp256k1:3tFRbMqmoa6AAALMrEFAYCEoHcqKxeW38YptwowBVBtXK1vo36HDbUWuR6EZmoK4JcH6HDkNMGGqP1ouV7VZUWya",
+//! )?;
+//! let key = root.derive(&"agent.near".parse().unwrap(), "ethereum-1")?;
+//! println!("{}", key.evm_address());
+//! println!("{}", key.bitcoin_p2wpkh_address(BitcoinNetwork::Mainnet));
+//! # Ok(())
+//! # }
+//! ```
+
+mod ed25519;
+mod secp256k1;
+mod signature;
+
+pub use ed25519::{Ed25519PublicKey, Ed25519Signature};
+pub use near_account_id::AccountId;
+pub use secp256k1::{BitcoinNetwork, Secp256k1PublicKey, evm_checksum_address};
+pub use signature::{
+    AffinePoint, EcdsaSignature, EcdsaSignatureResponse, Scalar, SignatureResponse,
+};
+
+use sha3::{Digest, Sha3_256};
+
+/// Prefix of the string the MPC network hashes into the tweak of a derived key.
+pub const TWEAK_DERIVATION_PREFIX: &str = "near-mpc-recovery v0.1.0 epsilon derivation:";
+
+#[derive(Debug, thiserror::Error)]
+pub enum DerivationError {
+    #[error("invalid public key: {0}")]
+    InvalidPublicKey(String),
+    #[error("invalid signature: {0}")]
+    InvalidSignature(String),
+    #[error("the tweak is not a valid secp256k1 scalar")]
+    InvalidTweak,
+    #[error("invalid signature response JSON: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// SHA3-256 of `TWEAK_DERIVATION_PREFIX`, the predecessor account, a comma, and the path.
+///
+/// Secp256k1 keys add the tweak read as a big-endian scalar times the generator to the root
+/// key, Ed25519 keys the tweak read as a little-endian scalar reduced modulo the group order.
+pub fn derive_tweak(predecessor_id: &AccountId, path: &str) -> [u8; 32] {
+    Sha3_256::digest(format!("{TWEAK_DERIVATION_PREFIX}{predecessor_id},{path}")).into()
+}
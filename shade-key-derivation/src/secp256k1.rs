@@ -0,0 +1,182 @@
+use crate::{AccountId, DerivationError, derive_tweak};
+use bech32::{Hrp, segwit};
+use k256::elliptic_curve::PrimeField;
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+use k256::{ProjectivePoint, PublicKey, Scalar};
+use ripemd::Ripemd160;
+use sha2::{Digest, Sha256};
+use sha3::Keccak256;
+
+const NEAR_KEY_PREFIX: &str = "secp256k1:";
+
+/// A Secp256k1 public key of the MPC network, a root key or a key derived from one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Secp256k1PublicKey(PublicKey);
+
+/// Network a Bitcoin address is encoded for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitcoinNetwork {
+    Mainnet,
+    /// Testnet and signet, which share the `tb` prefix.
+    Testnet,
+    Regtest,
+}
+
+impl BitcoinNetwork {
+    fn hrp(self) -> Hrp {
+        match self {
+            BitcoinNetwork::Mainnet => bech32::hrp::BC,
+            BitcoinNetwork::Testnet => bech32::hrp::TB,
+            BitcoinNetwork::Regtest => bech32::hrp::BCRT,
+        }
+    }
+}
+
+/// The EIP-55 mixed-case checksum encoding of an EVM address, with the `0x` prefix.
+pub fn evm_checksum_address(address: &[u8; 20]) -> String {
+    let lower = hex::encode(address);
+    let hash = Keccak256::digest(lower.as_bytes());
+    let checksummed: String = lower
+        .chars()
+        .enumerate()
+        .map(|(index, char)| {
+            let nibble = (hash[index / 2] >> (4 * (1 - index % 2))) & 0x0f;
+            if nibble >= 8 {
+                char.to_ascii_uppercase()
+            } else {
+                char
+            }
+        })
+        .collect();
+    format!("0x{checksummed}")
+}
+
+// BIP-340 tagged hash
+fn tagged_hash(tag: &str, data: &[u8]) -> [u8; 32] {
+    let tag_hash = Sha256::digest(tag.as_bytes());
+    Sha256::new()
+        .chain_update(tag_hash)
+        .chain_update(tag_hash)
+        .chain_update(data)
+        .finalize()
+        .into()
+}
+
+impl Secp256k1PublicKey {
+    /// Parse a key in the NEAR format, `secp256k1:` and the base58 of the 64 byte
+    /// uncompressed point without its `04` tag, which the MPC contract's `public_key` and
+    /// `derived_public_key` views return.
+    pub fn from_near_string(key: &str) -> Result<Self, DerivationError> {
+        let encoded = key.strip_prefix(NEAR_KEY_PREFIX).ok_or_else(|| {
+            DerivationError::InvalidPublicKey(format!("expected the {NEAR_KEY_PREFIX} prefix"))
+        })?;
+        let bytes = bs58::decode(encoded)
+            .into_vec()
+            .map_err(|error| DerivationError::InvalidPublicKey(error.to_string()))?;
+        if bytes.len() != 64 {
+            return Err(DerivationError::InvalidPublicKey(format!(
+                "expected 64 bytes, got {}",
+                bytes.len()
+            )));
+        }
+        Self::from_sec1_bytes(&[&[0x04], bytes.as_slice()].concat())
+    }
+
+    /// Parse a compressed or uncompressed SEC1 encoded key.
+    pub fn from_sec1_bytes(bytes: &[u8]) -> Result<Self, DerivationError> {
+        PublicKey::from_sec1_bytes(bytes)
+            .map(Self)
+            .map_err(|_| DerivationError::InvalidPublicKey("not a point on secp256k1".to_string()))
+    }
+
+    /// The key in the NEAR format the MPC contract uses.
+    pub fn to_near_string(&self) -> String {
+        let uncompressed = self.to_sec1_bytes(false);
+        format!(
+            "{NEAR_KEY_PREFIX}{}",
+            bs58::encode(&uncompressed[1..]).into_string()
+        )
+    }
+
+    /// The SEC1 encoding of the key, 33 bytes compressed or 65 bytes uncompressed.
+    pub fn to_sec1_bytes(&self, compressed: bool) -> Vec<u8> {
+        self.0.to_encoded_point(compressed).as_bytes().to_vec()
+    }
+
+    /// The key the MPC network signs with for `predecessor_id` and `path`: the root key plus
+    /// the tweak times the generator.
+    pub fn derive(&self, predecessor_id: &AccountId, path: &str) -> Result<Self, DerivationError> {
+        let tweak =
+            Option::<Scalar>::from(Scalar::from_repr(derive_tweak(predecessor_id, path).into()))
+                .ok_or(DerivationError::InvalidTweak)?;
+        let point = ProjectivePoint::GENERATOR * tweak + self.0.to_projective();
+        PublicKey::from_affine(point.to_affine())
+            .map(Self)
+            .map_err(|_| DerivationError::InvalidTweak)
+    }
+
+    /// The EVM address of the key, EIP-55 checksummed.
+    pub fn evm_address(&self) -> String {
+        evm_checksum_address(&self.evm_address_bytes())
+    }
+
+    /// The last 20 bytes of the keccak256 of the uncompressed key without its tag.
+    pub fn evm_address_bytes(&self) -> [u8; 20] {
+        let hash = Keccak256::digest(&self.to_sec1_bytes(false)[1..]);
+        hash[12..].try_into().unwrap()
+    }
+
+    /// The NEAR ETH-implicit account of the key, its lowercase EVM address.
+    pub fn near_eth_implicit_account_id(&self) -> AccountId {
+        format!("0x{}", hex::encode(self.evm_address_bytes()))
+            .parse()
+            .unwrap()
+    }
+
+    /// The P2WPKH output script, `0014` and the HASH160 of the compressed key, which the
+    /// contract's Bitcoin policies and spent outputs use.
+    pub fn bitcoin_p2wpkh_script_pubkey(&self) -> Vec<u8> {
+        [&[0x00, 0x14], self.hash160().as_slice()].concat()
+    }
+
+    /// The native SegWit (bech32) address of the key.
+    pub fn bitcoin_p2wpkh_address(&self, network: BitcoinNetwork) -> String {
+        segwit::encode_v0(network.hrp(), &self.hash160()).unwrap()
+    }
+
+    /// The BIP-86 P2TR output script, `5120` and the x-only output key.
+    ///
+    /// The contract does not sign for taproot outputs, BIP-341 key-path spends need Schnorr
+    /// signatures and the MPC network's Secp256k1 domain produces ECDSA.
+    pub fn bitcoin_p2tr_script_pubkey(&self) -> Vec<u8> {
+        [&[0x51, 0x20], self.taproot_output_key().as_slice()].concat()
+    }
+
+    /// The BIP-86 taproot (bech32m) address of the key, without a script tree.
+    pub fn bitcoin_p2tr_address(&self, network: BitcoinNetwork) -> String {
+        segwit::encode_v1(network.hrp(), &self.taproot_output_key()).unwrap()
+    }
+
+    fn hash160(&self) -> [u8; 20] {
+        Ripemd160::digest(Sha256::digest(self.to_sec1_bytes(true))).into()
+    }
+
+    // x of the internal key with an even y, tweaked by its TapTweak hash
+    fn taproot_output_key(&self) -> [u8; 32] {
+        let compressed = self.to_sec1_bytes(true);
+        let x_only: [u8; 32] = compressed[1..].try_into().unwrap();
+        let internal = if compressed[0] == 0x03 {
+            -self.0.to_projective()
+        } else {
+            self.0.to_projective()
+        };
+        // The hash is below the group order for all but a negligible share of keys
+        let tweak =
+            Option::<Scalar>::from(Scalar::from_repr(tagged_hash("TapTweak", &x_only).into()))
+                .expect("TapTweak hash is a valid scalar");
+        let output = (internal + ProjectivePoint::GENERATOR * tweak).to_affine();
+        output.to_encoded_point(true).as_bytes()[1..]
+            .try_into()
+            .unwrap()
+    }
+}
@@ -0,0 +1,203 @@
+use crate::{DerivationError, Ed25519Signature, Secp256k1PublicKey};
+use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+use k256::elliptic_curve::ops::Reduce;
+use k256::elliptic_curve::point::AffineCoordinates;
+use k256::elliptic_curve::sec1::FromEncodedPoint;
+use k256::elliptic_curve::{PrimeField, scalar::IsHigh};
+use k256::{AffinePoint as K256AffinePoint, EncodedPoint, U256};
+use serde::{Deserialize, Serialize};
+
+/// A signature as the contract's signing functions return it, the same JSON as the MPC
+/// contract's, tagged with its `scheme`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "scheme")]
+pub enum SignatureResponse {
+    Secp256k1(EcdsaSignatureResponse),
+    Ed25519 { signature: Vec<u8> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EcdsaSignatureResponse {
+    pub big_r: AffinePoint,
+    pub s: Scalar,
+    pub recovery_id: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AffinePoint {
+    /// Hex of the compressed point.
+    pub affine_point: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Scalar {
+    /// Hex of the big-endian scalar.
+    pub scalar: String,
+}
+
+/// An ECDSA signature with a low `s`, ready to be encoded for a chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EcdsaSignature {
+    r: [u8; 32],
+    s: [u8; 32],
+    recovery_id: u8,
+}
+
+fn invalid(message: &str) -> DerivationError {
+    DerivationError::InvalidSignature(message.to_string())
+}
+
+impl SignatureResponse {
+    /// Parse the JSON a signing request of the contract returns.
+    pub fn from_json(json: &str) -> Result<Self, DerivationError> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// The ECDSA signature of a `Secp256k1` response.
+    pub fn ecdsa(&self) -> Result<EcdsaSignature, DerivationError> {
+        match self {
+            SignatureResponse::Secp256k1(response) => EcdsaSignature::from_response(response),
+            SignatureResponse::Ed25519 { .. } => Err(invalid("expected a Secp256k1 signature")),
+        }
+    }
+
+    /// The signature of an `Ed25519` response.
+    pub fn ed25519(&self) -> Result<Ed25519Signature, DerivationError> {
+        match self {
+            SignatureResponse::Ed25519 { signature } => {
+                let bytes: [u8; 64] = signature
+                    .as_slice()
+                    .try_into()
+                    .map_err(|_| invalid("expected a 64 byte Ed25519 signature"))?;
+                Ok(Ed25519Signature::from_bytes(bytes))
+            }
+            SignatureResponse::Secp256k1(_) => Err(invalid("expected an Ed25519 signature")),
+        }
+    }
+}
+
+impl EcdsaSignature {
+    /// Decode a response, `r` is the x coordinate of `big_r` reduced modulo the group order.
+    ///
+    /// A high `s` is negated and the parity of `recovery_id` flipped with it, since Bitcoin
+    /// and EVM transactions only accept low `s` values.
+    pub fn from_response(response: &EcdsaSignatureResponse) -> Result<Self, DerivationError> {
+        let big_r = hex::decode(&response.big_r.affine_point)
+            .map_err(|_| invalid("big_r must be a hex string"))?;
+        let big_r = EncodedPoint::from_bytes(&big_r)
+            .ok()
+            .filter(|point| point.is_compressed())
+            .and_then(|point| {
+                Option::<K256AffinePoint>::from(K256AffinePoint::from_encoded_point(&point))
+            })
+            .ok_or_else(|| invalid("big_r must be a compressed point on secp256k1"))?;
+        let r = <k256::Scalar as Reduce<U256>>::reduce_bytes(&big_r.x());
+
+        let s: [u8; 32] = hex::decode(&response.s.scalar)
+            .ok()
+            .and_then(|s| s.try_into().ok())
+            .ok_or_else(|| invalid("s must be the hex of 32 bytes"))?;
+        let s = Option::<k256::Scalar>::from(k256::Scalar::from_repr(s.into()))
+            .ok_or_else(|| invalid("s must be below the group order"))?;
+        if bool::from(r.is_zero()) || bool::from(s.is_zero()) {
+            return Err(invalid("r and s must not be zero"));
+        }
+        if response.recovery_id > 3 {
+            return Err(invalid("recovery_id must be between 0 and 3"));
+        }
+
+        let (s, recovery_id) = if bool::from(s.is_high()) {
+            (-s, response.recovery_id ^ 1)
+        } else {
+            (s, response.recovery_id)
+        };
+        Ok(Self {
+            r: r.to_bytes().into(),
+            s: s.to_bytes().into(),
+            recovery_id,
+        })
+    }
+
+    pub fn r(&self) -> [u8; 32] {
+        self.r
+    }
+
+    pub fn s(&self) -> [u8; 32] {
+        self.s
+    }
+
+    /// The parity of the y coordinate of `R` in bit 0, and whether its x coordinate was at
+    /// least the group order in bit 1, which practically never happens.
+    pub fn recovery_id(&self) -> u8 {
+        self.recovery_id
+    }
+
+    /// The `y_parity` of typed EVM transactions, EIP-2930 and EIP-1559.
+    pub fn y_parity(&self) -> u8 {
+        self.recovery_id & 1
+    }
+
+    /// The `v` of a legacy EVM transaction, EIP-155 with a chain ID and 27 or 28 without.
+    pub fn evm_v(&self, chain_id: Option<u64>) -> u64 {
+        let y_parity = u64::from(self.y_parity());
+        match chain_id {
+            Some(chain_id) => chain_id * 2 + 35 + y_parity,
+            None => 27 + y_parity,
+        }
+    }
+
+    /// `r`, `s`, and `v` as 27 or 28, the 65 byte encoding of `personal_sign` and `ecrecover`.
+    pub fn to_evm_bytes(&self) -> [u8; 65] {
+        let mut bytes = [0; 65];
+        bytes[..32].copy_from_slice(&self.r);
+        bytes[32..64].copy_from_slice(&self.s);
+        bytes[64] = 27 + self.y_parity();
+        bytes
+    }
+
+    /// The DER encoding Bitcoin uses, without the sighash type.
+    pub fn to_der(&self) -> Vec<u8> {
+        let body = [der_integer(&self.r), der_integer(&self.s)].concat();
+        [vec![0x30, body.len() as u8], body].concat()
+    }
+
+    /// The DER encoding followed by `sighash_type`, as it goes in a witness or scriptSig.
+    /// The contract signs `SIGHASH_ALL` digests, type `0x01`.
+    pub fn to_bitcoin_bytes(&self, sighash_type: u8) -> Vec<u8> {
+        [self.to_der(), vec![sighash_type]].concat()
+    }
+
+    /// The signature in the NEAR format, `secp256k1:` and the base58 of `r`, `s`, and the
+    /// parity as 0 or 1.
+    pub fn to_near_string(&self) -> String {
+        let mut bytes = [0; 65];
+        bytes[..32].copy_from_slice(&self.r);
+        bytes[32..64].copy_from_slice(&self.s);
+        bytes[64] = self.y_parity();
+        format!("secp256k1:{}", bs58::encode(bytes).into_string())
+    }
+
+    /// The key that produced the signature over `prehash`, to check it against a derived key.
+    pub fn recover_public_key(
+        &self,
+        prehash: &[u8; 32],
+    ) -> Result<Secp256k1PublicKey, DerivationError> {
+        let signature = Signature::from_scalars(self.r, self.s)
+            .map_err(|_| invalid("r and s do not form a signature"))?;
+        let recovery_id = RecoveryId::from_byte(self.recovery_id)
+            .ok_or_else(|| invalid("recovery_id must be between 0 and 3"))?;
+        let key = VerifyingKey::recover_from_prehash(prehash, &signature, recovery_id)
+            .map_err(|_| invalid("no key recovers from the signature"))?;
+        Secp256k1PublicKey::from_sec1_bytes(key.to_encoded_point(true).as_bytes())
+    }
+}
+
+// A DER INTEGER of a positive big-endian number, minimal with a zero byte before a high bit
+fn der_integer(bytes: &[u8; 32]) -> Vec<u8> {
+    let start = bytes.iter().position(|byte| *byte != 0).unwrap_or(31);
+    let mut integer = bytes[start..].to_vec();
+    if integer[0] & 0x80 != 0 {
+        integer.insert(0, 0);
+    }
+    [vec![0x02, integer.len() as u8], integer].concat()
+}
@@ -0,0 +1,462 @@
+use curve25519_dalek::{EdwardsPoint, Scalar as EdScalar};
+use ed25519_dalek::hazmat::{ExpandedSecretKey, raw_sign};
+use k256::ecdsa::SigningKey;
+use k256::elliptic_curve::PrimeField;
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+use sha2::Sha512;
+use sha3::{Digest, Sha3_256};
+use shade_key_derivation::{
+    AccountId, AffinePoint, BitcoinNetwork, DerivationError, EcdsaSignature,
+    EcdsaSignatureResponse, Ed25519PublicKey, Ed25519Signature, Scalar, Secp256k1PublicKey,
+    SignatureResponse, derive_tweak, evm_checksum_address,
+};
+
+const GENERATOR: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+
+fn predecessor() -> AccountId {
+    "agent-contract.near".parse().unwrap()
+}
+
+fn secp256k1_key_of(secret: &k256::Scalar) -> Secp256k1PublicKey {
+    let point = (k256::ProjectivePoint::GENERATOR * secret).to_affine();
+    Secp256k1PublicKey::from_sec1_bytes(point.to_encoded_point(true).as_bytes()).unwrap()
+}
+
+fn secp256k1_secret(byte: u8) -> k256::Scalar {
+    let mut bytes = [0; 32];
+    bytes[31] = byte;
+    k256::Scalar::from_repr(bytes.into()).unwrap()
+}
+
+fn secp256k1_tweak(path: &str) -> k256::Scalar {
+    k256::Scalar::from_repr(derive_tweak(&predecessor(), path).into()).unwrap()
+}
+
+// -------- tweak --------
+
+#[test]
+fn test_tweak_hashes_predecessor_and_path() {
+    let expected: [u8; 32] = Sha3_256::digest(
+        "near-mpc-recovery v0.1.0 epsilon derivation:agent-contract.near,ethereum-1",
+    )
+    .into();
+    assert_eq!(derive_tweak(&predecessor(), "ethereum-1"), expected);
+    assert_ne!(
+        derive_tweak(&predecessor(), "ethereum-1"),
+        derive_tweak(&predecessor(), "ethereum-2")
+    );
+}
+
+// -------- Secp256k1 --------
+
+#[test]
+fn test_secp256k1_derivation_adds_the_tweak_to_the_secret() {
+    let root_secret = secp256k1_secret(42);
+    let root = secp256k1_key_of(&root_secret);
+    for path in ["", "ethereum-1", "account:agent.near/bitcoin"] {
+        let derived = root.derive(&predecessor(), path).unwrap();
+        assert_eq!(
+            derived,
+            secp256k1_key_of(&(root_secret + secp256k1_tweak(path)))
+        );
+    }
+    assert_ne!(
+        root.derive(&predecessor(), "ethereum-1").unwrap(),
+        root.derive(&"other.near".parse().unwrap(), "ethereum-1")
+            .unwrap()
+    );
+}
+
+#[test]
+fn test_secp256k1_near_string_round_trip() {
+    let key = secp256k1_key_of(&secp256k1_secret(1));
+    let near = key.to_near_string();
+    assert!(near.starts_with("secp256k1:"));
+    assert_eq!(Secp256k1PublicKey::from_near_string(&near).unwrap(), key);
+    // The uncompressed point without its tag, as the MPC contract encodes it
+    let bytes = bs58::decode(&near["secp256k1:".len()..])
+        .into_vec()
+        .unwrap();
+    assert_eq!(bytes, key.to_sec1_bytes(false)[1..]);
+
+    for invalid in [
+        "ed25519:11111111111111111111111111111111",
+        "secp256k1:0OIl",
+        "secp256k1:11111111111111111111111111111111",
+    ] {
+        assert!(matches!(
+            Secp256k1PublicKey::from_near_string(invalid),
+            Err(DerivationError::InvalidPublicKey(_))
+        ));
+    }
+    // 64 bytes that are not a point on the curve
+    let off_curve = format!("secp256k1:{}", bs58::encode([1u8; 64]).into_string());
+    assert!(Secp256k1PublicKey::from_near_string(&off_curve).is_err());
+}
+
+// EIP-55 examples
+#[test]
+fn test_evm_checksum_address() {
+    for address in [
+        "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+        "0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359",
+        "0xdbF03B407c01E7cD3CBea99509d93f8DDDC8C6FB",
+        "0xD1220A0cf47c7B9Be7A2E6BA89F429762e7b9aDb",
+    ] {
+        let bytes: [u8; 20] = hex::decode(&address[2..]).unwrap().try_into().unwrap();
+        assert_eq!(evm_checksum_address(&bytes), address);
+    }
+}
+
+// The addresses of the secret keys 1 and 2
+#[test]
+fn test_evm_address() {
+    let key = secp256k1_key_of(&secp256k1_secret(1));
+    assert_eq!(
+        key.evm_address(),
+        "0x7E5F4552091A69125d5DfCb7b8C2659029395Bdf"
+    );
+    assert_eq!(
+        key.near_eth_implicit_account_id().as_str(),
+        "0x7e5f4552091a69125d5dfcb7b8c2659029395bdf"
+    );
+    assert_eq!(
+        secp256k1_key_of(&secp256k1_secret(2)).evm_address(),
+        "0x2B5AD5c4795c026514f8317c7a215E218DcCD6cF"
+    );
+}
+
+// BIP-173 examples
+#[test]
+fn test_bitcoin_p2wpkh_address() {
+    let key = Secp256k1PublicKey::from_sec1_bytes(&hex::decode(GENERATOR).unwrap()).unwrap();
+    assert_eq!(
+        key.bitcoin_p2wpkh_address(BitcoinNetwork::Mainnet),
+        "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"
+    );
+    assert_eq!(
+        key.bitcoin_p2wpkh_address(BitcoinNetwork::Testnet),
+        "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx"
+    );
+    assert!(
+        key.bitcoin_p2wpkh_address(BitcoinNetwork::Regtest)
+            .starts_with("bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7k")
+    );
+    assert_eq!(
+        hex::encode(key.bitcoin_p2wpkh_script_pubkey()),
+        "0014751e76e8199196d454941c45d1b3a323f1433bd6"
+    );
+}
+
+// BIP-86 examples, the internal keys are x-only so they are lifted with an even y
+#[test]
+fn test_bitcoin_p2tr_address() {
+    for (internal_key, output_key, address) in [
+        (
+            "cc8a4bc64d897bddc5fbc2f670f7a8ba0b386779106cf1223c6fc5d7cd6fc115",
+            "a60869f0dbcf1dc659c9cecbaf8050135ea9e8cdc487053f1dc6880949dc684c",
+            "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr",
+        ),
+        (
+            "83dfe85a3151d2517290da461fe2815591ef69f2b18a2ce63f01697a8b313145",
+            "a82f29944d65b86ae6b5e5cc75e294ead6c59391a1edc5e016e3498c67fc7bbb",
+            "bc1p4qhjn9zdvkux4e44uhx8tc55attvtyu358kutcqkudyccelu0was9fqzwh",
+        ),
+    ] {
+        let key =
+            Secp256k1PublicKey::from_sec1_bytes(&hex::decode(format!("02{internal_key}")).unwrap())
+                .unwrap();
+        assert_eq!(key.bitcoin_p2tr_address(BitcoinNetwork::Mainnet), address);
+        assert_eq!(
+            hex::encode(key.bitcoin_p2tr_script_pubkey()),
+            format!("5120{output_key}")
+        );
+        // The same x with an odd y has the same taproot address
+        let odd =
+            Secp256k1PublicKey::from_sec1_bytes(&hex::decode(format!("03{internal_key}")).unwrap())
+                .unwrap();
+        assert_eq!(odd.bitcoin_p2tr_address(BitcoinNetwork::Mainnet), address);
+    }
+}
+
+// -------- Ed25519 --------
+
+fn ed25519_secret(byte: u8) -> EdScalar {
+    EdScalar::from_bytes_mod_order([byte; 32])
+}
+
+fn ed25519_key_of(secret: &EdScalar) -> Ed25519PublicKey {
+    Ed25519PublicKey::from_bytes(EdwardsPoint::mul_base(secret).compress().to_bytes()).unwrap()
+}
+
+#[test]
+fn test_ed25519_derivation_adds_the_tweak_to_the_secret() {
+    let root_secret = ed25519_secret(7);
+    let root = ed25519_key_of(&root_secret);
+    for path in ["", "solana-1", "measurements:0/wallet"] {
+        let tweak = EdScalar::from_bytes_mod_order(derive_tweak(&predecessor(), path));
+        assert_eq!(
+            root.derive(&predecessor(), path).unwrap(),
+            ed25519_key_of(&(root_secret + tweak))
+        );
+    }
+}
+
+#[test]
+fn test_ed25519_addresses() {
+    // The System program's address is the all-zero key
+    let zero = Ed25519PublicKey::from_bytes([0; 32]).unwrap();
+    assert_eq!(zero.solana_address(), "11111111111111111111111111111111");
+
+    // RFC 8032 test 1
+    let key = Ed25519PublicKey::from_bytes(
+        hex::decode("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a")
+            .unwrap()
+            .try_into()
+            .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(
+        key.near_implicit_account_id().as_str(),
+        "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a"
+    );
+    assert_eq!(
+        Ed25519PublicKey::from_near_string(&key.to_near_string()).unwrap(),
+        key
+    );
+    assert_eq!(
+        key.solana_address(),
+        key.to_near_string()["ed25519:".len()..]
+    );
+
+    assert!(Ed25519PublicKey::from_near_string("secp256k1:1111").is_err());
+    assert!(Ed25519PublicKey::from_near_string("ed25519:1111").is_err());
+}
+
+// RFC 8032 tests 1 and 2
+#[test]
+fn test_ed25519_verify() {
+    for (public_key, message, signature) in [
+        (
+            "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+            "",
+            "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+        ),
+        (
+            "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+            "72",
+            "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+        ),
+    ] {
+        let key =
+            Ed25519PublicKey::from_bytes(hex::decode(public_key).unwrap().try_into().unwrap())
+                .unwrap();
+        let message = hex::decode(message).unwrap();
+        let mut signature: [u8; 64] = hex::decode(signature).unwrap().try_into().unwrap();
+        assert!(Ed25519Signature::from_bytes(signature).verify(&key, &message));
+        assert!(!Ed25519Signature::from_bytes(signature).verify(&key, b"other"));
+        signature[63] ^= 0x10;
+        assert!(!Ed25519Signature::from_bytes(signature).verify(&key, &message));
+    }
+}
+
+// -------- Signature responses --------
+
+// Sign like the MPC network would with the derived secret and return the contract's JSON
+fn mpc_ecdsa_response(secret: &k256::Scalar, prehash: &[u8; 32], high_s: bool) -> String {
+    let signing_key = SigningKey::from_bytes(&secret.to_bytes()).unwrap();
+    let (signature, recovery_id) = signing_key.sign_prehash_recoverable(prehash).unwrap();
+    let (r, s) = signature.split_scalars();
+    let (s, y_odd) = if high_s {
+        (-*s, !recovery_id.is_y_odd())
+    } else {
+        (*s, recovery_id.is_y_odd())
+    };
+    let big_r = format!(
+        "{}{}",
+        if y_odd { "03" } else { "02" },
+        hex::encode(r.to_bytes())
+    );
+    serde_json::json!({
+        "scheme": "Secp256k1",
+        "big_r": { "affine_point": big_r },
+        "s": { "scalar": hex::encode(s.to_bytes()) },
+        "recovery_id": u8::from(y_odd),
+    })
+    .to_string()
+}
+
+#[test]
+fn test_ecdsa_response_recovers_the_derived_key() {
+    let root_secret = secp256k1_secret(42);
+    let root = secp256k1_key_of(&root_secret);
+    let derived = root.derive(&predecessor(), "ethereum-1").unwrap();
+    let derived_secret = root_secret + secp256k1_tweak("ethereum-1");
+    let prehash = [0x5a; 32];
+
+    for high_s in [false, true] {
+        let json = mpc_ecdsa_response(&derived_secret, &prehash, high_s);
+        let signature = SignatureResponse::from_json(&json)
+            .unwrap()
+            .ecdsa()
+            .unwrap();
+        assert_eq!(signature.recover_public_key(&prehash).unwrap(), derived);
+
+        // Low s whatever the MPC network returned
+        let s = k256::Scalar::from_repr(signature.s().into()).unwrap();
+        assert!(!bool::from(k256::elliptic_curve::scalar::IsHigh::is_high(
+            &s
+        )));
+
+        let evm = signature.to_evm_bytes();
+        assert_eq!(evm[..32], signature.r());
+        assert_eq!(evm[32..64], signature.s());
+        assert_eq!(evm[64], 27 + signature.y_parity());
+        assert_eq!(signature.evm_v(None), 27 + u64::from(signature.y_parity()));
+        assert_eq!(
+            signature.evm_v(Some(1)),
+            37 + u64::from(signature.y_parity())
+        );
+
+        let near = bs58::decode(&signature.to_near_string()["secp256k1:".len()..])
+            .into_vec()
+            .unwrap();
+        assert_eq!(near[64], signature.y_parity());
+    }
+}
+
+#[test]
+fn test_ecdsa_der_encoding() {
+    let signature = |r: [u8; 32], s: [u8; 32]| {
+        EcdsaSignature::from_response(&EcdsaSignatureResponse {
+            big_r: AffinePoint {
+                affine_point: format!("02{}", hex::encode(r)),
+            },
+            s: Scalar {
+                scalar: hex::encode(s),
+            },
+            recovery_id: 0,
+        })
+        .unwrap()
+    };
+    // r is the x of G, with its high bit clear, s is 1
+    let r: [u8; 32] = hex::decode(&GENERATOR[2..]).unwrap().try_into().unwrap();
+    let mut one = [0; 32];
+    one[31] = 1;
+    let der = signature(r, one).to_der();
+    assert_eq!(
+        hex::encode(&der),
+        format!("30250220{}020101", &GENERATOR[2..])
+    );
+    assert_eq!(
+        signature(r, one).to_bitcoin_bytes(0x01),
+        [der, vec![0x01]].concat()
+    );
+
+    // An r with its high bit set gets a leading zero byte
+    let big_r = secp256k1_key_of(&secp256k1_secret(3)).to_sec1_bytes(true);
+    assert!(big_r[1] & 0x80 != 0);
+    let der = signature(big_r[1..].try_into().unwrap(), one).to_der();
+    assert_eq!(der[..5], [0x30, 0x26, 0x02, 0x21, 0x00]);
+    assert_eq!(der[5..37], big_r[1..]);
+}
+
+#[test]
+fn test_invalid_ecdsa_responses() {
+    let valid = EcdsaSignatureResponse {
+        big_r: AffinePoint {
+            affine_point: GENERATOR.to_string(),
+        },
+        s: Scalar {
+            scalar: "01".repeat(32),
+        },
+        recovery_id: 0,
+    };
+    assert!(EcdsaSignature::from_response(&valid).is_ok());
+    let uncompressed = hex::encode(secp256k1_key_of(&secp256k1_secret(1)).to_sec1_bytes(false));
+    for response in [
+        EcdsaSignatureResponse {
+            big_r: AffinePoint {
+                affine_point: format!("02{}", "ff".repeat(32)),
+            },
+            ..valid.clone()
+        },
+        EcdsaSignatureResponse {
+            big_r: AffinePoint {
+                affine_point: uncompressed,
+            },
+            ..valid.clone()
+        },
+        EcdsaSignatureResponse {
+            s: Scalar {
+                scalar: "00".repeat(32),
+            },
+            ..valid.clone()
+        },
+        EcdsaSignatureResponse {
+            s: Scalar {
+                scalar: "ff".repeat(32),
+            },
+            ..valid.clone()
+        },
+        EcdsaSignatureResponse {
+            s: Scalar {
+                scalar: "01".repeat(31),
+            },
+            ..valid.clone()
+        },
+        EcdsaSignatureResponse {
+            recovery_id: 4,
+            ..valid.clone()
+        },
+    ] {
+        assert!(matches!(
+            EcdsaSignature::from_response(&response),
+            Err(DerivationError::InvalidSignature(_))
+        ));
+    }
+}
+
+#[test]
+fn test_ed25519_response_verifies_against_the_derived_key() {
+    let root_secret = ed25519_secret(7);
+    let root = ed25519_key_of(&root_secret);
+    let derived = root.derive(&predecessor(), "solana-1").unwrap();
+    let tweak = EdScalar::from_bytes_mod_order(derive_tweak(&predecessor(), "solana-1"));
+    let secret = ExpandedSecretKey {
+        scalar: root_secret + tweak,
+        hash_prefix: [9; 32],
+    };
+    let verifying_key = ed25519_dalek::VerifyingKey::from_bytes(&derived.to_bytes()).unwrap();
+    let message = b"solana message";
+    let signature = raw_sign::<Sha512>(&secret, message, &verifying_key).to_bytes();
+
+    let json = serde_json::json!({ "scheme": "Ed25519", "signature": signature.to_vec() });
+    let response = SignatureResponse::from_json(&json.to_string()).unwrap();
+    let parsed = response.ed25519().unwrap();
+    assert!(parsed.verify(&derived, message));
+    assert!(!parsed.verify(&root, message));
+    assert!(
+        verifying_key
+            .verify_strict(message, &ed25519_dalek::Signature::from_bytes(&signature))
+            .is_ok()
+    );
+    assert_eq!(
+        parsed.to_solana_string(),
+        bs58::encode(signature).into_string()
+    );
+    assert_eq!(
+        parsed.to_near_string(),
+        format!("ed25519:{}", parsed.to_solana_string())
+    );
+
+    assert!(response.ecdsa().is_err());
+    let short = SignatureResponse::Ed25519 {
+        signature: vec![1; 63],
+    };
+    assert!(short.ed25519().is_err());
+    assert!(matches!(
+        SignatureResponse::from_json(r#"{"scheme":"Secp256r1"}"#),
+        Err(DerivationError::Json(_))
+    ));
+}
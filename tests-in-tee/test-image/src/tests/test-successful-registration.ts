@@ -35,6 +35,7 @@ export default async function testSuccessfulRegistration(
             "b1bce08af8ed85b255f9fa2fe98b8feafa1460959d886e3914d533eca11cb6c6",
           key_type: "Ecdsa",
         },
+        deposit: "1",
       });
     } catch (error: any) {
       callError = error.message || String(error);